@@ -641,6 +641,7 @@ export class AppComponent {
         if (response.success && response.user_id) {
           this.userId = response.user_id;
           localStorage.setItem('user_id', this.userId.toString());
+          localStorage.setItem('session_token', response.token || '');
           this.isAuthenticated = true;
           this.setView('swipe');
         } else {
@@ -678,6 +679,7 @@ export class AppComponent {
         if (res.success) {
           this.userId = Number(res.user_id || 0);
           localStorage.setItem('user_id', this.userId.toString());
+          localStorage.setItem('session_token', res.token || '');
           this.isAuthenticated = true;
           this.setView('profile');
           this.showRegister = false;
@@ -698,6 +700,7 @@ export class AppComponent {
     this.userId = 0;
     this.hasProfile = false;
     localStorage.removeItem('user_id');
+    localStorage.removeItem('session_token');
     this.loginForm = { email: '', password: '' };
   }
 
@@ -15,7 +15,7 @@ import { BrowserModule } from '@angular/platform-browser';
 // Import forms module for two-way data binding with ngModel
 import { FormsModule } from '@angular/forms';
 // Import HTTP client module for making API requests
-import { HttpClientModule } from '@angular/common/http';
+import { HTTP_INTERCEPTORS, HttpClientModule } from '@angular/common/http';
 
 // Import application components
 import { AppComponent } from './app.component';
@@ -23,6 +23,8 @@ import { SwipeInterfaceComponent } from './components/swipe-interface/swipe-inte
 import { ChatWindowComponent } from './components/chat-window/chat-window.component';
 // Import routing module
 import { AppRoutingModule } from './app-routing.module';
+// Import interceptor that sends the session token
+import { AuthInterceptor } from './services/auth.interceptor';
 
 // Angular module decorator - this is the root module
 @NgModule({
@@ -39,8 +41,10 @@ import { AppRoutingModule } from './app-routing.module';
     HttpClientModule,   // For HTTP requests
     AppRoutingModule    // For routing
   ],
-  // Service providers (empty for now)
-  providers: [],
+  // Service providers
+  providers: [
+    { provide: HTTP_INTERCEPTORS, useClass: AuthInterceptor, multi: true }  // Adds the session token to requests
+  ],
   // Bootstrap component - the root component that starts the app
   bootstrap: [AppComponent]
 })
@@ -0,0 +1,29 @@
+/*
+Name: auth.interceptor.ts
+Description: Sends the logged in user's session token with every API request
+Programmer: Maren, Ibrahim, Zack
+Dates: 11/23/2025
+Revision: 1
+Pre/Post Conditions: The token returned by login or registration is stored in localStorage under session_token. Requests are sent unchanged when there is no token.
+Errors: None
+*/
+
+// Import Angular injectable decorator for dependency injection
+import { Injectable } from '@angular/core';
+// Import HTTP types for intercepting outgoing requests
+import { HttpEvent, HttpHandler, HttpInterceptor, HttpRequest } from '@angular/common/http';
+// Import Observable for handling asynchronous responses
+import { Observable } from 'rxjs';
+
+// Define the interceptor that adds the Authorization: Bearer header
+// The server uses it to tell which user is viewing profiles and making blocks
+@Injectable()
+export class AuthInterceptor implements HttpInterceptor {
+  intercept(req: HttpRequest<any>, next: HttpHandler): Observable<HttpEvent<any>> {
+    const token = localStorage.getItem('session_token');
+    if (!token) {
+      return next.handle(req);
+    }
+    return next.handle(req.clone({ setHeaders: { Authorization: `Bearer ${token}` } }));
+  }
+}
@@ -27,6 +27,7 @@ export interface RegisterResponse {
   success: boolean;  // Whether registration was successful
   message: string;   // Response message from server
   user_id?: number;  // Optional user ID if registration succeeds
+  token?: string;    // Session token sent with later requests
 }
 
 // Structure for the login payload data
@@ -40,6 +41,7 @@ export interface LoginResponse {
   success: boolean;  // Whether login was successful
   message: string;   // Response message from server
   user_id?: number;  // Optional user ID if login succeeds
+  token?: string;    // Session token sent with later requests
 }
 
 // Define the authentication service
@@ -36,7 +36,6 @@ export interface ProfileUpsertDto {
   year?: string | null;         // User's academic year
   bio?: string | null;          // User's biography/description
   interests?: string[] | null;   // Array of user's interests
-  profile_picture?: string | null; // Path to profile picture file
   gender?: string | null;        // User's gender
 }
 
@@ -0,0 +1,705 @@
+/*
+Name: JayMatch profile attributes
+Description: Loads the declarative schema of profile attributes and uses it to validate profile values, describe the filter options and filter the swipe queue
+Pre/Post Conditions: The schema is read once from attributes.json (or the file named by ATTRIBUTE_SCHEMA) the first time it is used. Attributes with their own profiles column are stored there; any other attribute is stored in the profiles.attributes JSON column, and its filter in preferences.filters.
+Errors: An unreadable or invalid schema file stops the server at startup. Invalid values from clients are returned as messages for a 400 response.
+*/
+
+use crate::vocabulary;
+use log::{info, warn};
+use rusqlite::types::Value as Sql;
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::fs;
+use std::sync::LazyLock;
+
+// Schema file read from the working directory when ATTRIBUTE_SCHEMA is not set
+pub const DEFAULT_SCHEMA_PATH: &str = "attributes.json";
+
+// Copy of the default schema used when the server runs without its config file
+const BUILTIN_SCHEMA: &str = include_str!("../attributes.json");
+
+// Attributes that have their own column in profiles
+const COLUMN_ATTRIBUTES: &[&str] = &["gender", "age", "major", "year", "is_felon"];
+
+// Filters that have their own column in preferences
+const PREFERENCE_COLUMNS: &[&str] = &[
+    "gender_preference",
+    "min_age",
+    "max_age",
+    "year_preference",
+    "major_preference",
+    "is_felon",
+];
+
+// Profile fields that are not attributes, so attributes cannot use their names
+const RESERVED_NAMES: &[&str] = &[
+    "user_id",
+    "email",
+    "password",
+    "name",
+    "bio",
+    "interests",
+    "profile_picture",
+    "photos",
+    "attributes",
+];
+
+// Choice lists longer than this are not repeated in error messages
+const MAX_LISTED_VALUES: usize = 10;
+
+// Kinds of value an attribute can hold
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AttributeType {
+    Choice,  // One of a fixed list of values
+    Integer, // Whole number, optionally limited by min and max
+    Boolean, // True or false
+    Text,    // Free text, optionally limited by max_length; cannot be filtered on
+}
+
+// Structure for one attribute in the schema
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Attribute {
+    pub name: String,  // Key in profile JSON
+    pub label: String, // Name shown to users
+    #[serde(rename = "type")]
+    pub kind: AttributeType,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub values: Vec<String>, // Allowed values of a choice, in display order
+    #[serde(default, skip_serializing)]
+    pub aliases: BTreeMap<String, String>, // Other spellings of choice values
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_length: Option<usize>,
+    #[serde(default)]
+    pub filterable: bool, // Users can filter their queue on it
+    #[serde(default)]
+    pub dealbreaker: bool, // Profiles failing the filter are removed rather than ranked lower
+    #[serde(default, skip_serializing)]
+    pub options_key: Option<String>, // Key used in GET /preference-options, default {name}_options
+}
+
+// Structure for the whole attribute schema
+#[derive(Deserialize, Debug)]
+pub struct Schema {
+    pub attributes: Vec<Attribute>,
+}
+
+static SCHEMA: LazyLock<Schema> = LazyLock::new(load_schema);
+
+// Function for getting the attribute schema, loading it on first use
+pub fn schema() -> &'static Schema {
+    &SCHEMA
+}
+
+// Helper function for reading the schema from ATTRIBUTE_SCHEMA, attributes.json or the built-in copy
+// Panics if the file is unreadable or invalid so a bad config stops the server at startup
+fn load_schema() -> Schema {
+    let (source, text) = match std::env::var("ATTRIBUTE_SCHEMA") {
+        Ok(path) => match fs::read_to_string(&path) {
+            Ok(text) => (path, text),
+            Err(e) => panic!("Failed to read attribute schema {}: {}", path, e),
+        },
+        Err(_) => match fs::read_to_string(DEFAULT_SCHEMA_PATH) {
+            Ok(text) => (DEFAULT_SCHEMA_PATH.to_string(), text),
+            Err(_) => ("built-in schema".to_string(), BUILTIN_SCHEMA.to_string()),
+        },
+    };
+    match parse_schema(&text) {
+        Ok(schema) => {
+            info!(
+                "Loaded {} profile attributes from {}",
+                schema.attributes.len(),
+                source
+            );
+            schema
+        }
+        Err(e) => panic!("Invalid attribute schema {}: {}", source, e),
+    }
+}
+
+// Function for parsing and checking a schema file
+// Returns a description of the first problem found
+pub fn parse_schema(text: &str) -> Result<Schema, String> {
+    let schema: Schema = serde_json::from_str(text).map_err(|e| e.to_string())?;
+    let mut names: Vec<&str> = Vec::new();
+    for attr in &schema.attributes {
+        let valid_name = !attr.name.is_empty()
+            && attr
+                .name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+        if !valid_name || RESERVED_NAMES.contains(&attr.name.as_str()) {
+            return Err(format!(
+                "{:?} cannot be used as an attribute name",
+                attr.name
+            ));
+        }
+        if names.contains(&attr.name.as_str()) {
+            return Err(format!("{} is defined more than once", attr.name));
+        }
+        names.push(&attr.name);
+        if attr.kind == AttributeType::Choice && attr.values.is_empty() {
+            return Err(format!("{} is a choice with no values", attr.name));
+        }
+        if attr.kind == AttributeType::Text && attr.filterable {
+            return Err(format!("{} is text and cannot be filterable", attr.name));
+        }
+        if attr.dealbreaker && !attr.filterable {
+            return Err(format!("{} is a dealbreaker but not filterable", attr.name));
+        }
+        if let Some((alias, _)) = attr.aliases.iter().find(|(_, v)| !attr.values.contains(v)) {
+            return Err(format!(
+                "Alias {} of {} is not one of its values",
+                alias, attr.name
+            ));
+        }
+    }
+    Ok(schema)
+}
+
+impl Schema {
+    // Looks up an attribute by name
+    pub fn get(&self, name: &str) -> Option<&Attribute> {
+        self.attributes.iter().find(|a| a.name == name)
+    }
+
+    // Attributes users can filter their queue on
+    pub fn filterable(&self) -> impl Iterator<Item = &Attribute> {
+        self.attributes.iter().filter(|a| a.filterable)
+    }
+
+    // Body of GET /preference-options
+    // Lists the values of each filterable attribute under its options key, plus the full schema
+    pub fn options_json(&self) -> Value {
+        let mut options = Map::new();
+        for attr in self.filterable() {
+            let values = match attr.kind {
+                AttributeType::Choice => serde_json::json!(attr.values),
+                AttributeType::Boolean => serde_json::json!([true, false]),
+                _ => serde_json::json!({"min": attr.min, "max": attr.max}),
+            };
+            let key = attr
+                .options_key
+                .clone()
+                .unwrap_or_else(|| format!("{}_options", attr.name));
+            options.insert(key, values);
+        }
+        let attributes: Vec<Value> = self
+            .attributes
+            .iter()
+            .map(|attr| {
+                let mut json = serde_json::to_value(attr).unwrap_or_default();
+                json["filter_keys"] = serde_json::json!(attr.filter_keys());
+                json
+            })
+            .collect();
+        options.insert("attributes".to_string(), Value::Array(attributes));
+        Value::Object(options)
+    }
+
+    // Checks the body of PUT /preferences
+    // Returns every filter key in the schema with its canonical value, or null if it was not given
+    // Keys that are not filters are ignored
+    pub fn validate_filters(
+        &self,
+        body: &Map<String, Value>,
+    ) -> Result<Map<String, Value>, String> {
+        let mut filters = Map::new();
+        for attr in self.filterable() {
+            for key in attr.filter_keys() {
+                let value = match body.get(&key) {
+                    Some(value) => attr.validate_filter(value)?,
+                    None => Value::Null,
+                };
+                filters.insert(key, value);
+            }
+            if attr.kind == AttributeType::Integer {
+                let min = filters[&format!("min_{}", attr.name)].as_i64();
+                let max = filters[&format!("max_{}", attr.name)].as_i64();
+                if let (Some(min), Some(max)) = (min, max)
+                    && min > max
+                {
+                    return Err(format!(
+                        "min_{} cannot be more than max_{}",
+                        attr.name, attr.name
+                    ));
+                }
+            }
+        }
+        Ok(filters)
+    }
+
+    // Writes a user's filters as SQL over the profiles table, for the queue query
+    // Placeholders are numbered from first_param so they follow the query's own parameters
+    pub fn filter_sql(&self, filters: &Map<String, Value>, first_param: usize) -> FilterSql {
+        let mut dealbreakers: Vec<String> = Vec::new();
+        let mut misses: Vec<String> = Vec::new();
+        let mut params: Vec<Sql> = Vec::new();
+        for attr in self.filterable() {
+            let Some(condition) = attr.filter_condition(filters, first_param, &mut params) else {
+                continue;
+            };
+            if attr.dealbreaker {
+                dealbreakers.push(condition);
+            } else {
+                misses.push(format!("(NOT {})", condition));
+            }
+        }
+        FilterSql {
+            dealbreakers: if dealbreakers.is_empty() {
+                "1".to_string()
+            } else {
+                dealbreakers.join(" AND ")
+            },
+            // A lone 0 in ORDER BY would be read as a column number
+            misses: if misses.is_empty() {
+                "0 + 0".to_string()
+            } else {
+                format!("({})", misses.join(" + "))
+            },
+            params,
+        }
+    }
+}
+
+// Structure for a user's filters written as SQL
+pub struct FilterSql {
+    pub dealbreakers: String, // Condition a profile must meet to be in the queue
+    pub misses: String,       // Number of other filters a profile fails
+    pub params: Vec<Sql>,     // Values of the placeholders in dealbreakers and misses
+}
+
+impl Attribute {
+    // Maps text typed by a user to one of the attribute's values
+    // Checks the values first and then the aliases, ignoring case, spacing and punctuation
+    pub fn canonical_choice(&self, value: &str) -> Option<&str> {
+        vocabulary::canonicalize(value, &self.values, &self.aliases)
+    }
+
+    // Message for a value that is not allowed
+    fn invalid(&self) -> String {
+        match self.kind {
+            AttributeType::Choice if self.values.len() <= MAX_LISTED_VALUES => {
+                format!("Invalid {}. Allowed: {}", self.name, self.values.join(", "))
+            }
+            AttributeType::Choice => format!(
+                "Invalid {}. See /preference-options for the allowed values",
+                self.name
+            ),
+            AttributeType::Integer => match (self.min, self.max) {
+                (Some(min), Some(max)) => {
+                    format!(
+                        "{} must be a whole number from {} to {}",
+                        self.name, min, max
+                    )
+                }
+                _ => format!("{} must be a whole number", self.name),
+            },
+            AttributeType::Boolean => format!("{} must be true or false", self.name),
+            AttributeType::Text => match self.max_length {
+                Some(max) => format!("{} must be text of at most {} characters", self.name, max),
+                None => format!("{} must be text", self.name),
+            },
+        }
+    }
+
+    // Checks a value for this attribute and returns it in canonical form
+    // Null is passed through so callers can use it to clear the attribute
+    pub fn validate(&self, value: &Value) -> Result<Value, String> {
+        let valid = match (self.kind, value) {
+            (_, Value::Null) => Some(Value::Null),
+            (AttributeType::Choice, Value::String(s)) => self.canonical_choice(s).map(Value::from),
+            (AttributeType::Integer, Value::Number(n)) => n
+                .as_i64()
+                .filter(|n| self.min.is_none_or(|min| *n >= min))
+                .filter(|n| self.max.is_none_or(|max| *n <= max))
+                .map(Value::from),
+            (AttributeType::Boolean, Value::Bool(_)) => Some(value.clone()),
+            (AttributeType::Text, Value::String(s))
+                if self.max_length.is_none_or(|max| s.chars().count() <= max) =>
+            {
+                Some(value.clone())
+            }
+            _ => None,
+        };
+        valid.ok_or_else(|| self.invalid())
+    }
+
+    // Keys used for this attribute's filter in preferences
+    // Choices use {name}_preference, integers min_{name} and max_{name}, and booleans {name}
+    pub fn filter_keys(&self) -> Vec<String> {
+        if !self.filterable {
+            return Vec::new();
+        }
+        match self.kind {
+            AttributeType::Choice => vec![format!("{}_preference", self.name)],
+            AttributeType::Integer => {
+                vec![format!("min_{}", self.name), format!("max_{}", self.name)]
+            }
+            _ => vec![self.name.clone()],
+        }
+    }
+
+    // Checks a filter value for this attribute and returns it in canonical form
+    fn validate_filter(&self, value: &Value) -> Result<Value, String> {
+        match (self.kind, value) {
+            (_, Value::Null) => Ok(Value::Null),
+            (AttributeType::Choice, Value::Array(items)) => {
+                let mut out: Vec<Value> = Vec::new();
+                for item in items {
+                    let canonical = self.validate(item)?;
+                    if !canonical.is_null() && !out.contains(&canonical) {
+                        out.push(canonical);
+                    }
+                }
+                Ok(Value::Array(out))
+            }
+            (AttributeType::Choice, _) => Err(format!("{}_preference must be a list", self.name)),
+            _ => self.validate(value),
+        }
+    }
+
+    // Expression for this attribute's value in a profiles row
+    fn column_sql(&self) -> String {
+        if COLUMN_ATTRIBUTES.contains(&self.name.as_str()) {
+            format!("profiles.{}", self.name)
+        } else {
+            format!("json_extract(profiles.attributes, '$.{}')", self.name)
+        }
+    }
+
+    // Condition a profile must meet to pass the user's filter on this attribute
+    // Returns None if the user has no filter on it; the filter's values are pushed onto params
+    // Profiles without a value never match a filter, so the condition is never null
+    fn filter_condition(
+        &self,
+        filters: &Map<String, Value>,
+        first_param: usize,
+        params: &mut Vec<Sql>,
+    ) -> Option<String> {
+        let filter = |key: String| filters.get(&key).filter(|v| !v.is_null());
+        let column = self.column_sql();
+        let mut placeholder = |value: Sql| {
+            params.push(value);
+            format!("?{}", first_param + params.len() - 1)
+        };
+        let condition = match self.kind {
+            AttributeType::Choice => {
+                let wanted = filter(format!("{}_preference", self.name))?.as_array()?;
+                let wanted: Vec<String> = wanted
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .map(|w| placeholder(Sql::Text(w.to_string())))
+                    .collect();
+                if wanted.is_empty() {
+                    return None;
+                }
+                format!("{} COLLATE NOCASE IN ({})", column, wanted.join(", "))
+            }
+            AttributeType::Integer => {
+                let min = filter(format!("min_{}", self.name)).and_then(Value::as_i64);
+                let max = filter(format!("max_{}", self.name)).and_then(Value::as_i64);
+                if min.is_none() && max.is_none() {
+                    return None;
+                }
+                let mut condition = format!("typeof({}) = 'integer'", column);
+                if let Some(min) = min {
+                    condition += &format!(" AND {} >= {}", column, placeholder(Sql::Integer(min)));
+                }
+                if let Some(max) = max {
+                    condition += &format!(" AND {} <= {}", column, placeholder(Sql::Integer(max)));
+                }
+                condition
+            }
+            AttributeType::Boolean => {
+                let wanted = filter(self.name.clone())?.as_bool()?;
+                format!("{} = {}", column, placeholder(Sql::Integer(wanted as i64)))
+            }
+            AttributeType::Text => return None,
+        };
+        Some(format!("COALESCE({}, 0)", condition))
+    }
+}
+
+// Helper function for converting a JSON value to a SQLite column value
+// Lists are stored as JSON text
+fn to_sql(value: &Value) -> Sql {
+    match value {
+        Value::Null => Sql::Null,
+        Value::Bool(b) => Sql::Integer(*b as i64),
+        Value::Number(n) => n.as_i64().map(Sql::Integer).unwrap_or(Sql::Null),
+        Value::String(s) => Sql::Text(s.clone()),
+        other => Sql::Text(other.to_string()),
+    }
+}
+
+// Function for reading the attributes stored in the profiles.attributes column
+// Only attributes that are in the schema and have no column of their own are returned
+pub fn extra_attributes(text: Option<String>) -> Map<String, Value> {
+    let mut stored: Map<String, Value> = text
+        .and_then(|t| serde_json::from_str(&t).ok())
+        .unwrap_or_default();
+    stored.retain(|name, value| {
+        !value.is_null()
+            && !COLUMN_ATTRIBUTES.contains(&name.as_str())
+            && schema().get(name).is_some()
+    });
+    stored
+}
+
+// Function for saving validated attribute values for a user
+// Null clears the attribute; attributes not in values are left as they are
+pub fn save_profile_attributes(
+    conn: &Connection,
+    user_id: i32,
+    values: &Map<String, Value>,
+) -> rusqlite::Result<()> {
+    let mut extras: Option<Map<String, Value>> = None;
+    for (name, value) in values {
+        if let Some(column) = COLUMN_ATTRIBUTES.iter().find(|c| **c == name) {
+            conn.execute(
+                &format!("UPDATE profiles SET {} = ?1 WHERE user_id = ?2", column),
+                params![to_sql(value), user_id],
+            )?;
+            continue;
+        }
+        let extras = match &mut extras {
+            Some(extras) => extras,
+            None => {
+                let text: Option<Option<String>> = conn
+                    .query_row(
+                        "SELECT attributes FROM profiles WHERE user_id = ?1",
+                        params![user_id],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                extras.insert(extra_attributes(text.flatten()))
+            }
+        };
+        if value.is_null() {
+            extras.remove(name);
+        } else {
+            extras.insert(name.clone(), value.clone());
+        }
+    }
+    if let Some(extras) = extras {
+        let text = (!extras.is_empty()).then(|| Value::Object(extras).to_string());
+        conn.execute(
+            "UPDATE profiles SET attributes = ?1 WHERE user_id = ?2",
+            params![text, user_id],
+        )?;
+    }
+    Ok(())
+}
+
+// Function for loading a user's filters
+// Returns every filter key in the schema, with null for filters that are not set
+pub fn load_filters(conn: &Connection, user_id: i32) -> rusqlite::Result<Map<String, Value>> {
+    let row: Option<(Vec<Sql>, Option<String>)> = conn
+        .query_row(
+            &format!(
+                "SELECT {}, filters FROM preferences WHERE user_id = ?1",
+                PREFERENCE_COLUMNS.join(", ")
+            ),
+            params![user_id],
+            |row| {
+                let columns = (0..PREFERENCE_COLUMNS.len())
+                    .map(|i| row.get(i))
+                    .collect::<rusqlite::Result<Vec<Sql>>>()?;
+                Ok((columns, row.get(PREFERENCE_COLUMNS.len())?))
+            },
+        )
+        .optional()?;
+    let (columns, extra) = row.unwrap_or_default();
+    let extra: Map<String, Value> = extra
+        .and_then(|t| serde_json::from_str(&t).ok())
+        .unwrap_or_default();
+    let mut filters = Map::new();
+    for attr in schema().filterable() {
+        for key in attr.filter_keys() {
+            let column = PREFERENCE_COLUMNS.iter().position(|c| *c == key);
+            let value = match column.and_then(|i| columns.get(i)) {
+                Some(Sql::Integer(n)) if attr.kind == AttributeType::Boolean => {
+                    Value::Bool(*n != 0)
+                }
+                Some(Sql::Integer(n)) => Value::from(*n),
+                Some(Sql::Text(t)) => serde_json::from_str(t).unwrap_or(Value::Null),
+                Some(_) => Value::Null,
+                None => extra.get(&key).cloned().unwrap_or(Value::Null),
+            };
+            filters.insert(key, value);
+        }
+    }
+    Ok(filters)
+}
+
+// Function for saving a user's filters as returned by validate_filters
+// Replaces every filter the user had before
+pub fn save_filters(
+    conn: &Connection,
+    user_id: i32,
+    filters: &Map<String, Value>,
+) -> rusqlite::Result<()> {
+    let mut values: Vec<Sql> = vec![Sql::Integer(user_id.into())];
+    for column in PREFERENCE_COLUMNS {
+        values.push(match filters.get(*column) {
+            Some(Value::Array(items)) => Sql::Text(Value::Array(items.clone()).to_string()),
+            Some(value) => to_sql(value),
+            None => Sql::Null,
+        });
+    }
+    let extra: Map<String, Value> = filters
+        .iter()
+        .filter(|(key, value)| !value.is_null() && !PREFERENCE_COLUMNS.contains(&key.as_str()))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    values.push(match extra.is_empty() {
+        true => Sql::Null,
+        false => Sql::Text(Value::Object(extra).to_string()),
+    });
+    let placeholders: Vec<String> = (1..=values.len()).map(|i| format!("?{}", i)).collect();
+    let updates: Vec<String> = PREFERENCE_COLUMNS
+        .iter()
+        .chain(["filters"].iter())
+        .zip(placeholders.iter().skip(1))
+        .map(|(column, p)| format!("{} = {}", column, p))
+        .collect();
+    conn.execute(
+        &format!(
+            "INSERT INTO preferences (user_id, {}, filters) VALUES ({})
+             ON CONFLICT(user_id) DO UPDATE SET {}",
+            PREFERENCE_COLUMNS.join(", "),
+            placeholders.join(", "),
+            updates.join(", ")
+        ),
+        rusqlite::params_from_iter(values),
+    )?;
+    Ok(())
+}
+
+// Helper function for keeping a value the normalization could not recognize
+// Adds it under key to the JSON object in the row's unrecognized_values column so it can be reviewed later
+fn keep_unrecognized(
+    conn: &Connection,
+    table: &str,
+    user_id: i32,
+    key: &str,
+    value: Value,
+) -> rusqlite::Result<()> {
+    let text: Option<String> = conn.query_row(
+        &format!(
+            "SELECT unrecognized_values FROM {} WHERE user_id = ?1",
+            table
+        ),
+        params![user_id],
+        |row| row.get(0),
+    )?;
+    let mut kept: Map<String, Value> = text
+        .and_then(|t| serde_json::from_str(&t).ok())
+        .unwrap_or_default();
+    kept.insert(key.to_string(), value);
+    conn.execute(
+        &format!(
+            "UPDATE {} SET unrecognized_values = ?1 WHERE user_id = ?2",
+            table
+        ),
+        params![Value::Object(kept).to_string(), user_id],
+    )?;
+    Ok(())
+}
+
+// Function for rewriting choice values saved before they were validated
+// Recognized values are replaced with their canonical form
+// Unrecognized ones are cleared from the column and moved to unrecognized_values so nothing is lost
+// Applies to choice attributes with their own column in profiles and to their filters in preferences
+pub fn normalize_existing(conn: &Connection) -> rusqlite::Result<()> {
+    let mut changed = 0;
+    let mut unrecognized = 0;
+    for attr in &schema().attributes {
+        if attr.kind != AttributeType::Choice || !COLUMN_ATTRIBUTES.contains(&attr.name.as_str()) {
+            continue;
+        }
+        let rows: Vec<(i32, String)> = conn
+            .prepare(&format!(
+                "SELECT user_id, {0} FROM profiles WHERE {0} IS NOT NULL",
+                attr.name
+            ))?
+            .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .filter_map(|r| r.ok())
+            .collect();
+        for (user_id, value) in rows {
+            let canonical = attr.canonical_choice(&value);
+            if canonical != Some(value.as_str()) {
+                conn.execute(
+                    &format!("UPDATE profiles SET {} = ?1 WHERE user_id = ?2", attr.name),
+                    params![canonical, user_id],
+                )?;
+                changed += 1;
+            }
+            if canonical.is_none() {
+                keep_unrecognized(conn, "profiles", user_id, &attr.name, Value::from(value))?;
+                unrecognized += 1;
+            }
+        }
+        let column = format!("{}_preference", attr.name);
+        if !PREFERENCE_COLUMNS.contains(&column.as_str()) {
+            continue;
+        }
+        let rows: Vec<(i32, String)> = conn
+            .prepare(&format!(
+                "SELECT user_id, {0} FROM preferences WHERE {0} IS NOT NULL",
+                column
+            ))?
+            .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .filter_map(|r| r.ok())
+            .collect();
+        for (user_id, text) in rows {
+            let values: Vec<String> = match serde_json::from_str(&text) {
+                Ok(values) => values,
+                Err(_) => vec![text.clone()],
+            };
+            let mut canonical: Vec<&str> = Vec::new();
+            let mut unknown: Vec<&str> = Vec::new();
+            for value in &values {
+                match attr.canonical_choice(value) {
+                    Some(value) if !canonical.contains(&value) => canonical.push(value),
+                    Some(_) => {}
+                    None => unknown.push(value),
+                }
+            }
+            if !unknown.is_empty() {
+                keep_unrecognized(conn, "preferences", user_id, &column, Value::from(unknown))?;
+                unrecognized += 1;
+            }
+            let new_text = (!canonical.is_empty())
+                .then(|| serde_json::to_string(&canonical).unwrap_or_default());
+            if new_text.as_deref() != Some(text.as_str()) {
+                conn.execute(
+                    &format!("UPDATE preferences SET {} = ?1 WHERE user_id = ?2", column),
+                    params![new_text, user_id],
+                )?;
+                changed += 1;
+            }
+        }
+    }
+    if changed > 0 {
+        info!(
+            "Normalized {} choice values saved before validation",
+            changed
+        );
+    }
+    if unrecognized > 0 {
+        warn!(
+            "Moved {} unrecognized choice values to unrecognized_values for review",
+            unrecognized
+        );
+    }
+    Ok(())
+}
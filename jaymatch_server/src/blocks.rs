@@ -0,0 +1,202 @@
+/*
+Name: JayMatch blocking
+Description: Endpoints and helpers for letting one user block another (Sprint 3, R27)
+Pre/Post Conditions: The blocked_users table must exist. A block hides both users from each other and removes any match between them.
+Errors: Database errors are returned as 500 responses, blocking or unblocking without a session as 401, and unknown block IDs or blocks owned by someone else as 404.
+*/
+
+use crate::{AppState, moderation, sessions};
+use actix_web::{HttpRequest, HttpResponse, Responder, web};
+use chrono::Utc;
+use log::info;
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::{Deserialize, Serialize};
+
+// Structure to hold a block record
+// Represents one user blocking another
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Block {
+    pub id: i64,
+    pub user_id: i32,
+    pub blocked_user_id: i32,
+    pub timestamp: i64,
+}
+
+// Structure for block requests
+// Used when a user blocks another user; the blocker is the user whose session sent it
+#[derive(Deserialize)]
+pub struct BlockRequest {
+    pub blocked_user_id: i32,
+}
+
+// Helper function for checking if either user has blocked the other
+// Blocking is symmetric for visibility, so both directions are checked
+// Returns false on database errors so callers fall back to normal behavior
+pub fn is_blocked(conn: &Connection, a: i32, b: i32) -> bool {
+    conn.query_row(
+        "SELECT 1 FROM blocked_users
+         WHERE (user_id = ?1 AND blocked_user_id = ?2) OR (user_id = ?2 AND blocked_user_id = ?1)",
+        params![a, b],
+        |_| Ok(()),
+    )
+    .is_ok()
+}
+
+// Helper function for checking if a profile must be kept from a viewer because of a block
+// Viewers without a session could be anyone, so they cannot see users who have blocked someone
+// Owners are never hidden from themselves
+pub fn hidden_from(conn: &Connection, viewer: Option<i32>, owner: i32) -> bool {
+    match viewer {
+        Some(v) if v == owner => false,
+        Some(v) => is_blocked(conn, v, owner),
+        None => conn
+            .query_row(
+                "SELECT 1 FROM blocked_users WHERE user_id = ?1",
+                params![owner],
+                |_| Ok(()),
+            )
+            .is_ok(),
+    }
+}
+
+// API for blocking a user: POST /blocks
+// Requires the Authorization: Bearer session token of the user making the block
+// Inserts a row into blocked_users and removes any existing match between the pair
+// Uses a transaction so the block and unmatch happen together
+// Returns the block ID, which is used to unblock later
+pub async fn create_block(
+    req: HttpRequest,
+    data: web::Json<BlockRequest>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let conn = state.db_conn.lock().unwrap();
+    let Some(uid) = sessions::session_user(&conn, &req) else {
+        return HttpResponse::Unauthorized().json(serde_json::json!({
+            "success": false,
+            "message": "Login required"
+        }));
+    };
+    if uid == data.blocked_user_id {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "success": false,
+            "message": "Cannot block yourself"
+        }));
+    }
+    if let Some(r) = moderation::account_restriction(&conn, uid) {
+        return moderation::restricted_response(&r);
+    }
+    let existing: Option<i64> = conn
+        .query_row(
+            "SELECT id FROM blocked_users WHERE user_id = ?1 AND blocked_user_id = ?2",
+            params![uid, data.blocked_user_id],
+            |row| row.get(0),
+        )
+        .optional()
+        .unwrap_or(None);
+    if let Some(id) = existing {
+        return HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": "User already blocked",
+            "block_id": id
+        }));
+    }
+    let (lower_id, higher_id) = if uid < data.blocked_user_id {
+        (uid, data.blocked_user_id)
+    } else {
+        (data.blocked_user_id, uid)
+    };
+    let ts = Utc::now().timestamp_millis();
+    let tx = match conn.unchecked_transaction() {
+        Ok(tx) => tx,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
+    };
+    if let Err(e) = tx.execute(
+        "INSERT INTO blocked_users (user_id, blocked_user_id, timestamp) VALUES (?1, ?2, ?3)",
+        params![uid, data.blocked_user_id, ts],
+    ) {
+        return HttpResponse::InternalServerError().body(format!("DB error: {}", e));
+    }
+    let block_id = tx.last_insert_rowid();
+    if let Err(e) = tx.execute(
+        "DELETE FROM matches WHERE user_id = ?1 AND matched_user_id = ?2",
+        params![lower_id, higher_id],
+    ) {
+        return HttpResponse::InternalServerError().body(format!("DB error: {}", e));
+    }
+    if let Err(e) = tx.commit() {
+        return HttpResponse::InternalServerError().body(format!("DB error: {}", e));
+    }
+    info!("User {} blocked user {}", uid, data.blocked_user_id);
+    HttpResponse::Ok().json(serde_json::json!({
+        "success": true,
+        "message": "User blocked",
+        "block_id": block_id
+    }))
+}
+
+// API for unblocking a user: DELETE /blocks/{id}
+// Requires the Authorization: Bearer session token of the user who made the block
+// Removes the block record with the given ID
+// Does not restore any match that was removed by the block
+pub async fn delete_block(
+    req: HttpRequest,
+    block_id: web::Path<i64>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let id = block_id.into_inner();
+    let conn = state.db_conn.lock().unwrap();
+    let Some(uid) = sessions::session_user(&conn, &req) else {
+        return HttpResponse::Unauthorized().json(serde_json::json!({
+            "success": false,
+            "message": "Login required"
+        }));
+    };
+    match conn.execute(
+        "DELETE FROM blocked_users WHERE id = ?1 AND user_id = ?2",
+        params![id, uid],
+    ) {
+        Ok(0) => HttpResponse::NotFound().json(serde_json::json!({
+            "success": false,
+            "message": "Block not found"
+        })),
+        Ok(_) => {
+            info!("Deleted block {}", id);
+            HttpResponse::Ok().json(serde_json::json!({
+                "success": true,
+                "message": "User unblocked"
+            }))
+        }
+        Err(e) => HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
+    }
+}
+
+// API for listing the users someone has blocked: GET /blocks/{user_id}
+// Queries the blocked_users table for blocks created by the user
+// Returns block records so the client can offer an unblock option
+pub async fn get_blocks(user_id: web::Path<i32>, state: web::Data<AppState>) -> impl Responder {
+    let uid = user_id.into_inner();
+    let conn = state.db_conn.lock().unwrap();
+    if let Some(r) = moderation::account_restriction(&conn, uid) {
+        return moderation::restricted_response(&r);
+    }
+    let mut stmt = match conn.prepare(
+        "SELECT id, user_id, blocked_user_id, timestamp FROM blocked_users
+         WHERE user_id = ?1
+         ORDER BY timestamp DESC",
+    ) {
+        Ok(s) => s,
+        Err(_) => return HttpResponse::InternalServerError().body("Database error"),
+    };
+    let blocks: Vec<Block> = match stmt.query_map(params![uid], |row| {
+        Ok(Block {
+            id: row.get(0)?,
+            user_id: row.get(1)?,
+            blocked_user_id: row.get(2)?,
+            timestamp: row.get(3)?,
+        })
+    }) {
+        Ok(rows) => rows.filter_map(|r| r.ok()).collect(),
+        Err(_) => return HttpResponse::InternalServerError().body("Database error"),
+    };
+    HttpResponse::Ok().json(blocks)
+}
@@ -0,0 +1,346 @@
+/*
+Name: JayMatch profile boosts
+Description: Lets users boost their profile to the front of other users' queues for 30 minutes, tracks the boosts they own, and reports how many extra views and likes each boost brought
+Pre/Post Conditions: The boosts and boost_credits tables must exist. Premium users get PREMIUM_BOOSTS free boosts every PREMIUM_BOOST_PERIOD_MS; other boosts come from boost_credits, which purchases add to and activations take from. A boost's views are counted as queues serve the profile while it runs.
+Errors: Missing sessions return 401, unknown products return 400, restricted accounts and users with no boosts left receive 403, starting a boost while one is running or while the profile is not shown in queues returns 409, provider failures return 502, database errors return 500.
+*/
+
+use crate::{AppState, entitlements, moderation, payments, sessions};
+use actix_web::{HttpRequest, HttpResponse, Responder, web};
+use chrono::Utc;
+use log::info;
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::{Deserialize, Serialize};
+
+// How long a boost lasts (30 minutes in milliseconds)
+pub const BOOST_DURATION_MS: i64 = 30 * 60 * 1000;
+
+// Free boosts a premium user gets in each PREMIUM_BOOST_PERIOD_MS
+pub const PREMIUM_BOOSTS: i64 = 1;
+
+// Length of the rolling window premium boosts are counted over (30 days in milliseconds)
+pub const PREMIUM_BOOST_PERIOD_MS: i64 = 30 * 24 * 60 * 60 * 1000;
+
+// How far back likes are counted to work out a user's usual rate (7 days in milliseconds)
+const BASELINE_MS: i64 = 7 * 24 * 60 * 60 * 1000;
+
+// SQL condition for a profiles row whose user has a boost running
+// Boosts always start when they are created, so only the end needs checking
+pub const ACTIVE_SQL: &str = "EXISTS (SELECT 1 FROM boosts
+    WHERE boosts.user_id = profiles.user_id
+      AND boosts.ends_at > CAST(strftime('%s', 'now') AS INTEGER) * 1000)";
+
+// Structure for a boost and what it produced
+// extra_views compares views with how often the profile would have been shown without the boost
+// extra_likes compares likes with the user's usual rate over the week before the boost
+#[derive(Serialize, Debug, Clone)]
+pub struct BoostSummary {
+    pub id: i64,
+    pub source: String,
+    pub started_at: i64,
+    pub ends_at: i64,
+    pub active: bool,
+    pub views: i64,
+    pub extra_views: i64,
+    pub likes: i64,
+    pub extra_likes: i64,
+}
+
+// Structure for buying boosts
+#[derive(Deserialize)]
+pub struct BoostPurchase {
+    pub product: String,
+}
+
+// Helper function for the response sent when the caller has no session
+fn login_required() -> HttpResponse {
+    HttpResponse::Unauthorized().json(serde_json::json!({
+        "success": false,
+        "message": "Login required"
+    }))
+}
+
+// Function for adding the boosts from a completed purchase to a user's inventory
+// Each checkout is only credited once; unknown products add nothing
+pub fn credit_purchase(
+    conn: &Connection,
+    user_id: i64,
+    product: &str,
+    checkout_id: &str,
+) -> rusqlite::Result<usize> {
+    let Some((_, quantity)) = payments::product_terms(product) else {
+        return Ok(0);
+    };
+    conn.execute(
+        "INSERT INTO boost_credits (user_id, amount, reason, reference, created_at)
+         SELECT ?1, ?2, 'purchase', ?3, ?4 WHERE EXISTS (SELECT 1 FROM profiles WHERE user_id = ?1)
+         ON CONFLICT(reference) DO NOTHING",
+        params![
+            user_id,
+            quantity,
+            checkout_id,
+            Utc::now().timestamp_millis()
+        ],
+    )
+}
+
+// Helper function for the number of boosts a user owns
+fn inventory(conn: &Connection, user_id: i32) -> i64 {
+    conn.query_row(
+        "SELECT COALESCE(SUM(amount), 0) FROM boost_credits WHERE user_id = ?1",
+        params![user_id],
+        |row| row.get(0),
+    )
+    .unwrap_or(0)
+}
+
+// Helper function for the free premium boosts a user has left in the current window
+fn premium_boosts_left(conn: &Connection, user_id: i32, now: i64) -> i64 {
+    if !entitlements::has(conn, user_id, entitlements::Entitlement::MonthlyBoost) {
+        return 0;
+    }
+    let used: i64 = conn
+        .query_row(
+            "SELECT COUNT(*) FROM boosts WHERE user_id = ?1 AND source = 'premium' AND started_at > ?2",
+            params![user_id, now - PREMIUM_BOOST_PERIOD_MS],
+            |row| row.get(0),
+        )
+        .unwrap_or(0);
+    (PREMIUM_BOOSTS - used).max(0)
+}
+
+// Helper function for counting likes a user received between two times
+fn likes_between(conn: &Connection, user_id: i32, from: i64, to: i64) -> rusqlite::Result<i64> {
+    conn.query_row(
+        "SELECT COUNT(*) FROM swipes
+         WHERE target_user_id = ?1 AND direction = 'like' AND created_at >= ?2 AND created_at < ?3",
+        params![user_id, from, to],
+        |row| row.get(0),
+    )
+}
+
+// Function for listing a user's boosts with their results, newest first
+pub fn boost_summaries(
+    conn: &Connection,
+    user_id: i32,
+    now: i64,
+) -> rusqlite::Result<Vec<BoostSummary>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, source, started_at, ends_at, views, expected_views FROM boosts
+         WHERE user_id = ?1 ORDER BY started_at DESC",
+    )?;
+    let rows: Vec<(i64, String, i64, i64, i64, f64)> = stmt
+        .query_map(params![user_id], |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+                row.get(5)?,
+            ))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    rows.into_iter()
+        .map(|(id, source, started_at, ends_at, views, expected_views)| {
+            let likes = likes_between(conn, user_id, started_at, ends_at)?;
+            let before = likes_between(conn, user_id, started_at - BASELINE_MS, started_at)?;
+            let usual_likes = before as f64 * (ends_at - started_at) as f64 / BASELINE_MS as f64;
+            Ok(BoostSummary {
+                id,
+                source,
+                started_at,
+                ends_at,
+                active: started_at <= now && now < ends_at,
+                views,
+                extra_views: (views as f64 - expected_views).round().max(0.0) as i64,
+                likes,
+                extra_likes: (likes as f64 - usual_likes).round().max(0.0) as i64,
+            })
+        })
+        .collect()
+}
+
+// Function for listing the users whose boost is running
+pub fn boosted_users(conn: &Connection) -> Vec<i32> {
+    conn.prepare(&format!(
+        "SELECT user_id FROM profiles WHERE {}",
+        ACTIVE_SQL
+    ))
+    .and_then(|mut stmt| stmt.query_map(params![], |row| row.get(0))?.collect())
+    .unwrap_or_default()
+}
+
+// Function for counting a queue batch towards the running boosts of the profiles in it
+// candidates is how many profiles the batch was picked from, so batch_size / candidates is
+// roughly how likely each profile was to be shown without a boost
+pub fn record_views(conn: &Connection, served: &[i32], candidates: usize, batch_size: usize) {
+    if served.is_empty() || candidates == 0 {
+        return;
+    }
+    let expected = (batch_size as f64 / candidates as f64).min(1.0);
+    let now = Utc::now().timestamp_millis();
+    for user_id in served {
+        let _ = conn.execute(
+            "UPDATE boosts SET views = views + 1, expected_views = expected_views + ?1
+             WHERE user_id = ?2 AND started_at <= ?3 AND ends_at > ?3",
+            params![expected, user_id, now],
+        );
+    }
+}
+
+// API for the logged in user's boosts: GET /me/boosts
+// Requires the Authorization: Bearer session token
+// Returns the boosts owned, free premium boosts left, the running boost if any, and every past boost with its results
+pub async fn get_boosts(req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    let conn = state.db_conn.lock().unwrap();
+    let uid = match sessions::session_user(&conn, &req) {
+        Some(uid) => uid,
+        None => return login_required(),
+    };
+    let now = Utc::now().timestamp_millis();
+    match boost_summaries(&conn, uid, now) {
+        Ok(boosts) => HttpResponse::Ok().json(serde_json::json!({
+            "user_id": uid,
+            "inventory": inventory(&conn, uid),
+            "premium_boosts_left": premium_boosts_left(&conn, uid, now),
+            "active": boosts.iter().find(|b| b.active),
+            "boosts": boosts
+        })),
+        Err(_) => HttpResponse::InternalServerError().body("Database error"),
+    }
+}
+
+// API for starting a boost: POST /me/boosts
+// Requires the Authorization: Bearer session token
+// Uses a free premium boost if the user has one left, otherwise one from their inventory
+// Returns the new boost
+pub async fn start_boost(req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    let conn = state.db_conn.lock().unwrap();
+    let uid = match sessions::session_user(&conn, &req) {
+        Some(uid) => uid,
+        None => return login_required(),
+    };
+    if let Some(r) = moderation::account_restriction(&conn, uid) {
+        return moderation::restricted_response(&r);
+    }
+    let now = Utc::now().timestamp_millis();
+    let running = conn
+        .query_row(
+            "SELECT id FROM boosts WHERE user_id = ?1 AND started_at <= ?2 AND ends_at > ?2",
+            params![uid, now],
+            |row| row.get::<_, i64>(0),
+        )
+        .optional();
+    let visible = conn
+        .query_row(
+            "SELECT visibility = 'active' FROM profiles WHERE user_id = ?1",
+            params![uid],
+            |row| row.get::<_, bool>(0),
+        )
+        .unwrap_or(false);
+    match running {
+        Ok(Some(_)) => {
+            return HttpResponse::Conflict().json(serde_json::json!({
+                "success": false,
+                "message": "A boost is already running"
+            }));
+        }
+        Ok(None) if !visible => {
+            return HttpResponse::Conflict().json(serde_json::json!({
+                "success": false,
+                "message": "Profiles that are paused or hidden cannot be boosted"
+            }));
+        }
+        Ok(None) => {}
+        Err(_) => return HttpResponse::InternalServerError().body("Database error"),
+    }
+    let source = if premium_boosts_left(&conn, uid, now) > 0 {
+        "premium"
+    } else if inventory(&conn, uid) > 0 {
+        "inventory"
+    } else {
+        let products: Vec<&str> = payments::PRODUCTS
+            .iter()
+            .map(|(name, _, _)| *name)
+            .collect();
+        return HttpResponse::Forbidden().json(serde_json::json!({
+            "success": false,
+            "message": "No boosts left",
+            "products": products
+        }));
+    };
+    let result = conn.unchecked_transaction().and_then(|tx| {
+        tx.execute(
+            "INSERT INTO boosts (user_id, source, started_at, ends_at) VALUES (?1, ?2, ?3, ?4)",
+            params![uid, source, now, now + BOOST_DURATION_MS],
+        )?;
+        if source == "inventory" {
+            tx.execute(
+                "INSERT INTO boost_credits (user_id, amount, reason, created_at) VALUES (?1, -1, 'used', ?2)",
+                params![uid, now],
+            )?;
+        }
+        tx.commit()
+    });
+    if let Err(e) = result {
+        return HttpResponse::InternalServerError().body(format!("DB error: {}", e));
+    }
+    info!("User {} started a {} boost", uid, source);
+    match boost_summaries(&conn, uid, now) {
+        Ok(boosts) => HttpResponse::Created().json(serde_json::json!({
+            "success": true,
+            "boost": boosts.first()
+        })),
+        Err(_) => HttpResponse::InternalServerError().body("Database error"),
+    }
+}
+
+// API for buying boosts: POST /me/boosts/checkout
+// Requires the Authorization: Bearer session token
+// Returns the checkout the client sends the user to; the boosts are added once the provider confirms payment
+pub async fn create_checkout(
+    req: HttpRequest,
+    data: web::Json<BoostPurchase>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let conn = state.db_conn.lock().unwrap();
+    let uid = match sessions::session_user(&conn, &req) {
+        Some(uid) => uid,
+        None => return login_required(),
+    };
+    if let Some(r) = moderation::account_restriction(&conn, uid) {
+        return moderation::restricted_response(&r);
+    }
+    let Some((price_cents, quantity)) = payments::product_terms(&data.product) else {
+        let products: Vec<&str> = payments::PRODUCTS
+            .iter()
+            .map(|(name, _, _)| *name)
+            .collect();
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "success": false,
+            "message": format!("product must be one of: {}", products.join(", "))
+        }));
+    };
+    match state.payments.create_checkout(uid, &data.product) {
+        Ok(checkout) => {
+            info!(
+                "User {} started checkout {} for {}",
+                uid, checkout.id, data.product
+            );
+            HttpResponse::Created().json(serde_json::json!({
+                "success": true,
+                "checkout_id": checkout.id,
+                "url": checkout.url,
+                "provider": state.payments.name(),
+                "product": data.product,
+                "price_cents": price_cents,
+                "quantity": quantity
+            }))
+        }
+        Err(message) => HttpResponse::BadGateway().json(serde_json::json!({
+            "success": false,
+            "message": message
+        })),
+    }
+}
@@ -0,0 +1,663 @@
+/*
+Name: JayMatch database setup
+Description: Creates the SQLite tables used by the server and upgrades databases made by older revisions
+Pre/Post Conditions: Takes an open connection. Afterwards every table exists, foreign keys are enforced, and rows owned by a profile are removed with it. Reports are moderation evidence, so they outlive both the reporter and the reported user.
+Errors: Any SQLite error while creating or migrating tables is returned to the caller.
+*/
+
+use crate::{attributes, interests, prompts};
+use rusqlite::{Connection, params};
+
+// Schema version stored in PRAGMA user_version
+// Version 1 adds ON DELETE CASCADE to every table that references a profile
+// Version 2 canonicalizes the free text choice values, such as years and majors, saved before they were validated
+// Values it cannot recognize are moved to unrecognized_values rather than thrown away
+// Version 3 moves interests saved as JSON or comma-separated text into profile_interests
+// Version 4 keeps reports when either user is deleted, clearing the reference instead
+pub const SCHEMA_VERSION: i32 = 4;
+
+// Tables rebuilt when upgrading a version 0 database
+// Ordered so tables are renamed before anything that references them is copied back
+const CASCADE_TABLES: &[&str] = &[
+    "matches",
+    "messages",
+    "preferences",
+    "blocked_users",
+    "reports",
+    "report_notes",
+    "sessions",
+];
+
+// Tables rebuilt when upgrading a version 1 to 3 database
+// report_notes references reports, so it is rebuilt with it
+const REPORT_TABLES: &[&str] = &["reports", "report_notes"];
+
+// Helper function for checking if a table exists
+fn table_exists(conn: &Connection, table: &str) -> rusqlite::Result<bool> {
+    conn.prepare("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1")?
+        .exists(params![table])
+}
+
+// Helper function for adding a column to an existing table
+// CREATE TABLE IF NOT EXISTS does not change databases made by older revisions
+pub fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> rusqlite::Result<()> {
+    let exists = conn
+        .prepare(&format!(
+            "SELECT 1 FROM pragma_table_info('{}') WHERE name = ?1",
+            table
+        ))?
+        .exists(params![column])?;
+    if !exists {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            params![],
+        )?;
+    }
+    Ok(())
+}
+
+// Helper function for renaming a column made by an older revision
+// Does nothing if the column has already been renamed
+fn rename_column_if_present(
+    conn: &Connection,
+    table: &str,
+    from: &str,
+    to: &str,
+) -> rusqlite::Result<()> {
+    let exists = conn
+        .prepare(&format!(
+            "SELECT 1 FROM pragma_table_info('{}') WHERE name = ?1",
+            table
+        ))?
+        .exists(params![from])?;
+    if exists {
+        conn.execute(
+            &format!("ALTER TABLE {} RENAME COLUMN {} TO {}", table, from, to),
+            params![],
+        )?;
+    }
+    Ok(())
+}
+
+// Function for preparing the database on startup
+// Creates missing tables, upgrades older databases, and turns on foreign key enforcement
+// Safe to run on every start
+pub fn init_db(conn: &Connection) -> rusqlite::Result<()> {
+    // Foreign keys must be off while tables are rebuilt
+    conn.pragma_update(None, "foreign_keys", "OFF")?;
+    let version: i32 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    let legacy = version < 1 && table_exists(conn, "profiles")?;
+    let tx = conn.unchecked_transaction()?;
+    let mut renamed: Vec<&str> = Vec::new();
+    let rebuilt = if legacy {
+        CASCADE_TABLES
+    } else if version < 4 {
+        REPORT_TABLES
+    } else {
+        &[]
+    };
+    if !rebuilt.is_empty() {
+        // Triggers follow their table when it is renamed, so drop it to be recreated on the new table
+        tx.execute(
+            "DROP TRIGGER IF EXISTS reports_snapshot_immutable",
+            params![],
+        )?;
+        for table in rebuilt {
+            if table_exists(&tx, table)? {
+                tx.execute(
+                    &format!("ALTER TABLE {} RENAME TO {}_legacy", table, table),
+                    params![],
+                )?;
+                renamed.push(table);
+            }
+        }
+    }
+    create_tables(&tx)?;
+    for table in &renamed {
+        copy_legacy_rows(&tx, table)?;
+        tx.execute(&format!("DROP TABLE {}_legacy", table), params![])?;
+    }
+    if version < 2 {
+        attributes::normalize_existing(&tx)?;
+    }
+    if version < 3 {
+        interests::migrate_legacy(&tx)?;
+    }
+    tx.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+    tx.commit()?;
+    conn.pragma_update(None, "foreign_keys", "ON")?;
+    Ok(())
+}
+
+// Helper function for copying rows from a renamed table into its rebuilt version
+// Rows that point at profiles which no longer exist are dropped instead of copied,
+// except reports, which keep their row with the missing user cleared
+fn copy_legacy_rows(conn: &Connection, table: &str) -> rusqlite::Result<usize> {
+    let sql = match table {
+        "matches" => {
+            "INSERT INTO matches (user_id, matched_user_id, timestamp)
+             SELECT user_id, matched_user_id, timestamp FROM matches_legacy
+             WHERE user_id IN (SELECT user_id FROM profiles)
+               AND matched_user_id IN (SELECT user_id FROM profiles)"
+        }
+        "messages" => {
+            "INSERT INTO messages (id, sender_id, receiver_id, content, timestamp)
+             SELECT id, sender_id, receiver_id, content, timestamp FROM messages_legacy
+             WHERE sender_id IN (SELECT user_id FROM profiles)
+               AND receiver_id IN (SELECT user_id FROM profiles)"
+        }
+        "preferences" => {
+            "INSERT INTO preferences (user_id, gender_preference, min_age, max_age, year_preference, major_preference, is_felon)
+             SELECT user_id, gender_preference, min_age, max_age, year_preference, major_preference, is_felon
+             FROM preferences_legacy
+             WHERE user_id IN (SELECT user_id FROM profiles)"
+        }
+        "blocked_users" => {
+            "INSERT INTO blocked_users (id, user_id, blocked_user_id, timestamp)
+             SELECT id, user_id, blocked_user_id, timestamp FROM blocked_users_legacy
+             WHERE user_id IN (SELECT user_id FROM profiles)
+               AND blocked_user_id IN (SELECT user_id FROM profiles)"
+        }
+        "reports" => {
+            "INSERT INTO reports (id, reporter_id, reported_user_id, report_type, message, snapshot, status, resolution, created_at, updated_at)
+             SELECT id,
+                    CASE WHEN reporter_id IN (SELECT user_id FROM profiles) THEN reporter_id END,
+                    CASE WHEN reported_user_id IN (SELECT user_id FROM profiles) THEN reported_user_id END,
+                    report_type, message, snapshot, status, resolution, created_at, updated_at
+             FROM reports_legacy"
+        }
+        "report_notes" => {
+            "INSERT INTO report_notes (id, report_id, author, note, timestamp)
+             SELECT id, report_id, author, note, timestamp FROM report_notes_legacy
+             WHERE report_id IN (SELECT id FROM reports)"
+        }
+        "sessions" => {
+            "INSERT INTO sessions (token, user_id, created_at, expires_at)
+             SELECT token, user_id, created_at, expires_at FROM sessions_legacy
+             WHERE user_id IN (SELECT user_id FROM profiles)"
+        }
+        _ => return Ok(0),
+    };
+    conn.execute(sql, params![])
+}
+
+// Function for creating every table the server uses
+// Every reference to a profile cascades so deleting the profile deletes its rows, except in reports
+fn create_tables(conn: &Connection) -> rusqlite::Result<()> {
+    // Create profiles table - stores user profile information
+    // interests mirrors profile_interests as a JSON list of catalogue names
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS profiles (
+            user_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            email TEXT UNIQUE NOT NULL,
+            password TEXT NOT NULL,
+            name TEXT,
+            age INTEGER,
+            major TEXT,
+            year TEXT,
+            bio TEXT,
+            interests TEXT,
+            profile_picture TEXT,
+            gender TEXT,
+            is_felon INTEGER,
+            account_status TEXT NOT NULL DEFAULT 'active',
+            suspended_until INTEGER,
+            moderation_reason TEXT,
+            deletion_due_at INTEGER,
+            attributes TEXT,
+            email_verified_at INTEGER,
+            visibility TEXT NOT NULL DEFAULT 'active',
+            incognito INTEGER NOT NULL DEFAULT 0,
+            unrecognized_values TEXT
+        )",
+        params![],
+    )?;
+    // Add moderation columns to profiles made before suspensions and bans existed
+    add_column_if_missing(
+        conn,
+        "profiles",
+        "account_status",
+        "TEXT NOT NULL DEFAULT 'active'",
+    )?;
+    add_column_if_missing(conn, "profiles", "suspended_until", "INTEGER")?;
+    add_column_if_missing(conn, "profiles", "moderation_reason", "TEXT")?;
+    // Add the soft-delete column to profiles made before deletion had a grace period
+    add_column_if_missing(conn, "profiles", "deletion_due_at", "INTEGER")?;
+    // Add the column for attributes from the attribute schema that have no column of their own
+    add_column_if_missing(conn, "profiles", "attributes", "TEXT")?;
+    // Add the visibility column to profiles made before profiles could be paused or hidden
+    add_column_if_missing(
+        conn,
+        "profiles",
+        "visibility",
+        "TEXT NOT NULL DEFAULT 'active'",
+    )?;
+    // Add the incognito flag to profiles made before incognito browsing existed
+    add_column_if_missing(conn, "profiles", "incognito", "INTEGER NOT NULL DEFAULT 0")?;
+    // Add the column recording when the user verified their current email address
+    add_column_if_missing(conn, "profiles", "email_verified_at", "INTEGER")?;
+    // Add the column holding choice values the version 2 upgrade could not recognize
+    add_column_if_missing(conn, "profiles", "unrecognized_values", "TEXT")?;
+    // A new email address has to be verified again
+    conn.execute(
+        "CREATE TRIGGER IF NOT EXISTS profiles_email_unverified
+         AFTER UPDATE OF email ON profiles
+         WHEN OLD.email != NEW.email
+         BEGIN
+            UPDATE profiles SET email_verified_at = NULL WHERE user_id = NEW.user_id;
+         END",
+        params![],
+    )?;
+    // Create matches table - stores relationships between matched users
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS matches (
+            user_id INTEGER NOT NULL,
+            matched_user_id INTEGER NOT NULL,
+            timestamp INTEGER NOT NULL,
+            PRIMARY KEY (user_id, matched_user_id),
+            FOREIGN KEY(user_id) REFERENCES profiles(user_id) ON DELETE CASCADE,
+            FOREIGN KEY(matched_user_id) REFERENCES profiles(user_id) ON DELETE CASCADE
+        )",
+        params![],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS matches_matched_user_id ON matches (matched_user_id)",
+        params![],
+    )?;
+    // Create messages table - stores chat messages between users
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS messages (
+            id INTEGER PRIMARY KEY,
+            sender_id INTEGER NOT NULL,
+            receiver_id INTEGER NOT NULL,
+            content TEXT NOT NULL,
+            timestamp INTEGER NOT NULL,
+            FOREIGN KEY(sender_id) REFERENCES profiles(user_id) ON DELETE CASCADE,
+            FOREIGN KEY(receiver_id) REFERENCES profiles(user_id) ON DELETE CASCADE
+        )",
+        params![],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS messages_sender_id ON messages (sender_id)",
+        params![],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS messages_receiver_id ON messages (receiver_id)",
+        params![],
+    )?;
+    // Create preferences table - stores user filter preferences for matching
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS preferences (
+            user_id INTEGER PRIMARY KEY,
+            gender_preference TEXT,
+            min_age INTEGER,
+            max_age INTEGER,
+            year_preference TEXT,
+            major_preference TEXT,
+            is_felon INTEGER,
+            filters TEXT,
+            unrecognized_values TEXT,
+            FOREIGN KEY(user_id) REFERENCES profiles(user_id) ON DELETE CASCADE
+        )",
+        params![],
+    )?;
+    // Add the column for filters on attributes that have no column of their own
+    add_column_if_missing(conn, "preferences", "filters", "TEXT")?;
+    // Add the column holding filter values the version 2 upgrade could not recognize
+    add_column_if_missing(conn, "preferences", "unrecognized_values", "TEXT")?;
+    // Create blocked users table - stores which users have blocked each other
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS blocked_users (
+            id INTEGER PRIMARY KEY,
+            user_id INTEGER NOT NULL,
+            blocked_user_id INTEGER NOT NULL,
+            timestamp INTEGER NOT NULL,
+            UNIQUE (user_id, blocked_user_id),
+            FOREIGN KEY(user_id) REFERENCES profiles(user_id) ON DELETE CASCADE,
+            FOREIGN KEY(blocked_user_id) REFERENCES profiles(user_id) ON DELETE CASCADE
+        )",
+        params![],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS blocked_users_blocked_user_id ON blocked_users (blocked_user_id)",
+        params![],
+    )?;
+    // Create reports table - stores user reports with a snapshot of the reported content
+    // A deleted user's ID is cleared rather than deleting the report, so the evidence survives
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS reports (
+            id INTEGER PRIMARY KEY,
+            reporter_id INTEGER,
+            reported_user_id INTEGER,
+            report_type TEXT NOT NULL,
+            message TEXT,
+            snapshot TEXT NOT NULL,
+            status TEXT NOT NULL DEFAULT 'open',
+            resolution TEXT,
+            created_at INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL,
+            FOREIGN KEY(reporter_id) REFERENCES profiles(user_id) ON DELETE SET NULL,
+            FOREIGN KEY(reported_user_id) REFERENCES profiles(user_id) ON DELETE SET NULL
+        )",
+        params![],
+    )?;
+    // Keep report snapshots immutable once they are written
+    conn.execute(
+        "CREATE TRIGGER IF NOT EXISTS reports_snapshot_immutable
+         BEFORE UPDATE OF snapshot ON reports
+         BEGIN
+            SELECT RAISE(ABORT, 'report snapshots are immutable');
+         END",
+        params![],
+    )?;
+    // Create report notes table - stores moderator annotations on reports
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS report_notes (
+            id INTEGER PRIMARY KEY,
+            report_id INTEGER NOT NULL,
+            author TEXT NOT NULL,
+            note TEXT NOT NULL,
+            timestamp INTEGER NOT NULL,
+            FOREIGN KEY(report_id) REFERENCES reports(id) ON DELETE CASCADE
+        )",
+        params![],
+    )?;
+    // Create banned emails table - stops banned users from registering again
+    // Not tied to a profile so the ban outlives the deleted account
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS banned_emails (
+            email TEXT PRIMARY KEY,
+            reason TEXT,
+            timestamp INTEGER NOT NULL
+        )",
+        params![],
+    )?;
+    // Create sessions table - stores login tokens
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS sessions (
+            token TEXT PRIMARY KEY,
+            user_id INTEGER NOT NULL,
+            created_at INTEGER NOT NULL,
+            expires_at INTEGER NOT NULL,
+            FOREIGN KEY(user_id) REFERENCES profiles(user_id) ON DELETE CASCADE
+        )",
+        params![],
+    )?;
+    // Create profile photos table - stores each user's ordered photos
+    // profiles.profile_picture mirrors the primary photo
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS profile_photos (
+            id INTEGER PRIMARY KEY,
+            user_id INTEGER NOT NULL,
+            media_key TEXT NOT NULL,
+            position INTEGER NOT NULL,
+            is_primary INTEGER NOT NULL DEFAULT 0,
+            created_at INTEGER NOT NULL,
+            FOREIGN KEY(user_id) REFERENCES profiles(user_id) ON DELETE CASCADE
+        )",
+        params![],
+    )?;
+    // Photos used to be stored by filesystem path; media::migrate_legacy_paths converts the values
+    rename_column_if_present(conn, "profile_photos", "file_path", "media_key")?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS profile_photos_user_id ON profile_photos (user_id, position)",
+        params![],
+    )?;
+    // Carry single profile pictures uploaded before multiple photos existed over as primary photos
+    conn.execute(
+        "INSERT INTO profile_photos (user_id, media_key, position, is_primary, created_at)
+         SELECT user_id, profile_picture, 0, 1, CAST(strftime('%s', 'now') AS INTEGER) * 1000
+         FROM profiles
+         WHERE profile_picture IS NOT NULL AND profile_picture != ''
+           AND user_id NOT IN (SELECT user_id FROM profile_photos)",
+        params![],
+    )?;
+    // Create data exports table - tracks personal data exports and their download links
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS data_exports (
+            id INTEGER PRIMARY KEY,
+            user_id INTEGER NOT NULL,
+            token TEXT UNIQUE NOT NULL,
+            status TEXT NOT NULL DEFAULT 'pending',
+            file_path TEXT,
+            error TEXT,
+            created_at INTEGER NOT NULL,
+            completed_at INTEGER,
+            expires_at INTEGER,
+            FOREIGN KEY(user_id) REFERENCES profiles(user_id) ON DELETE CASCADE
+        )",
+        params![],
+    )?;
+    // Create prompts table - the catalogue of profile prompts, filled from prompts::CATALOGUE
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS prompts (
+            id INTEGER PRIMARY KEY,
+            text TEXT UNIQUE NOT NULL,
+            active INTEGER NOT NULL DEFAULT 1
+        )",
+        params![],
+    )?;
+    prompts::seed_catalogue(conn)?;
+    // Create prompt answers table - stores each user's answers to up to three prompts
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS prompt_answers (
+            id INTEGER PRIMARY KEY,
+            user_id INTEGER NOT NULL,
+            prompt_id INTEGER NOT NULL,
+            answer TEXT NOT NULL,
+            position INTEGER NOT NULL,
+            created_at INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL,
+            UNIQUE(user_id, prompt_id),
+            FOREIGN KEY(user_id) REFERENCES profiles(user_id) ON DELETE CASCADE,
+            FOREIGN KEY(prompt_id) REFERENCES prompts(id)
+        )",
+        params![],
+    )?;
+    // Create swipes table - stores each like or pass, and the prompt answer a like was for
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS swipes (
+            id INTEGER PRIMARY KEY,
+            user_id INTEGER NOT NULL,
+            target_user_id INTEGER NOT NULL,
+            direction TEXT NOT NULL CHECK (direction IN ('like', 'pass')),
+            super_like INTEGER NOT NULL DEFAULT 0,
+            prompt_answer_id INTEGER,
+            created_at INTEGER NOT NULL,
+            UNIQUE(user_id, target_user_id),
+            FOREIGN KEY(user_id) REFERENCES profiles(user_id) ON DELETE CASCADE,
+            FOREIGN KEY(target_user_id) REFERENCES profiles(user_id) ON DELETE CASCADE,
+            FOREIGN KEY(prompt_answer_id) REFERENCES prompt_answers(id) ON DELETE SET NULL
+        )",
+        params![],
+    )?;
+    // Add the super like flag to swipes made before super likes existed
+    add_column_if_missing(conn, "swipes", "super_like", "INTEGER NOT NULL DEFAULT 0")?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS swipes_target_user_id ON swipes (target_user_id)",
+        params![],
+    )?;
+    // Create interests table - the catalogue of interest tags, filled from interests::CATALOGUE and by users
+    // slug is the name lowercased with only letters and digits kept, so tags differing in case or punctuation match
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS interests (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            slug TEXT UNIQUE NOT NULL,
+            curated INTEGER NOT NULL DEFAULT 0
+        )",
+        params![],
+    )?;
+    // Create interest synonyms table - other ways of writing a catalogue interest
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS interest_synonyms (
+            slug TEXT PRIMARY KEY,
+            interest_id INTEGER NOT NULL,
+            FOREIGN KEY(interest_id) REFERENCES interests(id) ON DELETE CASCADE
+        )",
+        params![],
+    )?;
+    interests::seed_catalogue(conn)?;
+    // Create profile interests table - links each profile to its interests in the order listed
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS profile_interests (
+            user_id INTEGER NOT NULL,
+            interest_id INTEGER NOT NULL,
+            position INTEGER NOT NULL,
+            PRIMARY KEY (user_id, interest_id),
+            FOREIGN KEY(user_id) REFERENCES profiles(user_id) ON DELETE CASCADE,
+            FOREIGN KEY(interest_id) REFERENCES interests(id)
+        )",
+        params![],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS profile_interests_interest_id ON profile_interests (interest_id)",
+        params![],
+    )?;
+    // Create email verifications table - stores links sent to verify a user's email address
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS email_verifications (
+            token TEXT PRIMARY KEY,
+            user_id INTEGER NOT NULL,
+            email TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            expires_at INTEGER NOT NULL,
+            FOREIGN KEY(user_id) REFERENCES profiles(user_id) ON DELETE CASCADE
+        )",
+        params![],
+    )?;
+    // Create preference interests table - the interests a user wants profiles in their queue to share
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS preference_interests (
+            user_id INTEGER NOT NULL,
+            interest_id INTEGER NOT NULL,
+            PRIMARY KEY (user_id, interest_id),
+            FOREIGN KEY(user_id) REFERENCES profiles(user_id) ON DELETE CASCADE,
+            FOREIGN KEY(interest_id) REFERENCES interests(id)
+        )",
+        params![],
+    )?;
+    // Create premium grants table - premium given to a user, until expires_at or indefinitely if it is NULL
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS premium_grants (
+            user_id INTEGER PRIMARY KEY,
+            granted_at INTEGER NOT NULL,
+            expires_at INTEGER,
+            reason TEXT,
+            FOREIGN KEY(user_id) REFERENCES profiles(user_id) ON DELETE CASCADE
+        )",
+        params![],
+    )?;
+    // Create swipe undos table - swipes users have taken back, used for daily undo limits and to restore queue order
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS swipe_undos (
+            id INTEGER PRIMARY KEY,
+            user_id INTEGER NOT NULL,
+            target_user_id INTEGER NOT NULL,
+            direction TEXT NOT NULL,
+            undone_at INTEGER NOT NULL,
+            FOREIGN KEY(user_id) REFERENCES profiles(user_id) ON DELETE CASCADE,
+            FOREIGN KEY(target_user_id) REFERENCES profiles(user_id) ON DELETE CASCADE
+        )",
+        params![],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS swipe_undos_user_id ON swipe_undos (user_id, undone_at)",
+        params![],
+    )?;
+    // Create subscriptions table - premium subscriptions bought through the payment provider
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS subscriptions (
+            id INTEGER PRIMARY KEY,
+            user_id INTEGER NOT NULL,
+            provider TEXT NOT NULL,
+            provider_subscription_id TEXT NOT NULL UNIQUE,
+            plan TEXT NOT NULL,
+            status TEXT NOT NULL CHECK (status IN ('active', 'past_due', 'canceled')),
+            current_period_end INTEGER NOT NULL,
+            created_at INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL,
+            FOREIGN KEY(user_id) REFERENCES profiles(user_id) ON DELETE CASCADE
+        )",
+        params![],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS subscriptions_user_id ON subscriptions (user_id)",
+        params![],
+    )?;
+    // Create payment events table - webhook events already applied, so repeats are ignored
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS payment_events (
+            id TEXT PRIMARY KEY,
+            type TEXT NOT NULL,
+            received_at INTEGER NOT NULL
+        )",
+        params![],
+    )?;
+    // Create boost credits table - boosts added to or taken from a user's inventory; the balance is the sum of amount
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS boost_credits (
+            id INTEGER PRIMARY KEY,
+            user_id INTEGER NOT NULL,
+            amount INTEGER NOT NULL,
+            reason TEXT NOT NULL CHECK (reason IN ('purchase', 'used')),
+            reference TEXT UNIQUE,
+            created_at INTEGER NOT NULL,
+            FOREIGN KEY(user_id) REFERENCES profiles(user_id) ON DELETE CASCADE
+        )",
+        params![],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS boost_credits_user_id ON boost_credits (user_id)",
+        params![],
+    )?;
+    // Create boosts table - each boost a user has run, with the views it got and the views expected without it
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS boosts (
+            id INTEGER PRIMARY KEY,
+            user_id INTEGER NOT NULL,
+            source TEXT NOT NULL CHECK (source IN ('premium', 'inventory')),
+            started_at INTEGER NOT NULL,
+            ends_at INTEGER NOT NULL,
+            views INTEGER NOT NULL DEFAULT 0,
+            expected_views REAL NOT NULL DEFAULT 0,
+            FOREIGN KEY(user_id) REFERENCES profiles(user_id) ON DELETE CASCADE
+        )",
+        params![],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS boosts_user_id ON boosts (user_id, ends_at)",
+        params![],
+    )?;
+    // Create profile impressions table - how often each profile was shown on each day (midnight UTC), by source
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS profile_impressions (
+            user_id INTEGER NOT NULL,
+            day INTEGER NOT NULL,
+            source TEXT NOT NULL CHECK (source IN ('queue', 'profile')),
+            count INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY (user_id, day, source),
+            FOREIGN KEY(user_id) REFERENCES profiles(user_id) ON DELETE CASCADE
+        )",
+        params![],
+    )?;
+    // Create impression viewers table - who has been shown each profile today, so each viewer counts once a day
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS impression_viewers (
+            user_id INTEGER NOT NULL,
+            viewer_id INTEGER NOT NULL,
+            day INTEGER NOT NULL,
+            source TEXT NOT NULL CHECK (source IN ('queue', 'profile')),
+            PRIMARY KEY (user_id, viewer_id, day, source),
+            FOREIGN KEY(user_id) REFERENCES profiles(user_id) ON DELETE CASCADE,
+            FOREIGN KEY(viewer_id) REFERENCES profiles(user_id) ON DELETE CASCADE
+        )",
+        params![],
+    )?;
+    Ok(())
+}
@@ -0,0 +1,146 @@
+/*
+Name: JayMatch account deletion
+Description: Soft-deletes accounts for a grace period, restores them on login, and permanently deletes them once the period ends
+Pre/Post Conditions: The profiles table must have the deletion_due_at column. Accounts pending deletion are hidden from every other user until they are restored or purged.
+Errors: SQLite errors are returned to the caller; the background job logs them and tries again on its next run.
+*/
+
+use crate::{AppState, exports, images, photos};
+use actix_web::web;
+use chrono::Utc;
+use log::{info, warn};
+use rusqlite::{Connection, params};
+use std::time::Duration;
+
+// Default number of days an account can be restored after deletion is requested
+pub const DEFAULT_GRACE_DAYS: i64 = 14;
+
+// How often the background job looks for accounts whose grace period has ended
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Helper function for reading the grace period from the DELETION_GRACE_DAYS environment variable
+// Falls back to DEFAULT_GRACE_DAYS when unset or invalid
+pub fn grace_period_from_env() -> i64 {
+    let days = std::env::var("DELETION_GRACE_DAYS")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .filter(|d| *d >= 0)
+        .unwrap_or(DEFAULT_GRACE_DAYS);
+    days * 24 * 60 * 60 * 1000
+}
+
+// Helper function for putting an account into the pending deletion state
+// Returns the time at which the account will be permanently deleted
+pub fn schedule_deletion(conn: &Connection, user_id: i32, grace_ms: i64) -> rusqlite::Result<i64> {
+    let due_at = Utc::now().timestamp_millis() + grace_ms;
+    conn.execute(
+        "UPDATE profiles SET deletion_due_at = ?1 WHERE user_id = ?2",
+        params![due_at, user_id],
+    )?;
+    Ok(due_at)
+}
+
+// Helper function for taking an account out of the pending deletion state
+// Returns true if the account was pending deletion
+pub fn restore_account(conn: &Connection, user_id: i32) -> rusqlite::Result<bool> {
+    let changed = conn.execute(
+        "UPDATE profiles SET deletion_due_at = NULL WHERE user_id = ?1 AND deletion_due_at IS NOT NULL",
+        params![user_id],
+    )?;
+    Ok(changed > 0)
+}
+
+// Helper function for checking when an account is due to be deleted
+// Returns None for accounts that are not pending deletion
+pub fn deletion_due_at(conn: &Connection, user_id: i32) -> Option<i64> {
+    conn.query_row(
+        "SELECT deletion_due_at FROM profiles WHERE user_id = ?1",
+        params![user_id],
+        |row| row.get(0),
+    )
+    .ok()
+    .flatten()
+}
+
+// Structure for the files of permanently deleted users
+// Collected while the database lock is held and removed by remove_files once it is released
+#[derive(Default, Debug)]
+pub struct DeletedFiles {
+    pub media_keys: Vec<String>, // Uploaded photos, removed with their thumbnails
+    pub export_paths: Vec<String>, // Data export ZIPs on disk
+}
+
+// Helper function for permanently deleting a user
+// Deleting the profile row cascades to every table that references it
+// Returns the user's photos and data exports for the caller to remove once the database lock is released;
+// nothing is removed if the delete fails
+pub fn hard_delete_user(conn: &Connection, user_id: i32) -> rusqlite::Result<DeletedFiles> {
+    let mut keys = photos::photo_keys(conn, user_id);
+    if let Ok(Some(picture)) = conn.query_row(
+        "SELECT profile_picture FROM profiles WHERE user_id = ?1",
+        params![user_id],
+        |row| row.get::<_, Option<String>>(0),
+    ) && !keys.contains(&picture)
+    {
+        keys.push(picture);
+    }
+    let export_paths = exports::user_export_paths(conn, user_id);
+    let tx = conn.unchecked_transaction()?;
+    tx.execute("DELETE FROM profiles WHERE user_id = ?1", params![user_id])?;
+    tx.commit()?;
+    Ok(DeletedFiles {
+        media_keys: keys,
+        export_paths,
+    })
+}
+
+// Function for removing the files of permanently deleted users
+// Runs on the blocking thread pool; callers release the database lock before awaiting this
+pub async fn remove_files(state: &AppState, files: DeletedFiles) {
+    images::remove_images(state.media.clone(), files.media_keys).await;
+    exports::remove_export_files(files.export_paths).await;
+}
+
+// Function for permanently deleting every account whose grace period ended before now
+// Returns the number of accounts deleted and their files, which remove_files removes
+pub fn purge_expired_deletions(
+    conn: &Connection,
+    now: i64,
+) -> rusqlite::Result<(usize, DeletedFiles)> {
+    let due: Vec<i32> = conn
+        .prepare("SELECT user_id FROM profiles WHERE deletion_due_at IS NOT NULL AND deletion_due_at <= ?1")?
+        .query_map(params![now], |row| row.get(0))?
+        .filter_map(|r| r.ok())
+        .collect();
+    let mut files = DeletedFiles::default();
+    for user_id in &due {
+        let deleted = hard_delete_user(conn, *user_id)?;
+        files.media_keys.extend(deleted.media_keys);
+        files.export_paths.extend(deleted.export_paths);
+        info!("User {} permanently deleted after grace period", user_id);
+    }
+    Ok((due.len(), files))
+}
+
+// Function for starting the background job that purges expired deletions
+// Runs once at startup and then every PURGE_INTERVAL
+pub fn start_purge_job(state: web::Data<AppState>) {
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let purged = purge_expired_deletions(
+                &state.db_conn.lock().unwrap(),
+                Utc::now().timestamp_millis(),
+            );
+            match purged {
+                Ok((0, _)) => {}
+                Ok((n, files)) => {
+                    info!("Purged {} accounts past their deletion grace period", n);
+                    remove_files(&state, files).await;
+                }
+                Err(e) => warn!("Failed to purge deleted accounts: {}", e),
+            }
+        }
+    });
+}
@@ -0,0 +1,447 @@
+/*
+Name: JayMatch entitlements
+Description: Works out which plan each user is on, which premium features that plan unlocks and how many times a day it allows limited actions (R26)
+Pre/Post Conditions: The premium_grants and subscriptions tables must exist. A user is premium while they hold an admin grant that has not expired or a paid up subscription; everyone else is on the free plan. Handlers ask for an Entitlement or Quota rather than checking the plan, so features and limits can move between plans here. Daily quotas reset at midnight UTC.
+Errors: Missing admin key returns 401, missing sessions return 401, grants ending in the past or with days out of range return 400, unknown users return 404, used up quotas return 429, database errors return 500.
+*/
+
+use crate::{AppState, admin_required, is_admin, sessions};
+use actix_web::{HttpRequest, HttpResponse, Responder, web};
+use chrono::{TimeZone, Utc};
+use log::{info, warn};
+use rusqlite::{Connection, params};
+use serde::Deserialize;
+use std::sync::LazyLock;
+
+// SQL condition for a profiles row whose user is currently premium
+// Active and cancelled subscriptions count until the end of the period paid for; past due ones do not
+pub const PREMIUM_SQL: &str = "(EXISTS (SELECT 1 FROM premium_grants
+    WHERE premium_grants.user_id = profiles.user_id
+      AND (premium_grants.expires_at IS NULL
+           OR premium_grants.expires_at > CAST(strftime('%s', 'now') AS INTEGER) * 1000))
+    OR EXISTS (SELECT 1 FROM subscriptions
+    WHERE subscriptions.user_id = profiles.user_id AND subscriptions.status IN ('active', 'canceled')
+      AND subscriptions.current_period_end > CAST(strftime('%s', 'now') AS INTEGER) * 1000))";
+
+// Plans a user can be on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Plan {
+    Free,
+    Premium,
+}
+
+impl Plan {
+    // Every plan, from lowest to highest
+    pub const ALL: &[Plan] = &[Plan::Free, Plan::Premium];
+
+    // Name of the plan as sent to clients
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Plan::Free => "free",
+            Plan::Premium => "premium",
+        }
+    }
+}
+
+// Features that are not available on every plan
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Entitlement {
+    // Only appear in the queues of users you have liked
+    Incognito,
+    // See the full profiles of users who liked you
+    SeeLikes,
+    // Like as many profiles as you want each day
+    UnlimitedLikes,
+    // A free profile boost every month
+    MonthlyBoost,
+}
+
+impl Entitlement {
+    // Every entitlement, in the order clients list them
+    pub const ALL: &[Entitlement] = &[
+        Entitlement::Incognito,
+        Entitlement::SeeLikes,
+        Entitlement::UnlimitedLikes,
+        Entitlement::MonthlyBoost,
+    ];
+
+    // Name of the entitlement as sent to clients
+    pub fn key(&self) -> &'static str {
+        match self {
+            Entitlement::Incognito => "incognito",
+            Entitlement::SeeLikes => "see_likes",
+            Entitlement::UnlimitedLikes => "unlimited_likes",
+            Entitlement::MonthlyBoost => "monthly_boost",
+        }
+    }
+
+    // Whether a plan includes this entitlement
+    pub fn included_in(&self, plan: Plan) -> bool {
+        match self {
+            Entitlement::Incognito | Entitlement::SeeLikes | Entitlement::MonthlyBoost => {
+                plan == Plan::Premium
+            }
+            Entitlement::UnlimitedLikes => Quota::Like.daily_limit(plan).is_none(),
+        }
+    }
+}
+
+// Actions a plan only allows a limited number of times per day
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Quota {
+    // Liking a profile
+    Like,
+    // Super liking a profile, which puts the user at the top of the other user's queue
+    SuperLike,
+    // Taking back the last swipe
+    Undo,
+}
+
+impl Quota {
+    // Every quota, in the order clients list them
+    pub const ALL: &[Quota] = &[Quota::Like, Quota::SuperLike, Quota::Undo];
+
+    // Name of the quota as sent to clients
+    pub fn key(&self) -> &'static str {
+        match self {
+            Quota::Like => "like",
+            Quota::SuperLike => "super_like",
+            Quota::Undo => "undo",
+        }
+    }
+
+    // Limit used when no environment variable sets one, or None for unlimited
+    fn default_limit(&self, plan: Plan) -> Option<i64> {
+        match (self, plan) {
+            (Quota::Like, Plan::Free) => Some(50),
+            (Quota::Like, Plan::Premium) => None,
+            (Quota::SuperLike, Plan::Free) => Some(1),
+            (Quota::SuperLike, Plan::Premium) => Some(5),
+            (Quota::Undo, Plan::Free) => Some(1),
+            (Quota::Undo, Plan::Premium) => Some(10),
+        }
+    }
+
+    // Most uses a plan allows per day, or None if the plan has no limit
+    pub fn daily_limit(&self, plan: Plan) -> Option<i64> {
+        QUOTA_LIMITS
+            .iter()
+            .find(|(q, p, _)| q == self && *p == plan)
+            .and_then(|(_, _, limit)| *limit)
+    }
+
+    // SQL counting a user's (?1) uses of the quota since a time (?2)
+    // Undone swipes are deleted, so undoing a like or super like gives it back
+    fn usage_sql(&self) -> &'static str {
+        match self {
+            Quota::Like => {
+                "SELECT COUNT(*) FROM swipes
+                 WHERE user_id = ?1 AND direction = 'like' AND super_like = 0 AND created_at >= ?2"
+            }
+            Quota::SuperLike => {
+                "SELECT COUNT(*) FROM swipes
+                 WHERE user_id = ?1 AND direction = 'like' AND super_like = 1 AND created_at >= ?2"
+            }
+            Quota::Undo => {
+                "SELECT COUNT(*) FROM swipe_undos WHERE user_id = ?1 AND undone_at >= ?2"
+            }
+        }
+    }
+}
+
+static QUOTA_LIMITS: LazyLock<Vec<(Quota, Plan, Option<i64>)>> = LazyLock::new(load_quota_limits);
+
+// Helper function for reading the daily limits, loading them on first use
+// DAILY_<QUOTA>_<PLAN> overrides a default, for example DAILY_LIKE_FREE=100 or DAILY_SUPER_LIKE_PREMIUM=unlimited
+// Values that are not a number or "unlimited" are ignored
+fn load_quota_limits() -> Vec<(Quota, Plan, Option<i64>)> {
+    let mut limits = Vec::new();
+    for quota in Quota::ALL {
+        for plan in Plan::ALL {
+            let var = format!(
+                "DAILY_{}_{}",
+                quota.key().to_uppercase(),
+                plan.as_str().to_uppercase()
+            );
+            let limit = match std::env::var(&var) {
+                Ok(v) if v.eq_ignore_ascii_case("unlimited") => None,
+                Ok(v) => match v.parse::<i64>() {
+                    Ok(n) if n >= 0 => Some(n),
+                    _ => {
+                        warn!("Ignoring {}={}, expected a number or unlimited", var, v);
+                        quota.default_limit(*plan)
+                    }
+                },
+                Err(_) => quota.default_limit(*plan),
+            };
+            limits.push((*quota, *plan, limit));
+        }
+    }
+    limits
+}
+
+// Function for counting how many times a user has used a quota today
+// Database errors count as no uses
+pub fn used_today(conn: &Connection, user_id: i32, quota: Quota, now: i64) -> i64 {
+    conn.query_row(quota.usage_sql(), params![user_id, day_start(now)], |row| {
+        row.get(0)
+    })
+    .unwrap_or(0)
+}
+
+// Function for checking whether a user can use a quota once more today
+// Returns the limit that has been reached, or None if the action is allowed
+pub fn quota_reached(conn: &Connection, user_id: i32, quota: Quota, now: i64) -> Option<i64> {
+    quota
+        .daily_limit(plan(conn, user_id))
+        .filter(|limit| used_today(conn, user_id, quota, now) >= *limit)
+}
+
+// Helper function for the start of the UTC day containing a timestamp, in milliseconds
+pub fn day_start(ts: i64) -> i64 {
+    let date = Utc
+        .timestamp_millis_opt(ts)
+        .single()
+        .unwrap_or_else(Utc::now)
+        .date_naive();
+    date.and_hms_opt(0, 0, 0)
+        .map(|d| d.and_utc().timestamp_millis())
+        .unwrap_or(ts)
+}
+
+// Helper function for when daily quotas next reset after a timestamp, in milliseconds
+pub fn quota_resets_at(ts: i64) -> i64 {
+    day_start(ts) + 24 * 60 * 60 * 1000
+}
+
+// Helper function for the response sent to a user who has used up a daily quota
+pub fn quota_exceeded(quota: Quota, limit: i64, now: i64) -> HttpResponse {
+    let resets_at = quota_resets_at(now);
+    HttpResponse::TooManyRequests()
+        .insert_header(("Retry-After", ((resets_at - now) / 1000).max(1).to_string()))
+        .json(serde_json::json!({
+            "success": false,
+            "message": format!("Daily {} limit of {} reached", quota.key(), limit),
+            "quota": quota.key(),
+            "limit": limit,
+            "resets_at": resets_at
+        }))
+}
+
+// Longest premium grant that can be given in days (about ten years)
+pub const MAX_GRANT_DAYS: i64 = 3650;
+
+// Structure for granting premium to a user
+// until is a timestamp in milliseconds, or days (1 to MAX_GRANT_DAYS) counts from now; with neither the grant does not expire
+#[derive(Deserialize)]
+pub struct GrantRequest {
+    pub until: Option<i64>,
+    pub days: Option<i64>,
+    pub reason: Option<String>,
+}
+
+// Function for finding which plan a user is on
+// Unknown users and database errors count as the free plan
+pub fn plan(conn: &Connection, user_id: i32) -> Plan {
+    let premium = conn
+        .query_row(
+            &format!("SELECT {} FROM profiles WHERE user_id = ?1", PREMIUM_SQL),
+            params![user_id],
+            |row| row.get::<_, bool>(0),
+        )
+        .unwrap_or(false);
+    if premium { Plan::Premium } else { Plan::Free }
+}
+
+// Function for finding when a premium user's premium ends
+// Returns None for free users and for users with a grant that does not expire
+// An active subscription renews, so this is when it next needs paying for
+pub fn premium_until(conn: &Connection, user_id: i32) -> Option<i64> {
+    if plan(conn, user_id) != Plan::Premium {
+        return None;
+    }
+    conn.query_row(
+        "SELECT CASE WHEN EXISTS (SELECT 1 FROM premium_grants WHERE user_id = ?1 AND expires_at IS NULL)
+                THEN NULL
+                ELSE MAX(
+                    COALESCE((SELECT expires_at FROM premium_grants WHERE user_id = ?1), 0),
+                    COALESCE((SELECT MAX(current_period_end) FROM subscriptions
+                              WHERE user_id = ?1 AND status IN ('active', 'canceled')), 0))
+                END",
+        params![user_id],
+        |row| row.get(0),
+    )
+    .unwrap_or(None)
+}
+
+// Function for checking if a user's plan includes an entitlement
+pub fn has(conn: &Connection, user_id: i32, entitlement: Entitlement) -> bool {
+    entitlement.included_in(plan(conn, user_id))
+}
+
+// Helper function for the response sent to a user whose plan lacks an entitlement
+pub fn premium_required(entitlement: Entitlement) -> HttpResponse {
+    HttpResponse::Forbidden().json(serde_json::json!({
+        "success": false,
+        "message": "This feature requires JayMatch Premium",
+        "entitlement": entitlement.key()
+    }))
+}
+
+// Helper function for the response sent when the caller has no session
+fn login_required() -> HttpResponse {
+    HttpResponse::Unauthorized().json(serde_json::json!({
+        "success": false,
+        "message": "Login required"
+    }))
+}
+
+// API for the logged in user's plan: GET /me/entitlements
+// Requires the Authorization: Bearer session token
+// Returns the plan, when premium ends (null if it does not), and the keys of the unlocked entitlements
+pub async fn get_entitlements(req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    let conn = state.db_conn.lock().unwrap();
+    let uid = match sessions::session_user(&conn, &req) {
+        Some(uid) => uid,
+        None => return login_required(),
+    };
+    let plan = plan(&conn, uid);
+    let premium_until = premium_until(&conn, uid);
+    let unlocked: Vec<&str> = Entitlement::ALL
+        .iter()
+        .filter(|e| e.included_in(plan))
+        .map(|e| e.key())
+        .collect();
+    HttpResponse::Ok().json(serde_json::json!({
+        "user_id": uid,
+        "plan": plan.as_str(),
+        "premium_until": premium_until,
+        "entitlements": unlocked
+    }))
+}
+
+// API for the logged in user's daily quotas: GET /me/quotas
+// Requires the Authorization: Bearer session token
+// Returns the limit, uses today and uses left for every quota, with null limits meaning unlimited, and when they reset
+pub async fn get_quotas(req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    let conn = state.db_conn.lock().unwrap();
+    let uid = match sessions::session_user(&conn, &req) {
+        Some(uid) => uid,
+        None => return login_required(),
+    };
+    let plan = plan(&conn, uid);
+    let now = Utc::now().timestamp_millis();
+    let mut quotas = serde_json::Map::new();
+    for quota in Quota::ALL {
+        let limit = quota.daily_limit(plan);
+        let used = used_today(&conn, uid, *quota, now);
+        quotas.insert(
+            quota.key().to_string(),
+            serde_json::json!({
+                "limit": limit,
+                "used": used,
+                "remaining": limit.map(|l| (l - used).max(0))
+            }),
+        );
+    }
+    HttpResponse::Ok().json(serde_json::json!({
+        "user_id": uid,
+        "plan": plan.as_str(),
+        "resets_at": quota_resets_at(now),
+        "quotas": quotas
+    }))
+}
+
+// Admin API for giving a user premium: PUT /admin/users/{user_id}/premium
+// Replaces any grant the user already has
+pub async fn grant_premium(
+    req: HttpRequest,
+    user_id: web::Path<i32>,
+    data: web::Json<GrantRequest>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    if !is_admin(&req) {
+        return admin_required();
+    }
+    let uid = user_id.into_inner();
+    let now = Utc::now().timestamp_millis();
+    let expires_at = match (data.until, data.days) {
+        (Some(until), _) => Some(until),
+        (None, Some(days)) => match (1..=MAX_GRANT_DAYS)
+            .contains(&days)
+            .then(|| days.checked_mul(24 * 60 * 60 * 1000))
+            .flatten()
+            .and_then(|ms| now.checked_add(ms))
+        {
+            Some(until) => Some(until),
+            None => {
+                return HttpResponse::BadRequest().json(serde_json::json!({
+                    "success": false,
+                    "message": format!("days must be from 1 to {}", MAX_GRANT_DAYS)
+                }));
+            }
+        },
+        (None, None) => None,
+    };
+    if expires_at.is_some_and(|e| e <= now) {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "success": false,
+            "message": "Premium must end in the future"
+        }));
+    }
+    let conn = state.db_conn.lock().unwrap();
+    let exists = conn
+        .query_row(
+            "SELECT 1 FROM profiles WHERE user_id = ?1",
+            params![uid],
+            |_| Ok(()),
+        )
+        .is_ok();
+    if !exists {
+        return HttpResponse::NotFound().body("User not found");
+    }
+    match conn.execute(
+        "INSERT INTO premium_grants (user_id, granted_at, expires_at, reason) VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT(user_id) DO UPDATE SET granted_at = ?2, expires_at = ?3, reason = ?4",
+        params![uid, now, expires_at, data.reason],
+    ) {
+        Ok(_) => {
+            info!("User {} granted premium until {:?}", uid, expires_at);
+            HttpResponse::Ok().json(serde_json::json!({
+                "success": true,
+                "user_id": uid,
+                "premium_until": expires_at
+            }))
+        }
+        Err(e) => HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
+    }
+}
+
+// Admin API for taking premium away from a user: DELETE /admin/users/{user_id}/premium
+// Removes the admin grant only; a paid subscription keeps the user premium
+// Features that need premium, like incognito, stop applying straight away
+pub async fn revoke_premium(
+    req: HttpRequest,
+    user_id: web::Path<i32>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    if !is_admin(&req) {
+        return admin_required();
+    }
+    let uid = user_id.into_inner();
+    let conn = state.db_conn.lock().unwrap();
+    match conn.execute(
+        "DELETE FROM premium_grants WHERE user_id = ?1",
+        params![uid],
+    ) {
+        Ok(0) => HttpResponse::NotFound().body("User has no premium grant"),
+        Ok(_) => {
+            info!("User {} premium revoked", uid);
+            HttpResponse::Ok().json(serde_json::json!({
+                "success": true,
+                "user_id": uid
+            }))
+        }
+        Err(e) => HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
+    }
+}
@@ -0,0 +1,469 @@
+/*
+Name: JayMatch data exports
+Description: Builds a ZIP of everything stored about a user and serves it from a download link that expires
+Pre/Post Conditions: The data_exports table must exist and the exports directory (EXPORT_DIR, default exports) must be writable. Exports are built in the background; the user polls GET /me/export until the link is ready. Builds interrupted by a restart or running past EXPORT_BUILD_TIMEOUT_MS are marked failed so the user can start a new one.
+Errors: Missing sessions return 401, unknown links return 404, expired links return 410, failed builds are recorded on the export and database errors return 500.
+*/
+
+use crate::media::{MediaStore, ObjectReader};
+use crate::{AppState, photos, sessions};
+use actix_web::{HttpRequest, HttpResponse, Responder, http::header, web};
+use chrono::Utc;
+use log::{info, warn};
+use rusqlite::{Connection, params, types::ValueRef};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// Default directory where finished exports are written
+// Can be changed with the EXPORT_DIR environment variable
+pub const DEFAULT_EXPORT_DIR: &str = "exports";
+
+// How long an export can stay pending before it is treated as failed (1 hour in milliseconds)
+pub const EXPORT_BUILD_TIMEOUT_MS: i64 = 60 * 60 * 1000;
+
+// How long a download link stays valid (24 hours in milliseconds)
+pub const EXPORT_TTL_MS: i64 = 24 * 60 * 60 * 1000;
+
+// How often the background job removes expired exports
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Helper function for reading the export directory from the EXPORT_DIR environment variable
+// Falls back to DEFAULT_EXPORT_DIR when unset or empty
+pub fn export_dir_from_env() -> PathBuf {
+    std::env::var("EXPORT_DIR")
+        .ok()
+        .filter(|d| !d.is_empty())
+        .unwrap_or_else(|| DEFAULT_EXPORT_DIR.to_string())
+        .into()
+}
+
+// Helper function for the response sent when the caller has no session
+fn login_required() -> HttpResponse {
+    HttpResponse::Unauthorized().json(serde_json::json!({
+        "success": false,
+        "message": "Login required"
+    }))
+}
+
+// Helper function for converting query results to JSON objects keyed by column name
+// Used so every column stored about the user ends up in the export
+fn query_json(
+    conn: &Connection,
+    sql: &str,
+    user_id: i32,
+) -> rusqlite::Result<Vec<serde_json::Value>> {
+    let mut stmt = conn.prepare(sql)?;
+    let columns: Vec<String> = stmt.column_names().iter().map(|c| c.to_string()).collect();
+    let rows = stmt.query_map(params![user_id], |row| {
+        let mut obj = serde_json::Map::new();
+        for (i, name) in columns.iter().enumerate() {
+            let value = match row.get_ref(i)? {
+                ValueRef::Null => serde_json::Value::Null,
+                ValueRef::Integer(n) => serde_json::json!(n),
+                ValueRef::Real(f) => serde_json::json!(f),
+                ValueRef::Text(t) => serde_json::json!(String::from_utf8_lossy(t)),
+                ValueRef::Blob(_) => serde_json::Value::Null,
+            };
+            obj.insert(name.clone(), value);
+        }
+        Ok(serde_json::Value::Object(obj))
+    })?;
+    rows.collect()
+}
+
+// Helper function for gathering the data included in a user's export
+// Returns the JSON document and the media keys of the user's uploaded images
+fn collect_user_data(
+    conn: &Connection,
+    user_id: i32,
+) -> rusqlite::Result<(serde_json::Value, Vec<String>)> {
+    let mut profile = query_json(conn, "SELECT * FROM profiles WHERE user_id = ?1", user_id)?;
+    // Passwords are never included in exports
+    if let Some(serde_json::Value::Object(p)) = profile.first_mut() {
+        p.remove("password");
+    }
+    let images = photos::photo_keys(conn, user_id);
+    let data = serde_json::json!({
+        "generated_at": Utc::now().timestamp_millis(),
+        "profile": profile.into_iter().next(),
+        "preferences": query_json(conn, "SELECT * FROM preferences WHERE user_id = ?1", user_id)?
+            .into_iter()
+            .next(),
+        "matches": query_json(
+            conn,
+            "SELECT * FROM matches WHERE user_id = ?1 OR matched_user_id = ?1 ORDER BY timestamp",
+            user_id
+        )?,
+        "messages": query_json(
+            conn,
+            "SELECT * FROM messages WHERE sender_id = ?1 OR receiver_id = ?1 ORDER BY timestamp",
+            user_id
+        )?,
+        "blocks": query_json(
+            conn,
+            "SELECT blocked_user_id, timestamp FROM blocked_users WHERE user_id = ?1",
+            user_id
+        )?,
+        "reports_filed": query_json(
+            conn,
+            "SELECT id, reported_user_id, report_type, message, status, created_at
+             FROM reports WHERE reporter_id = ?1",
+            user_id
+        )?,
+        "photos": query_json(
+            conn,
+            "SELECT id, position, is_primary, created_at FROM profile_photos WHERE user_id = ?1 ORDER BY position",
+            user_id
+        )?,
+        "interests_preference": query_json(
+            conn,
+            "SELECT i.name FROM preference_interests f JOIN interests i ON i.id = f.interest_id
+             WHERE f.user_id = ?1 ORDER BY i.name",
+            user_id
+        )?,
+        "prompt_answers": query_json(
+            conn,
+            "SELECT a.id, p.text AS prompt, a.answer, a.position, a.created_at, a.updated_at
+             FROM prompt_answers a JOIN prompts p ON p.id = a.prompt_id
+             WHERE a.user_id = ?1 ORDER BY a.position",
+            user_id
+        )?,
+        "swipes": query_json(
+            conn,
+            "SELECT target_user_id, direction, super_like, prompt_answer_id, created_at
+             FROM swipes WHERE user_id = ?1 ORDER BY created_at",
+            user_id
+        )?,
+        "swipe_undos": query_json(
+            conn,
+            "SELECT target_user_id, direction, undone_at FROM swipe_undos WHERE user_id = ?1 ORDER BY undone_at",
+            user_id
+        )?,
+        "premium": query_json(
+            conn,
+            "SELECT granted_at, expires_at FROM premium_grants WHERE user_id = ?1",
+            user_id
+        )?
+        .into_iter()
+        .next(),
+        "subscriptions": query_json(
+            conn,
+            "SELECT provider, plan, status, current_period_end, created_at, updated_at
+             FROM subscriptions WHERE user_id = ?1 ORDER BY created_at",
+            user_id
+        )?,
+        "boost_credits": query_json(
+            conn,
+            "SELECT amount, reason, created_at FROM boost_credits WHERE user_id = ?1 ORDER BY created_at",
+            user_id
+        )?,
+        "boosts": query_json(
+            conn,
+            "SELECT source, started_at, ends_at, views FROM boosts WHERE user_id = ?1 ORDER BY started_at",
+            user_id
+        )?,
+        "impressions": query_json(
+            conn,
+            "SELECT day, source, count FROM profile_impressions WHERE user_id = ?1 ORDER BY day, source",
+            user_id
+        )?,
+        "images": images
+            .iter()
+            .filter_map(|p| Path::new(p).file_name())
+            .map(|f| format!("images/{}", f.to_string_lossy()))
+            .collect::<Vec<_>>(),
+    });
+    Ok((data, images))
+}
+
+// Helper function for writing the export ZIP to disk
+// Contains data.json and an images folder with the user's uploads read from the media store
+fn write_zip(
+    path: &Path,
+    data: &serde_json::Value,
+    images: &[String],
+    store: &dyn MediaStore,
+) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let file = fs::File::create(path)?;
+    let mut zip = zip::ZipWriter::new(file);
+    let options = zip::write::SimpleFileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated);
+    zip.start_file("data.json", options)?;
+    zip.write_all(serde_json::to_string_pretty(data)?.as_bytes())?;
+    for image in images {
+        let name = match Path::new(image).file_name() {
+            Some(n) => n.to_string_lossy().to_string(),
+            None => continue,
+        };
+        match store.get(image) {
+            Ok(bytes) => {
+                zip.start_file(format!("images/{}", name), options)?;
+                zip.write_all(&bytes)?;
+            }
+            Err(e) => warn!("Skipping missing image {} in export: {}", image, e),
+        }
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+// Function for building an export in the background
+// Reads the data while holding the database lock, then writes the ZIP without it
+async fn build_export(state: web::Data<AppState>, export_id: i64, user_id: i32, token: String) {
+    let collected = {
+        let conn = state.db_conn.lock().unwrap();
+        collect_user_data(&conn, user_id)
+    };
+    let path = state.export_dir.join(format!("{}.zip", token));
+    let result = match collected {
+        Ok((data, images)) => {
+            let zip_path = path.clone();
+            let store = state.media.clone();
+            web::block(move || write_zip(&zip_path, &data, &images, store.as_ref()))
+                .await
+                .map_err(|e| e.to_string())
+                .and_then(|r| r.map_err(|e| e.to_string()))
+        }
+        Err(e) => Err(e.to_string()),
+    };
+    let update = {
+        let conn = state.db_conn.lock().unwrap();
+        let now = Utc::now().timestamp_millis();
+        match &result {
+            Ok(()) => conn.execute(
+                "UPDATE data_exports SET status = 'ready', file_path = ?1, completed_at = ?2, expires_at = ?3
+                 WHERE id = ?4 AND status = 'pending'",
+                params![path.to_string_lossy(), now, now + EXPORT_TTL_MS, export_id],
+            ),
+            Err(e) => conn.execute(
+                "UPDATE data_exports SET status = 'failed', error = ?1, completed_at = ?2, expires_at = ?2
+                 WHERE id = ?3 AND status = 'pending'",
+                params![e, now, export_id],
+            ),
+        }
+    };
+    match (result, update) {
+        (Ok(()), Ok(0)) => {
+            // The account was deleted or the build timed out while the export was being built
+            remove_export_files(vec![path.to_string_lossy().to_string()]).await;
+        }
+        (Ok(()), Ok(_)) => info!("Export {} ready for user {}", export_id, user_id),
+        (Err(e), _) => warn!("Export {} for user {} failed: {}", export_id, user_id, e),
+        (_, Err(e)) => warn!("Failed to record export {}: {}", export_id, e),
+    }
+}
+
+// Helper function for describing an export to its owner
+fn export_json(id: i64, status: &str, token: &str, expires_at: Option<i64>) -> serde_json::Value {
+    serde_json::json!({
+        "success": status != "failed",
+        "export_id": id,
+        "status": status,
+        "download_url": if status == "ready" { Some(format!("/exports/{}", token)) } else { None },
+        "expires_at": expires_at
+    })
+}
+
+// API for exporting everything stored about the logged in user: GET /me/export
+// Requires the Authorization: Bearer session token
+// Starts building a ZIP in the background and returns 202 until it is ready
+// Once ready returns the download link and when it expires
+// Calling again after the link expires or the build failed starts a new export
+// A build still pending after EXPORT_BUILD_TIMEOUT_MS counts as failed
+pub async fn get_export(req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    let conn = state.db_conn.lock().unwrap();
+    let uid = match sessions::session_user(&conn, &req) {
+        Some(uid) => uid,
+        None => return login_required(),
+    };
+    let now = Utc::now().timestamp_millis();
+    if let Err(e) = fail_stale_exports(&conn, now - EXPORT_BUILD_TIMEOUT_MS, now) {
+        return HttpResponse::InternalServerError().body(format!("DB error: {}", e));
+    }
+    let existing = conn.query_row(
+        "SELECT id, status, token, expires_at FROM data_exports
+         WHERE user_id = ?1 AND (status = 'pending' OR (status = 'ready' AND expires_at > ?2))
+         ORDER BY id DESC LIMIT 1",
+        params![uid, now],
+        |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, Option<i64>>(3)?,
+            ))
+        },
+    );
+    match existing {
+        Ok((id, status, token, expires_at)) if status == "ready" => {
+            return HttpResponse::Ok().json(export_json(id, &status, &token, expires_at));
+        }
+        Ok((id, status, token, expires_at)) => {
+            return HttpResponse::Accepted().json(export_json(id, &status, &token, expires_at));
+        }
+        Err(rusqlite::Error::QueryReturnedNoRows) => {}
+        Err(e) => return HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
+    }
+    let token = uuid::Uuid::new_v4().simple().to_string();
+    if let Err(e) = conn.execute(
+        "INSERT INTO data_exports (user_id, token, status, created_at) VALUES (?1, ?2, 'pending', ?3)",
+        params![uid, token, now],
+    ) {
+        return HttpResponse::InternalServerError().body(format!("DB error: {}", e));
+    }
+    let export_id = conn.last_insert_rowid();
+    drop(conn);
+    info!("Started export {} for user {}", export_id, uid);
+    actix_web::rt::spawn(build_export(state.clone(), export_id, uid, token.clone()));
+    HttpResponse::Accepted().json(export_json(export_id, "pending", &token, None))
+}
+
+// API for downloading a finished export: GET /exports/{token}
+// The token in the link is the only credential, so links stop working once they expire
+// Returns the ZIP as an attachment, streamed from disk
+pub async fn download_export(
+    token: web::Path<String>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let token = token.into_inner();
+    let result = state.db_conn.lock().unwrap().query_row(
+        "SELECT user_id, file_path, expires_at FROM data_exports WHERE token = ?1 AND status = 'ready'",
+        params![token],
+        |row| {
+            Ok((
+                row.get::<_, i32>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, i64>(2)?,
+            ))
+        },
+    );
+    let (uid, path, expires_at) = match result {
+        Ok(v) => v,
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return HttpResponse::NotFound().body("Export not found");
+        }
+        Err(_) => return HttpResponse::InternalServerError().body("Database error"),
+    };
+    if expires_at <= Utc::now().timestamp_millis() {
+        return HttpResponse::Gone().body("Export link has expired");
+    }
+    let opened = web::block(move || {
+        let file = fs::File::open(&path)?;
+        let len = file.metadata()?.len();
+        Ok::<_, std::io::Error>((Box::new(file) as ObjectReader, len))
+    })
+    .await;
+    match opened {
+        Ok(Ok((reader, len))) => HttpResponse::Ok()
+            .content_type("application/zip")
+            .insert_header((
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"jaymatch-export-{}.zip\"", uid),
+            ))
+            .no_chunking(len)
+            .streaming(photos::stream_reader(reader)),
+        _ => HttpResponse::NotFound().body("Export not found"),
+    }
+}
+
+// Function for marking exports that were started before the given time and never finished as failed
+// Called at startup for every pending export, since a restart stops their builds, and by GET /me/export for builds past the timeout
+// Failed exports expire immediately so the cleanup job removes them
+// Returns the number of exports marked failed
+pub fn fail_stale_exports(
+    conn: &Connection,
+    started_before: i64,
+    now: i64,
+) -> rusqlite::Result<usize> {
+    conn.execute(
+        "UPDATE data_exports SET status = 'failed', error = 'Export did not finish', completed_at = ?1, expires_at = ?1
+         WHERE status = 'pending' AND created_at <= ?2",
+        params![now, started_before],
+    )
+}
+
+// Helper function for listing the export files that belong to a user
+// Used before the user's rows are deleted; remove_export_files removes them once the database lock is released
+pub fn user_export_paths(conn: &Connection, user_id: i32) -> Vec<String> {
+    match conn
+        .prepare("SELECT file_path FROM data_exports WHERE user_id = ?1 AND file_path IS NOT NULL")
+    {
+        Ok(mut stmt) => stmt
+            .query_map(params![user_id], |row| row.get(0))
+            .map(|rows| rows.filter_map(|r| r.ok()).collect())
+            .unwrap_or_default(),
+        Err(_) => Vec::new(),
+    }
+}
+
+// Helper function for removing export files from disk
+// Runs on the blocking thread pool so a slow disk never stalls a worker
+// Callers release the database lock before awaiting this
+pub async fn remove_export_files(paths: Vec<String>) {
+    if paths.is_empty() {
+        return;
+    }
+    let removed = web::block(move || {
+        for path in &paths {
+            if let Err(e) = fs::remove_file(path)
+                && e.kind() != std::io::ErrorKind::NotFound
+            {
+                warn!("Failed to remove export {}: {}", path, e);
+            }
+        }
+    })
+    .await;
+    if let Err(e) = removed {
+        warn!("Failed to remove exports: {}", e);
+    }
+}
+
+// Function for deleting exports whose download link has expired
+// Removes the rows from the database and returns their files for remove_export_files
+// The files are left on disk so they can be removed after the database lock is released
+// Returns the number of exports removed and the paths of their files
+pub fn purge_expired_exports(
+    conn: &Connection,
+    now: i64,
+) -> rusqlite::Result<(usize, Vec<String>)> {
+    let expired: Vec<(i64, Option<String>)> = conn
+        .prepare("SELECT id, file_path FROM data_exports WHERE expires_at <= ?1")?
+        .query_map(params![now], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .filter_map(|r| r.ok())
+        .collect();
+    let mut paths = Vec::new();
+    for (id, path) in expired.iter() {
+        conn.execute("DELETE FROM data_exports WHERE id = ?1", params![id])?;
+        paths.extend(path.clone());
+    }
+    Ok((expired.len(), paths))
+}
+
+// Function for starting the background job that removes expired exports
+// Runs once at startup and then every CLEANUP_INTERVAL
+// The files are removed on the blocking pool once the database lock is released
+pub fn start_cleanup_job(state: web::Data<AppState>) {
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(CLEANUP_INTERVAL);
+        loop {
+            interval.tick().await;
+            let purged = purge_expired_exports(
+                &state.db_conn.lock().unwrap(),
+                Utc::now().timestamp_millis(),
+            );
+            match purged {
+                Ok((0, _)) => {}
+                Ok((n, paths)) => {
+                    remove_export_files(paths).await;
+                    info!("Removed {} expired exports", n);
+                }
+                Err(e) => warn!("Failed to remove expired exports: {}", e),
+            }
+        }
+    });
+}
@@ -0,0 +1,189 @@
+/*
+Name: JayMatch image processing
+Description: Validates uploaded photos, strips their metadata by re-encoding them as JPEG, and generates thumbnails
+Pre/Post Conditions: Takes the raw bytes a client uploaded, already checked against the upload size cap. On success the canonical image and every thumbnail size are saved to the media store under related keys.
+Errors: Files that are not JPEG, PNG, GIF or WebP images, or that are outside the dimension limits, are rejected with a message for the client. Failed writes remove anything already written.
+*/
+
+use crate::media::MediaStore;
+use actix_web::web;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits};
+use std::io::Cursor;
+use std::sync::Arc;
+
+// Error from processing an upload
+// Invalid uploads are the client's fault, storage failures are the server's
+#[derive(Debug)]
+pub enum ImageError {
+    Invalid(String),
+    Storage(String),
+}
+
+// Smallest width and height accepted for a photo
+pub const MIN_DIMENSION: u32 = 200;
+
+// Largest width and height accepted for a photo
+pub const MAX_DIMENSION: u32 = 8000;
+
+// Longest side of the stored full size image
+const CANONICAL_DIMENSION: u32 = 2048;
+
+// JPEG quality used for every stored image
+const JPEG_QUALITY: u8 = 85;
+
+// Thumbnail names and the length of their longest side
+pub const THUMBNAIL_SIZES: &[(&str, u32)] = &[("small", 160), ("medium", 480), ("large", 1080)];
+
+// Helper function for the key of a thumbnail of a stored image
+// profile_pictures/abc.jpg with size small is stored as profile_pictures/abc_small.jpg
+pub fn thumbnail_path(path: &str, size: &str) -> String {
+    match path.rsplit_once('.') {
+        Some((stem, ext)) => format!("{}_{}.{}", stem, size, ext),
+        None => format!("{}_{}", path, size),
+    }
+}
+
+// Helper function for checking if a requested thumbnail size exists
+pub fn is_thumbnail_size(size: &str) -> bool {
+    THUMBNAIL_SIZES.iter().any(|(name, _)| *name == size)
+}
+
+// Helper function for the content type of stored image bytes
+// Reads the file's signature instead of trusting its extension
+pub fn content_type(bytes: &[u8]) -> &'static str {
+    match image::guess_format(bytes) {
+        Ok(ImageFormat::Jpeg) => "image/jpeg",
+        Ok(ImageFormat::Png) => "image/png",
+        Ok(ImageFormat::Gif) => "image/gif",
+        Ok(ImageFormat::WebP) => "image/webp",
+        _ => "application/octet-stream",
+    }
+}
+
+// Helper function for removing a stored image and all of its thumbnails
+// Missing thumbnails are ignored so this is safe for images uploaded before thumbnails existed
+pub fn remove_image(store: &dyn MediaStore, key: &str) {
+    if let Err(e) = store.delete(key) {
+        log::warn!("Failed to remove image {}: {}", key, e);
+    }
+    for (size, _) in THUMBNAIL_SIZES {
+        let _ = store.delete(&thumbnail_path(key, size));
+    }
+}
+
+// Helper function for copying a stored image and its thumbnails to another key
+// Missing thumbnails are skipped, as in remove_image
+pub fn copy_image(store: &dyn MediaStore, from: &str, to: &str) -> std::io::Result<()> {
+    let data = store.get(from)?;
+    store.put(to, &data, content_type(&data))?;
+    for (size, _) in THUMBNAIL_SIZES {
+        if let Ok(thumb) = store.get(&thumbnail_path(from, size)) {
+            store.put(&thumbnail_path(to, size), &thumb, content_type(&thumb))?;
+        }
+    }
+    Ok(())
+}
+
+// Helper function for removing stored images and their thumbnails from a request handler
+// Runs on the blocking thread pool so slow storage never stalls a worker
+// Callers release the database lock before awaiting this
+pub async fn remove_images(store: Arc<dyn MediaStore>, keys: Vec<String>) {
+    if keys.is_empty() {
+        return;
+    }
+    let removed = web::block(move || {
+        for key in &keys {
+            remove_image(store.as_ref(), key);
+        }
+    })
+    .await;
+    if let Err(e) = removed {
+        log::warn!("Failed to remove images: {}", e);
+    }
+}
+
+// Helper function for encoding an image as JPEG
+// Encoding from decoded pixels means no EXIF or other metadata is carried over
+fn encode_jpeg(image: &DynamicImage) -> Result<Vec<u8>, ImageError> {
+    let mut out = Vec::new();
+    JpegEncoder::new_with_quality(&mut out, JPEG_QUALITY)
+        .encode_image(&DynamicImage::ImageRgb8(image.to_rgb8()))
+        .map_err(|e| ImageError::Storage(format!("Failed to encode image: {}", e)))?;
+    Ok(out)
+}
+
+// Helper function for decoding and checking an uploaded image
+// Reads the dimensions from the header before decoding so oversized images are rejected cheaply
+// Applies the EXIF orientation so photos taken sideways display upright once metadata is removed
+fn decode(bytes: &[u8]) -> Result<DynamicImage, String> {
+    let reader = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .map_err(|_| "Could not read image".to_string())?;
+    match reader.format() {
+        Some(ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::Gif | ImageFormat::WebP) => {}
+        _ => return Err("Image must be a JPEG, PNG, GIF or WebP".to_string()),
+    }
+    let (width, height) = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .ok()
+        .and_then(|r| r.into_dimensions().ok())
+        .ok_or_else(|| "Could not read image".to_string())?;
+    if width < MIN_DIMENSION || height < MIN_DIMENSION {
+        return Err(format!(
+            "Image must be at least {}x{} pixels",
+            MIN_DIMENSION, MIN_DIMENSION
+        ));
+    }
+    if width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return Err(format!(
+            "Image must be at most {}x{} pixels",
+            MAX_DIMENSION, MAX_DIMENSION
+        ));
+    }
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    let mut reader = reader;
+    reader.limits(limits);
+    let mut decoder = reader
+        .into_decoder()
+        .map_err(|_| "Could not read image".to_string())?;
+    let orientation = decoder.orientation().ok();
+    let mut image = DynamicImage::from_decoder(decoder)
+        .map_err(|_| "Image is corrupt or incomplete".to_string())?;
+    if let Some(orientation) = orientation {
+        image.apply_orientation(orientation);
+    }
+    Ok(image)
+}
+
+// Function for validating an upload and storing it with its thumbnails
+// Stores the canonical JPEG under key and each thumbnail under its thumbnail key
+// Returns a message for the client if the upload is not an acceptable image
+pub fn process_upload(bytes: &[u8], key: &str, store: &dyn MediaStore) -> Result<(), ImageError> {
+    let image = decode(bytes).map_err(ImageError::Invalid)?;
+    let canonical = if image.width() > CANONICAL_DIMENSION || image.height() > CANONICAL_DIMENSION {
+        image.resize(
+            CANONICAL_DIMENSION,
+            CANONICAL_DIMENSION,
+            FilterType::Lanczos3,
+        )
+    } else {
+        image
+    };
+    let mut outputs = vec![(key.to_string(), encode_jpeg(&canonical)?)];
+    // Thumbnails are made from the canonical image so they share its orientation
+    for (size, dimension) in THUMBNAIL_SIZES {
+        let thumb = canonical.thumbnail(*dimension, *dimension);
+        outputs.push((thumbnail_path(key, size), encode_jpeg(&thumb)?));
+    }
+    for (out_key, data) in &outputs {
+        if let Err(e) = store.put(out_key, data, "image/jpeg") {
+            remove_image(store, key);
+            return Err(ImageError::Storage(format!("Failed to store image: {}", e)));
+        }
+    }
+    Ok(())
+}
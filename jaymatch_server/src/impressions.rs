@@ -0,0 +1,158 @@
+/*
+Name: JayMatch profile impressions
+Description: Counts how often each profile is shown to other users, in queue batches and as full profile views, and reports the weekly total to the profile's owner (R26)
+Pre/Post Conditions: The profile_impressions and impression_viewers tables must exist. Impressions are kept as one count per profile, day (midnight UTC) and source; each viewer counts once a day, and who viewed a profile is only kept for the current day. A week is the current day and the six before it.
+Errors: Missing sessions return 401, restricted accounts receive 403, database errors return 500. Failing to record an impression never fails the request that served the profile.
+*/
+
+use crate::{AppState, entitlements, moderation, sessions};
+use actix_web::{HttpRequest, HttpResponse, Responder, web};
+use chrono::Utc;
+use log::warn;
+use rusqlite::{Connection, params};
+use serde::Serialize;
+
+// Number of days, including today, counted as this week
+pub const WEEK_DAYS: i64 = 7;
+
+const DAY_MS: i64 = 24 * 60 * 60 * 1000;
+
+// SQL expression for the impressions a profiles row has had this week
+// Used by the queue to give profiles that have been seen less a turn first
+pub const WEEKLY_SQL: &str = "(SELECT COALESCE(SUM(count), 0) FROM profile_impressions
+    WHERE profile_impressions.user_id = profiles.user_id
+      AND profile_impressions.day >= CAST(strftime('%s', 'now', 'start of day', '-6 days') AS INTEGER) * 1000)";
+
+// Where a profile was shown
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    // Served in a queue batch
+    Queue,
+    // Opened as a full profile by another user
+    Profile,
+}
+
+impl Source {
+    // Name of the source as stored and sent to clients
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Source::Queue => "queue",
+            Source::Profile => "profile",
+        }
+    }
+}
+
+// Structure for one day of a profile's impressions
+#[derive(Serialize, Debug, Clone)]
+pub struct DailyImpressions {
+    pub day: i64,
+    pub queue: i64,
+    pub profile: i64,
+}
+
+// Helper function for the response sent when the caller has no session
+fn login_required() -> HttpResponse {
+    HttpResponse::Unauthorized().json(serde_json::json!({
+        "success": false,
+        "message": "Login required"
+    }))
+}
+
+// Function for counting an impression of each of the given profiles by a viewer today
+// Profiles the viewer was already shown today from the same source are not counted again,
+// so refetching a queue batch or reopening a profile does not inflate the count
+pub fn record(conn: &Connection, viewer_id: i32, user_ids: &[i32], source: Source) {
+    let day = entitlements::day_start(Utc::now().timestamp_millis());
+    if let Err(e) = conn.execute(
+        "DELETE FROM impression_viewers WHERE day < ?1",
+        params![day],
+    ) {
+        warn!("Could not clear earlier impression viewers: {}", e);
+    }
+    for user_id in user_ids {
+        let counted = conn
+            .execute(
+                "INSERT OR IGNORE INTO impression_viewers (user_id, viewer_id, day, source) VALUES (?1, ?2, ?3, ?4)",
+                params![user_id, viewer_id, day, source.as_str()],
+            )
+            .and_then(|new| match new {
+                0 => Ok(0),
+                _ => conn.execute(
+                    "INSERT INTO profile_impressions (user_id, day, source, count) VALUES (?1, ?2, ?3, 1)
+                     ON CONFLICT(user_id, day, source) DO UPDATE SET count = count + 1",
+                    params![user_id, day, source.as_str()],
+                ),
+            });
+        if let Err(e) = counted {
+            warn!("Could not record impression of user {}: {}", user_id, e);
+        }
+    }
+}
+
+// Function for a profile's impressions per day over the WEEK_DAYS up to now, oldest first
+// Days with no impressions are included with zero counts
+pub fn weekly(
+    conn: &Connection,
+    user_id: i32,
+    now: i64,
+) -> rusqlite::Result<Vec<DailyImpressions>> {
+    let today = entitlements::day_start(now);
+    let mut days: Vec<DailyImpressions> = (0..WEEK_DAYS)
+        .rev()
+        .map(|n| DailyImpressions {
+            day: today - n * DAY_MS,
+            queue: 0,
+            profile: 0,
+        })
+        .collect();
+    let mut stmt = conn.prepare(
+        "SELECT day, source, count FROM profile_impressions WHERE user_id = ?1 AND day >= ?2",
+    )?;
+    let rows = stmt.query_map(params![user_id, days[0].day], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, i64>(2)?,
+        ))
+    })?;
+    for row in rows {
+        let (day, source, count) = row?;
+        if let Some(d) = days.iter_mut().find(|d| d.day == day) {
+            if source == Source::Queue.as_str() {
+                d.queue += count;
+            } else {
+                d.profile += count;
+            }
+        }
+    }
+    Ok(days)
+}
+
+// API for how often the logged in user's profile was seen this week: GET /me/impressions
+// Requires the Authorization: Bearer session token
+// Returns the week's total, split into queue impressions and full profile views, and the counts for each day
+pub async fn get_impressions(req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    let conn = state.db_conn.lock().unwrap();
+    let uid = match sessions::session_user(&conn, &req) {
+        Some(uid) => uid,
+        None => return login_required(),
+    };
+    if let Some(r) = moderation::account_restriction(&conn, uid) {
+        return moderation::restricted_response(&r);
+    }
+    match weekly(&conn, uid, Utc::now().timestamp_millis()) {
+        Ok(days) => {
+            let queue: i64 = days.iter().map(|d| d.queue).sum();
+            let profile: i64 = days.iter().map(|d| d.profile).sum();
+            HttpResponse::Ok().json(serde_json::json!({
+                "user_id": uid,
+                "since": days[0].day,
+                "seen_this_week": queue + profile,
+                "queue_impressions": queue,
+                "profile_views": profile,
+                "days": days
+            }))
+        }
+        Err(_) => HttpResponse::InternalServerError().body("Database error"),
+    }
+}
@@ -0,0 +1,342 @@
+/*
+Name: JayMatch interests
+Description: Keeps the catalogue of interest tags, links profiles to them, and provides tag search and the interest filter used by the queue
+Pre/Post Conditions: The interests, interest_synonyms, profile_interests and preference_interests tables must exist. profile_interests is the source of truth; profiles.interests always holds the same tags as a JSON list of their catalogue names so profile queries can return them without another query.
+Errors: Empty, overlong or too many tags and unknown interests in a filter return 400, database errors return 500.
+*/
+
+use crate::{AppState, moderation, vocabulary};
+use actix_web::{HttpResponse, Responder, web};
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::Serialize;
+use std::collections::HashMap;
+
+// Most interests a single profile can list
+pub const MAX_INTERESTS: usize = 20;
+
+// Longest tag accepted, in characters
+const MAX_TAG_CHARS: usize = 40;
+
+// Number of suggestions returned by GET /interests when no limit is given, and the most allowed
+const DEFAULT_SUGGESTIONS: i64 = 10;
+const MAX_SUGGESTIONS: i64 = 50;
+
+// Key for the interest filter in GET and PUT /preferences
+pub const FILTER_KEY: &str = "interests_preference";
+
+// Curated interests and the other ways people write them
+// Tags users type that match none of these are added to the catalogue as they are first written
+pub const CATALOGUE: &[(&str, &[&str])] = &[
+    ("Basketball", &["bball", "hoops"]),
+    ("Football", &["american football"]),
+    ("Soccer", &["futbol"]),
+    ("Running", &["jogging", "track"]),
+    ("Hiking", &["trails", "backpacking"]),
+    ("Rock Climbing", &["climbing", "bouldering"]),
+    (
+        "Working Out",
+        &["gym", "fitness", "lifting", "weightlifting"],
+    ),
+    ("Yoga", &[]),
+    ("Video Games", &["gaming", "games", "videogames"]),
+    ("Board Games", &["tabletop", "board gaming"]),
+    ("Reading", &["books", "literature"]),
+    ("Writing", &["creative writing"]),
+    ("Music", &[]),
+    ("Live Music", &["concerts", "gigs"]),
+    ("Movies", &["film", "films", "cinema"]),
+    ("TV Shows", &["tv", "television", "binge watching"]),
+    ("Anime", &[]),
+    ("Cooking", &["baking"]),
+    ("Coffee", &["cafes"]),
+    ("Photography", &["photos"]),
+    ("Art", &["drawing", "painting"]),
+    ("Travel", &["traveling", "travelling"]),
+    ("Dancing", &["dance"]),
+    ("Theatre", &["theater", "musicals"]),
+    ("Coding", &["programming", "software"]),
+    ("Volunteering", &["community service"]),
+    ("Pets", &["dogs", "cats", "animals"]),
+    ("Fashion", &["thrifting"]),
+    ("Camping", &[]),
+    ("Fishing", &[]),
+];
+
+// Structure for a tag suggestion returned by GET /interests
+// count is the number of active profiles listing the interest
+#[derive(Serialize, Debug, Clone)]
+pub struct Suggestion {
+    pub id: i64,
+    pub name: String,
+    pub count: i64,
+}
+
+// Condition that a profile in a queue query shares at least one interest the viewer (?1) filters on
+// Passes every profile when the viewer has no interest filter
+pub const FILTER_SQL: &str = "(NOT EXISTS (SELECT 1 FROM preference_interests WHERE user_id = ?1)
+    OR EXISTS (SELECT 1 FROM profile_interests pi
+               JOIN preference_interests f ON f.interest_id = pi.interest_id
+               WHERE f.user_id = ?1 AND pi.user_id = profiles.user_id))";
+
+// Number of interests a profile in a queue query has in common with the viewer (?1)
+pub const SHARED_SQL: &str = "(SELECT COUNT(*) FROM profile_interests pi
+    WHERE pi.user_id = profiles.user_id
+      AND pi.interest_id IN (SELECT interest_id FROM profile_interests WHERE user_id = ?1))";
+
+// Function for copying the catalogue and its synonyms into the database
+// Interests users added keep their IDs if they are later added to the catalogue
+pub fn seed_catalogue(conn: &Connection) -> rusqlite::Result<()> {
+    for (name, synonyms) in CATALOGUE {
+        conn.execute(
+            "INSERT INTO interests (name, slug, curated) VALUES (?1, ?2, 1)
+             ON CONFLICT(slug) DO UPDATE SET name = ?1, curated = 1",
+            params![name, vocabulary::match_key(name)],
+        )?;
+        for synonym in *synonyms {
+            conn.execute(
+                "INSERT OR REPLACE INTO interest_synonyms (slug, interest_id)
+                 SELECT ?1, id FROM interests WHERE slug = ?2",
+                params![vocabulary::match_key(synonym), vocabulary::match_key(name)],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+// Function for checking tags sent by a client
+// Trims them and collapses runs of whitespace, and drops repeats that only differ in case or punctuation
+pub fn clean_tags(tags: &[String]) -> Result<Vec<String>, String> {
+    let mut cleaned: Vec<String> = Vec::new();
+    for tag in tags {
+        let tag = tag.split_whitespace().collect::<Vec<_>>().join(" ");
+        let key = vocabulary::match_key(&tag);
+        if key.is_empty() {
+            return Err("Interests must contain a letter or digit".to_string());
+        }
+        if tag.chars().count() > MAX_TAG_CHARS {
+            return Err(format!(
+                "Interests can be at most {} characters",
+                MAX_TAG_CHARS
+            ));
+        }
+        if !cleaned.iter().any(|t| vocabulary::match_key(t) == key) {
+            cleaned.push(tag);
+        }
+    }
+    if cleaned.len() > MAX_INTERESTS {
+        return Err(format!("You can list at most {} interests", MAX_INTERESTS));
+    }
+    Ok(cleaned)
+}
+
+// Function for reading the interests field of a profile update
+// Null clears the interests
+pub fn tags_from_json(value: &serde_json::Value) -> Result<Vec<String>, String> {
+    match value {
+        serde_json::Value::Null => Ok(Vec::new()),
+        serde_json::Value::Array(items) => {
+            let tags: Option<Vec<String>> = items
+                .iter()
+                .map(|i| i.as_str().map(str::to_string))
+                .collect();
+            match tags {
+                Some(tags) => clean_tags(&tags),
+                None => Err("interests must be a list of strings or null".to_string()),
+            }
+        }
+        _ => Err("interests must be a list of strings or null".to_string()),
+    }
+}
+
+// Helper function for finding the interest a tag refers to by its name or a synonym
+// Returns the interest's ID and catalogue name
+pub fn find(conn: &Connection, tag: &str) -> rusqlite::Result<Option<(i64, String)>> {
+    conn.query_row(
+        "SELECT id, name FROM interests WHERE slug = ?1
+         UNION ALL
+         SELECT i.id, i.name FROM interest_synonyms s JOIN interests i ON i.id = s.interest_id
+         WHERE s.slug = ?1
+         LIMIT 1",
+        params![vocabulary::match_key(tag)],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+    .optional()
+}
+
+// Helper function for listing the catalogue names of a user's interests in the order they gave them
+pub fn list_interests(conn: &Connection, user_id: i32) -> rusqlite::Result<Vec<String>> {
+    conn.prepare(
+        "SELECT i.name FROM profile_interests pi JOIN interests i ON i.id = pi.interest_id
+         WHERE pi.user_id = ?1 ORDER BY pi.position",
+    )?
+    .query_map(params![user_id], |row| row.get(0))?
+    .collect()
+}
+
+// Function for replacing a user's interests with tags checked by clean_tags
+// Tags not in the catalogue are added to it
+// Updates profiles.interests to match and returns the catalogue names saved
+pub fn set_profile_interests(
+    conn: &Connection,
+    user_id: i32,
+    tags: &[String],
+) -> rusqlite::Result<Vec<String>> {
+    conn.execute(
+        "DELETE FROM profile_interests WHERE user_id = ?1",
+        params![user_id],
+    )?;
+    for (position, tag) in tags.iter().enumerate() {
+        let id = match find(conn, tag)? {
+            Some((id, _)) => id,
+            None => {
+                conn.execute(
+                    "INSERT INTO interests (name, slug, curated) VALUES (?1, ?2, 0)",
+                    params![tag, vocabulary::match_key(tag)],
+                )?;
+                conn.last_insert_rowid()
+            }
+        };
+        conn.execute(
+            "INSERT OR IGNORE INTO profile_interests (user_id, interest_id, position)
+             VALUES (?1, ?2, ?3)",
+            params![user_id, id, position as i64],
+        )?;
+    }
+    let names = list_interests(conn, user_id)?;
+    let mirror = match names.is_empty() {
+        true => None,
+        false => Some(serde_json::to_string(&names).unwrap_or_default()),
+    };
+    conn.execute(
+        "UPDATE profiles SET interests = ?1 WHERE user_id = ?2",
+        params![mirror, user_id],
+    )?;
+    Ok(names)
+}
+
+// Helper function for reading interests saved before the catalogue existed
+// Older revisions saved either a JSON list or comma-separated text
+fn parse_legacy(text: &str) -> Vec<String> {
+    serde_json::from_str::<Vec<String>>(text).unwrap_or_else(|_| {
+        text.split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect()
+    })
+}
+
+// Function for moving interests saved in profiles.interests into profile_interests
+// Tags that clean_tags would reject are dropped, and lists are cut to MAX_INTERESTS
+pub fn migrate_legacy(conn: &Connection) -> rusqlite::Result<()> {
+    let rows: Vec<(i32, String)> = conn
+        .prepare("SELECT user_id, interests FROM profiles WHERE interests IS NOT NULL")?
+        .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+    for (user_id, text) in rows {
+        let mut tags: Vec<String> = Vec::new();
+        for tag in parse_legacy(&text) {
+            let mut with_tag = tags.clone();
+            with_tag.push(tag);
+            if let Ok(cleaned) = clean_tags(&with_tag) {
+                tags = cleaned;
+            }
+        }
+        set_profile_interests(conn, user_id, &tags)?;
+    }
+    Ok(())
+}
+
+// Function for reading the interest filter from the body of PUT /preferences
+// Every tag must already be in the catalogue; returns their IDs
+pub fn filter_ids(
+    conn: &Connection,
+    body: &serde_json::Map<String, serde_json::Value>,
+) -> Result<Vec<i64>, String> {
+    let tags = tags_from_json(body.get(FILTER_KEY).unwrap_or(&serde_json::Value::Null))
+        .map_err(|_| format!("{} must be a list of strings or null", FILTER_KEY))?;
+    let mut ids = Vec::new();
+    for tag in tags {
+        match find(conn, &tag).ok().flatten() {
+            Some((id, _)) if !ids.contains(&id) => ids.push(id),
+            Some(_) => {}
+            None => return Err(format!("Unknown interest: {}", tag)),
+        }
+    }
+    Ok(ids)
+}
+
+// Function for replacing a user's interest filter
+pub fn save_filter(conn: &Connection, user_id: i32, ids: &[i64]) -> rusqlite::Result<()> {
+    conn.execute(
+        "DELETE FROM preference_interests WHERE user_id = ?1",
+        params![user_id],
+    )?;
+    for id in ids {
+        conn.execute(
+            "INSERT INTO preference_interests (user_id, interest_id) VALUES (?1, ?2)",
+            params![user_id, id],
+        )?;
+    }
+    Ok(())
+}
+
+// Function for loading the catalogue names in a user's interest filter
+// Returns null when the user has no interest filter
+pub fn load_filter(conn: &Connection, user_id: i32) -> rusqlite::Result<serde_json::Value> {
+    let names: Vec<String> = conn
+        .prepare(
+            "SELECT i.name FROM preference_interests f JOIN interests i ON i.id = f.interest_id
+             WHERE f.user_id = ?1 ORDER BY i.name",
+        )?
+        .query_map(params![user_id], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(match names.is_empty() {
+        true => serde_json::Value::Null,
+        false => serde_json::json!(names),
+    })
+}
+
+// API for searching interest tags: GET /interests?prefix=&limit=
+// Matches the start of an interest's name or of one of its synonyms, ignoring case and punctuation
+// Without a prefix returns the most popular interests
+// Results are ordered by how many active profiles list them
+// User-added tags that no profile lists any more are left out
+pub async fn search_interests(
+    query: web::Query<HashMap<String, String>>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let prefix = vocabulary::match_key(query.get("prefix").map(String::as_str).unwrap_or(""));
+    let limit = query
+        .get("limit")
+        .and_then(|l| l.parse::<i64>().ok())
+        .unwrap_or(DEFAULT_SUGGESTIONS)
+        .clamp(1, MAX_SUGGESTIONS);
+    let conn = state.db_conn.lock().unwrap();
+    let sql = format!(
+        "SELECT i.id, i.name,
+                (SELECT COUNT(*) FROM profile_interests pi JOIN profiles USING (user_id)
+                 WHERE pi.interest_id = i.id AND {}) AS count
+         FROM interests i
+         WHERE i.slug LIKE ?1 || '%'
+            OR i.id IN (SELECT interest_id FROM interest_synonyms WHERE slug LIKE ?1 || '%')
+         GROUP BY i.id
+         HAVING count > 0 OR i.curated = 1
+         ORDER BY count DESC, i.name
+         LIMIT ?2",
+        moderation::ACTIVE_ACCOUNT_SQL
+    );
+    let suggestions: rusqlite::Result<Vec<Suggestion>> = conn.prepare(&sql).and_then(|mut stmt| {
+        stmt.query_map(params![prefix, limit], |row| {
+            Ok(Suggestion {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                count: row.get(2)?,
+            })
+        })?
+        .collect()
+    });
+    match suggestions {
+        Ok(suggestions) => HttpResponse::Ok().json(suggestions),
+        Err(_) => HttpResponse::InternalServerError().body("Database error"),
+    }
+}
@@ -0,0 +1,125 @@
+/*
+Name: JayMatch likes received
+Description: Shows users who has liked them and is still waiting for a swipe back, with full profiles for premium users and a blurred teaser for everyone else (R26)
+Pre/Post Conditions: The swipes, matches, blocked_users and profiles tables must exist. A like is pending until the user swipes on the liker, they match, or either blocks the other.
+Errors: Missing sessions return 401, restricted accounts receive 403, database errors return 500.
+*/
+
+use crate::{
+    AppState, Profile, entitlements, interests, moderation, prompts, row_to_profile, sessions,
+    visibility,
+};
+use actix_web::{HttpRequest, HttpResponse, Responder, web};
+use rusqlite::{Connection, params};
+use serde::Serialize;
+
+// Structure for the prompt answer a like was attached to
+#[derive(Serialize, Debug, Clone)]
+pub struct LikedAnswer {
+    pub id: i64,
+    pub prompt: String,
+    pub answer: String,
+}
+
+// Structure for a like the user has received
+// profile is only filled in for users with the see_likes entitlement
+#[derive(Serialize, Debug, Clone)]
+struct ReceivedLike {
+    liked_at: i64,
+    super_like: bool,
+    prompt_answer: Option<LikedAnswer>,
+    blurred: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profile: Option<Profile>,
+}
+
+// Helper function for the response sent when the caller has no session
+fn login_required() -> HttpResponse {
+    HttpResponse::Unauthorized().json(serde_json::json!({
+        "success": false,
+        "message": "Login required"
+    }))
+}
+
+// Helper function for listing the likes a user has not answered yet, super likes first and then newest first
+// Likes from users the viewer cannot see (blocked, inactive or hidden) are left out
+// Returns each like with the liker's profile, which callers drop for free users
+fn pending_likes(conn: &Connection, user_id: i32) -> rusqlite::Result<Vec<ReceivedLike>> {
+    let sql = format!(
+        "SELECT profiles.user_id, email, name, age, major, year, bio, profile_picture, interests, gender, is_felon,
+                attributes, {} AS shared_interests, s.created_at, s.super_like, a.id, pr.text, a.answer
+         FROM swipes s
+         JOIN profiles ON profiles.user_id = s.user_id
+         LEFT JOIN prompt_answers a ON a.id = s.prompt_answer_id
+         LEFT JOIN prompts pr ON pr.id = a.prompt_id
+         WHERE s.target_user_id = ?1 AND s.direction = 'like'
+           AND NOT EXISTS (SELECT 1 FROM swipes back WHERE back.user_id = ?1 AND back.target_user_id = s.user_id)
+           AND s.user_id NOT IN (SELECT matched_user_id FROM matches WHERE user_id = ?1)
+           AND s.user_id NOT IN (SELECT user_id FROM matches WHERE matched_user_id = ?1)
+           AND s.user_id NOT IN (SELECT blocked_user_id FROM blocked_users WHERE user_id = ?1)
+           AND s.user_id NOT IN (SELECT user_id FROM blocked_users WHERE blocked_user_id = ?1)
+           AND {}
+           AND {}
+         ORDER BY s.super_like DESC, s.created_at DESC",
+        interests::SHARED_SQL,
+        moderation::ACTIVE_ACCOUNT_SQL,
+        visibility::UNHIDDEN_SQL
+    );
+    let mut stmt = conn.prepare(&sql)?;
+    stmt.query_map(params![user_id], |row| {
+        let answer = match row.get::<_, Option<i64>>(15)? {
+            Some(id) => Some(LikedAnswer {
+                id,
+                prompt: row.get(16)?,
+                answer: row.get(17)?,
+            }),
+            None => None,
+        };
+        Ok(ReceivedLike {
+            liked_at: row.get(13)?,
+            super_like: row.get(14)?,
+            prompt_answer: answer,
+            blurred: false,
+            profile: Some(row_to_profile(row)),
+        })
+    })?
+    .collect()
+}
+
+// API for the likes the logged in user has received: GET /likes/received
+// Requires the Authorization: Bearer session token
+// Users with the see_likes entitlement get each liker's full profile and prompt answers
+// Everyone else gets the count and, for each like, only when it was made, whether it was a super like
+// and which of their answers it was on
+pub async fn get_received_likes(req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    let conn = state.db_conn.lock().unwrap();
+    let uid = match sessions::session_user(&conn, &req) {
+        Some(uid) => uid,
+        None => return login_required(),
+    };
+    if let Some(r) = moderation::account_restriction(&conn, uid) {
+        return moderation::restricted_response(&r);
+    }
+    let pending = match pending_likes(&conn, uid) {
+        Ok(pending) => pending,
+        Err(_) => return HttpResponse::InternalServerError().body("Database error"),
+    };
+    let full = entitlements::has(&conn, uid, entitlements::Entitlement::SeeLikes);
+    let likes: Vec<ReceivedLike> = pending
+        .into_iter()
+        .map(|mut like| {
+            like.blurred = !full;
+            like.profile = like.profile.filter(|_| full).map(|mut profile| {
+                profile.prompts = prompts::list_answers(&conn, profile.user_id).ok();
+                profile
+            });
+            like
+        })
+        .collect();
+    HttpResponse::Ok().json(serde_json::json!({
+        "user_id": uid,
+        "count": likes.len(),
+        "blurred": !full,
+        "likes": likes
+    }))
+}
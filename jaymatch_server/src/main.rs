@@ -24,8 +24,6 @@ use actix_web_actors::ws;
 use chrono::{Local, Utc};
 // Environment logger configuration
 use env_logger::Env;
-// Stream utilities for async operations
-use futures_util::stream::StreamExt;
 // Logging macros
 use log::{info, warn};
 // SQLite database connection and parameter binding
@@ -36,12 +34,65 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 // File system operations
 use std::fs;
-// File writing operations
-use std::io::Write;
 // Path utilities
-use std::path::Path;
-// Mutex for thread-safe shared state
-use std::sync::Mutex;
+use std::path::{Path, PathBuf};
+// Shared ownership and mutex for thread-safe shared state
+use std::sync::{Arc, Mutex};
+
+// Blocking between users
+mod blocks;
+// Reports and the moderation queue
+mod reports;
+// Account suspensions and bans
+mod moderation;
+// Login session tokens
+mod sessions;
+// Table creation and schema upgrades
+mod db;
+// Multiple ordered profile photos
+mod photos;
+// Upload validation, re-encoding and thumbnails
+mod images;
+// Local and S3-compatible storage for uploaded images
+mod media;
+// Profile attribute schema, validation and queue filters
+mod attributes;
+// Matching typed values to canonical names and their aliases
+mod vocabulary;
+// Soft deletion with a grace period and the purge job
+mod deletion;
+// Personal data exports
+mod exports;
+// Prompt catalogue and profile prompt answers
+mod prompts;
+// Interest catalogue, tag search and the interest filter
+mod interests;
+// Profile completeness, the onboarding checklist and email verification
+mod onboarding;
+// Plans and the premium features they unlock
+mod entitlements;
+// Payment providers and webhook signatures
+mod payments;
+// Premium subscriptions and payment webhooks
+mod subscriptions;
+// Pausing, hiding and incognito profiles
+mod visibility;
+// Likes and passes on profiles
+mod swipes;
+// Likes waiting for a swipe back
+mod likes;
+// Profile boosts and their results
+mod boosts;
+// Counts of how often profiles are shown
+mod impressions;
+// Endpoint tests
+#[cfg(test)]
+mod tests;
+
+// Admin password used for moderator actions and forced account deletion
+const ADMIN_PASSWORD: &str = "1234";
+// Header that carries the admin password on moderator endpoints
+const ADMIN_HEADER: &str = "x-admin-key";
 
 // Data structure to hold and serialize profile data
 // Used for storing and transmitting user profile information
@@ -58,19 +109,24 @@ struct Profile {
     profile_picture: Option<String>,
     gender: Option<String>,
     is_felon: Option<bool>,
+    #[serde(flatten)]
+    attributes: serde_json::Map<String, serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    photos: Option<Vec<photos::Photo>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prompts: Option<Vec<prompts::PromptAnswer>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shared_interests: Option<i64>, // Interests in common with the user viewing the queue
 }
 
 // Structure to provide profile data to client for create/update operations
 #[derive(Serialize, Deserialize, Debug)]
 struct ProfileUpsert {
     name: Option<String>,
-    age: Option<i32>,
-    major: Option<String>,
-    year: Option<String>,
     bio: Option<String>,
     interests: Option<Vec<String>>,
-    profile_picture: Option<String>,
-    gender: Option<String>,
+    #[serde(flatten)]
+    attributes: serde_json::Map<String, serde_json::Value>,
 }
 
 // Structure to hold user authentication data
@@ -113,11 +169,23 @@ struct MessagePost {
 #[rtype(result = "()")]
 struct WsMessage(pub String);
 
+// WebSocket message telling a connection to close
+// Sent when an account is suspended or banned while connected
+#[derive(Message)]
+#[rtype(result = "()")]
+struct WsDisconnect(pub String);
+
 // Structure to hold application state
 // Shared across all HTTP handlers and WebSocket connections
 struct AppState {
-    db_conn: Mutex<Connection>,  // Thread-safe database connection
-    clients: Mutex<HashMap<i32, Recipient<WsMessage>>>,  // Active WebSocket clients by user ID
+    db_conn: Mutex<Connection>, // Thread-safe database connection
+    clients: Mutex<HashMap<i32, Addr<MyWs>>>, // Active WebSocket clients by user ID
+    deletion_grace_ms: i64,     // How long a deleted account can be restored
+    max_upload_bytes: usize,    // Largest image accepted by upload endpoints
+    staging_dir: PathBuf,       // Where uploads are streamed before they are checked
+    media: Arc<dyn media::MediaStore>, // Where uploaded images are stored
+    payments: Arc<dyn payments::PaymentProvider>, // Who premium subscriptions are bought through
+    export_dir: PathBuf,        // Where finished data exports are written
 }
 
 impl AppState {
@@ -125,10 +193,34 @@ impl AppState {
         Self {
             db_conn: Mutex::new(conn),
             clients: Mutex::new(HashMap::new()),
+            deletion_grace_ms: deletion::grace_period_from_env(),
+            max_upload_bytes: photos::max_upload_bytes_from_env(),
+            staging_dir: photos::staging_dir_from_env(),
+            media: media::store_from_env(),
+            payments: payments::provider_from_env(),
+            export_dir: exports::export_dir_from_env(),
         }
     }
 }
 
+// Helper function for the standard response to a non-admin calling a moderator endpoint
+fn admin_required() -> HttpResponse {
+    HttpResponse::Unauthorized().json(serde_json::json!({
+        "success": false,
+        "message": "Admin key required"
+    }))
+}
+
+// Helper function for checking if a request comes from a moderator
+// Compares the X-Admin-Key header against the admin password
+fn is_admin(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(ADMIN_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v == ADMIN_PASSWORD)
+        .unwrap_or(false)
+}
+
 // Health check endpoint: api.jaymatch.cc/health
 // Checks to see if the backend is active
 // Returns simple text response indicating server is running
@@ -140,6 +232,7 @@ async fn health() -> impl Responder {
 // Expects serialized JSON in the form of NewUser
 // Stores user in profiles SQLite table
 // Validates KU email domain (@ku.edu)
+// Logs the new user in, returning a session token as POST /login does
 async fn create_new_user(data: web::Json<NewUser>, state: web::Data<AppState>) -> impl Responder {
     // Enforce KU email domain
     let email_ok = data.email.to_lowercase().ends_with("@ku.edu");
@@ -150,6 +243,23 @@ async fn create_new_user(data: web::Json<NewUser>, state: web::Data<AppState>) -
         }));
     }
     let conn = state.db_conn.lock().unwrap();
+    if moderation::is_email_banned(&conn, &data.email) {
+        info!("Blocked registration for banned email {}", data.email);
+        return HttpResponse::Forbidden().json(serde_json::json!({
+            "success": false,
+            "message": "This email cannot be used to register"
+        }));
+    }
+    if let Ok(Some(_)) = conn.query_row(
+        "SELECT deletion_due_at FROM profiles WHERE email = ?1",
+        params![data.email],
+        |row| row.get::<_, Option<i64>>(0),
+    ) {
+        return HttpResponse::Conflict().json(serde_json::json!({
+            "success": false,
+            "message": "Account scheduled for deletion, log in to restore it"
+        }));
+    }
     let res = conn.execute(
         "INSERT INTO profiles (email, password, name) VALUES (?1, ?2, ?3)",
         params![data.email, data.password, data.name],
@@ -161,6 +271,13 @@ async fn create_new_user(data: web::Json<NewUser>, state: web::Data<AppState>) -
         }));
     }
     let user_id = conn.last_insert_rowid();
+    let token = match sessions::create_session(&conn, user_id as i32) {
+        Ok(t) => t,
+        Err(e) => {
+            warn!("Failed to create session: {}", e);
+            return HttpResponse::InternalServerError().body("Database error");
+        }
+    };
     info!(
         "Created new user with name {} email {}",
         data.name, data.email
@@ -168,76 +285,61 @@ async fn create_new_user(data: web::Json<NewUser>, state: web::Data<AppState>) -
     HttpResponse::Ok().json(serde_json::json!({
         "success": true,
         "message": "New user created",
-        "user_id": user_id
+        "user_id": user_id,
+        "token": token
     }))
 }
 
 // API endpoint to update a profile picture: POST /users/profile-picture
-// Stores the profile picture in uploads directory
-// Stores file metadata in the profiles table
+// Stores the profile picture in the media store
+// Replaces the user's primary photo, or adds it as the first photo
 // Handles multipart form data with user_id and image file
+// Rejects malformed bodies and files that are not valid images with 400
+// Rejects images over the upload size cap with 413
 async fn update_profile_picture(
-    mut payload: Multipart,
+    req: HttpRequest,
+    payload: Multipart,
     state: web::Data<AppState>,
 ) -> impl Responder {
-    let mut user_id: Option<i32> = None;
-    let mut file_path: Option<String> = None;
-    fs::create_dir_all("uploads/profile_pictures").unwrap_or_else(|e| {
-        warn!("Error creating directory: {}", e);
-    });
-    while let Some(item) = payload.next().await {
-        let mut field = item.unwrap();
-        let content_disposition = field.content_disposition();
-        let field_name = content_disposition.unwrap().get_name().unwrap_or("");
-        if field_name == "user_id" {
-            let mut data = Vec::new();
-            while let Some(chunk) = field.next().await {
-                data.extend_from_slice(&chunk.unwrap());
-            }
-            user_id = String::from_utf8(data).ok().and_then(|s| s.parse().ok());
-        } else if field_name == "image" {
-            let filename = content_disposition
-                .unwrap()
-                .get_filename()
-                .unwrap_or("unknown");
-            let extension = filename.rsplit('.').next().unwrap_or("jpg");
-            let timestamp = std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_millis();
-            let unique_filename = format!("{}_{:x}.{}", timestamp, timestamp, extension);
-            let filepath = format!("uploads/profile_pictures/{}", unique_filename);
-            let mut f = fs::File::create(&filepath).unwrap();
-            while let Some(chunk) = field.next().await {
-                let data = chunk.unwrap();
-                f.write_all(&data).unwrap();
-            }
-            file_path = Some(filepath);
-        }
-    }
-
-    if let (Some(uid), Some(path)) = (user_id, file_path) {
+    let upload = match photos::read_upload(&req, payload, &state).await {
+        Ok(u) => u,
+        Err(e) => return e.response(),
+    };
+    let (Some(uid), Some(key)) = (upload.user_id, upload.media_key.clone()) else {
+        images::remove_images(state.media.clone(), upload.media_key.into_iter().collect()).await;
+        return HttpResponse::BadRequest().body("Missing user_id or image");
+    };
+    // Returns the response and the image left unused, which is removed after the lock is released
+    let (response, unused) = {
         let conn = state.db_conn.lock().unwrap();
-        let result = conn.execute(
-            "UPDATE profiles SET profile_picture = ?1 WHERE user_id = ?2",
-            params![path, uid],
-        );
-        match result {
-            Ok(_) => HttpResponse::Ok().json(serde_json::json!({
-                "success": true,
-                "message": "Profile picture updated"
-            })),
-            Err(e) => HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
+        if let Some(r) = moderation::account_restriction(&conn, uid) {
+            (moderation::restricted_response(&r), Some(key))
+        } else {
+            match photos::replace_primary(&conn, uid, &key) {
+                Ok(old) => (
+                    HttpResponse::Ok().json(serde_json::json!({
+                        "success": true,
+                        "message": "Profile picture updated"
+                    })),
+                    old,
+                ),
+                Err(e) => (
+                    HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
+                    Some(key),
+                ),
+            }
         }
-    } else {
-        HttpResponse::BadRequest().body("Missing user_id or image")
-    }
+    };
+    images::remove_images(state.media.clone(), unused.into_iter().collect()).await;
+    response
 }
 
 // API for logging in: POST /login
 // Expects user info serialized JSON (email and password)
 // Returns response code indicating login status
 // Validates credentials against database
+// Suspended and banned accounts are refused before anything else happens
+// Logging in to an account that is pending deletion restores it
 async fn login(data: web::Json<User>, state: web::Data<AppState>) -> impl Responder {
     let conn = state.db_conn.lock().unwrap();
     let mut stmt =
@@ -263,11 +365,35 @@ async fn login(data: web::Json<User>, state: web::Data<AppState>) -> impl Respon
                 user.password, data.password
             );
             if user.password == data.password {
+                let uid = user.id.unwrap_or(0);
+                if let Some(r) = moderation::moderation_restriction(&conn, uid) {
+                    info!("Refused login for restricted account {}", data.email);
+                    return moderation::restricted_response(&r);
+                }
+                let restored = match deletion::restore_account(&conn, uid) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        warn!("Failed to restore account: {}", e);
+                        return HttpResponse::InternalServerError().body("Database error");
+                    }
+                };
+                if restored {
+                    info!("Restored account {} pending deletion", data.email);
+                }
+                let token = match sessions::create_session(&conn, uid) {
+                    Ok(t) => t,
+                    Err(e) => {
+                        warn!("Failed to create session: {}", e);
+                        return HttpResponse::InternalServerError().body("Database error");
+                    }
+                };
                 info!("User logged in successfully with email {}", data.email);
                 HttpResponse::Ok().json(serde_json::json!({
                     "success": true,
                     "message": "Login successful",
-                    "user_id": user.id
+                    "user_id": user.id,
+                    "token": token,
+                    "restored": restored
                 }))
             } else {
                 info!(
@@ -295,35 +421,24 @@ async fn login(data: web::Json<User>, state: web::Data<AppState>) -> impl Respon
 
 // API for retrieving a profile picture: GET /users/{user_id}/profile-picture
 // Expects user ID as path parameter
-// Returns profile picture from the profiles table and the uploads directory
-// Sets appropriate content-type header based on file extension
+// Returns profile picture from the profiles table and the media store
+// Supports size query parameter (small, medium or large) to get a thumbnail
+// Supports caching with ETag and If-None-Match, and Range requests
 async fn get_profile_picture(
+    req: HttpRequest,
     user_id: web::Path<i32>,
+    query: web::Query<HashMap<String, String>>,
     state: web::Data<AppState>,
 ) -> impl Responder {
-    let conn = state.db_conn.lock().unwrap();
-    let result = conn.query_row(
+    let result = state.db_conn.lock().unwrap().query_row(
         "SELECT profile_picture FROM profiles WHERE user_id = ?1",
         params![user_id.into_inner()],
         |row| row.get::<_, Option<String>>(0),
     );
     match result {
-        Ok(Some(path)) => match fs::read(&path) {
-            Ok(image_data) => {
-                let extension = path.rsplit('.').next().unwrap_or("jpg");
-                let content_type = match extension {
-                    "png" => "image/png",
-                    "jpg" | "jpeg" => "image/jpeg",
-                    "gif" => "image/gif",
-                    "webp" => "image/webp",
-                    _ => "application/octet-stream",
-                };
-                HttpResponse::Ok()
-                    .content_type(content_type)
-                    .body(image_data)
-            }
-            Err(_) => HttpResponse::NotFound().body("Image file not found"),
-        },
+        Ok(Some(key)) => {
+            photos::serve_image(&req, state.media.clone(), key, query.get("size")).await
+        }
         Ok(None) => HttpResponse::NotFound().body("No profile picture set"),
         Err(_) => HttpResponse::NotFound().body("User not found"),
     }
@@ -379,6 +494,9 @@ async fn index() -> impl Responder {
 // Alerts recipient of the message in real-time
 async fn post_message(data: web::Json<MessagePost>, state: web::Data<AppState>) -> impl Responder {
     let conn = state.db_conn.lock().unwrap();
+    if let Some(r) = moderation::account_restriction(&conn, data.sender_id) {
+        return moderation::restricted_response(&r);
+    }
     let (lower_id, higher_id) = if data.sender_id < data.receiver_id {
         (data.sender_id, data.receiver_id)
     } else {
@@ -395,6 +513,14 @@ async fn post_message(data: web::Json<MessagePost>, state: web::Data<AppState>)
             "message": "Cannot message users you haven't matched with"
         }));
     }
+    if blocks::is_blocked(&conn, data.sender_id, data.receiver_id)
+        || deletion::deletion_due_at(&conn, data.receiver_id).is_some()
+    {
+        return HttpResponse::Forbidden().json(serde_json::json!({
+            "success": false,
+            "message": "Cannot message this user"
+        }));
+    }
     let ts = Utc::now().timestamp_millis();
     match conn.execute(
         "INSERT INTO messages (sender_id, receiver_id, content, timestamp) VALUES (?1, ?2, ?3, ?4)",
@@ -415,10 +541,10 @@ async fn post_message(data: web::Json<MessagePost>, state: web::Data<AppState>)
             .to_string();
             let clients = state.clients.lock().unwrap();
             if let Some(recipient) = clients.get(&data.receiver_id) {
-                let _ = recipient.do_send(WsMessage(serialized.clone()));
+                recipient.do_send(WsMessage(serialized.clone()));
             }
             if let Some(sender_recipient) = clients.get(&data.sender_id) {
-                let _ = sender_recipient.do_send(WsMessage(serialized));
+                sender_recipient.do_send(WsMessage(serialized));
             }
             info!(
                 "Stored message {} from {} to {} at {}",
@@ -450,6 +576,9 @@ async fn get_messages(
         .unwrap_or(100);
 
     let conn = state.db_conn.lock().unwrap();
+    if let Some(r) = moderation::account_restriction(&conn, a) {
+        return moderation::restricted_response(&r);
+    }
     let mut stmt = conn
         .prepare(
             "SELECT id, sender_id, receiver_id, content, timestamp FROM messages
@@ -479,19 +608,13 @@ async fn get_messages(
 
 // Helper function for converting database row to profile data
 // Useful in data migrations and when reading from database
-// Handles parsing of interests JSON string or comma-separated values
+// Reads interests from the JSON list that mirrors profile_interests
+// Reads the shared interest count from column 12 when the query has one
 fn row_to_profile(row: &rusqlite::Row) -> Profile {
     let interests_text: Option<String> = row.get(8).ok();
-    let interests: Option<Vec<String>> = interests_text.as_ref().and_then(|txt| {
-        serde_json::from_str::<Vec<String>>(txt).ok().or_else(|| {
-            let items: Vec<String> = txt
-                .split(',')
-                .map(|s| s.trim().to_string())
-                .filter(|s| !s.is_empty())
-                .collect();
-            if items.is_empty() { None } else { Some(items) }
-        })
-    });
+    let interests: Option<Vec<String>> = interests_text
+        .as_ref()
+        .and_then(|txt| serde_json::from_str::<Vec<String>>(txt).ok());
     Profile {
         user_id: row.get(0).unwrap_or(0),
         email: row.get(1).unwrap_or_default(),
@@ -504,17 +627,46 @@ fn row_to_profile(row: &rusqlite::Row) -> Profile {
         profile_picture: row.get(7).ok(),
         gender: row.get(9).ok(),
         is_felon: row.get(10).ok(),
+        attributes: attributes::extra_attributes(row.get::<_, Option<String>>(11).ok().flatten()),
+        photos: None,
+        prompts: None,
+        shared_interests: row.get(12).ok(),
     }
 }
 
 // API for receiving all profile information for a user: GET /profiles/{user_id}
 // Queries all relevant tables to get and format data
+// The viewer is the user whose Authorization: Bearer session token is sent, so blocked users cannot see each other
+// Profiles of users who have blocked anyone are not shown to viewers without a session
+// Profiles pending deletion are only visible to their owner
+// Profiles hidden except to matches are only visible to their owner and matches
+// Includes the user's photos and prompt answers in display order
+// Counts a profile view impression when the viewer is another user with a session, once a day per viewer
 // Returns serialized user profile data as JSON
-async fn get_profile(user_id: web::Path<i32>, state: web::Data<AppState>) -> impl Responder {
+async fn get_profile(
+    req: HttpRequest,
+    user_id: web::Path<i32>,
+    state: web::Data<AppState>,
+) -> impl Responder {
     let uid = user_id.into_inner();
     let conn = state.db_conn.lock().unwrap();
+    let viewer_id = sessions::session_user(&conn, &req);
+    if let Some(viewer) = viewer_id
+        && let Some(r) = moderation::account_restriction(&conn, viewer)
+    {
+        return moderation::restricted_response(&r);
+    }
+    if viewer_id != Some(uid) && blocks::hidden_from(&conn, viewer_id, uid) {
+        return HttpResponse::NotFound().body("Profile not found");
+    }
+    if viewer_id != Some(uid) && deletion::deletion_due_at(&conn, uid).is_some() {
+        return HttpResponse::NotFound().body("Profile not found");
+    }
+    if visibility::hidden_from(&conn, viewer_id, uid) {
+        return HttpResponse::NotFound().body("Profile not found");
+    }
     let mut stmt = match conn.prepare(
-        "SELECT user_id, email, name, age, major, year, bio, profile_picture, interests, gender, is_felon
+        "SELECT user_id, email, name, age, major, year, bio, profile_picture, interests, gender, is_felon, attributes
  FROM profiles WHERE user_id = ?1",
     ) {
         Ok(s) => s,
@@ -522,7 +674,14 @@ async fn get_profile(user_id: web::Path<i32>, state: web::Data<AppState>) -> imp
     };
     let result = stmt.query_row(params![uid], |row| Ok(row_to_profile(row)));
     match result {
-        Ok(p) => HttpResponse::Ok().json(p),
+        Ok(mut p) => {
+            p.photos = photos::list_photos(&conn, uid).ok();
+            p.prompts = prompts::list_answers(&conn, uid).ok();
+            if let Some(viewer) = viewer_id.filter(|viewer| *viewer != uid) {
+                impressions::record(&conn, viewer, &[uid], impressions::Source::Profile);
+            }
+            HttpResponse::Ok().json(p)
+        }
         Err(rusqlite::Error::QueryReturnedNoRows) => {
             HttpResponse::NotFound().body("Profile not found")
         }
@@ -533,7 +692,9 @@ async fn get_profile(user_id: web::Path<i32>, state: web::Data<AppState>) -> imp
 // API for setting a user profile: PUT /profiles/{user_id}
 // Receives a user ID and a ProfileUpsert data structure
 // Updates SQLite tables with new profile data
-// Validates gender values and merges with existing profile data
+// Merges with existing profile data, so fields that are missing or null are kept
+// Attributes in the attribute schema are validated and saved in canonical form, so "CS" is saved as Computer Science
+// profile_picture is rejected with 400 since only the photo endpoints may set it
 async fn put_profile(
     user_id: web::Path<i32>,
     data: web::Json<ProfileUpsert>,
@@ -541,49 +702,54 @@ async fn put_profile(
 ) -> impl Responder {
     let uid = user_id.into_inner();
     let payload = data.into_inner();
-    const VALID_GENDERS: &[&str] = &["male", "female", "other"];
-    // const VALID_YEARS: &[&str] = &["freshman", "sophomore", "junior", "senior"];
-    // const VALID_MAJORS: &[&str] = &[
-    //     "computer science",
-    //     "information technology",
-    //     "electrical engineering",
-    //     "mechanical engineering",
-    //     "business",
-    //     "biology",
-    //     "psychology",
-    // ];
-    if let Some(ref g) = payload.gender {
-        let norm = g.to_ascii_lowercase();
-        if !VALID_GENDERS.contains(&norm.as_str()) {
+    if payload.attributes.contains_key("profile_picture") {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "success": false,
+            "message": "profile_picture is changed through the photo upload endpoints"
+        }));
+    }
+    let interest_tags = match payload
+        .interests
+        .as_deref()
+        .map(interests::clean_tags)
+        .transpose()
+    {
+        Ok(tags) => tags,
+        Err(message) => {
             return HttpResponse::BadRequest().json(serde_json::json!({
                 "success": false,
-                "message": "Invalid gender. Allowed: Male, Female, Other"
+                "message": message
             }));
         }
+    };
+    let mut attribute_values = serde_json::Map::new();
+    for (name, value) in &payload.attributes {
+        let Some(attr) = attributes::schema().get(name) else {
+            continue;
+        };
+        if value.is_null() {
+            continue;
+        }
+        match attr.validate(value) {
+            Ok(value) => {
+                attribute_values.insert(name.clone(), value);
+            }
+            Err(message) => {
+                return HttpResponse::BadRequest().json(serde_json::json!({
+                    "success": false,
+                    "message": message
+                }));
+            }
+        }
     }
-    // if let Some(ref y) = payload.year {
-    //     let norm = y.to_ascii_lowercase();
-    //     if !VALID_YEARS.contains(&norm.as_str()) {
-    //         return HttpResponse::BadRequest().json(serde_json::json!({
-    //             "success": false,
-    //             "message": "Invalid year. Allowed: Freshman, Sophomore, Junior, Senior"
-    //         }));
-    //     }
-    // }
-    // if let Some(ref m) = payload.major {
-    // let norm = m.to_ascii_lowercase();
-    // if !VALID_MAJORS.contains(&norm.as_str()) {
-    // return HttpResponse::BadRequest().json(serde_json::json!({
-    //     "success": false,
-    //     "message": "Invalid major."
-    // }));
-    // }
-    // }
     let conn = state.db_conn.lock().unwrap();
+    if let Some(r) = moderation::account_restriction(&conn, uid) {
+        return moderation::restricted_response(&r);
+    }
     let current: Option<Profile> = {
         let stmt = conn
             .prepare(
-                "SELECT user_id, email, name, age, major, year, bio, profile_picture, interests, gender, is_felon
+                "SELECT user_id, email, name, age, major, year, bio, profile_picture, interests, gender, is_felon, attributes
  FROM profiles WHERE user_id = ?1",
             )
             .ok();
@@ -597,77 +763,20 @@ async fn put_profile(
     let merged_name = payload
         .name
         .or_else(|| current.as_ref().and_then(|c| c.name.clone()));
-    let merged_age = payload.age.or_else(|| current.as_ref().and_then(|c| c.age));
-    // let validated_major = if let Some(m) = &payload.major {
-    //     let norm = m.to_ascii_lowercase();
-    //     Some(
-    //         VALID_MAJORS
-    //             .iter()
-    //             .find(|x| x.eq_ignore_ascii_case(&norm))
-    //             .unwrap()
-    //             .to_string()
-    //             .split_whitespace()
-    //             .map(|w| {
-    //                 let mut c = w.chars();
-    //                 c.next()
-    //                     .map(|f| f.to_ascii_uppercase().to_string())
-    //                     .unwrap_or_default()
-    //                     + c.as_str()
-    //             })
-    //             .collect::<Vec<String>>()
-    //             .join(" "),
-    //     )
-    // } else {
-    //     current.as_ref().and_then(|c| c.major.clone())
-    // };
-    // let validated_year = if let Some(y) = &payload.year {
-    //     match y.to_ascii_lowercase().as_str() {
-    //         "freshman" => Some("Freshman".to_string()),
-    //         "sophomore" => Some("Sophomore".to_string()),
-    //         "junior" => Some("Junior".to_string()),
-    //         "senior" => Some("Senior".to_string()),
-    //         _ => unreachable!(),
-    //     }
-    // } else {
-    //     current.as_ref().and_then(|c| c.year.clone())
-    // };
     let merged_bio = payload
         .bio
         .or_else(|| current.as_ref().and_then(|c| c.bio.clone()));
-    let merged_interests = payload
-        .interests
-        .or_else(|| current.as_ref().and_then(|c| c.interests.clone()));
-    let merged_picture = payload
-        .profile_picture
-        .or_else(|| current.as_ref().and_then(|c| c.profile_picture.clone()));
-    let validated_gender = if let Some(g) = &payload.gender {
-        match g.to_ascii_lowercase().as_str() {
-            "male" => Some("Male".to_string()),
-            "female" => Some("Female".to_string()),
-            "other" => Some("Other".to_string()),
-            _ => unreachable!(),
+    let result = conn.unchecked_transaction().and_then(|tx| {
+        tx.execute(
+            "UPDATE profiles SET name = ?1, bio = ?2 WHERE user_id = ?3",
+            params![merged_name, merged_bio, uid],
+        )?;
+        if let Some(tags) = &interest_tags {
+            interests::set_profile_interests(&tx, uid, tags)?;
         }
-    } else {
-        current.as_ref().and_then(|c| c.gender.clone())
-    };
-    let interests_text = merged_interests
-        .as_ref()
-        .map(|v| serde_json::to_string(v).unwrap_or_else(|_| "".to_string()));
-    let result = conn.execute(
-        "UPDATE profiles SET name = ?1, age = ?2, major = ?3, year = ?4, bio = ?5, interests = ?6, profile_picture = ?7, gender = ?8
-         WHERE user_id = ?9",
-        params![
-            merged_name,
-            merged_age,
-            payload.major,
-            payload.year,
-            merged_bio,
-            interests_text,
-            merged_picture,
-            validated_gender,
-            uid
-        ],
-    );
+        attributes::save_profile_attributes(&tx, uid, &attribute_values)?;
+        tx.commit()
+    });
 
     match result {
         Ok(_) => HttpResponse::Ok().json(serde_json::json!({"success": true, "user_id": uid})),
@@ -675,31 +784,162 @@ async fn put_profile(
     }
 }
 
+// Helper function for converting one member of a profile merge patch into a column value
+// Handles the fields that are not attributes in the attribute schema
+// Returns the column to update, or a message for the client if the value is not allowed
+fn profile_patch_value(
+    field: &str,
+    value: &serde_json::Value,
+) -> Result<(&'static str, rusqlite::types::Value), String> {
+    use rusqlite::types::Value as Sql;
+    use serde_json::Value as Json;
+    let text = |column: &'static str| match value {
+        Json::Null => Ok((column, Sql::Null)),
+        Json::String(s) => Ok((column, Sql::Text(s.clone()))),
+        _ => Err(format!("{} must be a string or null", field)),
+    };
+    match (field, value) {
+        ("name", _) => text("name"),
+        ("bio", _) => text("bio"),
+        ("email", Json::String(e)) if e.to_lowercase().ends_with("@ku.edu") => {
+            Ok(("email", Sql::Text(e.clone())))
+        }
+        ("email", _) => Err("email must be a ku.edu address".to_string()),
+        ("profile_picture", _) => {
+            Err("profile_picture is changed through the photo upload endpoints".to_string())
+        }
+        _ => Err(format!("Unknown profile field {}", field)),
+    }
+}
+
+// API for partially updating a user profile: PATCH /profiles/{user_id}
+// Expects a JSON Merge Patch (RFC 7396) with Content-Type application/merge-patch+json or application/json
+// Fields left out of the patch are kept, fields set to null are cleared, and any other value replaces the field
+// Interests are replaced as a whole list
+// Rejects unknown fields and invalid values with 400 without changing anything
+// Returns the updated profile
+async fn patch_profile(
+    req: HttpRequest,
+    user_id: web::Path<i32>,
+    body: web::Bytes,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let uid = user_id.into_inner();
+    let content_type = req
+        .headers()
+        .get(actix_web::http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .map(|v| v.trim().to_ascii_lowercase());
+    if !matches!(
+        content_type.as_deref(),
+        Some("application/merge-patch+json" | "application/json")
+    ) {
+        return HttpResponse::UnsupportedMediaType().json(serde_json::json!({
+            "success": false,
+            "message": "Content-Type must be application/merge-patch+json"
+        }));
+    }
+    let patch = match serde_json::from_slice::<serde_json::Value>(&body) {
+        Ok(serde_json::Value::Object(patch)) => patch,
+        _ => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "success": false,
+                "message": "Body must be a JSON object"
+            }));
+        }
+    };
+    let mut updates = Vec::new();
+    let mut attribute_values = serde_json::Map::new();
+    let mut interest_tags = None;
+    for (field, value) in &patch {
+        let result = match attributes::schema().get(field) {
+            Some(attr) => attr.validate(value).map(|value| {
+                attribute_values.insert(field.clone(), value);
+            }),
+            None if field == "interests" => {
+                interests::tags_from_json(value).map(|tags| interest_tags = Some(tags))
+            }
+            None => profile_patch_value(field, value).map(|update| updates.push(update)),
+        };
+        if let Err(message) = result {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "success": false,
+                "message": message
+            }));
+        }
+    }
+    let conn = state.db_conn.lock().unwrap();
+    if let Some(r) = moderation::account_restriction(&conn, uid) {
+        return moderation::restricted_response(&r);
+    }
+    let assignments = updates
+        .iter()
+        .enumerate()
+        .map(|(i, (column, _))| format!("{} = ?{}", column, i + 1))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let mut values: Vec<rusqlite::types::Value> =
+        updates.into_iter().map(|(_, value)| value).collect();
+    let applied = conn.unchecked_transaction().and_then(|tx| {
+        if !values.is_empty() {
+            values.push(uid.into());
+            let sql = format!(
+                "UPDATE profiles SET {} WHERE user_id = ?{}",
+                assignments,
+                values.len()
+            );
+            tx.execute(&sql, rusqlite::params_from_iter(values))?;
+        }
+        if let Some(tags) = &interest_tags {
+            interests::set_profile_interests(&tx, uid, tags)?;
+        }
+        attributes::save_profile_attributes(&tx, uid, &attribute_values)?;
+        tx.commit()
+    });
+    match applied {
+        Ok(_) => {}
+        Err(rusqlite::Error::SqliteFailure(e, _))
+            if e.code == rusqlite::ErrorCode::ConstraintViolation =>
+        {
+            return HttpResponse::Conflict().json(serde_json::json!({
+                "success": false,
+                "message": "Email is already in use"
+            }));
+        }
+        Err(e) => return HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
+    }
+    let result = conn.query_row(
+        "SELECT user_id, email, name, age, major, year, bio, profile_picture, interests, gender, is_felon, attributes
+ FROM profiles WHERE user_id = ?1",
+        params![uid],
+        |row| Ok(row_to_profile(row)),
+    );
+    match result {
+        Ok(mut p) => {
+            p.photos = photos::list_photos(&conn, uid).ok();
+            p.prompts = prompts::list_answers(&conn, uid).ok();
+            HttpResponse::Ok().json(p)
+        }
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            HttpResponse::NotFound().body("Profile not found")
+        }
+        Err(_) => HttpResponse::InternalServerError().body("Database error"),
+    }
+}
+
 // API for helping the frontend to know what profile filters exist: GET /preference-options
-// Returns a JSON object with lists of all available filter options
-// Includes gender, year, major, and felon options
+// Returns a JSON object with the values of every filterable attribute in the attribute schema
+// Also includes the schema itself so clients can build profile forms and filters from it
 async fn get_preference_options() -> impl Responder {
-    HttpResponse::Ok().json(serde_json::json!({
-        "gender_options": ["Male", "Female", "Other"],
-        "year_options": ["Freshman", "Sophomore", "Junior", "Senior", "Graduate"],
-        "major_options": [
-            "Computer Science",
-            "Information Technology",
-            "Electrical Engineering",
-            "Mechanical Engineering",
-            "Business",
-            "Biology",
-            "Psychology"
-        ],
-        "felon_options": [true, false]
-    }))
+    HttpResponse::Ok().json(attributes::schema().options_json())
 }
 
 // Structure for creating a WebSocket connection
 // Represents a single WebSocket client connection
 struct MyWs {
-    user_id: i32,                    // User ID associated with this connection
-    state: web::Data<AppState>,      // Shared application state
+    user_id: i32,               // User ID associated with this connection
+    state: web::Data<AppState>, // Shared application state
 }
 
 // WebSocket actor functionality
@@ -709,9 +949,8 @@ impl Actor for MyWs {
 
     fn started(&mut self, ctx: &mut Self::Context) {
         let addr = ctx.address();
-        let recipient = addr.recipient::<WsMessage>();
         let mut clients = self.state.clients.lock().unwrap();
-        clients.insert(self.user_id, recipient);
+        clients.insert(self.user_id, addr);
         let welcome = serde_json::json!({
             "type": "system",
             "payload": format!("Connected as user {}", self.user_id)
@@ -736,6 +975,20 @@ impl Handler<WsMessage> for MyWs {
     }
 }
 
+// How the websocket handles forced disconnects
+// Closes the connection with a policy violation code and the given reason
+impl Handler<WsDisconnect> for MyWs {
+    type Result = ();
+
+    fn handle(&mut self, msg: WsDisconnect, ctx: &mut Self::Context) {
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Policy,
+            description: Some(msg.0),
+        }));
+        ctx.stop();
+    }
+}
+
 // How the websocket handles streams
 // Processes incoming WebSocket messages (text, ping, pong, close)
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for MyWs {
@@ -746,12 +999,12 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for MyWs {
     ) {
         match item {
             Ok(ws::Message::Text(text)) => {
-                if let Ok(v) = serde_json::from_str::<serde_json::Value>(&text) {
-                    if v.get("type") == Some(&serde_json::Value::String("ping".to_string())) {
-                        let pong = serde_json::json!({"type":"pong"});
-                        ctx.text(pong.to_string());
-                        return;
-                    }
+                if let Ok(v) = serde_json::from_str::<serde_json::Value>(&text)
+                    && v.get("type") == Some(&serde_json::Value::String("ping".to_string()))
+                {
+                    let pong = serde_json::json!({"type":"pong"});
+                    ctx.text(pong.to_string());
+                    return;
                 }
                 ctx.text(text);
             }
@@ -775,6 +1028,12 @@ async fn ws_index(
     state: web::Data<AppState>,
 ) -> Result<HttpResponse, Error> {
     let user_id = path.into_inner();
+    {
+        let conn = state.db_conn.lock().unwrap();
+        if let Some(r) = moderation::account_restriction(&conn, user_id) {
+            return Ok(moderation::restricted_response(&r));
+        }
+    }
     let ws = MyWs {
         user_id,
         state: state.clone(),
@@ -783,38 +1042,53 @@ async fn ws_index(
 }
 
 // API endpoint to retrieve a filtered list of potential matches: GET /queue/{user_id}
-// Queries tables for all other user profiles that match the stored filters
-// Applies gender, age, year, major, and felon preferences
-// Returns the serialized profile data as JSON array
+// Queries tables for all other active user profiles that match the stored filters and have not been swiped on
+// Paused and hidden profiles are left out, as are incognito profiles that have not liked the user
+// Profiles brought back by undoing a swipe come first, then profiles that super liked the user,
+// then boosted profiles, whose boost counts each one served
+// Profiles must share an interest from the user's interest filter, if they have one
+// Profiles failing a dealbreaker filter are left out and the rest are ranked by how many filters they meet,
+// then by how many interests they share with the user, then by how few times they were seen this week
+// Profiles with a completeness score below onboarding::INCOMPLETE_SCORE come after the rest
+// Returns the serialized profile data, with prompt answers, as JSON array
 async fn get_queue(user_id: web::Path<i32>, state: web::Data<AppState>) -> impl Responder {
     let uid = user_id.into_inner();
     let conn = state.db_conn.lock().unwrap();
-    let prefs_result = conn.query_row(
-        "SELECT gender_preference, min_age, max_age, year_preference, major_preference, is_felon FROM preferences WHERE user_id = ?1",
-        params![uid],
-        |row| {
-            let gender_pref_text: Option<String> = row.get(0).ok();
-            let year_pref_text: Option<String> = row.get(3).ok();
-            let major_pref_text: Option<String> = row.get(4).ok();
-            Ok((
-                gender_pref_text.and_then(|txt| serde_json::from_str::<Vec<String>>(&txt).ok()),
-                row.get::<_, Option<i32>>(1).ok().flatten(),
-                row.get::<_, Option<i32>>(2).ok().flatten(),
-                year_pref_text.and_then(|txt| serde_json::from_str::<Vec<String>>(&txt).ok()),
-                major_pref_text.and_then(|txt| serde_json::from_str::<Vec<String>>(&txt).ok()),
-                row.get::<_, Option<bool>>(5).ok().flatten(),
-            ))
-        },
-    );
-    let (gender_pref, min_age, max_age, year_pref, major_pref, is_felon_pref) =
-        prefs_result.unwrap_or((None, None, None, None, None, None));
-    let mut stmt = match conn.prepare(
-        "SELECT user_id, email, name, age, major, year, bio, profile_picture, interests, gender, is_felon
+    if let Some(r) = moderation::account_restriction(&conn, uid) {
+        return moderation::restricted_response(&r);
+    }
+    let filters = attributes::load_filters(&conn, uid).unwrap_or_default();
+    let filter_sql = attributes::schema().filter_sql(&filters, 2);
+    let queue_sql = format!(
+        "SELECT user_id, email, name, age, major, year, bio, profile_picture, interests, gender, is_felon, attributes,
+                {} AS shared_interests
          FROM profiles 
          WHERE user_id != ?1
-         ORDER BY RANDOM()
+           AND user_id NOT IN (SELECT blocked_user_id FROM blocked_users WHERE user_id = ?1)
+           AND user_id NOT IN (SELECT user_id FROM blocked_users WHERE blocked_user_id = ?1)
+           AND user_id NOT IN (SELECT target_user_id FROM swipes WHERE user_id = ?1)
+           AND {}
+           AND {}
+           AND {}
+           AND {}
+           AND {}
+         ORDER BY {} DESC, {} DESC, {} DESC, {}, {} < {}, shared_interests DESC, {}, RANDOM()
          LIMIT 100",
-    ) {
+        interests::SHARED_SQL,
+        interests::FILTER_SQL,
+        moderation::ACTIVE_ACCOUNT_SQL,
+        visibility::QUEUE_SQL,
+        visibility::incognito_sql(),
+        filter_sql.dealbreakers,
+        swipes::RESTORED_SQL,
+        swipes::SUPER_LIKED_SQL,
+        boosts::ACTIVE_SQL,
+        filter_sql.misses,
+        onboarding::score_sql(),
+        onboarding::INCOMPLETE_SCORE,
+        impressions::WEEKLY_SQL
+    );
+    let mut stmt = match conn.prepare(&queue_sql) {
         Ok(s) => s,
         Err(_) => return HttpResponse::InternalServerError().body("Database error"),
     };
@@ -825,71 +1099,28 @@ async fn get_queue(user_id: web::Path<i32>, state: web::Data<AppState>) -> impl
             |row| row.get(0),
         )
         .ok();
-    let rows = stmt.query_map(params![uid], |row| Ok(row_to_profile(row)));
+    let mut values = vec![rusqlite::types::Value::Integer(uid as i64)];
+    values.extend(filter_sql.params);
+    let rows = stmt.query_map(rusqlite::params_from_iter(values), |row| {
+        Ok(row_to_profile(row))
+    });
     match rows {
         Ok(profiles) => {
             let mut profile_list: Vec<Profile> = profiles.filter_map(|r| r.ok()).collect();
-            profile_list.retain(|p| {
-                if let Some(ref genders) = gender_pref {
-                    if let Some(ref profile_gender) = p.gender {
-                        if !genders
-                            .iter()
-                            .any(|g| g.eq_ignore_ascii_case(profile_gender))
-                        {
-                            return false;
-                        }
-                    } else {
-                        return false;
-                    }
-                }
-                if let Some(min) = min_age {
-                    if let Some(age) = p.age {
-                        if age < min {
-                            return false;
-                        }
-                    } else {
-                        return false;
-                    }
-                }
-                if let Some(max) = max_age {
-                    if let Some(age) = p.age {
-                        if age > max {
-                            return false;
-                        }
-                    } else {
-                        return false;
-                    }
-                }
-                if let Some(ref years) = year_pref {
-                    if let Some(ref profile_year) = p.year {
-                        if !years.iter().any(|y| y.eq_ignore_ascii_case(profile_year)) {
-                            return false;
-                        }
-                    } else {
-                        return false;
-                    }
-                }
-                if let Some(ref majors) = major_pref {
-                    if let Some(ref profile_major) = p.major {
-                        if !majors.iter().any(|m| m.eq_ignore_ascii_case(profile_major)) {
-                            return false;
-                        }
-                    } else {
-                        return false;
-                    }
-                }
-                if let Some(is_felon) = is_felon_pref {
-                    if let Some(profile_is_felon) = p.is_felon {
-                        if profile_is_felon != is_felon {
-                            return false;
-                        }
-                    } else {
-                        return false;
-                    }
-                }
-                true
-            });
+            let boosted = boosts::boosted_users(&conn);
+            let candidates = profile_list.len();
             profile_list.truncate(20);
+            let served: Vec<i32> = profile_list
+                .iter()
+                .map(|p| p.user_id)
+                .filter(|id| boosted.contains(id))
+                .collect();
+            boosts::record_views(&conn, &served, candidates, 20);
+            let shown: Vec<i32> = profile_list.iter().map(|p| p.user_id).collect();
+            impressions::record(&conn, uid, &shown, impressions::Source::Queue);
+            for p in profile_list.iter_mut() {
+                p.prompts = prompts::list_answers(&conn, p.user_id).ok();
+            }
             info!(
                 "User {} ({}) requested queue, returning {} profiles",
                 uid,
@@ -910,31 +1141,6 @@ struct DeleteRequest {
     password: String,
 }
 
-// Structure for holding user preferences
-// Stores filter preferences for matching
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Preferences {
-    user_id: i32,
-    gender_preference: Option<Vec<String>>,
-    min_age: Option<i32>,
-    max_age: Option<i32>,
-    year_preference: Option<Vec<String>>,
-    major_preference: Option<Vec<String>>,
-    is_felon: Option<bool>,
-}
-
-// Structure to be sent between client and server for preferences
-// Used for creating or updating user preferences
-#[derive(Deserialize, Debug)]
-struct PreferencesUpsert {
-    gender_preference: Option<Vec<String>>,
-    min_age: Option<i32>,
-    max_age: Option<i32>,
-    year_preference: Option<Vec<String>>,
-    major_preference: Option<Vec<String>>,
-    is_felon: Option<bool>,
-}
-
 // Structure to hold match data
 // Represents a match between two users
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -942,6 +1148,8 @@ struct Match {
     user_id: i32,
     matched_user_id: i32,
     timestamp: i64,
+    #[serde(default)]
+    profile_picture: Option<String>, // Matched user's primary photo
 }
 
 // Structure for match requests
@@ -954,8 +1162,9 @@ struct MatchRequest {
 
 // API for deleting a user: POST /delete_user
 // Requires user password or admin password (1234)
-// Deletes all table related data for user (messages, profile, preferences)
-// Uses database transaction to ensure atomicity
+// Hides the account from everyone and schedules it for permanent deletion after the grace period
+// Logging in before then restores the account
+// Ends the user's sessions and closes their WebSocket connection
 async fn delete_user(data: web::Json<DeleteRequest>, state: web::Data<AppState>) -> impl Responder {
     let conn = state.db_conn.lock().unwrap();
     let mut stmt = match conn.prepare("SELECT user_id, password FROM profiles WHERE email = ?1") {
@@ -975,7 +1184,7 @@ async fn delete_user(data: web::Json<DeleteRequest>, state: web::Data<AppState>)
             }));
         }
     };
-    if data.password != "1234" && data.password != stored_password {
+    if data.password != ADMIN_PASSWORD && data.password != stored_password {
         info!(
             "Failed deletion attempt for email {} with password {}",
             data.email, data.password
@@ -985,30 +1194,37 @@ async fn delete_user(data: web::Json<DeleteRequest>, state: web::Data<AppState>)
             "message": "Invalid password"
         }));
     }
-    let tx = conn.unchecked_transaction().unwrap();
-    let _ = tx.execute(
-        "DELETE FROM messages WHERE sender_id = ?1 OR receiver_id = ?1",
-        params![user_id],
+    let due_at = match deletion::schedule_deletion(&conn, user_id, state.deletion_grace_ms) {
+        Ok(t) => t,
+        Err(e) => {
+            info!(
+                "Error deleting user {} with email {}: {}",
+                user_id, data.email, e
+            );
+            return HttpResponse::InternalServerError().body("Error deleting user");
+        }
+    };
+    moderation::terminate_live_connections(&conn, &state, user_id, "Account deleted");
+    info!(
+        "User {} ({}) scheduled for deletion at {}",
+        user_id, data.email, due_at
     );
-    let res = tx.execute("DELETE FROM profiles WHERE user_id = ?1", params![user_id]);
-    if res.is_err() {
-        info!("Error deleting user {} with email {}", user_id, data.email);
-        return HttpResponse::InternalServerError().body("Error deleting user");
-    }
-    tx.commit().unwrap();
-    info!("User {} ({}) permanently deleted", user_id, data.email);
     HttpResponse::Ok().json(serde_json::json!({
         "success": true,
-        "message": format!("User {} permanently deleted", data.email)
+        "message": format!("User {} scheduled for deletion", data.email),
+        "deletion_due_at": due_at
     }))
 }
 
 // API for retrieving user preferences: GET /preferences/{user_id}
-// Takes in a user ID and returns the structured preference data
-// Returns default empty preferences if user has none set
+// Takes in a user ID and returns every filter in the attribute schema
+// Filters the user has not set are returned as null
 async fn get_preferences(user_id: web::Path<i32>, state: web::Data<AppState>) -> impl Responder {
     let uid = user_id.into_inner();
     let conn = state.db_conn.lock().unwrap();
+    if let Some(r) = moderation::account_restriction(&conn, uid) {
+        return moderation::restricted_response(&r);
+    }
     let email: String = conn
         .query_row(
             "SELECT email FROM profiles WHERE user_id = ?1",
@@ -1016,55 +1232,51 @@ async fn get_preferences(user_id: web::Path<i32>, state: web::Data<AppState>) ->
             |row| row.get(0),
         )
         .unwrap_or_else(|_| "unknown".to_string());
-    let result = conn.query_row(
-        "SELECT user_id, gender_preference, min_age, max_age, year_preference, major_preference, is_felon FROM preferences WHERE user_id = ?1",
-        params![uid],
-        |row| {
-            let gender_pref_text: Option<String> = row.get(1).ok();
-            let year_pref_text: Option<String> = row.get(4).ok();
-            let major_pref_text: Option<String> = row.get(5).ok();
-            let gender_preference = gender_pref_text.and_then(|txt| serde_json::from_str(&txt).ok());
-            let year_preference = year_pref_text.and_then(|txt| serde_json::from_str(&txt).ok());
-            let major_preference = major_pref_text.and_then(|txt| serde_json::from_str(&txt).ok());
-            let is_felon = row.get::<_, Option<i32>>(6).ok().flatten().map(|v| v != 0);
-            Ok(Preferences {
-                user_id: row.get(0)?,
-                gender_preference,
-                min_age: row.get(2).ok(),
-                max_age: row.get(3).ok(),
-                year_preference,
-                major_preference,
-                is_felon,
-            })
-        },
-    );
+    let result = attributes::load_filters(&conn, uid)
+        .and_then(|filters| Ok((filters, interests::load_filter(&conn, uid)?)));
     info!("User {} ({}) fetched preferences", uid, email);
     match result {
-        Ok(prefs) => HttpResponse::Ok().json(prefs),
-        Err(rusqlite::Error::QueryReturnedNoRows) => HttpResponse::Ok().json(Preferences {
-            user_id: uid,
-            gender_preference: None,
-            min_age: None,
-            max_age: None,
-            year_preference: None,
-            major_preference: None,
-            is_felon: None,
-        }),
+        Ok((filters, interest_filter)) => {
+            let mut body = serde_json::Map::new();
+            body.insert("user_id".to_string(), uid.into());
+            body.extend(filters);
+            body.insert(interests::FILTER_KEY.to_string(), interest_filter);
+            HttpResponse::Ok().json(body)
+        }
         Err(_) => HttpResponse::InternalServerError().body("Database error"),
     }
 }
 
 // API for setting preferences: PUT /preferences/{user_id}
-// Takes in user ID and structured preference update data
-// Updates the preferences table using INSERT ... ON CONFLICT DO UPDATE
+// Takes in user ID and a JSON object of filters named as in GET /preference-options
+// interests_preference lists interests from GET /interests that profiles in the queue must share one of
+// Replaces all of the user's filters; filters that are missing or null are cleared
+// Maps choice values to their canonical names and rejects unknown ones with 400
 async fn put_preferences(
     user_id: web::Path<i32>,
-    data: web::Json<PreferencesUpsert>,
+    data: web::Json<serde_json::Value>,
     state: web::Data<AppState>,
 ) -> impl Responder {
     let uid = user_id.into_inner();
-    let payload = data.into_inner();
+    let Some(body) = data.as_object() else {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "success": false,
+            "message": "Preferences must be a JSON object"
+        }));
+    };
+    let filters = match attributes::schema().validate_filters(body) {
+        Ok(filters) => filters,
+        Err(message) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "success": false,
+                "message": message
+            }));
+        }
+    };
     let conn = state.db_conn.lock().unwrap();
+    if let Some(r) = moderation::account_restriction(&conn, uid) {
+        return moderation::restricted_response(&r);
+    }
     let email: String = conn
         .query_row(
             "SELECT email FROM profiles WHERE user_id = ?1",
@@ -1072,61 +1284,65 @@ async fn put_preferences(
             |row| row.get(0),
         )
         .unwrap_or_else(|_| "unknown".to_string());
-    let gender_pref_text = payload
-        .gender_preference
-        .as_ref()
-        .map(|v| serde_json::to_string(v).unwrap());
-    let year_pref_text = payload
-        .year_preference
-        .as_ref()
-        .map(|v| serde_json::to_string(v).unwrap());
-    let major_pref_text = payload
-        .major_preference
-        .as_ref()
-        .map(|v| serde_json::to_string(v).unwrap());
-    let is_felon_int = payload.is_felon.map(|b| if b { 1 } else { 0 });
-    let result = conn.execute(
-        "INSERT INTO preferences (user_id, gender_preference, min_age, max_age, year_preference, major_preference, is_felon)
- VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
- ON CONFLICT(user_id) DO UPDATE SET
- gender_preference = ?2, min_age = ?3, max_age = ?4, year_preference = ?5, major_preference = ?6, is_felon = ?7",
-        params![uid, gender_pref_text, payload.min_age, payload.max_age, year_pref_text, major_pref_text, is_felon_int],
+    let interest_ids = match interests::filter_ids(&conn, body) {
+        Ok(ids) => ids,
+        Err(message) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "success": false,
+                "message": message
+            }));
+        }
+    };
+    let result = conn.unchecked_transaction().and_then(|tx| {
+        attributes::save_filters(&tx, uid, &filters)?;
+        interests::save_filter(&tx, uid, &interest_ids)?;
+        tx.commit()
+    });
+    info!(
+        "User {} ({}) set prefs {:?} and interests {:?}",
+        uid, email, filters, interest_ids
     );
-    info!("User {} ({}) set prefs {:?}", uid, email, payload);
     match result {
         Ok(_) => HttpResponse::Ok().json(serde_json::json!({"success": true, "user_id": uid})),
         Err(e) => HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
     }
 }
 
+// Helper function for saving a match between two users
+// Normalizes user IDs (lower ID first) so each pair is stored once
+// Returns false if the match already existed
+fn insert_match(conn: &Connection, a: i32, b: i32, ts: i64) -> rusqlite::Result<bool> {
+    let (lower_id, higher_id) = if a < b { (a, b) } else { (b, a) };
+    let inserted = conn.execute(
+        "INSERT OR IGNORE INTO matches (user_id, matched_user_id, timestamp) VALUES (?1, ?2, ?3)",
+        params![lower_id, higher_id, ts],
+    )?;
+    Ok(inserted > 0)
+}
+
 // Function for creating a match between two users: POST /matches
 // Updates the matches table
 // Normalizes user IDs (lower ID first) to prevent duplicate matches
 // Checks if match already exists before creating
+// Refuses to match users who have blocked each other
 async fn create_match(data: web::Json<MatchRequest>, state: web::Data<AppState>) -> impl Responder {
     let conn = state.db_conn.lock().unwrap();
+    if let Some(r) = moderation::account_restriction(&conn, data.user_id) {
+        return moderation::restricted_response(&r);
+    }
+    if blocks::is_blocked(&conn, data.user_id, data.matched_user_id) {
+        return HttpResponse::Forbidden().json(serde_json::json!({
+            "success": false,
+            "message": "Cannot match with this user"
+        }));
+    }
     let ts = Utc::now().timestamp_millis();
-    let (lower_id, higher_id) = if data.user_id < data.matched_user_id {
-        (data.user_id, data.matched_user_id)
-    } else {
-        (data.matched_user_id, data.user_id)
-    };
-    let existing = conn.query_row(
-        "SELECT 1 FROM matches WHERE user_id = ?1 AND matched_user_id = ?2",
-        params![lower_id, higher_id],
-        |_| Ok(()),
-    );
-    if existing.is_ok() {
-        return HttpResponse::Ok().json(serde_json::json!({
+    match insert_match(&conn, data.user_id, data.matched_user_id, ts) {
+        Ok(false) => HttpResponse::Ok().json(serde_json::json!({
             "success": true,
             "message": "Match already exists"
-        }));
-    }
-    match conn.execute(
-        "INSERT INTO matches (user_id, matched_user_id, timestamp) VALUES (?1, ?2, ?3)",
-        params![lower_id, higher_id, ts],
-    ) {
-        Ok(_) => {
+        })),
+        Ok(true) => {
             info!(
                 "Created match between {} and {}",
                 data.user_id, data.matched_user_id
@@ -1145,6 +1361,9 @@ async fn create_match(data: web::Json<MatchRequest>, state: web::Data<AppState>)
 // Normalizes user IDs to find the correct match record
 async fn delete_match(data: web::Json<MatchRequest>, state: web::Data<AppState>) -> impl Responder {
     let conn = state.db_conn.lock().unwrap();
+    if let Some(r) = moderation::account_restriction(&conn, data.user_id) {
+        return moderation::restricted_response(&r);
+    }
     let (lower_id, higher_id) = if data.user_id < data.matched_user_id {
         (data.user_id, data.matched_user_id)
     } else {
@@ -1171,13 +1390,22 @@ async fn delete_match(data: web::Json<MatchRequest>, state: web::Data<AppState>)
 // Endpoint for retrieving matches for a given user: GET /matches/{user_id}
 // Queries the matches table
 // Returns all matches where user is either user_id or matched_user_id
+// Leaves out matches with accounts that are pending deletion
+// Includes each matched user's primary photo for the conversation list
 async fn get_matches(user_id: web::Path<i32>, state: web::Data<AppState>) -> impl Responder {
     let uid = user_id.into_inner();
     let conn = state.db_conn.lock().unwrap();
+    if let Some(r) = moderation::account_restriction(&conn, uid) {
+        return moderation::restricted_response(&r);
+    }
     let mut stmt = conn.prepare(
-        "SELECT CASE WHEN user_id = ?1 THEN matched_user_id ELSE user_id END as matched_id, timestamp
+        "SELECT CASE WHEN user_id = ?1 THEN matched_user_id ELSE user_id END as matched_id, timestamp,
+                (SELECT profile_picture FROM profiles
+                 WHERE user_id = CASE WHEN matches.user_id = ?1 THEN matches.matched_user_id ELSE matches.user_id END)
          FROM matches 
-         WHERE user_id = ?1 OR matched_user_id = ?1"
+         WHERE (user_id = ?1 OR matched_user_id = ?1)
+           AND (SELECT deletion_due_at FROM profiles
+                WHERE user_id = CASE WHEN matches.user_id = ?1 THEN matches.matched_user_id ELSE matches.user_id END) IS NULL"
     ).unwrap();
     let matches: Vec<Match> = stmt
         .query_map(params![uid], |row| {
@@ -1185,6 +1413,7 @@ async fn get_matches(user_id: web::Path<i32>, state: web::Data<AppState>) -> imp
                 user_id: uid,
                 matched_user_id: row.get(0)?,
                 timestamp: row.get(1)?,
+                profile_picture: row.get(2)?,
             })
         })
         .unwrap()
@@ -1203,10 +1432,10 @@ fn rotate_backups() {
     let timestamp = Local::now().format("%Y-%m-%d_%H-%M-%S");
     let backup_name = format!("db_{}.db", timestamp);
     let backup_path = format!("db_backups/{}", backup_name);
-    if Path::new("test.db").exists() {
-        if let Err(e) = fs::copy("test.db", &backup_path) {
-            eprintln!("Failed to create DB backup: {}", e);
-        }
+    if Path::new("test.db").exists()
+        && let Err(e) = fs::copy("test.db", &backup_path)
+    {
+        eprintln!("Failed to create DB backup: {}", e);
     }
     let mut backups: Vec<_> = fs::read_dir("db_backups")
         .unwrap()
@@ -1223,6 +1452,174 @@ fn rotate_backups() {
     }
 }
 
+// Function for registering every endpoint on the app
+// Shared by the server and the tests so both use the same routes
+fn configure_routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/", web::get().to(index))
+        .route("/health", web::get().to(health))
+        .route("/users/new", web::post().to(create_new_user))
+        .route(
+            "/users/profile-picture",
+            web::post().to(update_profile_picture),
+        )
+        .route("/login", web::post().to(login))
+        .route(
+            "/users/{user_id}/profile-picture",
+            web::get().to(get_profile_picture),
+        )
+        .route("/queue/{user_id}", web::get().to(get_queue))
+        .route("/profiles/{user_id}", web::get().to(get_profile))
+        .route("/profiles/{user_id}", web::put().to(put_profile))
+        .route("/profiles/{user_id}", web::patch().to(patch_profile))
+        .route("/messages", web::post().to(post_message))
+        .route("/messages/{a}/{b}", web::get().to(get_messages))
+        .route("/ws/{user_id}", web::get().to(ws_index))
+        .route("/delete_user", web::post().to(delete_user))
+        .route("/preferences/{user_id}", web::get().to(get_preferences))
+        .route("/preferences/{user_id}", web::put().to(put_preferences))
+        .route("/preference-options", web::get().to(get_preference_options))
+        .route("/interests", web::get().to(interests::search_interests))
+        .route("/prompts", web::get().to(prompts::get_prompts))
+        .route(
+            "/profiles/{user_id}/prompts",
+            web::get().to(prompts::get_answers),
+        )
+        .route(
+            "/profiles/{user_id}/prompts",
+            web::put().to(prompts::put_answers),
+        )
+        .route(
+            "/profiles/{user_id}/prompts/{answer_id}",
+            web::delete().to(prompts::delete_answer),
+        )
+        .route(
+            "/profiles/{user_id}/visibility",
+            web::get().to(visibility::get_visibility),
+        )
+        .route(
+            "/profiles/{user_id}/visibility",
+            web::put().to(visibility::put_visibility),
+        )
+        .route(
+            "/profiles/{user_id}/incognito",
+            web::put().to(visibility::put_incognito),
+        )
+        .route("/swipes", web::post().to(swipes::create_swipe))
+        .route("/swipes/undo", web::post().to(swipes::undo_swipe))
+        .route("/likes/received", web::get().to(likes::get_received_likes))
+        .route("/matches", web::post().to(create_match))
+        .route("/matches", web::delete().to(delete_match))
+        .route("/matches/{user_id}", web::get().to(get_matches))
+        .route("/blocks", web::post().to(blocks::create_block))
+        .route("/blocks/{id}", web::delete().to(blocks::delete_block))
+        .route("/blocks/{user_id}", web::get().to(blocks::get_blocks))
+        .route("/reports", web::post().to(reports::create_report))
+        .route("/admin/reports", web::get().to(reports::list_reports))
+        .route("/admin/reports/{id}", web::get().to(reports::get_report))
+        .route("/admin/reports/{id}", web::put().to(reports::update_report))
+        .route(
+            "/admin/reports/{id}/photos/{index}",
+            web::get().to(reports::get_report_photo),
+        )
+        .route(
+            "/admin/reports/{id}/notes",
+            web::post().to(reports::add_report_note),
+        )
+        .route(
+            "/admin/report-counts",
+            web::get().to(reports::get_report_counts),
+        )
+        .route(
+            "/admin/users/{user_id}/suspend",
+            web::post().to(moderation::suspend_user),
+        )
+        .route(
+            "/admin/users/{user_id}/ban",
+            web::post().to(moderation::ban_user),
+        )
+        .route(
+            "/admin/users/{user_id}/reinstate",
+            web::post().to(moderation::reinstate_user),
+        )
+        .route(
+            "/admin/users/{user_id}/status",
+            web::get().to(moderation::get_account_status),
+        )
+        .route(
+            "/admin/users/{user_id}/premium",
+            web::put().to(entitlements::grant_premium),
+        )
+        .route(
+            "/admin/users/{user_id}/premium",
+            web::delete().to(entitlements::revoke_premium),
+        )
+        .route("/users/{user_id}/photos", web::get().to(photos::get_photos))
+        .route(
+            "/users/{user_id}/photos",
+            web::post().to(photos::upload_photo),
+        )
+        .route(
+            "/users/{user_id}/photos/order",
+            web::put().to(photos::reorder_photos),
+        )
+        .route(
+            "/users/{user_id}/photos/{photo_id}",
+            web::delete().to(photos::delete_photo),
+        )
+        .route(
+            "/users/{user_id}/photos/{photo_id}/primary",
+            web::put().to(photos::set_primary_photo),
+        )
+        .route("/photos/{photo_id}", web::get().to(photos::get_photo))
+        .route("/me/export", web::get().to(exports::get_export))
+        .route("/me/onboarding", web::get().to(onboarding::get_onboarding))
+        .route(
+            "/me/entitlements",
+            web::get().to(entitlements::get_entitlements),
+        )
+        .route("/me/quotas", web::get().to(entitlements::get_quotas))
+        .route(
+            "/me/subscription",
+            web::get().to(subscriptions::get_subscription),
+        )
+        .route(
+            "/me/subscription/checkout",
+            web::post().to(subscriptions::create_checkout),
+        )
+        .route(
+            "/me/subscription/cancel",
+            web::post().to(subscriptions::cancel_subscription),
+        )
+        .route("/payments/webhook", web::post().to(subscriptions::webhook))
+        .route(
+            "/payments/fake/checkouts/{checkout_id}/complete",
+            web::post().to(subscriptions::fake_complete_checkout),
+        )
+        .route(
+            "/payments/fake/subscriptions/{subscription_id}/{event}",
+            web::post().to(subscriptions::fake_subscription_event),
+        )
+        .route(
+            "/me/impressions",
+            web::get().to(impressions::get_impressions),
+        )
+        .route("/me/boosts", web::get().to(boosts::get_boosts))
+        .route("/me/boosts", web::post().to(boosts::start_boost))
+        .route(
+            "/me/boosts/checkout",
+            web::post().to(boosts::create_checkout),
+        )
+        .route(
+            "/me/email-verification",
+            web::post().to(onboarding::request_verification),
+        )
+        .route(
+            "/verify-email/{token}",
+            web::get().to(onboarding::verify_email),
+        )
+        .route("/exports/{token}", web::get().to(exports::download_export));
+}
+
 // Main function to build the server
 // Entry point for the Actix web server
 #[actix_web::main]
@@ -1231,70 +1628,24 @@ async fn main() -> std::io::Result<()> {
     rotate_backups();
     // Enable logging - initialize logger from environment variables
     env_logger::init_from_env(Env::default().default_filter_or("info"));
+    // Load attribute schema - stops the server now if the schema file is invalid
+    attributes::schema();
     // Create database connection - open or create SQLite database file
     let conn = Connection::open("test.db").unwrap();
-    // Create matches table - stores relationships between matched users
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS matches (
-            user_id INTEGER NOT NULL,
-            matched_user_id INTEGER NOT NULL,
-            timestamp INTEGER NOT NULL,
-            PRIMARY KEY (user_id, matched_user_id),
-            FOREIGN KEY(user_id) REFERENCES profiles(user_id),
-            FOREIGN KEY(matched_user_id) REFERENCES profiles(user_id)
-        )",
-        params![],
-    )
-    .unwrap();
-    // Create profiles table - stores user profile information
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS profiles (
-            user_id INTEGER PRIMARY KEY AUTOINCREMENT,
-            email TEXT UNIQUE NOT NULL,
-            password TEXT NOT NULL,
-            name TEXT,
-            age INTEGER,
-            major TEXT,
-            year TEXT,
-            bio TEXT,
-            interests TEXT,
-            profile_picture TEXT,
-            gender TEXT,
-            is_felon INTEGER
-        )",
-        params![],
-    )
-    .unwrap();
-    // Create messages table - stores chat messages between users
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS messages (
-            id INTEGER PRIMARY KEY,
-            sender_id INTEGER NOT NULL,
-            receiver_id INTEGER NOT NULL,
-            content TEXT NOT NULL,
-            timestamp INTEGER NOT NULL
-        )",
-        params![],
-    )
-    .unwrap();
-    // Create preferences table - stores user filter preferences for matching
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS preferences (
-        user_id INTEGER PRIMARY KEY,
-        gender_preference TEXT,
-        min_age INTEGER,
-        max_age INTEGER,
-        year_preference TEXT,
-        major_preference TEXT,
-        is_felon INTEGER,
-        FOREIGN KEY(user_id) REFERENCES profiles(user_id)
-    )",
-        params![],
-    )
-    .unwrap();
+    // Create tables - creates or upgrades every table the server uses
+    db::init_db(&conn).unwrap();
     // Build app - create shared application state
     // Add all endpoints with the correct HTTP protocols
     let state = web::Data::new(AppState::new(conn));
+    // Migrate media - moves images saved by filesystem path into the media store
+    media::migrate_legacy_paths(&state.db_conn.lock().unwrap(), state.media.as_ref()).unwrap();
+    // Start purge job - permanently deletes accounts whose grace period has ended
+    deletion::start_purge_job(state.clone());
+    // Fail interrupted exports - builds still pending from before the restart will never finish
+    let now = Utc::now().timestamp_millis();
+    exports::fail_stale_exports(&state.db_conn.lock().unwrap(), now, now).unwrap();
+    // Start export cleanup job - removes exports whose download link has expired
+    exports::start_cleanup_job(state.clone());
     // Create HTTP server with all routes and middleware
     HttpServer::new(move || {
         App::new()
@@ -1302,40 +1653,25 @@ async fn main() -> std::io::Result<()> {
             .wrap(
                 Cors::default()
                     .allow_any_origin()
-                    .allowed_methods(vec!["GET", "POST", "PUT", "DELETE"])
+                    .allowed_methods(vec!["GET", "POST", "PUT", "PATCH", "DELETE"])
                     .allowed_headers(vec![
                         actix_web::http::header::AUTHORIZATION,
                         actix_web::http::header::ACCEPT,
                         actix_web::http::header::CONTENT_TYPE,
+                        actix_web::http::header::HeaderName::from_static(ADMIN_HEADER),
+                        actix_web::http::header::IF_NONE_MATCH,
+                        actix_web::http::header::IF_RANGE,
+                        actix_web::http::header::RANGE,
+                    ])
+                    .expose_headers(vec![
+                        actix_web::http::header::ETAG,
+                        actix_web::http::header::CONTENT_RANGE,
+                        actix_web::http::header::ACCEPT_RANGES,
                     ])
                     .max_age(3600),
             )
             .app_data(state.clone())
-            .route("/", web::get().to(index))
-            .route("/health", web::get().to(health))
-            .route("/users/new", web::post().to(create_new_user))
-            .route(
-                "/users/profile-picture",
-                web::post().to(update_profile_picture),
-            )
-            .route("/login", web::post().to(login))
-            .route(
-                "/users/{user_id}/profile-picture",
-                web::get().to(get_profile_picture),
-            )
-            .route("/queue/{user_id}", web::get().to(get_queue))
-            .route("/profiles/{user_id}", web::get().to(get_profile))
-            .route("/profiles/{user_id}", web::put().to(put_profile))
-            .route("/messages", web::post().to(post_message))
-            .route("/messages/{a}/{b}", web::get().to(get_messages))
-            .route("/ws/{user_id}", web::get().to(ws_index))
-            .route("/delete_user", web::post().to(delete_user))
-            .route("/preferences/{user_id}", web::get().to(get_preferences))
-            .route("/preferences/{user_id}", web::put().to(put_preferences))
-            .route("/preference-options", web::get().to(get_preference_options))
-            .route("/matches", web::post().to(create_match))
-            .route("/matches", web::delete().to(delete_match))
-            .route("/matches/{user_id}", web::get().to(get_matches))
+            .configure(configure_routes)
     })
     // Bind server to localhost port 8080
     // Run server and wait for Cloudflare tunnel to activate
@@ -0,0 +1,442 @@
+/*
+Name: JayMatch media storage
+Description: Stores uploaded images behind opaque keys, either on the local filesystem or in an S3-compatible bucket such as MinIO
+Pre/Post Conditions: The backend is chosen once at startup from environment variables. The database only ever holds keys like profile_pictures/abc.jpg, never filesystem paths or URLs.
+Errors: Every operation returns an io::Error; missing objects use ErrorKind::NotFound so callers can tell them apart from storage failures.
+*/
+
+use crate::images;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use log::{info, warn};
+use rusqlite::{Connection, params};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+// Default directory for the local filesystem backend
+pub const DEFAULT_MEDIA_DIR: &str = "uploads";
+
+// Largest object read back from S3 (64 MB)
+const MAX_OBJECT_BYTES: u64 = 64 * 1024 * 1024;
+
+// Size of the buffer used when hashing local files
+const HASH_CHUNK_BYTES: usize = 64 * 1024;
+
+// Streamed object data
+pub type ObjectReader = Box<dyn Read + Send>;
+
+// Structure for the details needed to serve an object without reading it
+#[derive(Clone, Debug)]
+pub struct ObjectInfo {
+    pub len: u64,             // Size in bytes
+    pub etag: String,         // Quoted strong entity tag derived from the content hash
+    pub content_type: String, // MIME type of the data
+}
+
+// Interface every media backend implements
+// Keys are relative, slash separated names chosen by the server
+pub trait MediaStore: Send + Sync {
+    // Stores data under key, replacing anything already there
+    fn put(&self, key: &str, data: &[u8], content_type: &str) -> io::Result<()>;
+    // Reads the data stored under key
+    fn get(&self, key: &str) -> io::Result<Vec<u8>>;
+    // Removes key, succeeding if it does not exist
+    fn delete(&self, key: &str) -> io::Result<()>;
+    // Looks up the size, entity tag and content type of key
+    fn info(&self, key: &str) -> io::Result<ObjectInfo>;
+    // Opens key for streaming, optionally limited to the inclusive byte range (start, end)
+    fn open(&self, key: &str, range: Option<(u64, u64)>) -> io::Result<ObjectReader>;
+}
+
+// Helper function for rejecting keys that could escape the storage root
+fn check_key(key: &str) -> io::Result<()> {
+    let valid = !key.is_empty()
+        && Path::new(key)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)));
+    if valid {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid media key {}", key),
+        ))
+    }
+}
+
+// Media backend that keeps files in a local directory
+// Entity tags are SHA-256 hashes of the file, cached until the file's size or modification time changes
+pub struct LocalStore {
+    root: PathBuf,
+    hashes: Mutex<HashMap<String, (u64, SystemTime, ObjectInfo)>>,
+}
+
+impl LocalStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            hashes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Helper function for the file that holds a key
+    fn path(&self, key: &str) -> io::Result<PathBuf> {
+        check_key(key)?;
+        Ok(self.root.join(key))
+    }
+}
+
+impl MediaStore for LocalStore {
+    fn put(&self, key: &str, data: &[u8], _content_type: &str) -> io::Result<()> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, data)
+    }
+
+    fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.path(key)?)
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        self.hashes.lock().unwrap().remove(key);
+        match fs::remove_file(self.path(key)?) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            other => other,
+        }
+    }
+
+    fn info(&self, key: &str) -> io::Result<ObjectInfo> {
+        let path = self.path(key)?;
+        let meta = fs::metadata(&path)?;
+        let modified = meta.modified()?;
+        if let Some((len, time, info)) = self.hashes.lock().unwrap().get(key)
+            && *len == meta.len()
+            && *time == modified
+        {
+            return Ok(info.clone());
+        }
+        // Hash in chunks so large files are never held in memory
+        let mut file = fs::File::open(&path)?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0u8; HASH_CHUNK_BYTES];
+        let mut head = Vec::new();
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            if head.is_empty() {
+                head = buf[..n].to_vec();
+            }
+            hasher.update(&buf[..n]);
+        }
+        let info = ObjectInfo {
+            len: meta.len(),
+            etag: format!("\"{}\"", hex::encode(hasher.finalize())),
+            content_type: images::content_type(&head).to_string(),
+        };
+        self.hashes
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), (meta.len(), modified, info.clone()));
+        Ok(info)
+    }
+
+    fn open(&self, key: &str, range: Option<(u64, u64)>) -> io::Result<ObjectReader> {
+        let mut file = fs::File::open(self.path(key)?)?;
+        match range {
+            Some((start, end)) => {
+                file.seek(SeekFrom::Start(start))?;
+                Ok(Box::new(file.take(end - start + 1)))
+            }
+            None => Ok(Box::new(file)),
+        }
+    }
+}
+
+// Media backend for S3 and S3-compatible servers such as MinIO
+// Uses path-style URLs (endpoint/bucket/key) signed with AWS Signature Version 4
+// Entity tags are the ones S3 reports, which are MD5 hashes of the content for single part uploads
+pub struct S3Store {
+    endpoint: String,
+    host: String,
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    agent: ureq::Agent,
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Helper function for computing an HMAC-SHA256
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+// Helper function for percent-encoding a key for a request path
+// Leaves unreserved characters and slashes as they are, as S3 expects
+fn uri_encode(key: &str) -> String {
+    key.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+impl S3Store {
+    pub fn new(
+        endpoint: &str,
+        bucket: &str,
+        region: &str,
+        access_key: &str,
+        secret_key: &str,
+    ) -> Self {
+        let endpoint = endpoint.trim_end_matches('/').to_string();
+        let host = endpoint
+            .split("://")
+            .last()
+            .unwrap_or(&endpoint)
+            .split('/')
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        Self {
+            endpoint,
+            host,
+            bucket: bucket.to_string(),
+            region: region.to_string(),
+            access_key: access_key.to_string(),
+            secret_key: secret_key.to_string(),
+            agent: ureq::AgentBuilder::new().build(),
+        }
+    }
+
+    // Helper function for building a signed request for an object
+    // Returns the request with Host, X-Amz-Date, X-Amz-Content-Sha256 and Authorization set
+    fn signed_request(&self, method: &str, key: &str, body: &[u8]) -> io::Result<ureq::Request> {
+        check_key(key)?;
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let path = format!("/{}/{}", uri_encode(&self.bucket), uri_encode(key));
+        let payload_hash = hex::encode(Sha256::digest(body));
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method, path, self.host, payload_hash, amz_date, signed_headers, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+        let k_date = hmac(format!("AWS4{}", self.secret_key).as_bytes(), &date);
+        let k_region = hmac(&k_date, &self.region);
+        let k_service = hmac(&k_region, "s3");
+        let k_signing = hmac(&k_service, "aws4_request");
+        let signature = hex::encode(hmac(&k_signing, &string_to_sign));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key, scope, signed_headers, signature
+        );
+        Ok(self
+            .agent
+            .request(method, &format!("{}{}", self.endpoint, path))
+            .set("Host", &self.host)
+            .set("X-Amz-Date", &amz_date)
+            .set("X-Amz-Content-Sha256", &payload_hash)
+            .set("Authorization", &authorization))
+    }
+}
+
+// Helper function for converting a failed S3 request into an io::Error
+// 404 responses become ErrorKind::NotFound
+fn s3_error(e: ureq::Error) -> io::Error {
+    match e {
+        ureq::Error::Status(404, _) => io::Error::new(io::ErrorKind::NotFound, "Object not found"),
+        ureq::Error::Status(code, resp) => io::Error::other(format!(
+            "S3 returned {}: {}",
+            code,
+            resp.into_string().unwrap_or_default()
+        )),
+        ureq::Error::Transport(t) => io::Error::other(t.to_string()),
+    }
+}
+
+impl MediaStore for S3Store {
+    fn put(&self, key: &str, data: &[u8], content_type: &str) -> io::Result<()> {
+        self.signed_request("PUT", key, data)?
+            .set("Content-Type", content_type)
+            .send_bytes(data)
+            .map_err(s3_error)?;
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        let resp = self
+            .signed_request("GET", key, b"")?
+            .call()
+            .map_err(s3_error)?;
+        let mut data = Vec::new();
+        resp.into_reader()
+            .take(MAX_OBJECT_BYTES)
+            .read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        match self.signed_request("DELETE", key, b"")?.call() {
+            Ok(_) => Ok(()),
+            Err(e) => match s3_error(e) {
+                e if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                e => Err(e),
+            },
+        }
+    }
+
+    fn info(&self, key: &str) -> io::Result<ObjectInfo> {
+        let resp = self
+            .signed_request("HEAD", key, b"")?
+            .call()
+            .map_err(s3_error)?;
+        let len = resp
+            .header("Content-Length")
+            .and_then(|v| v.parse().ok())
+            .ok_or_else(|| io::Error::other("S3 response has no Content-Length"))?;
+        let etag = resp
+            .header("ETag")
+            .ok_or_else(|| io::Error::other("S3 response has no ETag"))?;
+        Ok(ObjectInfo {
+            len,
+            etag: etag.to_string(),
+            content_type: resp.content_type().to_string(),
+        })
+    }
+
+    fn open(&self, key: &str, range: Option<(u64, u64)>) -> io::Result<ObjectReader> {
+        let mut request = self.signed_request("GET", key, b"")?;
+        if let Some((start, end)) = range {
+            request = request.set("Range", &format!("bytes={}-{}", start, end));
+        }
+        let resp = request.call().map_err(s3_error)?;
+        Ok(Box::new(resp.into_reader()))
+    }
+}
+
+// Function for choosing the media backend from environment variables
+// MEDIA_STORE=s3 uses S3_ENDPOINT, S3_BUCKET, S3_REGION, S3_ACCESS_KEY and S3_SECRET_KEY
+// Anything else stores files under MEDIA_DIR (default uploads)
+pub fn store_from_env() -> Arc<dyn MediaStore> {
+    let var = |name: &str| std::env::var(name).unwrap_or_default();
+    if var("MEDIA_STORE").eq_ignore_ascii_case("s3") {
+        let region = std::env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".to_string());
+        info!(
+            "Storing media in bucket {} at {}",
+            var("S3_BUCKET"),
+            var("S3_ENDPOINT")
+        );
+        Arc::new(S3Store::new(
+            &var("S3_ENDPOINT"),
+            &var("S3_BUCKET"),
+            &region,
+            &var("S3_ACCESS_KEY"),
+            &var("S3_SECRET_KEY"),
+        ))
+    } else {
+        let dir = std::env::var("MEDIA_DIR").unwrap_or_else(|_| DEFAULT_MEDIA_DIR.to_string());
+        Arc::new(LocalStore::new(dir))
+    }
+}
+
+// Helper function for the key a pre-migration path is stored under
+// uploads/profile_pictures/abc.png becomes profile_pictures/abc.png
+fn key_for_legacy_path(path: &str) -> Option<String> {
+    let file = Path::new(path).file_name()?.to_string_lossy().to_string();
+    Some(format!("profile_pictures/{}", file))
+}
+
+// Function for moving images saved before media keys existed into the media store
+// Rows holding a filesystem path are uploaded under a key and rewritten to hold the key
+// Thumbnails saved next to the file are uploaded under the matching thumbnail keys
+// Rows whose file cannot be read are left as they are and logged, so the migration can be rerun
+// once the working directory or upload path is fixed
+// The original files are left in place so nothing is lost if the store is misconfigured
+// Safe to run on every start; rows that already hold keys are skipped
+// Returns the number of rows migrated
+pub fn migrate_legacy_paths(conn: &Connection, store: &dyn MediaStore) -> rusqlite::Result<usize> {
+    let legacy: Vec<(i64, i32, String)> = conn
+        .prepare(
+            "SELECT id, user_id, media_key FROM profile_photos
+             WHERE media_key LIKE 'uploads/%' OR media_key LIKE '/%' OR media_key LIKE './%'",
+        )?
+        .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .filter_map(|r| r.ok())
+        .collect();
+    let mut migrated = 0;
+    for (id, user_id, path) in &legacy {
+        let Some(key) = key_for_legacy_path(path) else {
+            warn!("Cannot migrate {}: no file name", path);
+            continue;
+        };
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) => {
+                // Leave the row as it is so a later start can find the file
+                warn!("Failed to read {} for migration: {}", path, e);
+                continue;
+            }
+        };
+        let mut files = vec![(key.clone(), data)];
+        for (size, _) in images::THUMBNAIL_SIZES {
+            if let Ok(thumb) = fs::read(images::thumbnail_path(path, size)) {
+                files.push((images::thumbnail_path(&key, size), thumb));
+            }
+        }
+        let result = files
+            .iter()
+            .try_for_each(|(k, data)| store.put(k, data, images::content_type(data)));
+        if let Err(e) = result {
+            // Leave the row as it is so the next start tries again
+            warn!("Failed to migrate {}: {}", path, e);
+            continue;
+        }
+        let tx = conn.unchecked_transaction()?;
+        tx.execute(
+            "UPDATE profile_photos SET media_key = ?1 WHERE id = ?2",
+            params![key, id],
+        )?;
+        tx.execute(
+            "UPDATE profiles SET profile_picture = (SELECT media_key FROM profile_photos
+                 WHERE user_id = ?1 AND is_primary = 1)
+             WHERE user_id = ?1",
+            params![user_id],
+        )?;
+        tx.commit()?;
+        migrated += 1;
+    }
+    if migrated < legacy.len() {
+        warn!(
+            "{} images could not be migrated and were left for the next start",
+            legacy.len() - migrated
+        );
+    }
+    if migrated > 0 {
+        info!("Migrated {} images to media keys", migrated);
+    }
+    Ok(migrated)
+}
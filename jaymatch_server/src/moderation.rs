@@ -0,0 +1,334 @@
+/*
+Name: JayMatch moderation
+Description: Account suspensions and bans, and the checks every endpoint uses to enforce them
+Pre/Post Conditions: The profiles table must have the account_status, suspended_until, moderation_reason and deletion_due_at columns, and the banned_emails table must exist.
+Errors: Missing admin key returns 401, unknown users return 404, restricted accounts receive 403, database errors return 500.
+*/
+
+use crate::{AppState, WsDisconnect, admin_required, is_admin, sessions};
+use actix_web::{HttpRequest, HttpResponse, Responder, web};
+use chrono::{TimeZone, Utc};
+use log::info;
+use rusqlite::{Connection, params};
+use serde::Deserialize;
+
+// SQL condition for accounts that are allowed to appear to other users
+// Suspensions end on their own once suspended_until has passed
+// Accounts pending deletion are hidden until they are restored
+pub const ACTIVE_ACCOUNT_SQL: &str = "(deletion_due_at IS NULL AND (account_status = 'active'
+    OR (account_status = 'suspended' AND suspended_until <= CAST(strftime('%s', 'now') AS INTEGER) * 1000)))";
+
+// Restriction currently placed on an account
+#[derive(Debug, Clone, PartialEq)]
+pub enum Restriction {
+    Suspended { until: i64, reason: Option<String> },
+    Banned { reason: Option<String> },
+    PendingDeletion { purge_at: i64 },
+}
+
+// Structure for suspending a user
+// Either until (milliseconds since epoch) or hours must be given
+#[derive(Deserialize)]
+pub struct SuspendRequest {
+    pub until: Option<i64>,
+    pub hours: Option<i64>,
+    pub reason: Option<String>,
+}
+
+// Structure for banning a user
+#[derive(Deserialize)]
+pub struct BanRequest {
+    pub reason: Option<String>,
+}
+
+// Helper function for looking up the suspension or ban on an account, ignoring any pending deletion
+// Login uses this so a suspended or banned account is refused before it can be restored
+// Returns None for active accounts, expired suspensions and unknown users
+pub fn moderation_restriction(conn: &Connection, user_id: i32) -> Option<Restriction> {
+    load_restrictions(conn, user_id).and_then(|(restriction, _)| restriction)
+}
+
+// Helper function for looking up the restriction on an account
+// Bans take priority over a pending deletion, which takes priority over a suspension
+// Returns None for active accounts, expired suspensions and unknown users
+pub fn account_restriction(conn: &Connection, user_id: i32) -> Option<Restriction> {
+    match load_restrictions(conn, user_id)? {
+        (Some(Restriction::Banned { reason }), _) => Some(Restriction::Banned { reason }),
+        (_, Some(purge_at)) => Some(Restriction::PendingDeletion { purge_at }),
+        (restriction, None) => restriction,
+    }
+}
+
+// Helper function for reading an account's suspension or ban and when it is due to be deleted
+// Returns None for unknown users
+fn load_restrictions(
+    conn: &Connection,
+    user_id: i32,
+) -> Option<(Option<Restriction>, Option<i64>)> {
+    let (status, until, reason, purge_at) = conn
+        .query_row(
+            "SELECT account_status, suspended_until, moderation_reason, deletion_due_at
+             FROM profiles WHERE user_id = ?1",
+            params![user_id],
+            |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, Option<i64>>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                    row.get::<_, Option<i64>>(3)?,
+                ))
+            },
+        )
+        .ok()?;
+    let restriction = match (status.as_str(), until) {
+        ("banned", _) => Some(Restriction::Banned { reason }),
+        ("suspended", Some(until)) if until > Utc::now().timestamp_millis() => {
+            Some(Restriction::Suspended { until, reason })
+        }
+        _ => None,
+    };
+    Some((restriction, purge_at))
+}
+
+// Helper function for the response sent to a restricted account
+// Used by every endpoint that acts on behalf of a user
+pub fn restricted_response(restriction: &Restriction) -> HttpResponse {
+    match restriction {
+        Restriction::Banned { reason } => HttpResponse::Forbidden().json(serde_json::json!({
+            "success": false,
+            "message": "Account banned",
+            "reason": reason
+        })),
+        Restriction::Suspended { until, reason } => {
+            let until_text = Utc
+                .timestamp_millis_opt(*until)
+                .single()
+                .map(|t| t.to_rfc3339())
+                .unwrap_or_default();
+            HttpResponse::Forbidden().json(serde_json::json!({
+                "success": false,
+                "message": format!("Account suspended until {}", until_text),
+                "suspended_until": until,
+                "reason": reason
+            }))
+        }
+        Restriction::PendingDeletion { purge_at } => {
+            HttpResponse::Forbidden().json(serde_json::json!({
+                "success": false,
+                "message": "Account scheduled for deletion, log in to restore it",
+                "deletion_due_at": purge_at
+            }))
+        }
+    }
+}
+
+// Helper function for checking whether an email has been banned from registering
+// Emails are compared case-insensitively
+pub fn is_email_banned(conn: &Connection, email: &str) -> bool {
+    conn.query_row(
+        "SELECT 1 FROM banned_emails WHERE email = ?1",
+        params![email.to_lowercase()],
+        |_| Ok(()),
+    )
+    .is_ok()
+}
+
+// Helper function for ending a restricted or deleted user's live activity
+// Revokes their login sessions and closes their WebSocket if connected
+pub fn terminate_live_connections(conn: &Connection, state: &AppState, user_id: i32, reason: &str) {
+    if let Err(e) = sessions::revoke_sessions(conn, user_id) {
+        info!("Failed to revoke sessions for user {}: {}", user_id, e);
+    }
+    let clients = state.clients.lock().unwrap();
+    if let Some(addr) = clients.get(&user_id) {
+        addr.do_send(WsDisconnect(reason.to_string()));
+    }
+}
+
+// Admin API for suspending a user: POST /admin/users/{user_id}/suspend
+// Sets the account to suspended until the given time
+// Ends the user's sessions and WebSocket connection
+pub async fn suspend_user(
+    req: HttpRequest,
+    user_id: web::Path<i32>,
+    data: web::Json<SuspendRequest>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    if !is_admin(&req) {
+        return admin_required();
+    }
+    let uid = user_id.into_inner();
+    let now = Utc::now().timestamp_millis();
+    let until = match (data.until, data.hours) {
+        (Some(until), _) => until,
+        (None, Some(hours)) => match hours
+            .checked_mul(60 * 60 * 1000)
+            .and_then(|ms| now.checked_add(ms))
+        {
+            Some(until) => until,
+            None => {
+                return HttpResponse::BadRequest().json(serde_json::json!({
+                    "success": false,
+                    "message": "hours is too large"
+                }));
+            }
+        },
+        (None, None) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "success": false,
+                "message": "Suspension requires until or hours"
+            }));
+        }
+    };
+    if until <= now {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "success": false,
+            "message": "Suspension must end in the future"
+        }));
+    }
+    let conn = state.db_conn.lock().unwrap();
+    match conn.execute(
+        "UPDATE profiles SET account_status = 'suspended', suspended_until = ?1, moderation_reason = ?2
+         WHERE user_id = ?3",
+        params![until, data.reason, uid],
+    ) {
+        Ok(0) => HttpResponse::NotFound().body("User not found"),
+        Ok(_) => {
+            terminate_live_connections(&conn, &state, uid, "Account suspended");
+            info!("User {} suspended until {}", uid, until);
+            HttpResponse::Ok().json(serde_json::json!({
+                "success": true,
+                "user_id": uid,
+                "suspended_until": until
+            }))
+        }
+        Err(e) => HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
+    }
+}
+
+// Admin API for permanently banning a user: POST /admin/users/{user_id}/ban
+// Sets the account to banned and records the email so it cannot re-register
+// Ends the user's sessions and WebSocket connection
+pub async fn ban_user(
+    req: HttpRequest,
+    user_id: web::Path<i32>,
+    data: web::Json<BanRequest>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    if !is_admin(&req) {
+        return admin_required();
+    }
+    let uid = user_id.into_inner();
+    let conn = state.db_conn.lock().unwrap();
+    let email: String = match conn.query_row(
+        "SELECT email FROM profiles WHERE user_id = ?1",
+        params![uid],
+        |row| row.get(0),
+    ) {
+        Ok(e) => e,
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return HttpResponse::NotFound().body("User not found");
+        }
+        Err(e) => return HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
+    };
+    let ts = Utc::now().timestamp_millis();
+    let tx = match conn.unchecked_transaction() {
+        Ok(tx) => tx,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
+    };
+    let res = tx
+        .execute(
+            "UPDATE profiles SET account_status = 'banned', suspended_until = NULL, moderation_reason = ?1
+             WHERE user_id = ?2",
+            params![data.reason, uid],
+        )
+        .and_then(|_| {
+            tx.execute(
+                "INSERT INTO banned_emails (email, reason, timestamp) VALUES (?1, ?2, ?3)
+                 ON CONFLICT(email) DO UPDATE SET reason = ?2, timestamp = ?3",
+                params![email.to_lowercase(), data.reason, ts],
+            )
+        });
+    if let Err(e) = res.and_then(|_| tx.commit()) {
+        return HttpResponse::InternalServerError().body(format!("DB error: {}", e));
+    }
+    terminate_live_connections(&conn, &state, uid, "Account banned");
+    info!("User {} ({}) banned", uid, email);
+    HttpResponse::Ok().json(serde_json::json!({
+        "success": true,
+        "user_id": uid,
+        "message": "User banned"
+    }))
+}
+
+// Admin API for lifting a suspension or ban: POST /admin/users/{user_id}/reinstate
+// Sets the account back to active and allows the email to register again
+pub async fn reinstate_user(
+    req: HttpRequest,
+    user_id: web::Path<i32>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    if !is_admin(&req) {
+        return admin_required();
+    }
+    let uid = user_id.into_inner();
+    let conn = state.db_conn.lock().unwrap();
+    let res = conn.execute(
+        "UPDATE profiles SET account_status = 'active', suspended_until = NULL, moderation_reason = NULL
+         WHERE user_id = ?1",
+        params![uid],
+    );
+    match res {
+        Ok(0) => HttpResponse::NotFound().body("User not found"),
+        Ok(_) => {
+            let _ = conn.execute(
+                "DELETE FROM banned_emails WHERE email = (SELECT LOWER(email) FROM profiles WHERE user_id = ?1)",
+                params![uid],
+            );
+            info!("User {} reinstated", uid);
+            HttpResponse::Ok().json(serde_json::json!({
+                "success": true,
+                "user_id": uid,
+                "message": "User reinstated"
+            }))
+        }
+        Err(e) => HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
+    }
+}
+
+// Admin API for checking an account's moderation state: GET /admin/users/{user_id}/status
+// Returns the stored status along with how many reports the user has received
+pub async fn get_account_status(
+    req: HttpRequest,
+    user_id: web::Path<i32>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    if !is_admin(&req) {
+        return admin_required();
+    }
+    let uid = user_id.into_inner();
+    let conn = state.db_conn.lock().unwrap();
+    let result = conn.query_row(
+        "SELECT account_status, suspended_until, moderation_reason, deletion_due_at,
+                (SELECT COUNT(*) FROM reports WHERE reported_user_id = ?1)
+         FROM profiles WHERE user_id = ?1",
+        params![uid],
+        |row| {
+            Ok(serde_json::json!({
+                "user_id": uid,
+                "account_status": row.get::<_, String>(0)?,
+                "suspended_until": row.get::<_, Option<i64>>(1)?,
+                "reason": row.get::<_, Option<String>>(2)?,
+                "deletion_due_at": row.get::<_, Option<i64>>(3)?,
+                "report_count": row.get::<_, i64>(4)?,
+            }))
+        },
+    );
+    match result {
+        Ok(v) => HttpResponse::Ok().json(v),
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            HttpResponse::NotFound().body("User not found")
+        }
+        Err(_) => HttpResponse::InternalServerError().body("Database error"),
+    }
+}
@@ -0,0 +1,246 @@
+/*
+Name: JayMatch onboarding
+Description: Scores how complete each profile is, lists the onboarding steps a user has left (R29), and verifies users' email addresses
+Pre/Post Conditions: The profiles, profile_interests, prompt_answers, preferences and email_verifications tables must exist. Each step is a SQL condition on a profiles row, so the checklist and the queue use the same definition of complete.
+Errors: Missing sessions return 401, unknown verification links return 404, expired ones return 410, database errors return 500.
+*/
+
+use crate::{AppState, sessions};
+use actix_web::{HttpRequest, HttpResponse, Responder, web};
+use chrono::Utc;
+use log::info;
+use rusqlite::{Connection, params};
+use serde::Serialize;
+
+// Profiles scoring below this are shown after everyone else in queues
+pub const INCOMPLETE_SCORE: i64 = 40;
+
+// How long an email verification link stays valid (24 hours in milliseconds)
+pub const VERIFICATION_TTL_MS: i64 = 24 * 60 * 60 * 1000;
+
+// Structure for one onboarding step
+// condition is a SQL expression on a profiles row that is true once the step is done
+pub struct Step {
+    pub key: &'static str,
+    pub label: &'static str,
+    pub weight: i64,
+    pub condition: &'static str,
+}
+
+// Onboarding steps in the order the client should show them
+// Weights add up to 100, so a profile's score is a percentage
+pub const STEPS: &[Step] = &[
+    Step {
+        key: "photo",
+        label: "Add a profile photo",
+        weight: 25,
+        condition: "(profiles.profile_picture IS NOT NULL AND profiles.profile_picture != '')",
+    },
+    Step {
+        key: "bio",
+        label: "Write a bio of at least 50 characters",
+        weight: 15,
+        condition: "(length(trim(profiles.bio)) >= 50)",
+    },
+    Step {
+        key: "interests",
+        label: "List at least 3 interests",
+        weight: 15,
+        condition: "((SELECT COUNT(*) FROM profile_interests WHERE profile_interests.user_id = profiles.user_id) >= 3)",
+    },
+    Step {
+        key: "prompts",
+        label: "Answer a prompt",
+        weight: 15,
+        condition: "EXISTS (SELECT 1 FROM prompt_answers WHERE prompt_answers.user_id = profiles.user_id)",
+    },
+    Step {
+        key: "preferences",
+        label: "Set your match preferences",
+        weight: 10,
+        condition: "EXISTS (SELECT 1 FROM preferences WHERE preferences.user_id = profiles.user_id)",
+    },
+    Step {
+        key: "email_verified",
+        label: "Verify your email address",
+        weight: 20,
+        condition: "(profiles.email_verified_at IS NOT NULL)",
+    },
+];
+
+// Structure for a step in the onboarding checklist
+#[derive(Serialize, Debug, Clone)]
+pub struct StepStatus {
+    pub key: &'static str,
+    pub label: &'static str,
+    pub weight: i64,
+    pub done: bool,
+}
+
+// Helper function for the response sent when the caller has no session
+fn login_required() -> HttpResponse {
+    HttpResponse::Unauthorized().json(serde_json::json!({
+        "success": false,
+        "message": "Login required"
+    }))
+}
+
+// Function for the SQL expression giving a profiles row's completeness score from 0 to 100
+pub fn score_sql() -> String {
+    let terms: Vec<String> = STEPS
+        .iter()
+        .map(|s| format!("CASE WHEN {} THEN {} ELSE 0 END", s.condition, s.weight))
+        .collect();
+    format!("({})", terms.join(" + "))
+}
+
+// Function for checking each onboarding step for a user
+pub fn checklist(conn: &Connection, user_id: i32) -> rusqlite::Result<Vec<StepStatus>> {
+    let columns: Vec<String> = STEPS
+        .iter()
+        .map(|s| format!("COALESCE({}, 0)", s.condition))
+        .collect();
+    conn.query_row(
+        &format!(
+            "SELECT {} FROM profiles WHERE user_id = ?1",
+            columns.join(", ")
+        ),
+        params![user_id],
+        |row| {
+            STEPS
+                .iter()
+                .enumerate()
+                .map(|(i, s)| {
+                    Ok(StepStatus {
+                        key: s.key,
+                        label: s.label,
+                        weight: s.weight,
+                        done: row.get(i)?,
+                    })
+                })
+                .collect()
+        },
+    )
+}
+
+// API for the logged in user's onboarding checklist: GET /me/onboarding
+// Requires the Authorization: Bearer session token
+// Returns the completeness score, every step with whether it is done, and the keys of the steps left
+pub async fn get_onboarding(req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    let conn = state.db_conn.lock().unwrap();
+    let uid = match sessions::session_user(&conn, &req) {
+        Some(uid) => uid,
+        None => return login_required(),
+    };
+    match checklist(&conn, uid) {
+        Ok(steps) => {
+            let score: i64 = steps.iter().filter(|s| s.done).map(|s| s.weight).sum();
+            let remaining: Vec<&str> = steps.iter().filter(|s| !s.done).map(|s| s.key).collect();
+            HttpResponse::Ok().json(serde_json::json!({
+                "user_id": uid,
+                "score": score,
+                "complete": remaining.is_empty(),
+                "steps": steps,
+                "remaining": remaining
+            }))
+        }
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            HttpResponse::NotFound().body("Profile not found")
+        }
+        Err(_) => HttpResponse::InternalServerError().body("Database error"),
+    }
+}
+
+// API for asking for an email verification link: POST /me/email-verification
+// Requires the Authorization: Bearer session token
+// There is no mail service yet, so the link is written to the server log for the address
+// Returns 202 once the link is created, or 200 if the address is already verified
+pub async fn request_verification(req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    let conn = state.db_conn.lock().unwrap();
+    let uid = match sessions::session_user(&conn, &req) {
+        Some(uid) => uid,
+        None => return login_required(),
+    };
+    let profile = conn.query_row(
+        "SELECT email, email_verified_at FROM profiles WHERE user_id = ?1",
+        params![uid],
+        |row| Ok((row.get::<_, String>(0)?, row.get::<_, Option<i64>>(1)?)),
+    );
+    let email = match profile {
+        Ok((_, Some(_))) => {
+            return HttpResponse::Ok().json(serde_json::json!({
+                "success": true,
+                "verified": true
+            }));
+        }
+        Ok((email, None)) => email,
+        Err(_) => return HttpResponse::InternalServerError().body("Database error"),
+    };
+    let token = uuid::Uuid::new_v4().simple().to_string();
+    let now = Utc::now().timestamp_millis();
+    if let Err(e) = conn.execute(
+        "INSERT INTO email_verifications (token, user_id, email, created_at, expires_at)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![token, uid, email, now, now + VERIFICATION_TTL_MS],
+    ) {
+        return HttpResponse::InternalServerError().body(format!("DB error: {}", e));
+    }
+    info!(
+        "Email verification link for {}: /verify-email/{}",
+        email, token
+    );
+    HttpResponse::Accepted().json(serde_json::json!({
+        "success": true,
+        "verified": false,
+        "message": format!("Verification link sent to {}", email)
+    }))
+}
+
+// API for following an email verification link: GET /verify-email/{token}
+// The link only verifies the address it was sent to, so it stops working if the email is changed
+// Removes the user's other links once used
+pub async fn verify_email(token: web::Path<String>, state: web::Data<AppState>) -> impl Responder {
+    let conn = state.db_conn.lock().unwrap();
+    let now = Utc::now().timestamp_millis();
+    let found = conn.query_row(
+        "SELECT v.user_id, v.expires_at FROM email_verifications v
+         JOIN profiles p ON p.user_id = v.user_id AND p.email = v.email
+         WHERE v.token = ?1",
+        params![token.as_str()],
+        |row| Ok((row.get::<_, i32>(0)?, row.get::<_, i64>(1)?)),
+    );
+    let uid = match found {
+        Ok((uid, expires_at)) if expires_at > now => uid,
+        Ok(_) => {
+            return HttpResponse::Gone().json(serde_json::json!({
+                "success": false,
+                "message": "Verification link has expired"
+            }));
+        }
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return HttpResponse::NotFound().json(serde_json::json!({
+                "success": false,
+                "message": "Verification link not found"
+            }));
+        }
+        Err(_) => return HttpResponse::InternalServerError().body("Database error"),
+    };
+    let result = conn.unchecked_transaction().and_then(|tx| {
+        tx.execute(
+            "UPDATE profiles SET email_verified_at = ?1 WHERE user_id = ?2",
+            params![now, uid],
+        )?;
+        tx.execute(
+            "DELETE FROM email_verifications WHERE user_id = ?1",
+            params![uid],
+        )?;
+        tx.commit()
+    });
+    match result {
+        Ok(_) => {
+            info!("User {} verified their email", uid);
+            HttpResponse::Ok().json(serde_json::json!({"success": true, "verified": true}))
+        }
+        Err(e) => HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
+    }
+}
@@ -0,0 +1,263 @@
+/*
+Name: JayMatch payment providers
+Description: Talks to the payment provider that sells premium subscriptions and one-off purchases such as boosts, and checks the signatures on the webhooks it sends (R26)
+Pre/Post Conditions: The provider is chosen once at startup from environment variables. Only a local fake provider exists so far; it keeps its checkouts in memory and signs its webhooks with the same secret used to verify them.
+Errors: Provider calls return a message for the client when they fail; webhooks with a missing, stale or wrong signature are rejected.
+*/
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use log::{info, warn};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+// Header carrying a webhook's signature, in the form t=<timestamp ms>,v1=<hex HMAC-SHA256 of "t.body">
+pub const SIGNATURE_HEADER: &str = "x-jaymatch-signature";
+
+// Oldest webhook signature accepted, to stop old webhooks being replayed (5 minutes in milliseconds)
+pub const SIGNATURE_TOLERANCE_MS: i64 = 5 * 60 * 1000;
+
+// Webhook secret used when PAYMENT_WEBHOOK_SECRET is not set
+const DEFAULT_WEBHOOK_SECRET: &str = "jaymatch-dev-webhook-secret";
+
+// Plans that can be bought, with their price in cents and how many days each payment lasts
+pub const PLANS: &[(&str, i64, i64)] =
+    &[("premium_monthly", 999, 30), ("premium_yearly", 5999, 365)];
+
+// One-off products that can be bought, with their price in cents and how many of the item each gives
+pub const PRODUCTS: &[(&str, i64, i64)] = &[("boost_1", 299, 1), ("boost_5", 999, 5)];
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Structure for a checkout started with a provider
+// The client sends the user to url to pay
+#[derive(Clone, Debug)]
+pub struct Checkout {
+    pub id: String,
+    pub url: String,
+}
+
+// Structure for a webhook body and the signature header that goes with it
+#[derive(Clone, Debug)]
+pub struct SignedWebhook {
+    pub body: String,
+    pub signature: String,
+}
+
+// Interface every payment provider implements
+// Subscription changes reach the server as webhooks, never as return values
+pub trait PaymentProvider: Send + Sync {
+    // Name stored with each subscription
+    fn name(&self) -> &'static str;
+    // Starts a checkout for a user buying a plan or a product
+    fn create_checkout(&self, user_id: i32, item: &str) -> Result<Checkout, String>;
+    // Stops a subscription from renewing
+    fn cancel(&self, subscription_id: &str) -> Result<(), String>;
+    // Checks a webhook's signature header against its raw body
+    fn verify_webhook(&self, body: &[u8], signature: &str) -> bool;
+    // The fake provider, for the endpoints that simulate payments; real providers return None
+    fn simulator(&self) -> Option<&FakeProvider> {
+        None
+    }
+}
+
+// Helper function for looking up a plan's price in cents and length in days
+pub fn plan_terms(plan: &str) -> Option<(i64, i64)> {
+    PLANS
+        .iter()
+        .find(|(name, _, _)| *name == plan)
+        .map(|(_, price, days)| (*price, *days))
+}
+
+// Helper function for looking up a product's price in cents and how many of the item it gives
+pub fn product_terms(product: &str) -> Option<(i64, i64)> {
+    PRODUCTS
+        .iter()
+        .find(|(name, _, _)| *name == product)
+        .map(|(_, price, quantity)| (*price, *quantity))
+}
+
+// Helper function for signing a webhook body at a time
+fn signature_for(secret: &str, ts: i64, body: &str) -> String {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}.{}", ts, body).as_bytes());
+    format!("t={},v1={}", ts, hex::encode(mac.finalize().into_bytes()))
+}
+
+// Helper function for checking a signature header made by signature_for
+// Compares in constant time and rejects signatures older than SIGNATURE_TOLERANCE_MS
+fn signature_matches(secret: &str, body: &[u8], header: &str) -> bool {
+    let mut ts = None;
+    let mut sig = None;
+    for part in header.split(',') {
+        match part.trim().split_once('=') {
+            Some(("t", v)) => ts = v.parse::<i64>().ok(),
+            Some(("v1", v)) => sig = hex::decode(v).ok(),
+            _ => {}
+        }
+    }
+    let (Some(ts), Some(sig)) = (ts, sig) else {
+        return false;
+    };
+    if (Utc::now().timestamp_millis() - ts).abs() > SIGNATURE_TOLERANCE_MS {
+        return false;
+    }
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}.", ts).as_bytes());
+    mac.update(body);
+    mac.verify_slice(&sig).is_ok()
+}
+
+// Payment provider that runs inside the server, for development and tests
+// Nothing is charged; the simulate functions build the signed webhooks a real provider would send
+pub struct FakeProvider {
+    secret: String,
+    checkouts: Mutex<HashMap<String, (i32, String)>>,
+}
+
+impl FakeProvider {
+    pub fn new(secret: &str) -> Self {
+        Self {
+            secret: secret.to_string(),
+            checkouts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Signs a webhook body the way the provider would
+    pub fn sign(&self, body: &str) -> String {
+        signature_for(&self.secret, Utc::now().timestamp_millis(), body)
+    }
+
+    // Helper function for building a signed event
+    fn event(&self, kind: &str, data: serde_json::Value) -> SignedWebhook {
+        let body = serde_json::json!({
+            "id": format!("fake_evt_{}", uuid::Uuid::new_v4().simple()),
+            "type": kind,
+            "created": Utc::now().timestamp_millis(),
+            "data": data
+        })
+        .to_string();
+        SignedWebhook {
+            signature: self.sign(&body),
+            body,
+        }
+    }
+
+    // Simulates the user paying for a checkout
+    // Sends checkout.completed with a new subscription whose first period starts now for plans,
+    // or purchase.completed for products
+    pub fn complete_checkout(&self, checkout_id: &str) -> Result<SignedWebhook, String> {
+        let (user_id, item) = self
+            .checkouts
+            .lock()
+            .unwrap()
+            .remove(checkout_id)
+            .ok_or_else(|| format!("Checkout {} not found", checkout_id))?;
+        if product_terms(&item).is_some() {
+            return Ok(self.event(
+                "purchase.completed",
+                serde_json::json!({
+                    "checkout_id": checkout_id,
+                    "user_id": user_id,
+                    "product": item
+                }),
+            ));
+        }
+        let plan = item;
+        let (_, days) = plan_terms(&plan).ok_or_else(|| format!("Unknown plan {}", plan))?;
+        Ok(self.event(
+            "checkout.completed",
+            serde_json::json!({
+                "checkout_id": checkout_id,
+                "subscription_id": format!("fake_sub_{}", uuid::Uuid::new_v4().simple()),
+                "user_id": user_id,
+                "plan": plan,
+                "current_period_end": Utc::now().timestamp_millis() + days * 24 * 60 * 60 * 1000
+            }),
+        ))
+    }
+
+    // Simulates a successful renewal payment, extending the subscription to current_period_end
+    pub fn renew(&self, subscription_id: &str, current_period_end: i64) -> SignedWebhook {
+        self.event(
+            "subscription.renewed",
+            serde_json::json!({
+                "subscription_id": subscription_id,
+                "current_period_end": current_period_end
+            }),
+        )
+    }
+
+    // Simulates a renewal payment being declined
+    pub fn fail_payment(&self, subscription_id: &str) -> SignedWebhook {
+        self.event(
+            "payment.failed",
+            serde_json::json!({ "subscription_id": subscription_id }),
+        )
+    }
+
+    // Simulates the provider confirming that a subscription will not renew
+    pub fn cancellation(&self, subscription_id: &str) -> SignedWebhook {
+        self.event(
+            "subscription.canceled",
+            serde_json::json!({ "subscription_id": subscription_id }),
+        )
+    }
+}
+
+impl PaymentProvider for FakeProvider {
+    fn name(&self) -> &'static str {
+        "fake"
+    }
+
+    fn create_checkout(&self, user_id: i32, item: &str) -> Result<Checkout, String> {
+        if plan_terms(item).is_none() && product_terms(item).is_none() {
+            return Err(format!("Unknown plan or product {}", item));
+        }
+        let id = format!("fake_cs_{}", uuid::Uuid::new_v4().simple());
+        self.checkouts
+            .lock()
+            .unwrap()
+            .insert(id.clone(), (user_id, item.to_string()));
+        Ok(Checkout {
+            url: format!("/payments/fake/checkouts/{}/complete", id),
+            id,
+        })
+    }
+
+    fn cancel(&self, subscription_id: &str) -> Result<(), String> {
+        if subscription_id.starts_with("fake_sub_") {
+            Ok(())
+        } else {
+            Err(format!("Subscription {} not found", subscription_id))
+        }
+    }
+
+    fn verify_webhook(&self, body: &[u8], signature: &str) -> bool {
+        signature_matches(&self.secret, body, signature)
+    }
+
+    fn simulator(&self) -> Option<&FakeProvider> {
+        Some(self)
+    }
+}
+
+// Function for choosing the payment provider from environment variables
+// PAYMENT_PROVIDER selects the provider (only fake exists) and PAYMENT_WEBHOOK_SECRET signs its webhooks
+pub fn provider_from_env() -> Arc<dyn PaymentProvider> {
+    let provider = std::env::var("PAYMENT_PROVIDER").unwrap_or_else(|_| "fake".to_string());
+    if !provider.eq_ignore_ascii_case("fake") {
+        warn!(
+            "Unknown payment provider {}, using the fake provider",
+            provider
+        );
+    }
+    let secret = std::env::var("PAYMENT_WEBHOOK_SECRET").unwrap_or_else(|_| {
+        info!("PAYMENT_WEBHOOK_SECRET not set, using the development secret");
+        DEFAULT_WEBHOOK_SECRET.to_string()
+    });
+    Arc::new(FakeProvider::new(&secret))
+}
@@ -0,0 +1,809 @@
+/*
+Name: JayMatch profile photos
+Description: Lets each user keep several ordered profile photos with one marked as the primary photo
+Pre/Post Conditions: The profile_photos table must exist. profiles.profile_picture always holds the primary photo's media key so queues and match lists can show it without another query.
+Errors: Unknown photos and photos of users hidden from the viewer return 404, going over the photo limit or sending a bad order returns 400, restricted accounts receive 403, database errors return 500.
+*/
+
+use crate::images::{self, ImageError};
+use crate::media::{MediaStore, ObjectReader};
+use crate::{AppState, blocks, deletion, moderation, sessions, visibility};
+use actix_multipart::{Field, Multipart};
+use actix_web::{HttpRequest, HttpResponse, Responder, http::header, web};
+use chrono::Utc;
+use futures_util::stream::StreamExt;
+use log::{info, warn};
+use rusqlite::{Connection, params};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// Most photos a single user can have
+pub const MAX_PHOTOS: i64 = 6;
+
+// Default local directory uploads are streamed to before they are checked and sent to the media store
+// Can be changed with the UPLOAD_STAGING_DIR environment variable
+pub const DEFAULT_STAGING_DIR: &str = "uploads/staging";
+
+// Media key prefix for stored photos
+pub const KEY_PREFIX: &str = "profile_pictures";
+
+// Structure for a stored profile photo
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Photo {
+    pub id: i64,
+    pub position: i64,
+    pub is_primary: bool,
+    pub url: String,
+    pub thumbnails: BTreeMap<String, String>, // Thumbnail URLs by size name
+}
+
+// Structure for reordering a user's photos
+// photo_ids must list every photo the user has, in the new order
+#[derive(Deserialize)]
+pub struct PhotoOrder {
+    pub photo_ids: Vec<i64>,
+}
+
+// Default cap on the size of an uploaded image (10 MB)
+// Can be changed with the MAX_UPLOAD_BYTES environment variable
+pub const DEFAULT_MAX_UPLOAD_BYTES: usize = 10 * 1024 * 1024;
+
+// Room left for multipart boundaries and the user_id field when checking Content-Length
+const MULTIPART_OVERHEAD_BYTES: usize = 16 * 1024;
+
+// Longest value accepted for a text field such as user_id
+const MAX_TEXT_FIELD_BYTES: usize = 64;
+
+// How long clients may reuse an image before revalidating it with its ETag
+// Kept short because a photo URL shows a new image when the photo is replaced
+const IMAGE_MAX_AGE_SECS: u32 = 300;
+
+// Size of each chunk streamed to the client
+const STREAM_CHUNK_BYTES: usize = 64 * 1024;
+
+// Structure for the fields read from a photo upload
+pub struct Upload {
+    pub user_id: Option<i32>,
+    pub media_key: Option<String>,
+}
+
+// Error from reading a photo upload
+pub enum UploadError {
+    Invalid(String),
+    TooLarge(usize),
+    Internal(String),
+}
+
+impl UploadError {
+    // Helper function for the response sent for a failed upload
+    pub fn response(&self) -> HttpResponse {
+        match self {
+            UploadError::Invalid(message) => HttpResponse::BadRequest().json(serde_json::json!({
+                "success": false,
+                "message": message
+            })),
+            UploadError::TooLarge(limit) => {
+                HttpResponse::PayloadTooLarge().json(serde_json::json!({
+                    "success": false,
+                    "message": format!("Image must be at most {} bytes", limit),
+                    "max_bytes": limit
+                }))
+            }
+            UploadError::Internal(message) => {
+                warn!("Upload failed: {}", message);
+                HttpResponse::InternalServerError().body("Error storing image")
+            }
+        }
+    }
+}
+
+impl From<ImageError> for UploadError {
+    fn from(e: ImageError) -> Self {
+        match e {
+            ImageError::Invalid(message) => UploadError::Invalid(message),
+            ImageError::Storage(message) => UploadError::Internal(message),
+        }
+    }
+}
+
+// Helper function for reading the upload size cap from the MAX_UPLOAD_BYTES environment variable
+// Falls back to DEFAULT_MAX_UPLOAD_BYTES when unset or invalid
+pub fn max_upload_bytes_from_env() -> usize {
+    std::env::var("MAX_UPLOAD_BYTES")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(DEFAULT_MAX_UPLOAD_BYTES)
+}
+
+// Helper function for reading the staging directory from the UPLOAD_STAGING_DIR environment variable
+// Falls back to DEFAULT_STAGING_DIR when unset or empty
+pub fn staging_dir_from_env() -> PathBuf {
+    std::env::var("UPLOAD_STAGING_DIR")
+        .ok()
+        .filter(|d| !d.is_empty())
+        .unwrap_or_else(|| DEFAULT_STAGING_DIR.to_string())
+        .into()
+}
+
+// Helper function for the error returned when the multipart body cannot be parsed
+fn malformed(e: impl std::fmt::Display) -> UploadError {
+    UploadError::Invalid(format!("Malformed multipart body: {}", e))
+}
+
+// Helper function for reading a small text field into a string
+// Stops reading as soon as the field is longer than MAX_TEXT_FIELD_BYTES
+async fn read_text_field(field: &mut Field) -> Result<String, UploadError> {
+    let mut data = Vec::new();
+    while let Some(chunk) = field.next().await {
+        data.extend_from_slice(&chunk.map_err(malformed)?);
+        if data.len() > MAX_TEXT_FIELD_BYTES {
+            return Err(UploadError::Invalid("Form field is too long".to_string()));
+        }
+    }
+    String::from_utf8(data).map_err(|_| UploadError::Invalid("Form field is not text".to_string()))
+}
+
+// Helper function for streaming a file field to disk
+// Stops as soon as more than max_bytes arrive and removes the partial file on any failure
+async fn stream_to_file(
+    field: &mut Field,
+    path: &Path,
+    max_bytes: usize,
+) -> Result<(), UploadError> {
+    let result = async {
+        let mut f = fs::File::create(path).map_err(|e| UploadError::Internal(e.to_string()))?;
+        let mut written = 0usize;
+        while let Some(chunk) = field.next().await {
+            let data = chunk.map_err(malformed)?;
+            written += data.len();
+            if written > max_bytes {
+                return Err(UploadError::TooLarge(max_bytes));
+            }
+            f.write_all(&data)
+                .map_err(|e| UploadError::Internal(e.to_string()))?;
+        }
+        Ok(())
+    }
+    .await;
+    if result.is_err() {
+        let _ = fs::remove_file(path);
+    }
+    result
+}
+
+// Helper function for reading each multipart field into upload
+// Any image stored before an error is left in upload for the caller to remove
+async fn read_fields(
+    payload: &mut Multipart,
+    max_bytes: usize,
+    staging_dir: &Path,
+    store: &Arc<dyn MediaStore>,
+    upload: &mut Upload,
+) -> Result<(), UploadError> {
+    fs::create_dir_all(staging_dir).map_err(|e| UploadError::Internal(e.to_string()))?;
+    while let Some(item) = payload.next().await {
+        let mut field = item.map_err(malformed)?;
+        let field_name = field
+            .content_disposition()
+            .and_then(|cd| cd.get_name())
+            .unwrap_or("")
+            .to_string();
+        if field_name == "user_id" {
+            let text = read_text_field(&mut field).await?;
+            upload.user_id = Some(
+                text.trim()
+                    .parse()
+                    .map_err(|_| UploadError::Invalid("user_id must be a number".to_string()))?,
+            );
+        } else if field_name == "image" {
+            // The client's filename is ignored since every image is stored as JPEG
+            let name = uuid::Uuid::new_v4().simple().to_string();
+            let raw_path = staging_dir.join(format!("{}.upload", name));
+            stream_to_file(&mut field, &raw_path, max_bytes).await?;
+            let bytes = fs::read(&raw_path).map_err(|e| UploadError::Internal(e.to_string()));
+            let _ = fs::remove_file(&raw_path);
+            let bytes = bytes?;
+            let key = format!("{}/{}.jpg", KEY_PREFIX, name);
+            let stored_key = key.clone();
+            let block_store = store.clone();
+            web::block(move || images::process_upload(&bytes, &stored_key, block_store.as_ref()))
+                .await
+                .map_err(|e| UploadError::Internal(e.to_string()))??;
+            if let Some(previous) = upload.media_key.replace(key) {
+                images::remove_images(store.clone(), vec![previous]).await;
+            }
+        } else {
+            // Unknown fields are read and thrown away so the rest of the body can be parsed
+            let mut skipped = 0usize;
+            while let Some(chunk) = field.next().await {
+                skipped += chunk.map_err(malformed)?.len();
+                if skipped > max_bytes {
+                    return Err(UploadError::TooLarge(max_bytes));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Helper function for reading a multipart photo upload
+// Expects a user_id field and an image file field
+// Rejects bodies over max_bytes with 413, using Content-Length when the client sends it
+// and counting bytes as they stream in otherwise
+// Validates the image and stores it as a JPEG with thumbnails in the media store
+// Removes anything already stored if the upload fails
+pub async fn read_upload(
+    req: &HttpRequest,
+    mut payload: Multipart,
+    state: &AppState,
+) -> Result<Upload, UploadError> {
+    let max_bytes = state.max_upload_bytes;
+    let declared = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if let Some(length) = declared
+        && length > max_bytes + MULTIPART_OVERHEAD_BYTES
+    {
+        return Err(UploadError::TooLarge(max_bytes));
+    }
+    let mut upload = Upload {
+        user_id: None,
+        media_key: None,
+    };
+    if let Err(e) = read_fields(
+        &mut payload,
+        max_bytes,
+        &state.staging_dir,
+        &state.media,
+        &mut upload,
+    )
+    .await
+    {
+        images::remove_images(state.media.clone(), upload.media_key.into_iter().collect()).await;
+        return Err(e);
+    }
+    Ok(upload)
+}
+
+// Helper function for copying the primary photo's key onto the profile
+// Clears profile_picture when the user has no photos left
+fn sync_primary(conn: &Connection, user_id: i32) -> rusqlite::Result<()> {
+    conn.execute(
+        "UPDATE profiles SET profile_picture =
+            (SELECT media_key FROM profile_photos WHERE user_id = ?1 AND is_primary = 1)
+         WHERE user_id = ?1",
+        params![user_id],
+    )?;
+    Ok(())
+}
+
+// Helper function for renumbering a user's photos as 0, 1, 2, ... in their current order
+// Makes the first photo primary if none is
+fn normalize_photos(conn: &Connection, user_id: i32) -> rusqlite::Result<()> {
+    let ids: Vec<i64> = conn
+        .prepare("SELECT id FROM profile_photos WHERE user_id = ?1 ORDER BY position, id")?
+        .query_map(params![user_id], |row| row.get(0))?
+        .filter_map(|r| r.ok())
+        .collect();
+    for (i, id) in ids.iter().enumerate() {
+        conn.execute(
+            "UPDATE profile_photos SET position = ?1 WHERE id = ?2",
+            params![i as i64, id],
+        )?;
+    }
+    let has_primary: bool = conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM profile_photos WHERE user_id = ?1 AND is_primary = 1)",
+        params![user_id],
+        |row| row.get(0),
+    )?;
+    if !has_primary && let Some(first) = ids.first() {
+        conn.execute(
+            "UPDATE profile_photos SET is_primary = 1 WHERE id = ?1",
+            params![first],
+        )?;
+    }
+    sync_primary(conn, user_id)
+}
+
+// Helper function for adding an uploaded photo to the end of a user's photos
+// The first photo a user adds becomes their primary photo, as does any photo added with make_primary
+// Returns the new photo's ID, or None if the user already has MAX_PHOTOS photos
+pub fn add_photo(
+    conn: &Connection,
+    user_id: i32,
+    media_key: &str,
+    make_primary: bool,
+) -> rusqlite::Result<Option<i64>> {
+    let count: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM profile_photos WHERE user_id = ?1",
+        params![user_id],
+        |row| row.get(0),
+    )?;
+    if count >= MAX_PHOTOS {
+        return Ok(None);
+    }
+    let tx = conn.unchecked_transaction()?;
+    if make_primary {
+        tx.execute(
+            "UPDATE profile_photos SET is_primary = 0 WHERE user_id = ?1",
+            params![user_id],
+        )?;
+    }
+    tx.execute(
+        "INSERT INTO profile_photos (user_id, media_key, position, is_primary, created_at)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            user_id,
+            media_key,
+            count,
+            make_primary || count == 0,
+            Utc::now().timestamp_millis()
+        ],
+    )?;
+    let id = tx.last_insert_rowid();
+    sync_primary(&tx, user_id)?;
+    tx.commit()?;
+    Ok(Some(id))
+}
+
+// Helper function for replacing the image behind a user's primary photo
+// Adds the photo as primary if the user has no photos yet
+// Returns the replaced key so the caller can remove it, or None if nothing was replaced
+pub fn replace_primary(
+    conn: &Connection,
+    user_id: i32,
+    media_key: &str,
+) -> rusqlite::Result<Option<String>> {
+    let old: Option<String> = match conn.query_row(
+        "SELECT media_key FROM profile_photos WHERE user_id = ?1 AND is_primary = 1",
+        params![user_id],
+        |row| row.get(0),
+    ) {
+        Ok(p) => Some(p),
+        Err(rusqlite::Error::QueryReturnedNoRows) => None,
+        Err(e) => return Err(e),
+    };
+    if old.is_none() {
+        add_photo(conn, user_id, media_key, true)?;
+        return Ok(None);
+    }
+    let tx = conn.unchecked_transaction()?;
+    tx.execute(
+        "UPDATE profile_photos SET media_key = ?1, created_at = ?2 WHERE user_id = ?3 AND is_primary = 1",
+        params![media_key, Utc::now().timestamp_millis(), user_id],
+    )?;
+    sync_primary(&tx, user_id)?;
+    tx.commit()?;
+    Ok(old)
+}
+
+// Helper function for listing a user's photos in display order
+pub fn list_photos(conn: &Connection, user_id: i32) -> rusqlite::Result<Vec<Photo>> {
+    conn.prepare(
+        "SELECT id, position, is_primary FROM profile_photos WHERE user_id = ?1 ORDER BY position, id",
+    )?
+    .query_map(params![user_id], |row| {
+        let id: i64 = row.get(0)?;
+        Ok(Photo {
+            id,
+            position: row.get(1)?,
+            is_primary: row.get(2)?,
+            url: format!("/photos/{}", id),
+            thumbnails: images::THUMBNAIL_SIZES
+                .iter()
+                .map(|(size, _)| (size.to_string(), format!("/photos/{}?size={}", id, size)))
+                .collect(),
+        })
+    })?
+    .collect()
+}
+
+// Helper function for listing the media keys of a user's photos
+// Used when deleting or exporting an account
+pub fn photo_keys(conn: &Connection, user_id: i32) -> Vec<String> {
+    match conn.prepare("SELECT media_key FROM profile_photos WHERE user_id = ?1 ORDER BY position")
+    {
+        Ok(mut stmt) => stmt
+            .query_map(params![user_id], |row| row.get(0))
+            .map(|rows| rows.filter_map(|r| r.ok()).collect())
+            .unwrap_or_default(),
+        Err(_) => Vec::new(),
+    }
+}
+
+// Helper function for the response sent when a user is at the photo limit
+pub fn too_many_photos() -> HttpResponse {
+    HttpResponse::BadRequest().json(serde_json::json!({
+        "success": false,
+        "message": format!("A profile can have at most {} photos", MAX_PHOTOS)
+    }))
+}
+
+// API for adding a photo: POST /users/{user_id}/photos
+// Expects multipart form data with an image file
+// Rejects malformed bodies and invalid images with 400 and oversized images with 413
+// Adds the photo after the user's existing photos
+pub async fn upload_photo(
+    req: HttpRequest,
+    user_id: web::Path<i32>,
+    payload: Multipart,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let uid = user_id.into_inner();
+    let key = match read_upload(&req, payload, &state).await {
+        Ok(Upload {
+            media_key: Some(k), ..
+        }) => k,
+        Ok(_) => return HttpResponse::BadRequest().body("Missing image"),
+        Err(e) => return e.response(),
+    };
+    // The image is removed after the lock is released if it was not added
+    let (response, added) = {
+        let conn = state.db_conn.lock().unwrap();
+        if let Some(r) = moderation::account_restriction(&conn, uid) {
+            (moderation::restricted_response(&r), false)
+        } else {
+            match add_photo(&conn, uid, &key, false) {
+                Ok(Some(id)) => {
+                    info!("User {} added photo {}", uid, id);
+                    (
+                        HttpResponse::Ok().json(serde_json::json!({
+                            "success": true,
+                            "photo_id": id,
+                            "photos": list_photos(&conn, uid).unwrap_or_default()
+                        })),
+                        true,
+                    )
+                }
+                Ok(None) => (too_many_photos(), false),
+                Err(e) => (
+                    HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
+                    false,
+                ),
+            }
+        }
+    };
+    if !added {
+        images::remove_images(state.media.clone(), vec![key]).await;
+    }
+    response
+}
+
+// Helper function for checking if a user's photos must be kept from a viewer
+// Applies the same block, pending deletion and visibility rules as GET /profiles/{user_id}
+// viewer is the user behind the request's session, or None without one
+fn hidden_from(conn: &Connection, viewer: Option<i32>, owner: i32) -> bool {
+    if viewer == Some(owner) {
+        return false;
+    }
+    blocks::hidden_from(conn, viewer, owner)
+        || deletion::deletion_due_at(conn, owner).is_some()
+        || visibility::hidden_from(conn, viewer, owner)
+}
+
+// API for listing a user's photos: GET /users/{user_id}/photos
+// Returns the photos in display order with the primary photo marked
+// Users the viewer cannot see return 404, the same as their profile
+pub async fn get_photos(
+    req: HttpRequest,
+    user_id: web::Path<i32>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let uid = user_id.into_inner();
+    let conn = state.db_conn.lock().unwrap();
+    if hidden_from(&conn, sessions::session_user(&conn, &req), uid) {
+        return HttpResponse::NotFound().body("Profile not found");
+    }
+    match list_photos(&conn, uid) {
+        Ok(photos) => HttpResponse::Ok().json(photos),
+        Err(_) => HttpResponse::InternalServerError().body("Database error"),
+    }
+}
+
+// Helper function for checking if an If-None-Match or If-Range header value names an entity tag
+// Accepts * and comma separated lists, ignoring the weak prefix as If-None-Match requires
+fn etag_matches(header: &str, etag: &str) -> bool {
+    header.split(',').map(str::trim).any(|tag| {
+        tag == "*"
+            || tag.strip_prefix("W/").unwrap_or(tag) == etag.strip_prefix("W/").unwrap_or(etag)
+    })
+}
+
+// Helper function for parsing a Range header against an object of len bytes
+// Returns Ok(None) for headers that should be ignored, such as multiple ranges or other units
+// Returns Err(()) when the range cannot be satisfied
+fn parse_range(header: &str, len: u64) -> Result<Option<(u64, u64)>, ()> {
+    let spec = match header.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return Ok(None),
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(parts) => parts,
+        None => return Ok(None),
+    };
+    let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+        // bytes=-500 is the last 500 bytes
+        (Err(_), Ok(suffix)) if start.is_empty() => {
+            if suffix == 0 {
+                return Err(());
+            }
+            (len.saturating_sub(suffix), len.saturating_sub(1))
+        }
+        (Ok(start), Err(_)) if end.is_empty() => (start, len.saturating_sub(1)),
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+        _ => return Ok(None),
+    };
+    if len == 0 || range.0 >= len {
+        return Err(());
+    }
+    Ok(Some(range))
+}
+
+// Helper function for turning a blocking reader into a response body stream
+// Each chunk is read on the blocking thread pool so slow storage never stalls a worker
+pub fn stream_reader(
+    reader: ObjectReader,
+) -> impl futures_util::Stream<Item = Result<web::Bytes, actix_web::Error>> {
+    futures_util::stream::try_unfold(reader, |mut reader| async move {
+        let (reader, chunk) = web::block(move || {
+            let mut buf = vec![0u8; STREAM_CHUNK_BYTES];
+            let n = reader.read(&mut buf)?;
+            buf.truncate(n);
+            Ok::<_, io::Error>((reader, buf))
+        })
+        .await??;
+        if chunk.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some((web::Bytes::from(chunk), reader)))
+        }
+    })
+}
+
+// Helper function for serving a stored image or one of its thumbnails
+// Falls back to the full image for photos uploaded before thumbnails existed
+// Sends an ETag and Cache-Control, answers matching If-None-Match with 304 and supports single byte ranges
+// The image is streamed from the media store rather than read into memory
+// Unknown sizes return 400
+pub async fn serve_image(
+    req: &HttpRequest,
+    store: Arc<dyn MediaStore>,
+    key: String,
+    size: Option<&String>,
+) -> HttpResponse {
+    if let Some(size) = size
+        && !images::is_thumbnail_size(size)
+    {
+        return HttpResponse::BadRequest().body("Unknown image size");
+    }
+    let thumbnail = size.map(|size| images::thumbnail_path(&key, size));
+    let lookup_store = store.clone();
+    let looked_up = web::block(move || {
+        let found = match thumbnail {
+            Some(thumb) => match lookup_store.info(&thumb) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                other => Some((thumb, other)),
+            },
+            None => None,
+        };
+        found.unwrap_or_else(|| (key.clone(), lookup_store.info(&key)))
+    })
+    .await;
+    let (key, info) = match looked_up {
+        Ok((key, Ok(info))) => (key, info),
+        Ok((_, Err(e))) if e.kind() == io::ErrorKind::NotFound => {
+            return HttpResponse::NotFound().body("Image file not found");
+        }
+        Ok((key, Err(e))) => {
+            warn!("Failed to read image {}: {}", key, e);
+            return HttpResponse::InternalServerError().body("Error reading image");
+        }
+        Err(_) => return HttpResponse::InternalServerError().body("Error reading image"),
+    };
+    let cache_control = format!("public, max-age={}", IMAGE_MAX_AGE_SECS);
+    let header_value = |name| req.headers().get(name).and_then(|v| v.to_str().ok());
+    if let Some(tags) = header_value(header::IF_NONE_MATCH)
+        && etag_matches(tags, &info.etag)
+    {
+        return HttpResponse::NotModified()
+            .insert_header((header::ETAG, info.etag))
+            .insert_header((header::CACHE_CONTROL, cache_control))
+            .finish();
+    }
+    // A Range is only honoured if the client's copy is still current
+    let range_header = header_value(header::RANGE)
+        .filter(|_| header_value(header::IF_RANGE).is_none_or(|tag| etag_matches(tag, &info.etag)));
+    let range = match range_header.map(|h| parse_range(h, info.len)) {
+        Some(Ok(range)) => range,
+        Some(Err(())) => {
+            return HttpResponse::RangeNotSatisfiable()
+                .insert_header((header::CONTENT_RANGE, format!("bytes */{}", info.len)))
+                .finish();
+        }
+        None => None,
+    };
+    let opened = web::block(move || store.open(&key, range)).await;
+    let reader = match opened {
+        Ok(Ok(reader)) => reader,
+        Ok(Err(e)) if e.kind() == io::ErrorKind::NotFound => {
+            return HttpResponse::NotFound().body("Image file not found");
+        }
+        _ => return HttpResponse::InternalServerError().body("Error reading image"),
+    };
+    let mut response = match range {
+        Some(_) => HttpResponse::PartialContent(),
+        None => HttpResponse::Ok(),
+    };
+    response
+        .content_type(info.content_type)
+        .insert_header((header::ETAG, info.etag))
+        .insert_header((header::CACHE_CONTROL, cache_control))
+        .insert_header((header::ACCEPT_RANGES, "bytes"));
+    let body_len = match range {
+        Some((start, end)) => {
+            response.insert_header((
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end, info.len),
+            ));
+            end - start + 1
+        }
+        None => info.len,
+    };
+    response
+        .no_chunking(body_len)
+        .streaming(stream_reader(reader))
+}
+
+// API for serving a photo: GET /photos/{photo_id}
+// Supports size query parameter (small, medium or large) to get a thumbnail
+// Supports caching with ETag and If-None-Match, and Range requests
+// Photos of users the viewer cannot see return 404, the same as unknown photos
+pub async fn get_photo(
+    req: HttpRequest,
+    photo_id: web::Path<i64>,
+    query: web::Query<HashMap<String, String>>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let key = {
+        let conn = state.db_conn.lock().unwrap();
+        let result = conn.query_row(
+            "SELECT user_id, media_key FROM profile_photos WHERE id = ?1",
+            params![photo_id.into_inner()],
+            |row| Ok((row.get::<_, i32>(0)?, row.get::<_, String>(1)?)),
+        );
+        match result {
+            Ok((owner, key)) if !hidden_from(&conn, sessions::session_user(&conn, &req), owner) => {
+                key
+            }
+            _ => return HttpResponse::NotFound().body("Photo not found"),
+        }
+    };
+    serve_image(&req, state.media.clone(), key, query.get("size")).await
+}
+
+// API for removing a photo: DELETE /users/{user_id}/photos/{photo_id}
+// Deletes the image and closes the gap in the order
+// If the primary photo is removed the next photo becomes primary
+pub async fn delete_photo(
+    path: web::Path<(i32, i64)>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let (uid, photo_id) = path.into_inner();
+    // The image is removed once the lock is released
+    let (key, photos) = {
+        let conn = state.db_conn.lock().unwrap();
+        if let Some(r) = moderation::account_restriction(&conn, uid) {
+            return moderation::restricted_response(&r);
+        }
+        let key: String = match conn.query_row(
+            "SELECT media_key FROM profile_photos WHERE id = ?1 AND user_id = ?2",
+            params![photo_id, uid],
+            |row| row.get(0),
+        ) {
+            Ok(p) => p,
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                return HttpResponse::NotFound().body("Photo not found");
+            }
+            Err(e) => return HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
+        };
+        let res = conn.unchecked_transaction().and_then(|tx| {
+            tx.execute(
+                "DELETE FROM profile_photos WHERE id = ?1",
+                params![photo_id],
+            )?;
+            normalize_photos(&tx, uid)?;
+            tx.commit()
+        });
+        if let Err(e) = res {
+            return HttpResponse::InternalServerError().body(format!("DB error: {}", e));
+        }
+        info!("User {} removed photo {}", uid, photo_id);
+        (key, list_photos(&conn, uid).unwrap_or_default())
+    };
+    images::remove_images(state.media.clone(), vec![key]).await;
+    HttpResponse::Ok().json(serde_json::json!({
+        "success": true,
+        "photos": photos
+    }))
+}
+
+// API for reordering photos: PUT /users/{user_id}/photos/order
+// Expects every one of the user's photo IDs in the new display order
+pub async fn reorder_photos(
+    user_id: web::Path<i32>,
+    data: web::Json<PhotoOrder>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let uid = user_id.into_inner();
+    let conn = state.db_conn.lock().unwrap();
+    if let Some(r) = moderation::account_restriction(&conn, uid) {
+        return moderation::restricted_response(&r);
+    }
+    let mut current: Vec<i64> = match list_photos(&conn, uid) {
+        Ok(photos) => photos.iter().map(|p| p.id).collect(),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
+    };
+    let mut requested = data.photo_ids.clone();
+    current.sort_unstable();
+    requested.sort_unstable();
+    if current != requested {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "success": false,
+            "message": "photo_ids must list each of the user's photos exactly once"
+        }));
+    }
+    let res = conn.unchecked_transaction().and_then(|tx| {
+        for (i, id) in data.photo_ids.iter().enumerate() {
+            tx.execute(
+                "UPDATE profile_photos SET position = ?1 WHERE id = ?2",
+                params![i as i64, id],
+            )?;
+        }
+        tx.commit()
+    });
+    match res {
+        Ok(_) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "photos": list_photos(&conn, uid).unwrap_or_default()
+        })),
+        Err(e) => HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
+    }
+}
+
+// API for choosing the primary photo: PUT /users/{user_id}/photos/{photo_id}/primary
+// The primary photo is the one shown in queues and match lists
+pub async fn set_primary_photo(
+    path: web::Path<(i32, i64)>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let (uid, photo_id) = path.into_inner();
+    let conn = state.db_conn.lock().unwrap();
+    if let Some(r) = moderation::account_restriction(&conn, uid) {
+        return moderation::restricted_response(&r);
+    }
+    let res = conn.unchecked_transaction().and_then(|tx| {
+        let changed = tx.execute(
+            "UPDATE profile_photos SET is_primary = (id = ?1) WHERE user_id = ?2
+             AND EXISTS (SELECT 1 FROM profile_photos WHERE id = ?1 AND user_id = ?2)",
+            params![photo_id, uid],
+        )?;
+        sync_primary(&tx, uid)?;
+        tx.commit()?;
+        Ok(changed)
+    });
+    match res {
+        Ok(0) => HttpResponse::NotFound().body("Photo not found"),
+        Ok(_) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "photos": list_photos(&conn, uid).unwrap_or_default()
+        })),
+        Err(e) => HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
+    }
+}
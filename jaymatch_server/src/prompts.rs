@@ -0,0 +1,277 @@
+/*
+Name: JayMatch profile prompts
+Description: Keeps the curated catalogue of profile prompts and lets each user answer up to three of them on their profile
+Pre/Post Conditions: The prompts and prompt_answers tables must exist. The catalogue below is copied into the prompts table at startup; prompts removed from it stay in the table so existing answers keep their text, but can no longer be chosen.
+Errors: Unknown or retired prompts, duplicate prompts, empty or overlong answers and more than MAX_ANSWERS answers return 400, restricted accounts receive 403, unknown answers return 404, database errors return 500.
+*/
+
+use crate::{AppState, moderation};
+use actix_web::{HttpResponse, Responder, web};
+use chrono::Utc;
+use log::info;
+use rusqlite::{Connection, params};
+use serde::{Deserialize, Serialize};
+
+// Most prompts a single user can answer
+pub const MAX_ANSWERS: usize = 3;
+
+// Longest answer accepted, in characters
+const MAX_ANSWER_CHARS: usize = 300;
+
+// Prompts users can choose from
+// Add new prompts to the end; removing one retires it without touching existing answers
+pub const CATALOGUE: &[&str] = &[
+    "My ideal Saturday in Lawrence is…",
+    "The best spot on campus to study is…",
+    "My go-to order at Mass Street is…",
+    "I'll know it's a match when…",
+    "The way to win me over is…",
+    "A class that changed how I think…",
+    "My most irrational fear…",
+    "Rock Chalk or nothing because…",
+    "Two truths and a lie…",
+    "The hallmark of a good relationship is…",
+    "I geek out on…",
+    "After graduation I want to…",
+    "My simple pleasures…",
+    "We'll get along if…",
+    "The most spontaneous thing I've done…",
+];
+
+// Structure for a prompt in the catalogue
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Prompt {
+    pub id: i64,
+    pub text: String,
+}
+
+// Structure for a user's answer to a prompt, as shown on their profile
+// id is used to like this answer when swiping
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PromptAnswer {
+    pub id: i64,
+    pub prompt_id: i64,
+    pub prompt: String,
+    pub answer: String,
+    pub position: i64,
+}
+
+// Structure for one answer sent by the client
+#[derive(Deserialize)]
+pub struct AnswerInput {
+    pub prompt_id: i64,
+    pub answer: String,
+}
+
+// Structure for replacing a user's prompt answers
+// answers are shown on the profile in the order given
+#[derive(Deserialize)]
+pub struct AnswersUpdate {
+    pub answers: Vec<AnswerInput>,
+}
+
+// Function for copying the catalogue into the prompts table
+// Prompts no longer in the catalogue are marked inactive rather than deleted
+pub fn seed_catalogue(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute("UPDATE prompts SET active = 0", params![])?;
+    for text in CATALOGUE {
+        conn.execute(
+            "INSERT INTO prompts (text, active) VALUES (?1, 1)
+             ON CONFLICT(text) DO UPDATE SET active = 1",
+            params![text],
+        )?;
+    }
+    Ok(())
+}
+
+// Helper function for listing a user's prompt answers in display order
+pub fn list_answers(conn: &Connection, user_id: i32) -> rusqlite::Result<Vec<PromptAnswer>> {
+    let mut stmt = conn.prepare(
+        "SELECT a.id, a.prompt_id, p.text, a.answer, a.position
+         FROM prompt_answers a JOIN prompts p ON p.id = a.prompt_id
+         WHERE a.user_id = ?1 ORDER BY a.position",
+    )?;
+    stmt.query_map(params![user_id], |row| {
+        Ok(PromptAnswer {
+            id: row.get(0)?,
+            prompt_id: row.get(1)?,
+            prompt: row.get(2)?,
+            answer: row.get(3)?,
+            position: row.get(4)?,
+        })
+    })?
+    .collect()
+}
+
+// Helper function for the response sent for an answer set that cannot be saved
+fn invalid_answers(message: String) -> HttpResponse {
+    HttpResponse::BadRequest().json(serde_json::json!({
+        "success": false,
+        "message": message
+    }))
+}
+
+// Helper function for checking a new set of answers
+// Returns the answers with surrounding whitespace removed
+fn check_answers(conn: &Connection, answers: Vec<AnswerInput>) -> Result<Vec<AnswerInput>, String> {
+    if answers.len() > MAX_ANSWERS {
+        return Err(format!("You can answer at most {} prompts", MAX_ANSWERS));
+    }
+    let mut checked: Vec<AnswerInput> = Vec::new();
+    for input in answers {
+        if checked.iter().any(|a| a.prompt_id == input.prompt_id) {
+            return Err(format!(
+                "Prompt {} is answered more than once",
+                input.prompt_id
+            ));
+        }
+        let active = conn
+            .query_row(
+                "SELECT 1 FROM prompts WHERE id = ?1 AND active = 1",
+                params![input.prompt_id],
+                |_| Ok(()),
+            )
+            .is_ok();
+        if !active {
+            return Err(format!(
+                "Prompt {} is not in the catalogue",
+                input.prompt_id
+            ));
+        }
+        let answer = input.answer.trim().to_string();
+        if answer.is_empty() {
+            return Err("Answers cannot be empty".to_string());
+        }
+        if answer.chars().count() > MAX_ANSWER_CHARS {
+            return Err(format!(
+                "Answers can be at most {} characters",
+                MAX_ANSWER_CHARS
+            ));
+        }
+        checked.push(AnswerInput {
+            prompt_id: input.prompt_id,
+            answer,
+        });
+    }
+    Ok(checked)
+}
+
+// API for listing the prompt catalogue: GET /prompts
+// Returns the prompts users can currently choose from
+pub async fn get_prompts(state: web::Data<AppState>) -> impl Responder {
+    let conn = state.db_conn.lock().unwrap();
+    let prompts: rusqlite::Result<Vec<Prompt>> = conn
+        .prepare("SELECT id, text FROM prompts WHERE active = 1 ORDER BY id")
+        .and_then(|mut stmt| {
+            stmt.query_map(params![], |row| {
+                Ok(Prompt {
+                    id: row.get(0)?,
+                    text: row.get(1)?,
+                })
+            })?
+            .collect()
+        });
+    match prompts {
+        Ok(prompts) => HttpResponse::Ok().json(prompts),
+        Err(_) => HttpResponse::InternalServerError().body("Database error"),
+    }
+}
+
+// API for listing a user's prompt answers: GET /profiles/{user_id}/prompts
+pub async fn get_answers(user_id: web::Path<i32>, state: web::Data<AppState>) -> impl Responder {
+    let conn = state.db_conn.lock().unwrap();
+    match list_answers(&conn, user_id.into_inner()) {
+        Ok(answers) => HttpResponse::Ok().json(answers),
+        Err(_) => HttpResponse::InternalServerError().body("Database error"),
+    }
+}
+
+// API for setting a user's prompt answers: PUT /profiles/{user_id}/prompts
+// Replaces every answer the user had with the ones given, up to MAX_ANSWERS
+// Answers to a prompt the user had already answered keep their ID, so likes on them stay attached
+// Returns the saved answers
+pub async fn put_answers(
+    user_id: web::Path<i32>,
+    data: web::Json<AnswersUpdate>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let uid = user_id.into_inner();
+    let conn = state.db_conn.lock().unwrap();
+    if let Some(r) = moderation::account_restriction(&conn, uid) {
+        return moderation::restricted_response(&r);
+    }
+    let answers = match check_answers(&conn, data.into_inner().answers) {
+        Ok(answers) => answers,
+        Err(message) => return invalid_answers(message),
+    };
+    let now = Utc::now().timestamp_millis();
+    let result = conn.unchecked_transaction().and_then(|tx| {
+        let keep: Vec<String> = answers.iter().map(|a| a.prompt_id.to_string()).collect();
+        tx.execute(
+            &format!(
+                "DELETE FROM prompt_answers WHERE user_id = ?1 AND prompt_id NOT IN ({})",
+                keep.join(", ")
+            ),
+            params![uid],
+        )?;
+        for (position, input) in answers.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO prompt_answers (user_id, prompt_id, answer, position, created_at, updated_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?5)
+                 ON CONFLICT(user_id, prompt_id) DO UPDATE SET
+                 answer = ?3, position = ?4,
+                 updated_at = CASE WHEN answer = ?3 THEN updated_at ELSE ?5 END",
+                params![uid, input.prompt_id, input.answer, position as i64, now],
+            )?;
+        }
+        tx.commit()
+    });
+    if let Err(e) = result {
+        return HttpResponse::InternalServerError().body(format!("DB error: {}", e));
+    }
+    info!("User {} answered {} prompts", uid, answers.len());
+    match list_answers(&conn, uid) {
+        Ok(answers) => HttpResponse::Ok().json(answers),
+        Err(_) => HttpResponse::InternalServerError().body("Database error"),
+    }
+}
+
+// API for removing one prompt answer: DELETE /profiles/{user_id}/prompts/{answer_id}
+// Likes on the answer are kept but no longer point at it
+// Renumbers the remaining answers so their positions stay 0, 1, 2
+pub async fn delete_answer(
+    path: web::Path<(i32, i64)>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let (uid, answer_id) = path.into_inner();
+    let conn = state.db_conn.lock().unwrap();
+    if let Some(r) = moderation::account_restriction(&conn, uid) {
+        return moderation::restricted_response(&r);
+    }
+    let result = conn.unchecked_transaction().and_then(|tx| {
+        let removed = tx.execute(
+            "DELETE FROM prompt_answers WHERE id = ?1 AND user_id = ?2",
+            params![answer_id, uid],
+        )?;
+        tx.execute(
+            "UPDATE prompt_answers SET position = (
+                 SELECT COUNT(*) FROM prompt_answers b
+                 WHERE b.user_id = prompt_answers.user_id AND b.position < prompt_answers.position
+             ) WHERE user_id = ?1",
+            params![uid],
+        )?;
+        tx.commit()?;
+        Ok(removed)
+    });
+    match result {
+        Ok(0) => HttpResponse::NotFound().json(serde_json::json!({
+            "success": false,
+            "message": "Answer not found"
+        })),
+        Ok(_) => {
+            info!("User {} removed prompt answer {}", uid, answer_id);
+            HttpResponse::Ok().json(serde_json::json!({"success": true}))
+        }
+        Err(e) => HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
+    }
+}
@@ -0,0 +1,555 @@
+/*
+Name: JayMatch reports
+Description: Endpoints for reporting profiles and messages, and the admin moderation queue (Sprint 3, R27)
+Pre/Post Conditions: The reports and report_notes tables must exist. Every report stores a snapshot of the reported content taken when it was filed; photos in a profile snapshot are copied to media keys owned by the report. Reports outlive deleted accounts; the deleted user's ID becomes null.
+Errors: Invalid categories and message IDs that are not between the two users return 400, missing admin key returns 401, unknown reports return 404, database errors return 500.
+*/
+
+use crate::media::MediaStore;
+use crate::{
+    AppState, admin_required, images, is_admin, moderation, photos, prompts, row_to_profile,
+};
+use actix_web::{HttpRequest, HttpResponse, Responder, web};
+use chrono::Utc;
+use log::{info, warn};
+use rusqlite::{Connection, params};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// Allowed values for report_type
+// Kept short so moderators can sort the queue by category
+pub const REPORT_TYPES: &[&str] = &[
+    "harassment",
+    "spam",
+    "fake_profile",
+    "inappropriate_content",
+    "underage",
+    "other",
+];
+
+// Allowed values for a report's status as it moves through the moderation queue
+pub const REPORT_STATUSES: &[&str] = &["open", "triaged", "resolved"];
+
+// Folder in the media store for photos copied into report snapshots
+const SNAPSHOT_MEDIA_PREFIX: &str = "reports";
+
+// Structure to hold a report as seen by moderators
+// The snapshot is the reported content exactly as it was when the report was filed
+// reporter_id and reported_user_id are None once that user's account has been deleted
+#[derive(Serialize, Debug)]
+pub struct Report {
+    pub id: i64,
+    pub reporter_id: Option<i32>,
+    pub reported_user_id: Option<i32>,
+    pub report_type: String,
+    pub message: Option<String>,
+    pub snapshot: serde_json::Value,
+    pub status: String,
+    pub resolution: Option<String>,
+    pub created_at: i64,
+    pub updated_at: i64,
+    pub notes: Vec<ReportNote>,
+}
+
+// Structure to hold a moderator annotation on a report
+#[derive(Serialize, Debug)]
+pub struct ReportNote {
+    pub id: i64,
+    pub author: String,
+    pub note: String,
+    pub timestamp: i64,
+}
+
+// Structure for filing a report
+// message_ids selects specific messages to snapshot; without it the profile is snapshotted
+#[derive(Deserialize)]
+pub struct ReportPost {
+    pub reporter_id: i32,
+    pub reported_user_id: i32,
+    pub report_type: String,
+    pub message: Option<String>,
+    pub message_ids: Option<Vec<i64>>,
+}
+
+// Structure for moderators changing the state of a report
+#[derive(Deserialize)]
+pub struct ReportUpdate {
+    pub status: String,
+    pub resolution: Option<String>,
+}
+
+// Structure for moderators adding a note to a report
+#[derive(Deserialize)]
+pub struct ReportNotePost {
+    pub author: Option<String>,
+    pub note: String,
+}
+
+// Reasons a report's snapshot could not be built
+#[derive(Debug)]
+enum SnapshotError {
+    // Requested message IDs that are missing or not between the reporter and the reported user
+    UnknownMessages(Vec<i64>),
+    Database(rusqlite::Error),
+}
+
+impl From<rusqlite::Error> for SnapshotError {
+    fn from(e: rusqlite::Error) -> Self {
+        SnapshotError::Database(e)
+    }
+}
+
+// Helper function for building the snapshot stored with a report
+// Copies the selected messages between the pair, or the reported profile when no messages are given
+// A profile snapshot includes the prompt answers and the media keys of the photos, which copy_snapshot_photos replaces with copies
+// Only messages exchanged between the reporter and the reported user can be captured; any other ID fails the whole snapshot
+fn build_snapshot(
+    conn: &Connection,
+    reporter_id: i32,
+    reported_user_id: i32,
+    message_ids: &Option<Vec<i64>>,
+) -> Result<serde_json::Value, SnapshotError> {
+    if let Some(ids) = message_ids.as_ref().filter(|ids| !ids.is_empty()) {
+        let mut stmt = conn.prepare(
+            "SELECT id, sender_id, receiver_id, content, timestamp FROM messages
+             WHERE id = ?1
+               AND ((sender_id = ?2 AND receiver_id = ?3) OR (sender_id = ?3 AND receiver_id = ?2))",
+        )?;
+        let mut messages = Vec::new();
+        let mut unknown = Vec::new();
+        for (i, id) in ids.iter().enumerate() {
+            if ids[..i].contains(id) {
+                continue;
+            }
+            let found = stmt.query_row(params![id, reporter_id, reported_user_id], |row| {
+                Ok(serde_json::json!({
+                    "id": row.get::<_, i64>(0)?,
+                    "sender_id": row.get::<_, i32>(1)?,
+                    "receiver_id": row.get::<_, i32>(2)?,
+                    "content": row.get::<_, String>(3)?,
+                    "timestamp": row.get::<_, i64>(4)?,
+                }))
+            });
+            match found {
+                Ok(m) => messages.push(m),
+                Err(rusqlite::Error::QueryReturnedNoRows) => unknown.push(*id),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if !unknown.is_empty() {
+            return Err(SnapshotError::UnknownMessages(unknown));
+        }
+        return Ok(serde_json::json!({ "kind": "messages", "messages": messages }));
+    }
+    let mut profile = conn.query_row(
+        "SELECT user_id, email, name, age, major, year, bio, profile_picture, interests, gender, is_felon, attributes
+         FROM profiles WHERE user_id = ?1",
+        params![reported_user_id],
+        |row| Ok(row_to_profile(row)),
+    )?;
+    profile.prompts = Some(prompts::list_answers(conn, reported_user_id)?);
+    let photos: Vec<serde_json::Value> = conn
+        .prepare(
+            "SELECT position, is_primary, media_key FROM profile_photos
+             WHERE user_id = ?1 ORDER BY position, id",
+        )?
+        .query_map(params![reported_user_id], |row| {
+            Ok(serde_json::json!({
+                "position": row.get::<_, i64>(0)?,
+                "is_primary": row.get::<_, bool>(1)?,
+                "media_key": row.get::<_, String>(2)?,
+            }))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(serde_json::json!({ "kind": "profile", "profile": profile, "photos": photos }))
+}
+
+// Helper function for copying the photos in a profile snapshot to keys owned by the report
+// The reported user can then replace or delete their photos without the evidence going with them
+// Runs on the blocking thread pool; callers release the database lock before awaiting this
+// Photos that can no longer be read are kept in the snapshot with a null media_key
+// Returns the keys written, so they can be removed if the report is not saved
+async fn copy_snapshot_photos(
+    store: Arc<dyn MediaStore>,
+    snapshot: &mut serde_json::Value,
+) -> Vec<String> {
+    let originals: Vec<String> = snapshot["photos"]
+        .as_array()
+        .map(|photos| {
+            photos
+                .iter()
+                .filter_map(|p| p["media_key"].as_str().map(String::from))
+                .collect()
+        })
+        .unwrap_or_default();
+    if originals.is_empty() {
+        return Vec::new();
+    }
+    let copied = web::block(move || {
+        originals
+            .into_iter()
+            .map(|from| {
+                let ext = from.rsplit_once('.').map_or("jpg", |(_, ext)| ext);
+                let to = format!(
+                    "{}/{}.{}",
+                    SNAPSHOT_MEDIA_PREFIX,
+                    uuid::Uuid::new_v4().simple(),
+                    ext
+                );
+                match images::copy_image(store.as_ref(), &from, &to) {
+                    Ok(()) => (from, Some(to)),
+                    Err(e) => {
+                        warn!("Failed to copy {} into a report snapshot: {}", from, e);
+                        images::remove_image(store.as_ref(), &to);
+                        (from, None)
+                    }
+                }
+            })
+            .collect::<HashMap<String, Option<String>>>()
+    })
+    .await
+    .unwrap_or_else(|e| {
+        warn!("Failed to copy report snapshot photos: {}", e);
+        HashMap::new()
+    });
+    let copy_of =
+        |key: &serde_json::Value| key.as_str().and_then(|k| copied.get(k).cloned().flatten());
+    for photo in snapshot["photos"].as_array_mut().into_iter().flatten() {
+        photo["media_key"] = serde_json::json!(copy_of(&photo["media_key"]));
+    }
+    snapshot["profile"]["profile_picture"] =
+        serde_json::json!(copy_of(&snapshot["profile"]["profile_picture"]));
+    copied.into_values().flatten().collect()
+}
+
+// Helper function for loading a single report with its notes
+// Returns QueryReturnedNoRows when the report does not exist
+fn load_report(conn: &Connection, id: i64) -> rusqlite::Result<Report> {
+    let mut report = conn.query_row(
+        "SELECT id, reporter_id, reported_user_id, report_type, message, snapshot, status, resolution, created_at, updated_at
+         FROM reports WHERE id = ?1",
+        params![id],
+        row_to_report,
+    )?;
+    let mut stmt = conn.prepare(
+        "SELECT id, author, note, timestamp FROM report_notes WHERE report_id = ?1 ORDER BY timestamp",
+    )?;
+    report.notes = stmt
+        .query_map(params![id], |row| {
+            Ok(ReportNote {
+                id: row.get(0)?,
+                author: row.get(1)?,
+                note: row.get(2)?,
+                timestamp: row.get(3)?,
+            })
+        })?
+        .filter_map(|r| r.ok())
+        .collect();
+    Ok(report)
+}
+
+// Helper function for converting a reports row into a Report without notes
+fn row_to_report(row: &rusqlite::Row) -> rusqlite::Result<Report> {
+    let snapshot_text: String = row.get(5)?;
+    Ok(Report {
+        id: row.get(0)?,
+        reporter_id: row.get(1)?,
+        reported_user_id: row.get(2)?,
+        report_type: row.get(3)?,
+        message: row.get(4)?,
+        snapshot: serde_json::from_str(&snapshot_text).unwrap_or(serde_json::Value::Null),
+        status: row.get(6)?,
+        resolution: row.get(7)?,
+        created_at: row.get(8)?,
+        updated_at: row.get(9)?,
+        notes: Vec::new(),
+    })
+}
+
+// API for reporting a profile or messages: POST /reports
+// Validates report_type against REPORT_TYPES
+// Every message_ids entry must be a message between the reporter and the reported user
+// Stores an immutable snapshot of the reported messages or profile, with copies of the profile's photos
+// Returns the ID of the new report
+pub async fn create_report(
+    data: web::Json<ReportPost>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let report_type = data.report_type.to_ascii_lowercase();
+    if !REPORT_TYPES.contains(&report_type.as_str()) {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "success": false,
+            "message": format!("Invalid report_type. Allowed: {}", REPORT_TYPES.join(", "))
+        }));
+    }
+    if data.reporter_id == data.reported_user_id {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "success": false,
+            "message": "Cannot report yourself"
+        }));
+    }
+    // Returns the snapshot, or the response to send if it could not be built
+    let built = {
+        let conn = state.db_conn.lock().unwrap();
+        match moderation::account_restriction(&conn, data.reporter_id) {
+            Some(r) => Err(moderation::restricted_response(&r)),
+            None => build_snapshot(
+                &conn,
+                data.reporter_id,
+                data.reported_user_id,
+                &data.message_ids,
+            )
+            .map_err(|e| match e {
+                SnapshotError::UnknownMessages(ids) => {
+                    HttpResponse::BadRequest().json(serde_json::json!({
+                        "success": false,
+                        "message": "Some messages are not between you and the reported user",
+                        "message_ids": ids
+                    }))
+                }
+                SnapshotError::Database(rusqlite::Error::QueryReturnedNoRows) => {
+                    HttpResponse::NotFound().body("Reported user not found")
+                }
+                SnapshotError::Database(e) => {
+                    HttpResponse::InternalServerError().body(format!("DB error: {}", e))
+                }
+            }),
+        }
+    };
+    let mut snapshot = match built {
+        Ok(s) => s,
+        Err(response) => return response,
+    };
+    let copies = copy_snapshot_photos(state.media.clone(), &mut snapshot).await;
+    let ts = Utc::now().timestamp_millis();
+    let inserted = {
+        let conn = state.db_conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO reports (reporter_id, reported_user_id, report_type, message, snapshot, status, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, 'open', ?6, ?6)",
+            params![
+                data.reporter_id,
+                data.reported_user_id,
+                report_type,
+                data.message,
+                snapshot.to_string(),
+                ts
+            ],
+        )
+        .map(|_| conn.last_insert_rowid())
+    };
+    match inserted {
+        Ok(report_id) => {
+            info!(
+                "User {} reported user {} for {}",
+                data.reporter_id, data.reported_user_id, report_type
+            );
+            HttpResponse::Ok().json(serde_json::json!({
+                "success": true,
+                "message": "Report submitted",
+                "report_id": report_id
+            }))
+        }
+        Err(e) => {
+            images::remove_images(state.media.clone(), copies).await;
+            HttpResponse::InternalServerError().body(format!("DB error: {}", e))
+        }
+    }
+}
+
+// Admin API for listing reports: GET /admin/reports
+// Supports status and reported_user_id query parameters for filtering
+// Returns reports oldest first so the queue is worked in order
+pub async fn list_reports(
+    req: HttpRequest,
+    query: web::Query<HashMap<String, String>>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    if !is_admin(&req) {
+        return admin_required();
+    }
+    let status = query.get("status").cloned();
+    let reported: Option<i32> = query.get("reported_user_id").and_then(|s| s.parse().ok());
+    let conn = state.db_conn.lock().unwrap();
+    let mut stmt = match conn.prepare(
+        "SELECT id, reporter_id, reported_user_id, report_type, message, snapshot, status, resolution, created_at, updated_at
+         FROM reports
+         WHERE (?1 IS NULL OR status = ?1) AND (?2 IS NULL OR reported_user_id = ?2)
+         ORDER BY created_at",
+    ) {
+        Ok(s) => s,
+        Err(_) => return HttpResponse::InternalServerError().body("Database error"),
+    };
+    let reports: Vec<Report> = match stmt.query_map(params![status, reported], row_to_report) {
+        Ok(rows) => rows.filter_map(|r| r.ok()).collect(),
+        Err(_) => return HttpResponse::InternalServerError().body("Database error"),
+    };
+    HttpResponse::Ok().json(reports)
+}
+
+// Admin API for viewing a single report: GET /admin/reports/{id}
+// Returns the report with its snapshot and all moderator notes
+pub async fn get_report(
+    req: HttpRequest,
+    report_id: web::Path<i64>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    if !is_admin(&req) {
+        return admin_required();
+    }
+    let conn = state.db_conn.lock().unwrap();
+    match load_report(&conn, report_id.into_inner()) {
+        Ok(r) => HttpResponse::Ok().json(r),
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            HttpResponse::NotFound().body("Report not found")
+        }
+        Err(_) => HttpResponse::InternalServerError().body("Database error"),
+    }
+}
+
+// Admin API for viewing a photo kept in a report's snapshot: GET /admin/reports/{id}/photos/{index}
+// index is the photo's place in the snapshot's photos list
+// Supports the size query parameter, caching and Range requests like GET /photos/{id}
+pub async fn get_report_photo(
+    req: HttpRequest,
+    path: web::Path<(i64, usize)>,
+    query: web::Query<HashMap<String, String>>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    if !is_admin(&req) {
+        return admin_required();
+    }
+    let (id, index) = path.into_inner();
+    let snapshot = state.db_conn.lock().unwrap().query_row(
+        "SELECT snapshot FROM reports WHERE id = ?1",
+        params![id],
+        |row| row.get::<_, String>(0),
+    );
+    let key = match snapshot {
+        Ok(text) => serde_json::from_str::<serde_json::Value>(&text)
+            .ok()
+            .and_then(|s| s["photos"][index]["media_key"].as_str().map(String::from)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return HttpResponse::NotFound().body("Report not found");
+        }
+        Err(_) => return HttpResponse::InternalServerError().body("Database error"),
+    };
+    match key {
+        Some(key) => photos::serve_image(&req, state.media.clone(), key, query.get("size")).await,
+        None => HttpResponse::NotFound().body("Photo not found"),
+    }
+}
+
+// Admin API for triaging or resolving a report: PUT /admin/reports/{id}
+// Validates status against REPORT_STATUSES
+// Only the status and resolution change, the snapshot is never rewritten
+pub async fn update_report(
+    req: HttpRequest,
+    report_id: web::Path<i64>,
+    data: web::Json<ReportUpdate>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    if !is_admin(&req) {
+        return admin_required();
+    }
+    let id = report_id.into_inner();
+    let status = data.status.to_ascii_lowercase();
+    if !REPORT_STATUSES.contains(&status.as_str()) {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "success": false,
+            "message": format!("Invalid status. Allowed: {}", REPORT_STATUSES.join(", "))
+        }));
+    }
+    let conn = state.db_conn.lock().unwrap();
+    let ts = Utc::now().timestamp_millis();
+    match conn.execute(
+        "UPDATE reports SET status = ?1, resolution = COALESCE(?2, resolution), updated_at = ?3 WHERE id = ?4",
+        params![status, data.resolution, ts, id],
+    ) {
+        Ok(0) => HttpResponse::NotFound().body("Report not found"),
+        Ok(_) => {
+            info!("Report {} moved to {}", id, status);
+            HttpResponse::Ok().json(serde_json::json!({"success": true, "report_id": id}))
+        }
+        Err(e) => HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
+    }
+}
+
+// Admin API for annotating a report: POST /admin/reports/{id}/notes
+// Appends a note to the report, notes cannot be edited afterwards
+pub async fn add_report_note(
+    req: HttpRequest,
+    report_id: web::Path<i64>,
+    data: web::Json<ReportNotePost>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    if !is_admin(&req) {
+        return admin_required();
+    }
+    let id = report_id.into_inner();
+    if data.note.trim().is_empty() {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "success": false,
+            "message": "Note cannot be empty"
+        }));
+    }
+    let conn = state.db_conn.lock().unwrap();
+    let exists = conn.query_row("SELECT 1 FROM reports WHERE id = ?1", params![id], |_| {
+        Ok(())
+    });
+    if exists.is_err() {
+        return HttpResponse::NotFound().body("Report not found");
+    }
+    let ts = Utc::now().timestamp_millis();
+    let author = data.author.clone().unwrap_or_else(|| "admin".to_string());
+    if let Err(e) = conn.execute(
+        "INSERT INTO report_notes (report_id, author, note, timestamp) VALUES (?1, ?2, ?3, ?4)",
+        params![id, author, data.note, ts],
+    ) {
+        return HttpResponse::InternalServerError().body(format!("DB error: {}", e));
+    }
+    let note_id = conn.last_insert_rowid();
+    match conn.execute(
+        "UPDATE reports SET updated_at = ?1 WHERE id = ?2",
+        params![ts, id],
+    ) {
+        Ok(_) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "note_id": note_id
+        })),
+        Err(e) => HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
+    }
+}
+
+// Admin API for report counts per user: GET /admin/report-counts
+// Groups reports by the reported user, most reported first
+// Reports about accounts that have since been deleted are left out
+// Includes how many of each user's reports are still unresolved
+pub async fn get_report_counts(req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    if !is_admin(&req) {
+        return admin_required();
+    }
+    let conn = state.db_conn.lock().unwrap();
+    let mut stmt = match conn.prepare(
+        "SELECT reported_user_id, COUNT(*), SUM(CASE WHEN status != 'resolved' THEN 1 ELSE 0 END)
+         FROM reports
+         WHERE reported_user_id IS NOT NULL
+         GROUP BY reported_user_id
+         ORDER BY COUNT(*) DESC",
+    ) {
+        Ok(s) => s,
+        Err(_) => return HttpResponse::InternalServerError().body("Database error"),
+    };
+    let counts: Vec<serde_json::Value> = match stmt.query_map(params![], |row| {
+        Ok(serde_json::json!({
+            "user_id": row.get::<_, i32>(0)?,
+            "report_count": row.get::<_, i64>(1)?,
+            "unresolved_count": row.get::<_, i64>(2)?,
+        }))
+    }) {
+        Ok(rows) => rows.filter_map(|r| r.ok()).collect(),
+        Err(_) => return HttpResponse::InternalServerError().body("Database error"),
+    };
+    HttpResponse::Ok().json(counts)
+}
@@ -0,0 +1,49 @@
+/*
+Name: JayMatch sessions
+Description: Login session tokens so the server can tell which user is making a request and end sessions remotely
+Pre/Post Conditions: The sessions table must exist. A token is created on every successful login and is valid until it expires or is revoked.
+Errors: Missing, unknown or expired tokens resolve to no user; database errors are passed back to the caller.
+*/
+
+use actix_web::HttpRequest;
+use chrono::Utc;
+use rusqlite::{Connection, params};
+
+// How long a login session stays valid (30 days in milliseconds)
+pub const SESSION_TTL_MS: i64 = 30 * 24 * 60 * 60 * 1000;
+
+// Helper function for starting a new session after a successful login
+// Stores a random token for the user and returns it
+pub fn create_session(conn: &Connection, user_id: i32) -> rusqlite::Result<String> {
+    let token = uuid::Uuid::new_v4().simple().to_string();
+    let now = Utc::now().timestamp_millis();
+    conn.execute(
+        "INSERT INTO sessions (token, user_id, created_at, expires_at) VALUES (?1, ?2, ?3, ?4)",
+        params![token, user_id, now, now + SESSION_TTL_MS],
+    )?;
+    Ok(token)
+}
+
+// Helper function for finding the user behind a request
+// Reads the token from the Authorization: Bearer header
+// Returns None if the header is missing or the session is unknown or expired
+pub fn session_user(conn: &Connection, req: &HttpRequest) -> Option<i32> {
+    let header = req
+        .headers()
+        .get(actix_web::http::header::AUTHORIZATION)?
+        .to_str()
+        .ok()?;
+    let token = header.strip_prefix("Bearer ")?.trim();
+    conn.query_row(
+        "SELECT user_id FROM sessions WHERE token = ?1 AND expires_at > ?2",
+        params![token, Utc::now().timestamp_millis()],
+        |row| row.get(0),
+    )
+    .ok()
+}
+
+// Helper function for ending every session a user has open
+// Used when an account is suspended, banned or deleted
+pub fn revoke_sessions(conn: &Connection, user_id: i32) -> rusqlite::Result<usize> {
+    conn.execute("DELETE FROM sessions WHERE user_id = ?1", params![user_id])
+}
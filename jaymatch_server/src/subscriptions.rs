@@ -0,0 +1,445 @@
+/*
+Name: JayMatch subscriptions
+Description: Lets users buy, check and cancel a premium subscription, and applies the webhooks the payment provider sends as payments succeed or fail, crediting one-off purchases such as boosts (R26)
+Pre/Post Conditions: The subscriptions and payment_events tables must exist and AppState must hold a payment provider. Subscriptions only change when a signed webhook arrives, except that cancelling stops renewal straight away. A subscription gives premium until current_period_end while it is active or cancelled; a failed payment ends premium until a renewal succeeds.
+Errors: Missing sessions return 401, unknown plans and malformed webhooks return 400, bad webhook signatures and missing admin keys return 401, restricted accounts receive 403, unknown checkouts or subscriptions return 404, buying while already subscribed returns 409, provider failures return 502, database errors return 500.
+*/
+
+use crate::payments::{self, SignedWebhook};
+use crate::{AppState, admin_required, boosts, entitlements, is_admin, moderation, sessions};
+use actix_web::{HttpRequest, HttpResponse, Responder, web};
+use chrono::Utc;
+use log::{info, warn};
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::{Deserialize, Serialize};
+
+// Structure for a user's subscription as sent to clients
+// status is one of:
+// active: renews at current_period_end
+// past_due: the last payment failed, so premium is paused until a renewal succeeds
+// canceled: will not renew, but stays premium until current_period_end
+#[derive(Serialize, Debug, Clone)]
+pub struct Subscription {
+    pub id: String,
+    pub provider: String,
+    pub plan: String,
+    pub status: String,
+    pub current_period_end: i64,
+    pub renews: bool,
+}
+
+// Structure for starting a checkout
+#[derive(Deserialize)]
+pub struct CheckoutRequest {
+    pub plan: String,
+}
+
+// Structure for a webhook event sent by the payment provider
+#[derive(Deserialize)]
+pub struct WebhookEvent {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub data: serde_json::Value,
+}
+
+// Error from applying a webhook event
+// Unusable event data is the provider's fault, database failures are the server's
+#[derive(Debug)]
+pub enum WebhookError {
+    Invalid(String),
+    Database(rusqlite::Error),
+}
+
+impl From<rusqlite::Error> for WebhookError {
+    fn from(e: rusqlite::Error) -> Self {
+        WebhookError::Database(e)
+    }
+}
+
+// Helper function for the response sent when the caller has no session
+fn login_required() -> HttpResponse {
+    HttpResponse::Unauthorized().json(serde_json::json!({
+        "success": false,
+        "message": "Login required"
+    }))
+}
+
+// Helper function for the response sent when the payment provider refuses a request
+fn provider_error(message: String) -> HttpResponse {
+    HttpResponse::BadGateway().json(serde_json::json!({
+        "success": false,
+        "message": message
+    }))
+}
+
+// Helper function for a user's most recent subscription
+pub fn latest_subscription(
+    conn: &Connection,
+    user_id: i32,
+) -> rusqlite::Result<Option<Subscription>> {
+    conn.query_row(
+        "SELECT provider_subscription_id, provider, plan, status, current_period_end FROM subscriptions
+         WHERE user_id = ?1 ORDER BY created_at DESC, id DESC LIMIT 1",
+        params![user_id],
+        |row| {
+            let status: String = row.get(3)?;
+            Ok(Subscription {
+                id: row.get(0)?,
+                provider: row.get(1)?,
+                plan: row.get(2)?,
+                renews: status != "canceled",
+                status,
+                current_period_end: row.get(4)?,
+            })
+        },
+    )
+    .optional()
+}
+
+// Helper function for reading a text field from a webhook's data
+fn data_str<'a>(event: &'a WebhookEvent, field: &str) -> Result<&'a str, WebhookError> {
+    event.data[field]
+        .as_str()
+        .ok_or_else(|| WebhookError::Invalid(format!("{} event is missing {}", event.kind, field)))
+}
+
+// Helper function for reading a number field from a webhook's data
+fn data_i64(event: &WebhookEvent, field: &str) -> Result<i64, WebhookError> {
+    event.data[field]
+        .as_i64()
+        .ok_or_else(|| WebhookError::Invalid(format!("{} event is missing {}", event.kind, field)))
+}
+
+// Helper function for changing a subscription's state, or crediting a purchase, for one webhook event
+// Returns the number of rows changed
+fn apply_event(
+    conn: &Connection,
+    provider: &str,
+    event: &WebhookEvent,
+    now: i64,
+) -> Result<usize, WebhookError> {
+    let changed = match event.kind.as_str() {
+        "checkout.completed" => conn.execute(
+            "INSERT INTO subscriptions
+                (user_id, provider, provider_subscription_id, plan, status, current_period_end, created_at, updated_at)
+             SELECT ?1, ?2, ?3, ?4, 'active', ?5, ?6, ?6 WHERE EXISTS (SELECT 1 FROM profiles WHERE user_id = ?1)
+             ON CONFLICT(provider_subscription_id) DO NOTHING",
+            params![
+                data_i64(event, "user_id")?,
+                provider,
+                data_str(event, "subscription_id")?,
+                data_str(event, "plan")?,
+                data_i64(event, "current_period_end")?,
+                now
+            ],
+        ),
+        "subscription.renewed" => conn.execute(
+            "UPDATE subscriptions SET status = 'active', current_period_end = ?1, updated_at = ?2
+             WHERE provider_subscription_id = ?3",
+            params![
+                data_i64(event, "current_period_end")?,
+                now,
+                data_str(event, "subscription_id")?
+            ],
+        ),
+        "payment.failed" => conn.execute(
+            "UPDATE subscriptions SET status = 'past_due', updated_at = ?1
+             WHERE provider_subscription_id = ?2 AND status = 'active'",
+            params![now, data_str(event, "subscription_id")?],
+        ),
+        "subscription.canceled" => conn.execute(
+            "UPDATE subscriptions SET status = 'canceled', updated_at = ?1
+             WHERE provider_subscription_id = ?2",
+            params![now, data_str(event, "subscription_id")?],
+        ),
+        "purchase.completed" => boosts::credit_purchase(
+            conn,
+            data_i64(event, "user_id")?,
+            data_str(event, "product")?,
+            data_str(event, "checkout_id")?,
+        ),
+        _ => Ok(0),
+    }?;
+    Ok(changed)
+}
+
+// Function for checking and applying a webhook from the payment provider
+// Each event is applied once; repeats of an event already seen are acknowledged and ignored
+// Event types the server does not use are acknowledged without changing anything
+pub fn process_webhook(
+    conn: &Connection,
+    state: &AppState,
+    body: &[u8],
+    signature: &str,
+) -> HttpResponse {
+    if !state.payments.verify_webhook(body, signature) {
+        warn!("Rejected payment webhook with a bad signature");
+        return HttpResponse::Unauthorized().json(serde_json::json!({
+            "success": false,
+            "message": "Invalid webhook signature"
+        }));
+    }
+    let event: WebhookEvent = match serde_json::from_slice(body) {
+        Ok(event) => event,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "success": false,
+                "message": format!("Invalid webhook body: {}", e)
+            }));
+        }
+    };
+    let now = Utc::now().timestamp_millis();
+    let tx = match conn.unchecked_transaction() {
+        Ok(tx) => tx,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
+    };
+    let first = match tx.execute(
+        "INSERT OR IGNORE INTO payment_events (id, type, received_at) VALUES (?1, ?2, ?3)",
+        params![event.id, event.kind, now],
+    ) {
+        Ok(n) => n > 0,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
+    };
+    if !first {
+        return HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "event": event.kind,
+            "duplicate": true
+        }));
+    }
+    let changed = apply_event(&tx, state.payments.name(), &event, now)
+        .and_then(|n| tx.commit().map(|_| n).map_err(WebhookError::from));
+    match changed {
+        Ok(n) => {
+            info!(
+                "Applied payment webhook {} ({}), {} subscriptions changed",
+                event.id, event.kind, n
+            );
+            HttpResponse::Ok().json(serde_json::json!({
+                "success": true,
+                "event": event.kind,
+                "duplicate": false
+            }))
+        }
+        Err(WebhookError::Invalid(message)) => HttpResponse::BadRequest().json(serde_json::json!({
+            "success": false,
+            "message": message
+        })),
+        Err(WebhookError::Database(e)) => {
+            HttpResponse::InternalServerError().body(format!("DB error: {}", e))
+        }
+    }
+}
+
+// API for receiving webhooks from the payment provider: POST /payments/webhook
+// The signature header must match the raw body
+pub async fn webhook(
+    req: HttpRequest,
+    body: web::Bytes,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let signature = req
+        .headers()
+        .get(payments::SIGNATURE_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let conn = state.db_conn.lock().unwrap();
+    process_webhook(&conn, &state, &body, &signature)
+}
+
+// API for starting a premium checkout: POST /me/subscription/checkout
+// Requires the Authorization: Bearer session token
+// Returns the checkout the client sends the user to; premium starts once the provider confirms payment
+pub async fn create_checkout(
+    req: HttpRequest,
+    data: web::Json<CheckoutRequest>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let conn = state.db_conn.lock().unwrap();
+    let uid = match sessions::session_user(&conn, &req) {
+        Some(uid) => uid,
+        None => return login_required(),
+    };
+    if let Some(r) = moderation::account_restriction(&conn, uid) {
+        return moderation::restricted_response(&r);
+    }
+    let Some((price_cents, days)) = payments::plan_terms(&data.plan) else {
+        let plans: Vec<&str> = payments::PLANS.iter().map(|(name, _, _)| *name).collect();
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "success": false,
+            "message": format!("plan must be one of: {}", plans.join(", "))
+        }));
+    };
+    let now = Utc::now().timestamp_millis();
+    match latest_subscription(&conn, uid) {
+        Ok(Some(s)) if s.renews && s.current_period_end > now => {
+            return HttpResponse::Conflict().json(serde_json::json!({
+                "success": false,
+                "message": "You already have a subscription",
+                "subscription": s
+            }));
+        }
+        Ok(_) => {}
+        Err(_) => return HttpResponse::InternalServerError().body("Database error"),
+    }
+    match state.payments.create_checkout(uid, &data.plan) {
+        Ok(checkout) => {
+            info!(
+                "User {} started checkout {} for {}",
+                uid, checkout.id, data.plan
+            );
+            HttpResponse::Created().json(serde_json::json!({
+                "success": true,
+                "checkout_id": checkout.id,
+                "url": checkout.url,
+                "provider": state.payments.name(),
+                "plan": data.plan,
+                "price_cents": price_cents,
+                "period_days": days
+            }))
+        }
+        Err(message) => provider_error(message),
+    }
+}
+
+// API for the logged in user's subscription: GET /me/subscription
+// Requires the Authorization: Bearer session token
+// Returns whether the user is premium, when premium ends, and their latest subscription (null if they never had one)
+pub async fn get_subscription(req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    let conn = state.db_conn.lock().unwrap();
+    let uid = match sessions::session_user(&conn, &req) {
+        Some(uid) => uid,
+        None => return login_required(),
+    };
+    match latest_subscription(&conn, uid) {
+        Ok(subscription) => HttpResponse::Ok().json(serde_json::json!({
+            "user_id": uid,
+            "is_premium": entitlements::plan(&conn, uid) == entitlements::Plan::Premium,
+            "subscription_expiration": entitlements::premium_until(&conn, uid),
+            "subscription": subscription
+        })),
+        Err(_) => HttpResponse::InternalServerError().body("Database error"),
+    }
+}
+
+// API for cancelling the logged in user's subscription: POST /me/subscription/cancel
+// Requires the Authorization: Bearer session token
+// Stops renewal at the provider; premium lasts until the end of the period already paid for
+pub async fn cancel_subscription(req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    let conn = state.db_conn.lock().unwrap();
+    let uid = match sessions::session_user(&conn, &req) {
+        Some(uid) => uid,
+        None => return login_required(),
+    };
+    let subscription = match latest_subscription(&conn, uid) {
+        Ok(Some(s)) if s.renews => s,
+        Ok(_) => {
+            return HttpResponse::NotFound().json(serde_json::json!({
+                "success": false,
+                "message": "No subscription to cancel"
+            }));
+        }
+        Err(_) => return HttpResponse::InternalServerError().body("Database error"),
+    };
+    if let Err(message) = state.payments.cancel(&subscription.id) {
+        return provider_error(message);
+    }
+    match conn.execute(
+        "UPDATE subscriptions SET status = 'canceled', updated_at = ?1 WHERE provider_subscription_id = ?2",
+        params![Utc::now().timestamp_millis(), subscription.id],
+    ) {
+        Ok(_) => {
+            info!("User {} cancelled subscription {}", uid, subscription.id);
+            HttpResponse::Ok().json(serde_json::json!({
+                "success": true,
+                "subscription": Subscription {
+                    status: "canceled".to_string(),
+                    renews: false,
+                    ..subscription
+                }
+            }))
+        }
+        Err(e) => HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
+    }
+}
+
+// Helper function for delivering a webhook built by the fake provider
+fn deliver(state: &AppState, webhook: SignedWebhook) -> HttpResponse {
+    let conn = state.db_conn.lock().unwrap();
+    process_webhook(&conn, state, webhook.body.as_bytes(), &webhook.signature)
+}
+
+// Helper function for the response sent when the payment provider is not the fake one
+fn no_simulator() -> HttpResponse {
+    HttpResponse::NotFound().body("Payment simulation is only available with the fake provider")
+}
+
+// API for paying for a fake checkout: POST /payments/fake/checkouts/{checkout_id}/complete
+// Stands in for the provider's payment page, so it needs no session or admin key
+// Sends the checkout.completed webhook
+pub async fn fake_complete_checkout(
+    checkout_id: web::Path<String>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let Some(fake) = state.payments.simulator() else {
+        return no_simulator();
+    };
+    match fake.complete_checkout(&checkout_id) {
+        Ok(webhook) => deliver(&state, webhook),
+        Err(message) => HttpResponse::NotFound().json(serde_json::json!({
+            "success": false,
+            "message": message
+        })),
+    }
+}
+
+// Admin API for simulating provider events on a subscription: POST /payments/fake/subscriptions/{subscription_id}/{event}
+// event is renew (charges the next period), fail (declines the next payment) or cancel (cancelled at the provider)
+pub async fn fake_subscription_event(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    if !is_admin(&req) {
+        return admin_required();
+    }
+    let Some(fake) = state.payments.simulator() else {
+        return no_simulator();
+    };
+    let (subscription_id, event) = path.into_inner();
+    let found = {
+        let conn = state.db_conn.lock().unwrap();
+        conn.query_row(
+            "SELECT plan, current_period_end FROM subscriptions WHERE provider_subscription_id = ?1",
+            params![subscription_id],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)),
+        )
+    };
+    let (plan, current_period_end) = match found {
+        Ok(found) => found,
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return HttpResponse::NotFound().body("Subscription not found");
+        }
+        Err(_) => return HttpResponse::InternalServerError().body("Database error"),
+    };
+    let webhook = match event.as_str() {
+        "renew" => {
+            let days = payments::plan_terms(&plan)
+                .map(|(_, days)| days)
+                .unwrap_or(30);
+            let start = current_period_end.max(Utc::now().timestamp_millis());
+            fake.renew(&subscription_id, start + days * 24 * 60 * 60 * 1000)
+        }
+        "fail" => fake.fail_payment(&subscription_id),
+        "cancel" => fake.cancellation(&subscription_id),
+        _ => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "success": false,
+                "message": "event must be renew, fail or cancel"
+            }));
+        }
+    };
+    deliver(&state, webhook)
+}
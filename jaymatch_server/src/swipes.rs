@@ -0,0 +1,316 @@
+/*
+Name: JayMatch swipes
+Description: Records each like, super like or pass a user gives another profile, optionally attached to one of that profile's prompt answers, creates a match when two users like each other, and lets users undo their last swipe
+Pre/Post Conditions: The swipes, swipe_undos, prompt_answers and matches tables must exist. A user has at most one swipe on each profile; swiping again replaces it, and replacing a like with a pass removes the match the like made. A super like is stored as a like with super_like set, so it counts as a like everywhere else. Undoing a swipe puts the profile back at the front of the user's queue and removes any match the swipe made.
+Errors: Swiping on yourself, an unknown direction or an answer that is not on the swiped profile returns 400, swiping on a blocked user returns 403, unknown, deleted or hidden profiles return 404, having no swipe to undo returns 404, swipes older than the undo window return 410, used up like, super like or undo quotas return 429, restricted accounts receive 403, database errors return 500.
+*/
+
+use crate::{AppState, WsMessage, blocks, deletion, entitlements, moderation, visibility};
+use actix_web::{HttpResponse, Responder, web};
+use chrono::Utc;
+use log::info;
+use rusqlite::{Connection, params};
+use serde::Deserialize;
+
+// How long after a swipe it can still be undone (5 minutes in milliseconds)
+pub const UNDO_WINDOW_MS: i64 = 5 * 60 * 1000;
+
+// SQL condition for a profile brought back into the queue of the user bound to ?1 by an undo
+pub const RESTORED_SQL: &str = "EXISTS (SELECT 1 FROM swipe_undos
+    WHERE swipe_undos.user_id = ?1 AND swipe_undos.target_user_id = profiles.user_id)";
+
+// SQL condition for a profile that has super liked the user bound to ?1
+pub const SUPER_LIKED_SQL: &str = "EXISTS (SELECT 1 FROM swipes
+    WHERE swipes.user_id = profiles.user_id AND swipes.target_user_id = ?1 AND swipes.super_like = 1)";
+
+// Structure for swipe requests
+// direction is "like", "super_like" or "pass"; prompt_answer_id may only be given with a like or super like
+#[derive(Deserialize)]
+pub struct SwipeRequest {
+    pub user_id: i32,
+    pub target_user_id: i32,
+    pub direction: String,
+    pub prompt_answer_id: Option<i64>,
+}
+
+// Structure for undo requests
+#[derive(Deserialize)]
+pub struct UndoRequest {
+    pub user_id: i32,
+}
+
+// Helper function for the response sent for a swipe that cannot be recorded
+fn invalid_swipe(message: &str) -> HttpResponse {
+    HttpResponse::BadRequest().json(serde_json::json!({
+        "success": false,
+        "message": message
+    }))
+}
+
+// Helper function for telling a user over their WebSocket that a match with them was removed
+fn notify_match_removed(state: &AppState, user_id: i32, matched_user_id: i32) {
+    let notice = serde_json::json!({
+        "type": "match_removed",
+        "payload": {"user_id": user_id, "matched_user_id": matched_user_id}
+    })
+    .to_string();
+    let clients = state.clients.lock().unwrap();
+    if let Some(addr) = clients.get(&user_id) {
+        addr.do_send(WsMessage(notice));
+    }
+}
+
+// Helper function for checking if one user has liked another
+pub fn has_liked(conn: &Connection, user_id: i32, target_user_id: i32) -> bool {
+    conn.query_row(
+        "SELECT 1 FROM swipes WHERE user_id = ?1 AND target_user_id = ?2 AND direction = 'like'",
+        params![user_id, target_user_id],
+        |_| Ok(()),
+    )
+    .is_ok()
+}
+
+// API for swiping on a profile: POST /swipes
+// Saves the like, super like or pass, replacing any earlier swipe by the same user on the same profile
+// A like can name one of the target's prompt answers to show what it was for
+// Likes and super likes are limited per day by the user's plan; repeating a swipe already made does not use the quota
+// A super like puts the user at the top of the target's queue
+// Creates the match when the target has already liked the user back
+// Passing on a profile the user had liked removes their match, as undo does, so the like cannot be
+// swapped for a pass to get its quota back while keeping the match
+// Returns whether the swipe made or removed a match
+pub async fn create_swipe(
+    data: web::Json<SwipeRequest>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let swipe = data.into_inner();
+    if swipe.user_id == swipe.target_user_id {
+        return invalid_swipe("Cannot swipe on yourself");
+    }
+    let quota = match swipe.direction.as_str() {
+        "like" => Some(entitlements::Quota::Like),
+        "super_like" => Some(entitlements::Quota::SuperLike),
+        "pass" => None,
+        _ => return invalid_swipe("direction must be like, super_like or pass"),
+    };
+    let super_like = swipe.direction == "super_like";
+    let direction = if super_like {
+        "like"
+    } else {
+        swipe.direction.as_str()
+    };
+    if swipe.direction == "pass" && swipe.prompt_answer_id.is_some() {
+        return invalid_swipe("Only likes can be attached to a prompt answer");
+    }
+    let conn = state.db_conn.lock().unwrap();
+    if let Some(r) = moderation::account_restriction(&conn, swipe.user_id) {
+        return moderation::restricted_response(&r);
+    }
+    let target_exists = conn
+        .query_row(
+            "SELECT 1 FROM profiles WHERE user_id = ?1",
+            params![swipe.target_user_id],
+            |_| Ok(()),
+        )
+        .is_ok();
+    if !target_exists
+        || deletion::deletion_due_at(&conn, swipe.target_user_id).is_some()
+        || visibility::hidden_from(&conn, Some(swipe.user_id), swipe.target_user_id)
+    {
+        return HttpResponse::NotFound().json(serde_json::json!({
+            "success": false,
+            "message": "Profile not found"
+        }));
+    }
+    if blocks::is_blocked(&conn, swipe.user_id, swipe.target_user_id) {
+        return HttpResponse::Forbidden().json(serde_json::json!({
+            "success": false,
+            "message": "Cannot swipe on this user"
+        }));
+    }
+    if let Some(answer_id) = swipe.prompt_answer_id {
+        let on_target = conn
+            .query_row(
+                "SELECT 1 FROM prompt_answers WHERE id = ?1 AND user_id = ?2",
+                params![answer_id, swipe.target_user_id],
+                |_| Ok(()),
+            )
+            .is_ok();
+        if !on_target {
+            return invalid_swipe("That prompt answer is not on this profile");
+        }
+    }
+    let ts = Utc::now().timestamp_millis();
+    let repeated = conn
+        .query_row(
+            "SELECT 1 FROM swipes
+             WHERE user_id = ?1 AND target_user_id = ?2 AND direction = ?3 AND super_like = ?4",
+            params![swipe.user_id, swipe.target_user_id, direction, super_like],
+            |_| Ok(()),
+        )
+        .is_ok();
+    if let Some(quota) = quota
+        && !repeated
+        && let Some(limit) = entitlements::quota_reached(&conn, swipe.user_id, quota, ts)
+    {
+        return entitlements::quota_exceeded(quota, limit, ts);
+    }
+    let (lower_id, higher_id) = if swipe.user_id < swipe.target_user_id {
+        (swipe.user_id, swipe.target_user_id)
+    } else {
+        (swipe.target_user_id, swipe.user_id)
+    };
+    let result = conn.unchecked_transaction().and_then(|tx| {
+        let was_liked = has_liked(&tx, swipe.user_id, swipe.target_user_id);
+        tx.execute(
+            "INSERT INTO swipes (user_id, target_user_id, direction, super_like, prompt_answer_id, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT(user_id, target_user_id) DO UPDATE SET
+             direction = ?3, super_like = ?4, prompt_answer_id = ?5, created_at = ?6",
+            params![
+                swipe.user_id,
+                swipe.target_user_id,
+                direction,
+                super_like,
+                swipe.prompt_answer_id,
+                ts
+            ],
+        )?;
+        let matched = direction == "like" && has_liked(&tx, swipe.target_user_id, swipe.user_id);
+        if matched {
+            crate::insert_match(&tx, swipe.user_id, swipe.target_user_id, ts)?;
+        }
+        let unmatched = was_liked
+            && direction == "pass"
+            && tx.execute(
+                "DELETE FROM matches WHERE user_id = ?1 AND matched_user_id = ?2",
+                params![lower_id, higher_id],
+            )? > 0;
+        tx.commit()?;
+        Ok((matched, unmatched))
+    });
+    drop(conn);
+    match result {
+        Ok((matched, unmatched)) => {
+            if unmatched {
+                notify_match_removed(&state, swipe.target_user_id, swipe.user_id);
+            }
+            info!(
+                "User {} swiped {} on user {}{}",
+                swipe.user_id,
+                swipe.direction,
+                swipe.target_user_id,
+                if matched {
+                    " and matched"
+                } else if unmatched {
+                    " and unmatched"
+                } else {
+                    ""
+                }
+            );
+            HttpResponse::Ok().json(serde_json::json!({
+                "success": true,
+                "matched": matched,
+                "unmatched": unmatched
+            }))
+        }
+        Err(e) => HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
+    }
+}
+
+// API for taking back the last swipe: POST /swipes/undo
+// Only the user's most recent swipe can be undone, and only within UNDO_WINDOW_MS of making it
+// The profile goes back to the front of the user's queue
+// If the swipe made a match, the match is removed and the other user is told over their WebSocket
+// Limited per day by the user's plan
+pub async fn undo_swipe(
+    data: web::Json<UndoRequest>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let uid = data.user_id;
+    let conn = state.db_conn.lock().unwrap();
+    if let Some(r) = moderation::account_restriction(&conn, uid) {
+        return moderation::restricted_response(&r);
+    }
+    let last = conn.query_row(
+        "SELECT target_user_id, CASE WHEN super_like = 1 THEN 'super_like' ELSE direction END, created_at
+         FROM swipes WHERE user_id = ?1 ORDER BY created_at DESC, id DESC LIMIT 1",
+        params![uid],
+        |row| {
+            Ok((
+                row.get::<_, i32>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, i64>(2)?,
+            ))
+        },
+    );
+    let (target, direction, swiped_at) = match last {
+        Ok(last) => last,
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return HttpResponse::NotFound().json(serde_json::json!({
+                "success": false,
+                "message": "No swipe to undo"
+            }));
+        }
+        Err(_) => return HttpResponse::InternalServerError().body("Database error"),
+    };
+    let now = Utc::now().timestamp_millis();
+    if now - swiped_at > UNDO_WINDOW_MS {
+        return HttpResponse::Gone().json(serde_json::json!({
+            "success": false,
+            "message": "That swipe can no longer be undone"
+        }));
+    }
+    let quota = entitlements::Quota::Undo;
+    let limit = quota.daily_limit(entitlements::plan(&conn, uid));
+    let used = entitlements::used_today(&conn, uid, quota, now);
+    if let Some(limit) = limit
+        && used >= limit
+    {
+        return entitlements::quota_exceeded(quota, limit, now);
+    }
+    let (lower_id, higher_id) = if uid < target {
+        (uid, target)
+    } else {
+        (target, uid)
+    };
+    let result = conn.unchecked_transaction().and_then(|tx| {
+        tx.execute(
+            "DELETE FROM swipes WHERE user_id = ?1 AND target_user_id = ?2",
+            params![uid, target],
+        )?;
+        // A match made by this swipe has the swipe's timestamp; older matches are left alone
+        let unmatched = tx.execute(
+            "DELETE FROM matches WHERE user_id = ?1 AND matched_user_id = ?2 AND timestamp = ?3",
+            params![lower_id, higher_id, swiped_at],
+        )? > 0;
+        tx.execute(
+            "INSERT INTO swipe_undos (user_id, target_user_id, direction, undone_at)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![uid, target, direction, now],
+        )?;
+        tx.commit()?;
+        Ok(unmatched)
+    });
+    let unmatched = match result {
+        Ok(unmatched) => unmatched,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
+    };
+    if unmatched {
+        notify_match_removed(&state, target, uid);
+    }
+    info!(
+        "User {} undid their {} on user {}{}",
+        uid,
+        direction,
+        target,
+        if unmatched { " and unmatched" } else { "" }
+    );
+    HttpResponse::Ok().json(serde_json::json!({
+        "success": true,
+        "target_user_id": target,
+        "direction": direction,
+        "unmatched": unmatched,
+        "undos_left": limit.map(|l| l - used - 1)
+    }))
+}
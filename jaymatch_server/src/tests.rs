@@ -0,0 +1,4085 @@
+/*
+Name: JayMatch endpoint tests
+Description: Runs requests against the real routes using an in-memory database
+Pre/Post Conditions: Each test builds its own database, so tests do not share state.
+Errors: Failed assertions fail the test.
+*/
+
+use crate::media::MediaStore;
+use crate::{
+    AppState, attributes, boosts, configure_routes, db, deletion, entitlements, exports, images,
+    impressions, interests, media, photos, prompts, sessions,
+};
+use actix_web::{App, test, web};
+use rusqlite::{Connection, params};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// Helper function for creating app state backed by a fresh in-memory database
+// Media, staged uploads and exports are kept in a directory of the test's own under the system temp directory
+fn new_test_state() -> AppState {
+    let conn = Connection::open_in_memory().unwrap();
+    db::init_db(&conn).unwrap();
+    let root = test_dir("state");
+    AppState {
+        media: Arc::new(media::LocalStore::new(root.join("media"))),
+        staging_dir: root.join("staging"),
+        export_dir: root.join("exports"),
+        ..AppState::new(conn)
+    }
+}
+
+// Helper function for new_test_state shared with the app as web::Data
+fn test_state() -> web::Data<AppState> {
+    web::Data::new(new_test_state())
+}
+
+// Helper function for removing the directory a test's state writes files to
+fn remove_test_files(state: &AppState) {
+    if let Some(root) = state.staging_dir.parent() {
+        let _ = fs::remove_dir_all(root);
+    }
+}
+
+// Helper function for the Authorization header of a new session for a user
+// Lets tests act as a user without logging in through POST /login
+fn bearer(state: &AppState, user_id: i32) -> (&'static str, String) {
+    let token = sessions::create_session(&state.db_conn.lock().unwrap(), user_id).unwrap();
+    ("Authorization", format!("Bearer {}", token))
+}
+
+// Helper function for a unique path under the system temp directory
+// Lets tests write files without touching the repository or each other
+fn test_dir(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "jaymatch-{}-{}",
+        name,
+        uuid::Uuid::new_v4().simple()
+    ))
+}
+
+// Helper function for building a multipart body for POST /users/profile-picture
+// Returns the content type header value and the encoded body
+fn profile_picture_body(user_id: i32, filename: &str, image: &[u8]) -> (String, Vec<u8>) {
+    let boundary = "jaymatchtestboundary";
+    let mut body = Vec::new();
+    body.extend_from_slice(
+        format!(
+            "--{b}\r\nContent-Disposition: form-data; name=\"user_id\"\r\n\r\n{id}\r\n\
+             --{b}\r\nContent-Disposition: form-data; name=\"image\"; filename=\"{f}\"\r\n\
+             Content-Type: application/octet-stream\r\n\r\n",
+            b = boundary,
+            id = user_id,
+            f = filename
+        )
+        .as_bytes(),
+    );
+    body.extend_from_slice(image);
+    body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+    (format!("multipart/form-data; boundary={}", boundary), body)
+}
+
+// Helper function for listing the staged uploads and the files in a test's local media store
+fn upload_files(state: &AppState) -> BTreeSet<String> {
+    let root = state.staging_dir.parent().unwrap();
+    let stored = root.join("media").join(photos::KEY_PREFIX);
+    [state.staging_dir.as_path(), stored.as_path()]
+        .iter()
+        .flat_map(|dir| fs::read_dir(dir).into_iter().flatten())
+        .filter_map(|e| e.ok())
+        .map(|e| e.path().to_string_lossy().to_string())
+        .collect()
+}
+
+// Helper function for encoding a solid colour PNG of the given size
+fn png_image(width: u32, height: u32) -> Vec<u8> {
+    let mut out = Vec::new();
+    image::RgbImage::from_pixel(width, height, image::Rgb([200, 30, 30]))
+        .write_to(&mut std::io::Cursor::new(&mut out), image::ImageFormat::Png)
+        .unwrap();
+    out
+}
+
+// Helper function for counting rows that match a query
+fn count(conn: &Connection, sql: &str, user_id: i32) -> i64 {
+    conn.query_row(sql, params![user_id], |row| row.get(0))
+        .unwrap()
+}
+
+#[actix_web::test]
+async fn delete_user_leaves_no_orphaned_rows() {
+    let state = test_state();
+    let app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(configure_routes),
+    )
+    .await;
+    for name in ["alice", "bob", "carol"] {
+        let req = test::TestRequest::post()
+            .uri("/users/new")
+            .set_json(serde_json::json!({
+                "name": name,
+                "password": "pw",
+                "email": format!("{}@ku.edu", name)
+            }))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+    let requests = vec![
+        (
+            "/matches",
+            serde_json::json!({"user_id": 1, "matched_user_id": 2}),
+        ),
+        (
+            "/messages",
+            serde_json::json!({"sender_id": 1, "receiver_id": 2, "content": "hi"}),
+        ),
+        (
+            "/messages",
+            serde_json::json!({"sender_id": 2, "receiver_id": 1, "content": "hey"}),
+        ),
+        (
+            "/reports",
+            serde_json::json!({"reporter_id": 1, "reported_user_id": 2, "report_type": "spam"}),
+        ),
+        (
+            "/reports",
+            serde_json::json!({"reporter_id": 2, "reported_user_id": 1, "report_type": "other"}),
+        ),
+        (
+            "/login",
+            serde_json::json!({"email": "alice@ku.edu", "password": "pw"}),
+        ),
+    ];
+    for (uri, body) in requests {
+        let req = test::TestRequest::post()
+            .uri(uri)
+            .set_json(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success(), "POST {} failed", uri);
+    }
+    let req = test::TestRequest::post()
+        .uri("/blocks")
+        .insert_header(bearer(&state, 1))
+        .set_json(serde_json::json!({"blocked_user_id": 3}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let req = test::TestRequest::put()
+        .uri("/preferences/1")
+        .set_json(serde_json::json!({"min_age": 18}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let req = test::TestRequest::put()
+        .uri("/profiles/1/prompts")
+        .set_json(serde_json::json!({"answers": [{"prompt_id": 1, "answer": "Clinton Lake"}]}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    for (user_id, target_user_id) in [(1, 2), (2, 1)] {
+        let req = test::TestRequest::post()
+            .uri("/swipes")
+            .set_json(serde_json::json!({
+                "user_id": user_id,
+                "target_user_id": target_user_id,
+                "direction": "pass"
+            }))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+    let req = test::TestRequest::post()
+        .uri("/admin/reports/2/notes")
+        .insert_header((crate::ADMIN_HEADER, crate::ADMIN_PASSWORD))
+        .set_json(serde_json::json!({"note": "looking into it"}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let (content_type, body) = profile_picture_body(1, "me.png", &png_image(400, 300));
+    let req = test::TestRequest::post()
+        .uri("/users/profile-picture")
+        .insert_header(("content-type", content_type))
+        .set_payload(body)
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let picture: String = state
+        .db_conn
+        .lock()
+        .unwrap()
+        .query_row(
+            "SELECT profile_picture FROM profiles WHERE user_id = 1",
+            params![],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert!(state.media.get(&picture).is_ok());
+    let thumbnail = images::thumbnail_path(&picture, "small");
+    assert!(state.media.get(&thumbnail).is_ok());
+
+    let req = test::TestRequest::post()
+        .uri("/delete_user")
+        .set_json(serde_json::json!({"email": "alice@ku.edu", "password": "pw"}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let req = test::TestRequest::get()
+        .uri("/profiles/1")
+        .insert_header(bearer(&state, 2))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 404);
+
+    assert_eq!(
+        count(
+            &state.db_conn.lock().unwrap(),
+            "SELECT COUNT(*) FROM profiles WHERE user_id = ?1",
+            1
+        ),
+        1
+    );
+    let (purged, files) =
+        deletion::purge_expired_deletions(&state.db_conn.lock().unwrap(), i64::MAX).unwrap();
+    assert_eq!(purged, 1);
+    assert_eq!(files.media_keys, vec![picture.clone()]);
+    assert!(state.media.get(&thumbnail).is_ok());
+    deletion::remove_files(&state, files).await;
+    assert!(upload_files(&state).is_empty());
+    let conn = state.db_conn.lock().unwrap();
+    let checks = [
+        "SELECT COUNT(*) FROM profiles WHERE user_id = ?1",
+        "SELECT COUNT(*) FROM matches WHERE user_id = ?1 OR matched_user_id = ?1",
+        "SELECT COUNT(*) FROM messages WHERE sender_id = ?1 OR receiver_id = ?1",
+        "SELECT COUNT(*) FROM preferences WHERE user_id = ?1",
+        "SELECT COUNT(*) FROM blocked_users WHERE user_id = ?1 OR blocked_user_id = ?1",
+        "SELECT COUNT(*) FROM reports WHERE reporter_id = ?1 OR reported_user_id = ?1",
+        "SELECT COUNT(*) FROM sessions WHERE user_id = ?1",
+        "SELECT COUNT(*) FROM prompt_answers WHERE user_id = ?1",
+        "SELECT COUNT(*) FROM swipes WHERE user_id = ?1 OR target_user_id = ?1",
+        "SELECT COUNT(*) FROM swipe_undos WHERE user_id = ?1 OR target_user_id = ?1",
+        "SELECT COUNT(*) FROM subscriptions WHERE user_id = ?1",
+        "SELECT COUNT(*) FROM boosts WHERE user_id = ?1",
+        "SELECT COUNT(*) FROM boost_credits WHERE user_id = ?1",
+        "SELECT COUNT(*) FROM profile_impressions WHERE user_id = ?1",
+        "SELECT COUNT(*) FROM impression_viewers WHERE user_id = ?1 OR viewer_id = ?1",
+    ];
+    for sql in checks {
+        assert_eq!(count(&conn, sql, 1), 0, "orphaned rows left by: {}", sql);
+    }
+    let violations = conn
+        .prepare("PRAGMA foreign_key_check")
+        .unwrap()
+        .query_map(params![], |_| Ok(()))
+        .unwrap()
+        .count();
+    assert_eq!(violations, 0);
+    // Reports are kept as evidence, with the deleted user's ID cleared
+    assert_eq!(
+        count(
+            &conn,
+            "SELECT COUNT(*) FROM reports
+             WHERE ?1 IN (reporter_id, reported_user_id) AND (reporter_id IS NULL OR reported_user_id IS NULL)",
+            2
+        ),
+        2
+    );
+    assert_eq!(
+        count(
+            &conn,
+            "SELECT COUNT(*) FROM report_notes WHERE report_id = ?1",
+            2
+        ),
+        1
+    );
+    assert_eq!(
+        count(
+            &conn,
+            "SELECT COUNT(*) FROM profiles WHERE user_id != ?1",
+            1
+        ),
+        2
+    );
+    assert!(state.media.get(&picture).is_err());
+    assert!(state.media.get(&thumbnail).is_err());
+    remove_test_files(&state);
+}
+
+#[actix_web::test]
+async fn init_db_upgrades_legacy_tables_and_drops_orphans() {
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch(
+        "PRAGMA foreign_keys = OFF;
+        CREATE TABLE profiles (
+            user_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            email TEXT UNIQUE NOT NULL,
+            password TEXT NOT NULL,
+            name TEXT, age INTEGER, major TEXT, year TEXT, bio TEXT,
+            interests TEXT, profile_picture TEXT, gender TEXT, is_felon INTEGER
+        );
+        CREATE TABLE matches (
+            user_id INTEGER NOT NULL,
+            matched_user_id INTEGER NOT NULL,
+            timestamp INTEGER NOT NULL,
+            PRIMARY KEY (user_id, matched_user_id),
+            FOREIGN KEY(user_id) REFERENCES profiles(user_id),
+            FOREIGN KEY(matched_user_id) REFERENCES profiles(user_id)
+        );
+        CREATE TABLE messages (
+            id INTEGER PRIMARY KEY,
+            sender_id INTEGER NOT NULL,
+            receiver_id INTEGER NOT NULL,
+            content TEXT NOT NULL,
+            timestamp INTEGER NOT NULL
+        );
+        INSERT INTO profiles (email, password) VALUES ('a@ku.edu', 'pw'), ('b@ku.edu', 'pw');
+        INSERT INTO matches VALUES (1, 2, 0), (1, 99, 0);
+        INSERT INTO messages (sender_id, receiver_id, content, timestamp) VALUES (1, 2, 'hi', 0), (99, 1, 'gone', 0);",
+    )
+    .unwrap();
+    db::init_db(&conn).unwrap();
+    assert_eq!(count(&conn, "SELECT COUNT(*) FROM matches WHERE ?1", 1), 1);
+    assert_eq!(count(&conn, "SELECT COUNT(*) FROM messages WHERE ?1", 1), 1);
+    conn.execute("DELETE FROM profiles WHERE user_id = 2", params![])
+        .unwrap();
+    assert_eq!(count(&conn, "SELECT COUNT(*) FROM matches WHERE ?1", 1), 0);
+    assert_eq!(count(&conn, "SELECT COUNT(*) FROM messages WHERE ?1", 1), 0);
+}
+
+#[actix_web::test]
+async fn init_db_keeps_reports_on_databases_that_cascaded_them() {
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch(
+        "PRAGMA foreign_keys = OFF;
+        CREATE TABLE profiles (
+            user_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            email TEXT UNIQUE NOT NULL,
+            password TEXT NOT NULL,
+            name TEXT, age INTEGER, major TEXT, year TEXT, bio TEXT,
+            interests TEXT, profile_picture TEXT, gender TEXT, is_felon INTEGER
+        );
+        CREATE TABLE reports (
+            id INTEGER PRIMARY KEY,
+            reporter_id INTEGER NOT NULL,
+            reported_user_id INTEGER NOT NULL,
+            report_type TEXT NOT NULL,
+            message TEXT,
+            snapshot TEXT NOT NULL,
+            status TEXT NOT NULL DEFAULT 'open',
+            resolution TEXT,
+            created_at INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL,
+            FOREIGN KEY(reporter_id) REFERENCES profiles(user_id) ON DELETE CASCADE,
+            FOREIGN KEY(reported_user_id) REFERENCES profiles(user_id) ON DELETE CASCADE
+        );
+        CREATE TABLE report_notes (
+            id INTEGER PRIMARY KEY,
+            report_id INTEGER NOT NULL,
+            author TEXT NOT NULL,
+            note TEXT NOT NULL,
+            timestamp INTEGER NOT NULL,
+            FOREIGN KEY(report_id) REFERENCES reports(id) ON DELETE CASCADE
+        );
+        INSERT INTO profiles (email, password) VALUES ('a@ku.edu', 'pw'), ('b@ku.edu', 'pw');
+        INSERT INTO reports (reporter_id, reported_user_id, report_type, snapshot, created_at, updated_at)
+        VALUES (1, 2, 'spam', '{}', 0, 0), (1, 99, 'other', '{}', 0, 0);
+        INSERT INTO report_notes (report_id, author, note, timestamp) VALUES (1, 'admin', 'checked', 0);
+        PRAGMA user_version = 3;",
+    )
+    .unwrap();
+    db::init_db(&conn).unwrap();
+    assert_eq!(
+        count(
+            &conn,
+            "SELECT COUNT(*) FROM reports WHERE reported_user_id IS NULL AND id = ?1",
+            2
+        ),
+        1
+    );
+    conn.execute("DELETE FROM profiles WHERE user_id = 2", params![])
+        .unwrap();
+    assert_eq!(count(&conn, "SELECT COUNT(*) FROM reports WHERE ?1", 1), 2);
+    assert_eq!(
+        count(
+            &conn,
+            "SELECT COUNT(*) FROM report_notes WHERE report_id = ?1",
+            1
+        ),
+        1
+    );
+    assert!(
+        conn.execute("UPDATE reports SET snapshot = 'changed'", params![])
+            .is_err()
+    );
+    let violations = conn
+        .prepare("PRAGMA foreign_key_check")
+        .unwrap()
+        .query_map(params![], |_| Ok(()))
+        .unwrap()
+        .count();
+    assert_eq!(violations, 0);
+}
+
+#[actix_web::test]
+async fn profile_picture_rejects_malformed_multipart() {
+    let state = test_state();
+    let app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(configure_routes),
+    )
+    .await;
+    let req = test::TestRequest::post()
+        .uri("/users/new")
+        .set_json(serde_json::json!({"name": "a", "password": "pw", "email": "a@ku.edu"}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let boundary = "multipart/form-data; boundary=jaymatchtestboundary";
+    let (_, valid) = profile_picture_body(1, "me.png", &png_image(300, 300));
+    let truncated = valid[..valid.len() / 2].to_vec();
+    let cases: Vec<(&str, &str, Vec<u8>)> = vec![
+        ("not multipart", "application/json", b"{}".to_vec()),
+        ("missing boundary", "multipart/form-data", valid.clone()),
+        ("truncated body", boundary, truncated),
+        (
+            "no content disposition",
+            boundary,
+            b"--jaymatchtestboundary\r\n\r\n1\r\n--jaymatchtestboundary--\r\n".to_vec(),
+        ),
+        (
+            "non-numeric user_id",
+            boundary,
+            b"--jaymatchtestboundary\r\nContent-Disposition: form-data; name=\"user_id\"\r\n\r\nabc\r\n\
+              --jaymatchtestboundary--\r\n"
+                .to_vec(),
+        ),
+        (
+            "oversized user_id",
+            boundary,
+            format!(
+                "--jaymatchtestboundary\r\nContent-Disposition: form-data; name=\"user_id\"\r\n\r\n{}\r\n\
+                 --jaymatchtestboundary--\r\n",
+                "1".repeat(1000)
+            )
+            .into_bytes(),
+        ),
+        (
+            "missing image",
+            boundary,
+            b"--jaymatchtestboundary\r\nContent-Disposition: form-data; name=\"user_id\"\r\n\r\n1\r\n\
+              --jaymatchtestboundary--\r\n"
+                .to_vec(),
+        ),
+        (
+            "not an image",
+            boundary,
+            profile_picture_body(1, "me.png", b"not really a png").1,
+        ),
+    ];
+    for (name, content_type, body) in cases {
+        let req = test::TestRequest::post()
+            .uri("/users/profile-picture")
+            .insert_header(("content-type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400, "{}", name);
+    }
+    assert!(upload_files(&state).is_empty());
+    let conn = state.db_conn.lock().unwrap();
+    assert_eq!(
+        count(
+            &conn,
+            "SELECT COUNT(*) FROM profile_photos WHERE user_id = ?1",
+            1
+        ),
+        0
+    );
+    remove_test_files(&state);
+}
+
+#[actix_web::test]
+async fn profile_picture_over_size_cap_returns_413() {
+    let mut state = new_test_state();
+    state.max_upload_bytes = 4096;
+    let state = web::Data::new(state);
+    let app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(configure_routes),
+    )
+    .await;
+    let req = test::TestRequest::post()
+        .uri("/users/new")
+        .set_json(serde_json::json!({"name": "a", "password": "pw", "email": "a@ku.edu"}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    // Slightly over the cap, so it is only caught while streaming
+    let (content_type, body) = profile_picture_body(1, "me.png", &[0u8; 5000]);
+    let req = test::TestRequest::post()
+        .uri("/users/profile-picture")
+        .insert_header(("content-type", content_type))
+        .set_payload(body)
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 413);
+    // Far over the cap, so it is refused from the Content-Length header
+    let (content_type, body) = profile_picture_body(1, "me.png", &vec![0u8; 100_000]);
+    let req = test::TestRequest::post()
+        .uri("/users/1/photos")
+        .insert_header(("content-type", content_type))
+        .set_payload(body)
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 413);
+    assert!(upload_files(&state).is_empty());
+    let conn = state.db_conn.lock().unwrap();
+    assert_eq!(
+        count(
+            &conn,
+            "SELECT COUNT(*) FROM profile_photos WHERE user_id = ?1",
+            1
+        ),
+        0
+    );
+    remove_test_files(&state);
+}
+
+// Profile used as the starting point for the PATCH /profiles tests
+fn full_profile() -> serde_json::Value {
+    serde_json::json!({
+        "name": "Alice",
+        "age": 20,
+        "major": "Biology",
+        "year": "Junior",
+        "bio": "Hi there",
+        "interests": ["hiking", "chess"],
+        "gender": "Female",
+        "is_felon": false
+    })
+}
+
+// Sends a merge patch to PATCH /profiles/1
+// Evaluates to the status code and the JSON body, which is null for non-JSON responses
+macro_rules! send_patch {
+    ($app:expr, $patch:expr) => {{
+        let req = test::TestRequest::patch()
+            .uri("/profiles/1")
+            .insert_header(("content-type", "application/merge-patch+json"))
+            .set_payload($patch.to_string())
+            .to_request();
+        let resp = test::call_service($app, req).await;
+        let status = resp.status().as_u16();
+        let body = test::read_body(resp).await;
+        (
+            status,
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap_or(serde_json::Value::Null),
+        )
+    }};
+}
+
+// Helper function for checking merge patch semantics for one profile field
+// Setting the field changes only that field, leaving it out keeps it, and null clears it
+async fn assert_patch_field(field: &str, value: serde_json::Value, stored: serde_json::Value) {
+    let state = test_state();
+    let app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(configure_routes),
+    )
+    .await;
+    let req = test::TestRequest::post()
+        .uri("/users/new")
+        .set_json(serde_json::json!({"name": "a", "password": "pw", "email": "a@ku.edu"}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let (status, before) = send_patch!(&app, full_profile());
+    assert_eq!(status, 200);
+
+    let (status, after) = send_patch!(&app, serde_json::json!({ field: value }));
+    assert_eq!(status, 200, "setting {} failed: {}", field, after);
+    assert_eq!(after[field], stored, "{} was not set", field);
+    for (other, old) in before.as_object().unwrap() {
+        if other != field {
+            assert_eq!(&after[other], old, "setting {} changed {}", field, other);
+        }
+    }
+
+    let (status, kept) = send_patch!(&app, serde_json::json!({}));
+    assert_eq!(status, 200);
+    assert_eq!(kept, after, "an empty patch changed the profile");
+
+    let (status, cleared) = send_patch!(&app, serde_json::json!({ field: null }));
+    assert_eq!(status, 200, "clearing {} failed: {}", field, cleared);
+    assert!(cleared[field].is_null(), "{} was not cleared", field);
+    for (other, old) in after.as_object().unwrap() {
+        if other != field {
+            assert_eq!(&cleared[other], old, "clearing {} changed {}", field, other);
+        }
+    }
+}
+
+#[actix_web::test]
+async fn patch_profile_name() {
+    assert_patch_field("name", "Alicia".into(), "Alicia".into()).await;
+}
+
+#[actix_web::test]
+async fn patch_profile_age() {
+    assert_patch_field("age", 21.into(), 21.into()).await;
+}
+
+#[actix_web::test]
+async fn patch_profile_major() {
+    assert_patch_field("major", "econ".into(), "Economics".into()).await;
+}
+
+#[actix_web::test]
+async fn patch_profile_year() {
+    assert_patch_field("year", "sr".into(), "Senior".into()).await;
+}
+
+#[actix_web::test]
+async fn patch_profile_bio() {
+    assert_patch_field("bio", "New bio".into(), "New bio".into()).await;
+}
+
+#[actix_web::test]
+async fn patch_profile_interests() {
+    assert_patch_field(
+        "interests",
+        serde_json::json!(["climbing", " Chess "]),
+        // Tags not in the catalogue keep the spelling they were first saved with in full_profile
+        serde_json::json!(["Rock Climbing", "chess"]),
+    )
+    .await;
+}
+
+#[actix_web::test]
+async fn patch_profile_gender() {
+    assert_patch_field("gender", "other".into(), "Other".into()).await;
+}
+
+#[actix_web::test]
+async fn patch_profile_is_felon() {
+    assert_patch_field("is_felon", true.into(), true.into()).await;
+}
+
+#[actix_web::test]
+async fn patch_profile_email() {
+    let state = test_state();
+    let app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(configure_routes),
+    )
+    .await;
+    for email in ["a@ku.edu", "b@ku.edu"] {
+        let req = test::TestRequest::post()
+            .uri("/users/new")
+            .set_json(serde_json::json!({"name": "a", "password": "pw", "email": email}))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+    let (status, profile) = send_patch!(&app, serde_json::json!({"email": "new@ku.edu"}));
+    assert_eq!(status, 200);
+    assert_eq!(profile["email"], "new@ku.edu");
+    assert_eq!(
+        send_patch!(&app, serde_json::json!({"email": "b@ku.edu"})).0,
+        409
+    );
+    // Every account needs an email, so it cannot be cleared
+    assert_eq!(send_patch!(&app, serde_json::json!({"email": null})).0, 400);
+    assert_eq!(
+        send_patch!(&app, serde_json::json!({"email": "a@gmail.com"})).0,
+        400
+    );
+    let (_, profile) = send_patch!(&app, serde_json::json!({}));
+    assert_eq!(profile["email"], "new@ku.edu");
+}
+
+#[actix_web::test]
+async fn patch_profile_rejects_invalid_patches() {
+    let state = test_state();
+    let app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(configure_routes),
+    )
+    .await;
+    let req = test::TestRequest::post()
+        .uri("/users/new")
+        .set_json(serde_json::json!({"name": "a", "password": "pw", "email": "a@ku.edu"}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let (_, before) = send_patch!(&app, full_profile());
+    let invalid = [
+        serde_json::json!({"name": 5}),
+        serde_json::json!({"age": "twenty"}),
+        serde_json::json!({"age": 20.5}),
+        serde_json::json!({"interests": "hiking"}),
+        serde_json::json!({"interests": [1, 2]}),
+        serde_json::json!({"gender": "unknown"}),
+        serde_json::json!({"is_felon": "no"}),
+        serde_json::json!({"profile_picture": "profile_pictures/someone_else.jpg"}),
+        serde_json::json!({"password": "hunter2"}),
+        // One bad field means nothing is applied
+        serde_json::json!({"name": "Changed", "age": "twenty"}),
+        serde_json::json!(["name"]),
+    ];
+    for patch in invalid {
+        assert_eq!(send_patch!(&app, patch.clone()).0, 400, "{}", patch);
+    }
+    let (_, after) = send_patch!(&app, serde_json::json!({}));
+    assert_eq!(after, before);
+
+    let req = test::TestRequest::patch()
+        .uri("/profiles/1")
+        .insert_header(("content-type", "text/plain"))
+        .set_payload(r#"{"name": "Changed"}"#)
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 415);
+    let req = test::TestRequest::patch()
+        .uri("/profiles/99")
+        .set_json(serde_json::json!({"name": "Nobody"}))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 404);
+}
+
+#[actix_web::test]
+async fn put_profile_keeps_fields_it_is_not_given() {
+    let state = test_state();
+    let app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(configure_routes),
+    )
+    .await;
+    let req = test::TestRequest::post()
+        .uri("/users/new")
+        .set_json(serde_json::json!({"name": "a", "password": "pw", "email": "a@ku.edu"}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let (_, before) = send_patch!(&app, full_profile());
+    let req = test::TestRequest::put()
+        .uri("/profiles/1")
+        .set_json(serde_json::json!({"bio": "Updated"}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let (_, after) = send_patch!(&app, serde_json::json!({}));
+    assert_eq!(after["bio"], "Updated");
+    assert_eq!(after["major"], before["major"]);
+    assert_eq!(after["year"], before["year"]);
+}
+
+#[actix_web::test]
+async fn put_profile_cannot_point_profile_picture_at_another_users_image() {
+    let state = test_state();
+    let app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(configure_routes),
+    )
+    .await;
+    for name in ["a", "b"] {
+        let req = test::TestRequest::post()
+            .uri("/users/new")
+            .set_json(serde_json::json!({
+                "name": name,
+                "password": "pw",
+                "email": format!("{}@ku.edu", name)
+            }))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+    state
+        .db_conn
+        .lock()
+        .unwrap()
+        .execute(
+            "UPDATE profiles SET profile_picture = 'profile_pictures/b.png' WHERE user_id = 2",
+            [],
+        )
+        .unwrap();
+    let req = test::TestRequest::put()
+        .uri("/profiles/1")
+        .set_json(
+            serde_json::json!({"bio": "Mine now", "profile_picture": "profile_pictures/b.png"}),
+        )
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 400);
+    let conn = state.db_conn.lock().unwrap();
+    assert_eq!(
+        count(
+            &conn,
+            "SELECT COUNT(*) FROM profiles WHERE user_id = ?1 AND profile_picture IS NULL AND bio IS NULL",
+            1
+        ),
+        1
+    );
+    assert_eq!(
+        count(
+            &conn,
+            "SELECT COUNT(*) FROM profiles WHERE user_id = ?1 AND profile_picture = 'profile_pictures/b.png'",
+            2
+        ),
+        1
+    );
+}
+
+#[actix_web::test]
+async fn year_and_major_are_canonicalized() {
+    let state = test_state();
+    let app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(configure_routes),
+    )
+    .await;
+    for name in ["a", "b"] {
+        let req = test::TestRequest::post()
+            .uri("/users/new")
+            .set_json(serde_json::json!({
+                "name": name,
+                "password": "pw",
+                "email": format!("{}@ku.edu", name)
+            }))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+    let req = test::TestRequest::put()
+        .uri("/profiles/1")
+        .set_json(serde_json::json!({"year": "jr", "major": "CompSci"}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let (_, profile) = send_patch!(&app, serde_json::json!({}));
+    assert_eq!(profile["year"], "Junior");
+    assert_eq!(profile["major"], "Computer Science");
+    let (status, profile) = send_patch!(&app, serde_json::json!({"major": "Comp Sci"}));
+    assert_eq!(status, 200);
+    assert_eq!(profile["major"], "Computer Science");
+    for body in [
+        serde_json::json!({"major": "Basket Weaving"}),
+        serde_json::json!({"year": "twelfth"}),
+    ] {
+        let req = test::TestRequest::put()
+            .uri("/profiles/1")
+            .set_json(&body)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            400,
+            "{}",
+            body
+        );
+        assert_eq!(send_patch!(&app, body.clone()).0, 400, "{}", body);
+    }
+
+    let req = test::TestRequest::put()
+        .uri("/preferences/2")
+        .set_json(serde_json::json!({"year_preference": ["3rd year", "Junior"], "major_preference": ["cs"]}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let req = test::TestRequest::get().uri("/preferences/2").to_request();
+    let prefs: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(prefs["year_preference"], serde_json::json!(["Junior"]));
+    assert_eq!(
+        prefs["major_preference"],
+        serde_json::json!(["Computer Science"])
+    );
+    let req = test::TestRequest::get().uri("/queue/2").to_request();
+    let queue: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(queue.as_array().unwrap().len(), 1);
+    let req = test::TestRequest::put()
+        .uri("/preferences/2")
+        .set_json(serde_json::json!({"major_preference": ["Underwater Basket Weaving"]}))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 400);
+}
+
+#[actix_web::test]
+async fn init_db_normalizes_existing_years_and_majors() {
+    let conn = Connection::open_in_memory().unwrap();
+    db::init_db(&conn).unwrap();
+    conn.execute_batch(
+        "INSERT INTO profiles (email, password, year, major) VALUES
+            ('a@ku.edu', 'pw', 'sophomore', 'cs'),
+            ('b@ku.edu', 'pw', 'Senior', 'Comp Sci'),
+            ('c@ku.edu', 'pw', 'eventually', 'Basket Weaving');
+        INSERT INTO preferences (user_id, year_preference, major_preference)
+            VALUES (1, '[\"sr\", \"nope\"]', '[\"nope\"]');
+        PRAGMA user_version = 1;",
+    )
+    .unwrap();
+    db::init_db(&conn).unwrap();
+    let rows: Vec<(Option<String>, Option<String>)> = conn
+        .prepare("SELECT year, major FROM profiles ORDER BY user_id")
+        .unwrap()
+        .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))
+        .unwrap()
+        .map(|r| r.unwrap())
+        .collect();
+    let text = |s: &str| Some(s.to_string());
+    assert_eq!(
+        rows,
+        vec![
+            (text("Sophomore"), text("Computer Science")),
+            (text("Senior"), text("Computer Science")),
+            (None, None),
+        ]
+    );
+    let prefs: (Option<String>, Option<String>) = conn
+        .query_row(
+            "SELECT year_preference, major_preference FROM preferences WHERE user_id = 1",
+            params![],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .unwrap();
+    assert_eq!(prefs, (text("[\"Senior\"]"), None));
+    // Values that could not be recognized are kept for review instead of being lost
+    let kept = |sql: &str| -> serde_json::Value {
+        let text: Option<String> = conn.query_row(sql, params![], |row| row.get(0)).unwrap();
+        text.map(|t| serde_json::from_str(&t).unwrap())
+            .unwrap_or_default()
+    };
+    assert_eq!(
+        kept("SELECT unrecognized_values FROM profiles WHERE user_id = 3"),
+        serde_json::json!({"year": "eventually", "major": "Basket Weaving"})
+    );
+    assert!(kept("SELECT unrecognized_values FROM profiles WHERE user_id = 1").is_null());
+    assert_eq!(
+        kept("SELECT unrecognized_values FROM preferences WHERE user_id = 1"),
+        serde_json::json!({"year_preference": ["nope"], "major_preference": ["nope"]})
+    );
+}
+
+#[actix_web::test]
+async fn attribute_schema_drives_validation_and_filters() {
+    let schema = attributes::parse_schema(
+        r#"{"attributes": [
+            {"name": "pronouns", "label": "Pronouns", "type": "text", "max_length": 20},
+            {"name": "looking_for", "label": "Looking for", "type": "choice",
+             "values": ["Friends", "Dating"], "aliases": {"relationship": "Dating"},
+             "filterable": true},
+            {"name": "height", "label": "Height", "type": "integer", "min": 120, "max": 230,
+             "filterable": true, "dealbreaker": true}
+        ]}"#,
+    )
+    .unwrap();
+    let pronouns = schema.get("pronouns").unwrap();
+    assert_eq!(
+        pronouns.validate(&serde_json::json!("they/them")).unwrap(),
+        "they/them"
+    );
+    assert!(
+        pronouns
+            .validate(&serde_json::json!("x".repeat(21)))
+            .is_err()
+    );
+    let looking_for = schema.get("looking_for").unwrap();
+    assert_eq!(
+        looking_for
+            .validate(&serde_json::json!("Relationship"))
+            .unwrap(),
+        "Dating"
+    );
+    assert!(
+        looking_for
+            .validate(&serde_json::json!("Pen pals"))
+            .is_err()
+    );
+    assert!(
+        schema
+            .validate_filters(
+                serde_json::json!({"min_height": 190, "max_height": 150})
+                    .as_object()
+                    .unwrap()
+            )
+            .is_err()
+    );
+
+    let filters = schema
+        .validate_filters(
+            serde_json::json!({"looking_for_preference": ["dating"], "min_height": 160})
+                .as_object()
+                .unwrap(),
+        )
+        .unwrap();
+    assert_eq!(
+        filters["looking_for_preference"],
+        serde_json::json!(["Dating"])
+    );
+    assert!(filters["max_height"].is_null());
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute(
+        "CREATE TABLE profiles (user_id INTEGER PRIMARY KEY, attributes TEXT)",
+        params![],
+    )
+    .unwrap();
+    for (id, attributes) in [
+        (
+            1,
+            serde_json::json!({"looking_for": "Friends", "height": 170}),
+        ),
+        (
+            2,
+            serde_json::json!({"looking_for": "Dating", "height": 150}),
+        ),
+        (
+            3,
+            serde_json::json!({"looking_for": "Dating", "height": 180}),
+        ),
+        (4, serde_json::json!({"height": 165})),
+    ] {
+        conn.execute(
+            "INSERT INTO profiles (user_id, attributes) VALUES (?1, ?2)",
+            params![id, attributes.to_string()],
+        )
+        .unwrap();
+    }
+    let filter_sql = schema.filter_sql(&filters, 1);
+    let kept: Vec<i64> = conn
+        .prepare(&format!(
+            "SELECT user_id FROM profiles WHERE {} ORDER BY {}, user_id",
+            filter_sql.dealbreakers, filter_sql.misses
+        ))
+        .unwrap()
+        .query_map(rusqlite::params_from_iter(filter_sql.params), |row| {
+            row.get(0)
+        })
+        .unwrap()
+        .map(|r| r.unwrap())
+        .collect();
+    assert_eq!(kept, vec![3, 1, 4]);
+
+    for bad in [
+        r#"{"attributes": [{"name": "email", "label": "Email", "type": "text"}]}"#,
+        r#"{"attributes": [{"name": "vibe", "label": "Vibe", "type": "choice"}]}"#,
+        r#"{"attributes": [{"name": "motto", "label": "Motto", "type": "text", "filterable": true}]}"#,
+        r#"{"attributes": [{"name": "pets", "label": "Pets", "type": "boolean", "dealbreaker": true}]}"#,
+        r#"{"attributes": [{"name": "tea", "label": "Tea", "type": "choice", "values": ["Green"],
+            "aliases": {"black": "Black"}}]}"#,
+    ] {
+        assert!(attributes::parse_schema(bad).is_err(), "{}", bad);
+    }
+}
+
+#[actix_web::test]
+async fn preferences_follow_the_attribute_schema() {
+    let state = test_state();
+    let app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(configure_routes),
+    )
+    .await;
+    let req = test::TestRequest::get()
+        .uri("/preference-options")
+        .to_request();
+    let options: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    for attr in attributes::schema().filterable() {
+        let listed = options["attributes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|a| a["name"] == attr.name.as_str())
+            .unwrap();
+        assert_eq!(listed["filter_keys"], serde_json::json!(attr.filter_keys()));
+    }
+    assert_eq!(
+        options["gender_options"],
+        serde_json::json!(["Male", "Female", "Other"])
+    );
+
+    for (id, (name, age)) in [("a", 20), ("b", 30), ("c", 40)].into_iter().enumerate() {
+        let req = test::TestRequest::post()
+            .uri("/users/new")
+            .set_json(serde_json::json!({
+                "name": name,
+                "password": "pw",
+                "email": format!("{}@ku.edu", name)
+            }))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let req = test::TestRequest::put()
+            .uri(&format!("/profiles/{}", id + 1))
+            .set_json(serde_json::json!({"age": age, "gender": "female"}))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+    let req = test::TestRequest::put()
+        .uri("/profiles/1")
+        .set_json(serde_json::json!({"age": 12}))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 400);
+
+    let req = test::TestRequest::put()
+        .uri("/preferences/1")
+        .set_json(serde_json::json!({"min_age": 25, "gender_preference": ["f"]}))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 400);
+    let req = test::TestRequest::put()
+        .uri("/preferences/1")
+        .set_json(serde_json::json!({"min_age": 25, "gender_preference": ["female"]}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let req = test::TestRequest::get().uri("/preferences/1").to_request();
+    let prefs: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(prefs["user_id"], 1);
+    assert_eq!(prefs["min_age"], 25);
+    assert!(prefs["max_age"].is_null());
+    assert_eq!(prefs["gender_preference"], serde_json::json!(["Female"]));
+    let req = test::TestRequest::get().uri("/queue/1").to_request();
+    let queue: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let mut ages: Vec<i64> = queue
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|p| p["age"].as_i64().unwrap())
+        .collect();
+    ages.sort();
+    assert_eq!(ages, vec![30, 40]);
+
+    let req = test::TestRequest::put()
+        .uri("/preferences/1")
+        .set_json(serde_json::json!({"min_age": 50, "max_age": 45}))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 400);
+    let req = test::TestRequest::put()
+        .uri("/preferences/1")
+        .set_json(serde_json::json!([]))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 400);
+}
+
+#[actix_web::test]
+async fn dealbreakers_are_applied_before_the_queue_is_limited() {
+    let state = test_state();
+    let app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(configure_routes),
+    )
+    .await;
+    let req = test::TestRequest::post()
+        .uri("/users/new")
+        .set_json(serde_json::json!({"name": "a", "password": "pw", "email": "a@ku.edu"}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let req = test::TestRequest::put()
+        .uri("/preferences/1")
+        .set_json(serde_json::json!({"gender_preference": ["Female"]}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+
+    // 150 profiles that super liked the user rank first but fail the gender dealbreaker
+    {
+        let conn = state.db_conn.lock().unwrap();
+        let now = chrono::Utc::now().timestamp_millis();
+        for i in 0..150 {
+            conn.execute(
+                "INSERT INTO profiles (email, password, name, gender) VALUES (?1, 'pw', ?1, 'Male')",
+                params![format!("m{}@ku.edu", i)],
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO swipes (user_id, target_user_id, direction, super_like, created_at)
+                 VALUES (?1, 1, 'like', 1, ?2)",
+                params![conn.last_insert_rowid(), now],
+            )
+            .unwrap();
+        }
+        conn.execute(
+            "INSERT INTO profiles (email, password, name, gender) VALUES ('f@ku.edu', 'pw', 'f', 'Female')",
+            params![],
+        )
+        .unwrap();
+    }
+    let req = test::TestRequest::get().uri("/queue/1").to_request();
+    let queue: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+    let emails: Vec<&str> = queue.iter().map(|p| p["email"].as_str().unwrap()).collect();
+    assert_eq!(emails, vec!["f@ku.edu"]);
+}
+
+#[actix_web::test]
+async fn prompt_answers_and_likes_on_them() {
+    let state = test_state();
+    let app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(configure_routes),
+    )
+    .await;
+    for name in ["a", "b"] {
+        let req = test::TestRequest::post()
+            .uri("/users/new")
+            .set_json(serde_json::json!({
+                "name": name,
+                "password": "pw",
+                "email": format!("{}@ku.edu", name)
+            }))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+    let req = test::TestRequest::get().uri("/prompts").to_request();
+    let catalogue: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        catalogue.as_array().unwrap().len(),
+        prompts::CATALOGUE.len()
+    );
+    assert_eq!(catalogue[0]["text"], prompts::CATALOGUE[0]);
+
+    let answers = |ids: &[i64]| {
+        serde_json::json!({
+            "answers": ids
+                .iter()
+                .map(|id| serde_json::json!({"prompt_id": id, "answer": format!(" answer {} ", id)}))
+                .collect::<Vec<_>>()
+        })
+    };
+    for (body, status) in [
+        (answers(&[1, 2, 3, 4]), 400),
+        (answers(&[1, 1]), 400),
+        (answers(&[9999]), 400),
+        (
+            serde_json::json!({"answers": [{"prompt_id": 1, "answer": "   "}]}),
+            400,
+        ),
+        (answers(&[1, 2, 3]), 200),
+    ] {
+        let req = test::TestRequest::put()
+            .uri("/profiles/1/prompts")
+            .set_json(&body)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            status,
+            "{}",
+            body
+        );
+    }
+    let req = test::TestRequest::get().uri("/profiles/1").to_request();
+    let profile: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let saved = profile["prompts"].as_array().unwrap().clone();
+    assert_eq!(saved.len(), 3);
+    assert_eq!(saved[0]["prompt"], prompts::CATALOGUE[0]);
+    assert_eq!(saved[0]["answer"], "answer 1");
+    let req = test::TestRequest::get().uri("/queue/2").to_request();
+    let queue: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(queue[0]["prompts"], serde_json::json!(saved));
+
+    // Reordering keeps answer IDs so likes stay attached
+    let req = test::TestRequest::put()
+        .uri("/profiles/1/prompts")
+        .set_json(answers(&[3, 2]))
+        .to_request();
+    let reordered: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(reordered[0]["id"], saved[2]["id"]);
+    assert_eq!(reordered[1]["id"], saved[1]["id"]);
+    let liked_answer = saved[1]["id"].as_i64().unwrap();
+
+    for (body, status) in [
+        (
+            serde_json::json!({"user_id": 2, "target_user_id": 1, "direction": "like", "prompt_answer_id": saved[0]["id"]}),
+            400,
+        ),
+        (
+            serde_json::json!({"user_id": 2, "target_user_id": 1, "direction": "pass", "prompt_answer_id": liked_answer}),
+            400,
+        ),
+        (
+            serde_json::json!({"user_id": 2, "target_user_id": 1, "direction": "superlike"}),
+            400,
+        ),
+        (
+            serde_json::json!({"user_id": 2, "target_user_id": 2, "direction": "like"}),
+            400,
+        ),
+        (
+            serde_json::json!({"user_id": 2, "target_user_id": 7, "direction": "like"}),
+            404,
+        ),
+    ] {
+        let req = test::TestRequest::post()
+            .uri("/swipes")
+            .set_json(&body)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            status,
+            "{}",
+            body
+        );
+    }
+    let req = test::TestRequest::post()
+        .uri("/swipes")
+        .set_json(serde_json::json!({"user_id": 2, "target_user_id": 1, "direction": "like", "prompt_answer_id": liked_answer}))
+        .to_request();
+    let swipe: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(swipe["matched"], false);
+    let req = test::TestRequest::get().uri("/queue/2").to_request();
+    let queue: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(queue, serde_json::json!([]));
+    let req = test::TestRequest::post()
+        .uri("/swipes")
+        .set_json(serde_json::json!({"user_id": 1, "target_user_id": 2, "direction": "like"}))
+        .to_request();
+    let swipe: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(swipe["matched"], true);
+    let req = test::TestRequest::get().uri("/matches/2").to_request();
+    let matches: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(matches.as_array().unwrap().len(), 1);
+
+    let req = test::TestRequest::delete()
+        .uri(&format!("/profiles/1/prompts/{}", saved[2]["id"]))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let req = test::TestRequest::delete()
+        .uri(&format!("/profiles/2/prompts/{}", liked_answer))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 404);
+    let req = test::TestRequest::delete()
+        .uri(&format!("/profiles/1/prompts/{}", liked_answer))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let req = test::TestRequest::get()
+        .uri("/profiles/1/prompts")
+        .to_request();
+    let remaining: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(remaining, serde_json::json!([]));
+    let conn = state.db_conn.lock().unwrap();
+    let attached: Option<i64> = conn
+        .query_row(
+            "SELECT prompt_answer_id FROM swipes WHERE user_id = 2",
+            params![],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(attached, None);
+}
+
+#[actix_web::test]
+async fn interests_are_normalized_searchable_and_rank_the_queue() {
+    let state = test_state();
+    let app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(configure_routes),
+    )
+    .await;
+    let tags = [
+        serde_json::json!(["Bouldering", "hiking", "Chess"]),
+        serde_json::json!(["rock  climbing", "CHESS", "chess!"]),
+        serde_json::json!(["Gaming"]),
+    ];
+    for (id, (name, tags)) in ["a", "b", "c"].into_iter().zip(tags).enumerate() {
+        let req = test::TestRequest::post()
+            .uri("/users/new")
+            .set_json(serde_json::json!({
+                "name": name,
+                "password": "pw",
+                "email": format!("{}@ku.edu", name)
+            }))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let req = test::TestRequest::put()
+            .uri(&format!("/profiles/{}", id + 1))
+            .set_json(serde_json::json!({ "interests": tags }))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+    let too_many: Vec<String> = (0..=interests::MAX_INTERESTS)
+        .map(|i| format!("tag {}", i))
+        .collect();
+    for bad in [serde_json::json!(too_many), serde_json::json!(["  "])] {
+        let req = test::TestRequest::put()
+            .uri("/profiles/3")
+            .set_json(serde_json::json!({ "interests": bad }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 400);
+    }
+    let req = test::TestRequest::get().uri("/profiles/2").to_request();
+    let profile: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        profile["interests"],
+        serde_json::json!(["Rock Climbing", "Chess"])
+    );
+
+    let search = |query: &str| test::TestRequest::get().uri(&format!("/interests{}", query));
+    let found: serde_json::Value =
+        test::call_and_read_body_json(&app, search("?prefix=ro").to_request()).await;
+    assert_eq!(found[0]["name"], "Rock Climbing");
+    assert_eq!(found[0]["count"], 2);
+    let found: serde_json::Value =
+        test::call_and_read_body_json(&app, search("?prefix=BOULD").to_request()).await;
+    assert_eq!(found[0]["name"], "Rock Climbing");
+    let found: serde_json::Value =
+        test::call_and_read_body_json(&app, search("?prefix=che").to_request()).await;
+    assert_eq!(
+        found,
+        serde_json::json!([{"id": found[0]["id"], "name": "Chess", "count": 2}])
+    );
+    let found: serde_json::Value =
+        test::call_and_read_body_json(&app, search("?limit=2").to_request()).await;
+    let names: Vec<&str> = found
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|i| i["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, vec!["Chess", "Rock Climbing"]);
+
+    let req = test::TestRequest::get().uri("/queue/1").to_request();
+    let queue: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let ranked: Vec<(i64, i64)> = queue
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|p| {
+            (
+                p["user_id"].as_i64().unwrap(),
+                p["shared_interests"].as_i64().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(ranked, vec![(2, 2), (3, 0)]);
+
+    let req = test::TestRequest::put()
+        .uri("/preferences/1")
+        .set_json(serde_json::json!({"interests_preference": ["Knitting"]}))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 400);
+    let req = test::TestRequest::put()
+        .uri("/preferences/1")
+        .set_json(serde_json::json!({"interests_preference": ["video games", "gaming"]}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let req = test::TestRequest::get().uri("/preferences/1").to_request();
+    let prefs: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        prefs["interests_preference"],
+        serde_json::json!(["Video Games"])
+    );
+    let req = test::TestRequest::get().uri("/queue/1").to_request();
+    let queue: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(queue.as_array().unwrap().len(), 1);
+    assert_eq!(queue[0]["user_id"], 3);
+}
+
+#[actix_web::test]
+async fn init_db_moves_legacy_interests_into_the_catalogue() {
+    let conn = Connection::open_in_memory().unwrap();
+    db::init_db(&conn).unwrap();
+    conn.execute_batch(
+        "INSERT INTO profiles (email, password, interests) VALUES
+            ('a@ku.edu', 'pw', 'hiking, Chess,  ,HIKING'),
+            ('b@ku.edu', 'pw', '[\"film\", \"chess\"]'),
+            ('c@ku.edu', 'pw', '');
+        PRAGMA user_version = 2;",
+    )
+    .unwrap();
+    db::init_db(&conn).unwrap();
+    let mirrors: Vec<Option<String>> = conn
+        .prepare("SELECT interests FROM profiles ORDER BY user_id")
+        .unwrap()
+        .query_map(params![], |row| row.get(0))
+        .unwrap()
+        .map(|r| r.unwrap())
+        .collect();
+    assert_eq!(
+        mirrors,
+        vec![
+            Some("[\"Hiking\",\"Chess\"]".to_string()),
+            Some("[\"Movies\",\"Chess\"]".to_string()),
+            None,
+        ]
+    );
+    assert_eq!(
+        interests::list_interests(&conn, 2).unwrap(),
+        vec!["Movies", "Chess"]
+    );
+    let chess_rows: i64 = conn
+        .query_row(
+            "SELECT COUNT(*) FROM profile_interests pi JOIN interests i ON i.id = pi.interest_id
+             WHERE i.slug = 'chess'",
+            params![],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(chess_rows, 2);
+}
+
+#[actix_web::test]
+async fn onboarding_checklist_tracks_completeness() {
+    let state = test_state();
+    let app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(configure_routes),
+    )
+    .await;
+    for name in ["a", "b", "c"] {
+        let req = test::TestRequest::post()
+            .uri("/users/new")
+            .set_json(serde_json::json!({
+                "name": name,
+                "password": "pw",
+                "email": format!("{}@ku.edu", name)
+            }))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+    let req = test::TestRequest::post()
+        .uri("/login")
+        .set_json(serde_json::json!({"email": "a@ku.edu", "password": "pw"}))
+        .to_request();
+    let login: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let auth = (
+        "Authorization",
+        format!("Bearer {}", login["token"].as_str().unwrap()),
+    );
+    let req = test::TestRequest::get().uri("/me/onboarding").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 401);
+    let onboarding = || {
+        test::TestRequest::get()
+            .uri("/me/onboarding")
+            .insert_header(auth.clone())
+            .to_request()
+    };
+    let checklist: serde_json::Value = test::call_and_read_body_json(&app, onboarding()).await;
+    assert_eq!(checklist["score"], 0);
+    assert_eq!(
+        checklist["remaining"],
+        serde_json::json!([
+            "photo",
+            "bio",
+            "interests",
+            "prompts",
+            "preferences",
+            "email_verified"
+        ])
+    );
+
+    let req = test::TestRequest::put()
+        .uri("/profiles/1")
+        .set_json(serde_json::json!({
+            "bio": "Junior studying economics who spends weekends hiking Clinton Lake.",
+            "interests": ["hiking", "coffee", "chess"]
+        }))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let req = test::TestRequest::put()
+        .uri("/profiles/1/prompts")
+        .set_json(serde_json::json!({"answers": [{"prompt_id": 1, "answer": "Farmers market"}]}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let req = test::TestRequest::put()
+        .uri("/preferences/1")
+        .set_json(serde_json::json!({}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let checklist: serde_json::Value = test::call_and_read_body_json(&app, onboarding()).await;
+    assert_eq!(checklist["score"], 55);
+    assert_eq!(
+        checklist["remaining"],
+        serde_json::json!(["photo", "email_verified"])
+    );
+
+    // Profile 1 now scores above the incomplete cut-off, so it is queued ahead of
+    // profile 3 even though profile 3 shares more interests with the viewer
+    let req = test::TestRequest::put()
+        .uri("/profiles/2")
+        .set_json(serde_json::json!({"interests": ["hiking", "coffee", "chess"]}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let req = test::TestRequest::put()
+        .uri("/profiles/3")
+        .set_json(serde_json::json!({"interests": ["hiking", "coffee", "chess"]}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let req = test::TestRequest::put()
+        .uri("/profiles/1")
+        .set_json(serde_json::json!({"interests": ["hiking", "movies", "cooking"]}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let req = test::TestRequest::get().uri("/queue/2").to_request();
+    let queue: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let order: Vec<i64> = queue
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|p| p["user_id"].as_i64().unwrap())
+        .collect();
+    assert_eq!(order, vec![1, 3]);
+
+    let verify = || {
+        test::TestRequest::post()
+            .uri("/me/email-verification")
+            .insert_header(auth.clone())
+            .to_request()
+    };
+    assert_eq!(test::call_service(&app, verify()).await.status(), 202);
+    let token: String = state
+        .db_conn
+        .lock()
+        .unwrap()
+        .query_row(
+            "SELECT token FROM email_verifications WHERE user_id = 1",
+            params![],
+            |row| row.get(0),
+        )
+        .unwrap();
+    let req = test::TestRequest::get()
+        .uri("/verify-email/not-a-token")
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 404);
+    let req = test::TestRequest::get()
+        .uri(&format!("/verify-email/{}", token))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let checklist: serde_json::Value = test::call_and_read_body_json(&app, onboarding()).await;
+    assert_eq!(checklist["score"], 75);
+    assert_eq!(test::call_service(&app, verify()).await.status(), 200);
+
+    // Changing the address means verifying it again
+    let (status, _) = send_patch!(&app, serde_json::json!({"email": "a2@ku.edu"}));
+    assert_eq!(status, 200);
+    let checklist: serde_json::Value = test::call_and_read_body_json(&app, onboarding()).await;
+    assert_eq!(checklist["score"], 55);
+    assert_eq!(test::call_service(&app, verify()).await.status(), 202);
+    let token: String = {
+        let conn = state.db_conn.lock().unwrap();
+        conn.execute(
+            "UPDATE email_verifications SET expires_at = 0 WHERE user_id = 1",
+            params![],
+        )
+        .unwrap();
+        conn.query_row(
+            "SELECT token FROM email_verifications WHERE user_id = 1",
+            params![],
+            |row| row.get(0),
+        )
+        .unwrap()
+    };
+    let req = test::TestRequest::get()
+        .uri(&format!("/verify-email/{}", token))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 410);
+}
+
+#[actix_web::test]
+async fn paused_and_hidden_profiles_leave_the_queue_but_keep_matches() {
+    let state = test_state();
+    let app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(configure_routes),
+    )
+    .await;
+    for name in ["a", "b", "c"] {
+        let req = test::TestRequest::post()
+            .uri("/users/new")
+            .set_json(serde_json::json!({
+                "name": name,
+                "password": "pw",
+                "email": format!("{}@ku.edu", name)
+            }))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+    let req = test::TestRequest::post()
+        .uri("/matches")
+        .set_json(serde_json::json!({"user_id": 1, "matched_user_id": 2}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let queue_ids = |body: serde_json::Value| -> Vec<i64> {
+        body.as_array()
+            .unwrap()
+            .iter()
+            .map(|p| p["user_id"].as_i64().unwrap())
+            .collect()
+    };
+    let set_visibility = |visibility: &str| {
+        test::TestRequest::put()
+            .uri("/profiles/2/visibility")
+            .set_json(serde_json::json!({ "visibility": visibility }))
+            .to_request()
+    };
+    let view = |viewer: Option<i32>| {
+        let req = test::TestRequest::get().uri("/profiles/2");
+        match viewer {
+            Some(viewer) => req.insert_header(bearer(&state, viewer)).to_request(),
+            None => req.to_request(),
+        }
+    };
+
+    assert_eq!(
+        test::call_service(&app, set_visibility("invisible"))
+            .await
+            .status(),
+        400
+    );
+    assert!(
+        test::call_service(&app, set_visibility("paused"))
+            .await
+            .status()
+            .is_success()
+    );
+    let req = test::TestRequest::get().uri("/queue/3").to_request();
+    assert_eq!(
+        queue_ids(test::call_and_read_body_json(&app, req).await),
+        vec![1]
+    );
+    assert!(
+        test::call_service(&app, view(Some(3)))
+            .await
+            .status()
+            .is_success()
+    );
+
+    assert!(
+        test::call_service(&app, set_visibility("hidden_except_matches"))
+            .await
+            .status()
+            .is_success()
+    );
+    let req = test::TestRequest::get()
+        .uri("/profiles/2/visibility")
+        .to_request();
+    let current: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(current["visibility"], "hidden_except_matches");
+    let req = test::TestRequest::get().uri("/queue/3").to_request();
+    assert_eq!(
+        queue_ids(test::call_and_read_body_json(&app, req).await),
+        vec![1]
+    );
+    assert_eq!(test::call_service(&app, view(Some(3))).await.status(), 404);
+    assert_eq!(test::call_service(&app, view(None)).await.status(), 404);
+    assert!(
+        test::call_service(&app, view(Some(1)))
+            .await
+            .status()
+            .is_success()
+    );
+    assert!(
+        test::call_service(&app, view(Some(2)))
+            .await
+            .status()
+            .is_success()
+    );
+    let req = test::TestRequest::post()
+        .uri("/swipes")
+        .set_json(serde_json::json!({"user_id": 3, "target_user_id": 2, "direction": "like"}))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 404);
+    for (sender, receiver) in [(1, 2), (2, 1)] {
+        let req = test::TestRequest::post()
+            .uri("/messages")
+            .set_json(serde_json::json!({
+                "sender_id": sender,
+                "receiver_id": receiver,
+                "content": "still here"
+            }))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+
+    assert!(
+        test::call_service(&app, set_visibility("active"))
+            .await
+            .status()
+            .is_success()
+    );
+    let req = test::TestRequest::get().uri("/queue/3").to_request();
+    let mut ids = queue_ids(test::call_and_read_body_json(&app, req).await);
+    ids.sort();
+    assert_eq!(ids, vec![1, 2]);
+}
+
+#[actix_web::test]
+async fn hidden_profiles_reach_their_owner_and_matches_by_session() {
+    let state = test_state();
+    let app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(configure_routes),
+    )
+    .await;
+    let mut auth = Vec::new();
+    for name in ["a", "b", "c"] {
+        let req = test::TestRequest::post()
+            .uri("/users/new")
+            .set_json(serde_json::json!({
+                "name": name,
+                "password": "pw",
+                "email": format!("{}@ku.edu", name)
+            }))
+            .to_request();
+        let created: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        auth.push((
+            "Authorization",
+            format!("Bearer {}", created["token"].as_str().unwrap()),
+        ));
+    }
+    let req = test::TestRequest::post()
+        .uri("/matches")
+        .set_json(serde_json::json!({"user_id": 1, "matched_user_id": 2}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let req = test::TestRequest::put()
+        .uri("/profiles/2/visibility")
+        .set_json(serde_json::json!({"visibility": "hidden_except_matches"}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+
+    // The owner and their match fetch the profile as the frontend does, with only their session
+    for viewer in [&auth[1], &auth[0]] {
+        let req = test::TestRequest::get()
+            .uri("/profiles/2")
+            .insert_header(viewer.clone())
+            .to_request();
+        let profile: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(profile["user_id"], 2);
+    }
+    let req = test::TestRequest::get()
+        .uri("/profiles/2")
+        .insert_header(auth[2].clone())
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 404);
+    for uri in ["/profiles/2", "/profiles/2?viewer_id=2"] {
+        let req = test::TestRequest::get().uri(uri).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 404, "{}", uri);
+    }
+}
+
+#[actix_web::test]
+async fn incognito_profiles_only_reach_users_they_liked() {
+    let state = test_state();
+    let app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(configure_routes),
+    )
+    .await;
+    for name in ["a", "b", "c"] {
+        let req = test::TestRequest::post()
+            .uri("/users/new")
+            .set_json(serde_json::json!({
+                "name": name,
+                "password": "pw",
+                "email": format!("{}@ku.edu", name)
+            }))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+    let queue_ids = |body: serde_json::Value| -> Vec<i64> {
+        let mut ids: Vec<i64> = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|p| p["user_id"].as_i64().unwrap())
+            .collect();
+        ids.sort();
+        ids
+    };
+    let set_incognito = |incognito: bool| {
+        test::TestRequest::put()
+            .uri("/profiles/2/incognito")
+            .set_json(serde_json::json!({ "incognito": incognito }))
+            .to_request()
+    };
+    let premium = |grant: bool| {
+        let req = if grant {
+            test::TestRequest::put().set_json(serde_json::json!({"days": 30}))
+        } else {
+            test::TestRequest::delete()
+        };
+        req.uri("/admin/users/2/premium")
+            .insert_header(("X-Admin-Key", "1234"))
+            .to_request()
+    };
+
+    let refused = test::call_service(&app, set_incognito(true)).await;
+    assert_eq!(refused.status(), 403);
+    let body: serde_json::Value = test::read_body_json(refused).await;
+    assert_eq!(body["entitlement"], "incognito");
+    let req = test::TestRequest::put()
+        .uri("/admin/users/2/premium")
+        .set_json(serde_json::json!({}))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 401);
+    for days in [0, -1, entitlements::MAX_GRANT_DAYS + 1, i64::MAX] {
+        let req = test::TestRequest::put()
+            .uri("/admin/users/2/premium")
+            .insert_header(("X-Admin-Key", "1234"))
+            .set_json(serde_json::json!({ "days": days }))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            400,
+            "{}",
+            days
+        );
+    }
+    assert!(
+        test::call_service(&app, premium(true))
+            .await
+            .status()
+            .is_success()
+    );
+    let req = test::TestRequest::post()
+        .uri("/login")
+        .set_json(serde_json::json!({"email": "b@ku.edu", "password": "pw"}))
+        .to_request();
+    let login: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let req = test::TestRequest::get()
+        .uri("/me/entitlements")
+        .insert_header((
+            "Authorization",
+            format!("Bearer {}", login["token"].as_str().unwrap()),
+        ))
+        .to_request();
+    let plan: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(plan["plan"], "premium");
+    assert!(
+        plan["entitlements"]
+            .as_array()
+            .unwrap()
+            .contains(&serde_json::json!("incognito"))
+    );
+    assert!(plan["premium_until"].is_i64());
+
+    assert!(
+        test::call_service(&app, set_incognito(true))
+            .await
+            .status()
+            .is_success()
+    );
+    for viewer in [1, 3] {
+        let req = test::TestRequest::get()
+            .uri(&format!("/queue/{}", viewer))
+            .to_request();
+        assert!(!queue_ids(test::call_and_read_body_json(&app, req).await).contains(&2));
+    }
+    let req = test::TestRequest::post()
+        .uri("/swipes")
+        .set_json(serde_json::json!({"user_id": 2, "target_user_id": 1, "direction": "like"}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let req = test::TestRequest::get().uri("/queue/1").to_request();
+    assert_eq!(
+        queue_ids(test::call_and_read_body_json(&app, req).await),
+        vec![2, 3]
+    );
+    let req = test::TestRequest::get().uri("/queue/3").to_request();
+    assert_eq!(
+        queue_ids(test::call_and_read_body_json(&app, req).await),
+        vec![1]
+    );
+
+    // Incognito stops hiding the profile once premium ends, without clearing the flag
+    assert!(
+        test::call_service(&app, premium(false))
+            .await
+            .status()
+            .is_success()
+    );
+    let req = test::TestRequest::get().uri("/queue/3").to_request();
+    assert_eq!(
+        queue_ids(test::call_and_read_body_json(&app, req).await),
+        vec![1, 2]
+    );
+    let req = test::TestRequest::get()
+        .uri("/profiles/2/visibility")
+        .to_request();
+    let current: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(current["incognito"], true);
+    assert!(
+        test::call_service(&app, set_incognito(false))
+            .await
+            .status()
+            .is_success()
+    );
+}
+
+#[actix_web::test]
+async fn received_likes_are_blurred_until_premium() {
+    let state = test_state();
+    let app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(configure_routes),
+    )
+    .await;
+    for name in ["a", "b", "c", "d", "e"] {
+        let req = test::TestRequest::post()
+            .uri("/users/new")
+            .set_json(serde_json::json!({
+                "name": name,
+                "password": "pw",
+                "email": format!("{}@ku.edu", name)
+            }))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+    let req = test::TestRequest::put()
+        .uri("/profiles/1/prompts")
+        .set_json(serde_json::json!({"answers": [{"prompt_id": 1, "answer": "Clinton Lake"}]}))
+        .to_request();
+    let answers: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let answer_id = answers[0]["id"].as_i64().unwrap();
+    let swipe = |user_id: i32, target_user_id: i32, direction: &str| {
+        test::TestRequest::post()
+            .uri("/swipes")
+            .set_json(serde_json::json!({
+                "user_id": user_id,
+                "target_user_id": target_user_id,
+                "direction": direction
+            }))
+            .to_request()
+    };
+    // 2 is still waiting, 3 was liked back, 4 was passed on and 5 is blocked
+    let req = test::TestRequest::post()
+        .uri("/swipes")
+        .set_json(serde_json::json!({
+            "user_id": 2,
+            "target_user_id": 1,
+            "direction": "like",
+            "prompt_answer_id": answer_id
+        }))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    for (user_id, target_user_id, direction) in [
+        (3, 1, "like"),
+        (1, 3, "like"),
+        (4, 1, "like"),
+        (1, 4, "pass"),
+        (5, 1, "like"),
+    ] {
+        let req = swipe(user_id, target_user_id, direction);
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+    let req = test::TestRequest::post()
+        .uri("/blocks")
+        .insert_header(bearer(&state, 1))
+        .set_json(serde_json::json!({"blocked_user_id": 5}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+
+    let req = test::TestRequest::get().uri("/likes/received").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 401);
+    let req = test::TestRequest::post()
+        .uri("/login")
+        .set_json(serde_json::json!({"email": "a@ku.edu", "password": "pw"}))
+        .to_request();
+    let login: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let auth = (
+        "Authorization",
+        format!("Bearer {}", login["token"].as_str().unwrap()),
+    );
+    let received = || {
+        test::TestRequest::get()
+            .uri("/likes/received")
+            .insert_header(auth.clone())
+            .to_request()
+    };
+    let teaser: serde_json::Value = test::call_and_read_body_json(&app, received()).await;
+    assert_eq!(teaser["count"], 1);
+    assert_eq!(teaser["blurred"], true);
+    assert_eq!(
+        teaser["likes"][0]["prompt_answer"]["answer"],
+        "Clinton Lake"
+    );
+    assert!(teaser["likes"][0].get("profile").is_none());
+
+    let req = test::TestRequest::put()
+        .uri("/admin/users/1/premium")
+        .insert_header(("X-Admin-Key", "1234"))
+        .set_json(serde_json::json!({}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let full: serde_json::Value = test::call_and_read_body_json(&app, received()).await;
+    assert_eq!(full["count"], 1);
+    assert_eq!(full["blurred"], false);
+    assert_eq!(full["likes"][0]["profile"]["user_id"], 2);
+    assert_eq!(full["likes"][0]["profile"]["name"], "b");
+
+    let req = swipe(1, 2, "like");
+    let swiped: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(swiped["matched"], true);
+    let after: serde_json::Value = test::call_and_read_body_json(&app, received()).await;
+    assert_eq!(after["count"], 0);
+}
+
+#[actix_web::test]
+async fn undoing_a_swipe_restores_the_profile_and_unwinds_the_match() {
+    let state = test_state();
+    let app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(configure_routes),
+    )
+    .await;
+    for name in ["a", "b", "c", "d"] {
+        let req = test::TestRequest::post()
+            .uri("/users/new")
+            .set_json(serde_json::json!({
+                "name": name,
+                "password": "pw",
+                "email": format!("{}@ku.edu", name)
+            }))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+    let swipe = |user_id: i32, target_user_id: i32, direction: &str| {
+        test::TestRequest::post()
+            .uri("/swipes")
+            .set_json(serde_json::json!({
+                "user_id": user_id,
+                "target_user_id": target_user_id,
+                "direction": direction
+            }))
+            .to_request()
+    };
+    let undo = || {
+        test::TestRequest::post()
+            .uri("/swipes/undo")
+            .set_json(serde_json::json!({"user_id": 1}))
+            .to_request()
+    };
+    let match_count = || {
+        let conn = state.db_conn.lock().unwrap();
+        count(&conn, "SELECT COUNT(*) FROM matches WHERE user_id = ?1", 1)
+    };
+
+    assert_eq!(test::call_service(&app, undo()).await.status(), 404);
+    assert!(
+        test::call_service(&app, swipe(2, 1, "like"))
+            .await
+            .status()
+            .is_success()
+    );
+    let swiped: serde_json::Value = test::call_and_read_body_json(&app, swipe(1, 2, "like")).await;
+    assert_eq!(swiped["matched"], true);
+    assert_eq!(match_count(), 1);
+    let undone: serde_json::Value = test::call_and_read_body_json(&app, undo()).await;
+    assert_eq!(undone["target_user_id"], 2);
+    assert_eq!(undone["unmatched"], true);
+    assert_eq!(undone["undos_left"], 0);
+    assert_eq!(match_count(), 0);
+    for _ in 0..5 {
+        let req = test::TestRequest::get().uri("/queue/1").to_request();
+        let queue: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(queue[0]["user_id"], 2);
+        assert_eq!(queue.as_array().unwrap().len(), 3);
+    }
+
+    // Free users get one undo a day
+    assert!(
+        test::call_service(&app, swipe(1, 3, "pass"))
+            .await
+            .status()
+            .is_success()
+    );
+    let limited = test::call_service(&app, undo()).await;
+    assert_eq!(limited.status(), 429);
+    let body: serde_json::Value = test::read_body_json(limited).await;
+    assert_eq!(body["quota"], "undo");
+    assert!(body["resets_at"].as_i64().unwrap() > chrono::Utc::now().timestamp_millis());
+    let req = test::TestRequest::put()
+        .uri("/admin/users/1/premium")
+        .insert_header(("X-Admin-Key", "1234"))
+        .set_json(serde_json::json!({}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let undone: serde_json::Value = test::call_and_read_body_json(&app, undo()).await;
+    assert_eq!(undone["target_user_id"], 3);
+    assert_eq!(undone["unmatched"], false);
+
+    assert!(
+        test::call_service(&app, swipe(1, 4, "pass"))
+            .await
+            .status()
+            .is_success()
+    );
+    {
+        let conn = state.db_conn.lock().unwrap();
+        conn.execute(
+            "UPDATE swipes SET created_at = 0 WHERE user_id = 1",
+            params![],
+        )
+        .unwrap();
+    }
+    assert_eq!(test::call_service(&app, undo()).await.status(), 410);
+}
+
+#[actix_web::test]
+async fn daily_quotas_limit_likes_and_super_likes_lead_the_queue() {
+    let state = test_state();
+    let app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(configure_routes),
+    )
+    .await;
+    for name in ["a", "b", "c", "d"] {
+        let req = test::TestRequest::post()
+            .uri("/users/new")
+            .set_json(serde_json::json!({
+                "name": name,
+                "password": "pw",
+                "email": format!("{}@ku.edu", name)
+            }))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+    let swipe = |user_id: i32, target_user_id: i32, direction: &str| {
+        test::TestRequest::post()
+            .uri("/swipes")
+            .set_json(serde_json::json!({
+                "user_id": user_id,
+                "target_user_id": target_user_id,
+                "direction": direction
+            }))
+            .to_request()
+    };
+
+    assert!(
+        test::call_service(&app, swipe(2, 1, "super_like"))
+            .await
+            .status()
+            .is_success()
+    );
+    for _ in 0..5 {
+        let req = test::TestRequest::get().uri("/queue/1").to_request();
+        let queue: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(queue[0]["user_id"], 2);
+    }
+    // Repeating a super like does not use the quota, a second one does
+    assert!(
+        test::call_service(&app, swipe(2, 1, "super_like"))
+            .await
+            .status()
+            .is_success()
+    );
+    let limited = test::call_service(&app, swipe(2, 3, "super_like")).await;
+    assert_eq!(limited.status(), 429);
+    let body: serde_json::Value = test::read_body_json(limited).await;
+    assert_eq!(body["quota"], "super_like");
+    assert_eq!(body["limit"], 1);
+    assert!(body["resets_at"].as_i64().unwrap() > chrono::Utc::now().timestamp_millis());
+    let swiped: serde_json::Value = test::call_and_read_body_json(&app, swipe(1, 2, "like")).await;
+    assert_eq!(swiped["matched"], true);
+    // Passing on a liked profile takes its match away with the like
+    let swiped: serde_json::Value = test::call_and_read_body_json(&app, swipe(2, 1, "pass")).await;
+    assert_eq!(swiped["unmatched"], true);
+    let req = test::TestRequest::get().uri("/matches/1").to_request();
+    let matches: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+    assert!(matches.is_empty());
+    let swiped: serde_json::Value = test::call_and_read_body_json(&app, swipe(2, 1, "pass")).await;
+    assert_eq!(swiped["unmatched"], false);
+
+    // Fill user 3's free likes for today
+    {
+        let conn = state.db_conn.lock().unwrap();
+        let now = chrono::Utc::now().timestamp_millis();
+        for i in 0..50 {
+            conn.execute(
+                "INSERT INTO profiles (email, password) VALUES (?1, 'pw')",
+                params![format!("filler{}@ku.edu", i)],
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO swipes (user_id, target_user_id, direction, created_at)
+                 VALUES (3, ?1, 'like', ?2)",
+                params![conn.last_insert_rowid(), now],
+            )
+            .unwrap();
+        }
+    }
+    assert_eq!(
+        test::call_service(&app, swipe(3, 4, "like")).await.status(),
+        429
+    );
+    assert!(
+        test::call_service(&app, swipe(3, 4, "pass"))
+            .await
+            .status()
+            .is_success()
+    );
+    let req = test::TestRequest::post()
+        .uri("/login")
+        .set_json(serde_json::json!({"email": "c@ku.edu", "password": "pw"}))
+        .to_request();
+    let login: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let auth = (
+        "Authorization",
+        format!("Bearer {}", login["token"].as_str().unwrap()),
+    );
+    let quotas = || {
+        test::TestRequest::get()
+            .uri("/me/quotas")
+            .insert_header(auth.clone())
+            .to_request()
+    };
+    let free: serde_json::Value = test::call_and_read_body_json(&app, quotas()).await;
+    assert_eq!(free["plan"], "free");
+    assert_eq!(
+        free["quotas"]["like"],
+        serde_json::json!({"limit": 50, "used": 50, "remaining": 0})
+    );
+    assert_eq!(free["quotas"]["super_like"]["remaining"], 1);
+
+    let req = test::TestRequest::put()
+        .uri("/admin/users/3/premium")
+        .insert_header(("X-Admin-Key", "1234"))
+        .set_json(serde_json::json!({}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    assert!(
+        test::call_service(&app, swipe(3, 4, "like"))
+            .await
+            .status()
+            .is_success()
+    );
+    let premium: serde_json::Value = test::call_and_read_body_json(&app, quotas()).await;
+    assert_eq!(premium["quotas"]["like"]["limit"], serde_json::Value::Null);
+    assert_eq!(premium["quotas"]["like"]["used"], 51);
+    assert_eq!(premium["quotas"]["super_like"]["limit"], 5);
+}
+
+#[actix_web::test]
+async fn subscriptions_follow_signed_payment_webhooks() {
+    let state = test_state();
+    let app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(configure_routes),
+    )
+    .await;
+    let req = test::TestRequest::post()
+        .uri("/users/new")
+        .set_json(serde_json::json!({"name": "a", "password": "pw", "email": "a@ku.edu"}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let req = test::TestRequest::post()
+        .uri("/login")
+        .set_json(serde_json::json!({"email": "a@ku.edu", "password": "pw"}))
+        .to_request();
+    let login: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let auth = (
+        "Authorization",
+        format!("Bearer {}", login["token"].as_str().unwrap()),
+    );
+    let subscription = || {
+        test::TestRequest::get()
+            .uri("/me/subscription")
+            .insert_header(auth.clone())
+            .to_request()
+    };
+    let checkout = |plan: &str| {
+        test::TestRequest::post()
+            .uri("/me/subscription/checkout")
+            .insert_header(auth.clone())
+            .set_json(serde_json::json!({ "plan": plan }))
+            .to_request()
+    };
+    let simulate = |subscription_id: &str, event: &str| {
+        test::TestRequest::post()
+            .uri(&format!(
+                "/payments/fake/subscriptions/{}/{}",
+                subscription_id, event
+            ))
+            .insert_header(("X-Admin-Key", "1234"))
+            .to_request()
+    };
+
+    let current: serde_json::Value = test::call_and_read_body_json(&app, subscription()).await;
+    assert_eq!(current["is_premium"], false);
+    assert!(current["subscription"].is_null());
+    assert_eq!(
+        test::call_service(&app, checkout("lifetime"))
+            .await
+            .status(),
+        400
+    );
+    let started = test::call_service(&app, checkout("premium_monthly")).await;
+    assert_eq!(started.status(), 201);
+    let started: serde_json::Value = test::read_body_json(started).await;
+    let req = test::TestRequest::post()
+        .uri(started["url"].as_str().unwrap())
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let current: serde_json::Value = test::call_and_read_body_json(&app, subscription()).await;
+    assert_eq!(current["is_premium"], true);
+    assert_eq!(current["subscription"]["status"], "active");
+    assert_eq!(
+        current["subscription_expiration"],
+        current["subscription"]["current_period_end"]
+    );
+    let sub_id = current["subscription"]["id"].as_str().unwrap().to_string();
+    let period_end = current["subscription"]["current_period_end"]
+        .as_i64()
+        .unwrap();
+    assert_eq!(
+        test::call_service(&app, checkout("premium_yearly"))
+            .await
+            .status(),
+        409
+    );
+    let req = test::TestRequest::put()
+        .uri("/profiles/1/incognito")
+        .set_json(serde_json::json!({"incognito": true}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+
+    // Webhooks must be signed, and each event is only applied once
+    let body = serde_json::json!({
+        "id": "evt_test_failed",
+        "type": "payment.failed",
+        "created": 0,
+        "data": {"subscription_id": sub_id}
+    })
+    .to_string();
+    let webhook = |signature: String| {
+        test::TestRequest::post()
+            .uri("/payments/webhook")
+            .insert_header(("X-JayMatch-Signature", signature))
+            .set_payload(body.clone())
+            .to_request()
+    };
+    let signature = state.payments.simulator().unwrap().sign(&body);
+    let forged = signature.replace("v1=", "v1=00");
+    assert_eq!(
+        test::call_service(&app, webhook(forged)).await.status(),
+        401
+    );
+    let stale = format!("t=0,{}", signature.split_once(',').unwrap().1);
+    assert_eq!(test::call_service(&app, webhook(stale)).await.status(), 401);
+    let applied: serde_json::Value =
+        test::call_and_read_body_json(&app, webhook(signature.clone())).await;
+    assert_eq!(applied["duplicate"], false);
+    let current: serde_json::Value = test::call_and_read_body_json(&app, subscription()).await;
+    assert_eq!(current["is_premium"], false);
+    assert_eq!(current["subscription"]["status"], "past_due");
+    assert!(
+        test::call_service(&app, simulate(&sub_id, "renew"))
+            .await
+            .status()
+            .is_success()
+    );
+    let replayed: serde_json::Value = test::call_and_read_body_json(&app, webhook(signature)).await;
+    assert_eq!(replayed["duplicate"], true);
+    let current: serde_json::Value = test::call_and_read_body_json(&app, subscription()).await;
+    assert_eq!(current["is_premium"], true);
+    assert_eq!(current["subscription"]["status"], "active");
+    assert!(
+        current["subscription"]["current_period_end"]
+            .as_i64()
+            .unwrap()
+            > period_end
+    );
+
+    let req = test::TestRequest::post()
+        .uri("/me/subscription/cancel")
+        .insert_header(auth.clone())
+        .to_request();
+    let cancelled: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(cancelled["subscription"]["renews"], false);
+    let req = test::TestRequest::post()
+        .uri("/me/subscription/cancel")
+        .insert_header(auth.clone())
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 404);
+    assert!(
+        test::call_service(&app, simulate(&sub_id, "cancel"))
+            .await
+            .status()
+            .is_success()
+    );
+    let current: serde_json::Value = test::call_and_read_body_json(&app, subscription()).await;
+    assert_eq!(current["is_premium"], true);
+    assert_eq!(current["subscription"]["status"], "canceled");
+    {
+        let conn = state.db_conn.lock().unwrap();
+        conn.execute(
+            "UPDATE subscriptions SET current_period_end = 0 WHERE user_id = 1",
+            params![],
+        )
+        .unwrap();
+    }
+    let current: serde_json::Value = test::call_and_read_body_json(&app, subscription()).await;
+    assert_eq!(current["is_premium"], false);
+    assert!(current["subscription_expiration"].is_null());
+}
+
+#[actix_web::test]
+async fn boosts_lead_the_queue_and_report_their_results() {
+    let state = test_state();
+    let app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(configure_routes),
+    )
+    .await;
+    for i in 0..4 {
+        let req = test::TestRequest::post()
+            .uri("/users/new")
+            .set_json(serde_json::json!({
+                "name": format!("u{}", i),
+                "password": "pw",
+                "email": format!("u{}@ku.edu", i)
+            }))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+    let req = test::TestRequest::post()
+        .uri("/login")
+        .set_json(serde_json::json!({"email": "u3@ku.edu", "password": "pw"}))
+        .to_request();
+    let login: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let auth = (
+        "Authorization",
+        format!("Bearer {}", login["token"].as_str().unwrap()),
+    );
+    let boosts = || {
+        test::TestRequest::get()
+            .uri("/me/boosts")
+            .insert_header(auth.clone())
+            .to_request()
+    };
+    let start = || {
+        test::TestRequest::post()
+            .uri("/me/boosts")
+            .insert_header(auth.clone())
+            .to_request()
+    };
+
+    // Free users need to buy boosts first
+    let current: serde_json::Value = test::call_and_read_body_json(&app, boosts()).await;
+    assert_eq!(current["inventory"], 0);
+    assert_eq!(current["premium_boosts_left"], 0);
+    assert!(current["active"].is_null());
+    assert_eq!(test::call_service(&app, start()).await.status(), 403);
+    let req = test::TestRequest::post()
+        .uri("/me/boosts/checkout")
+        .insert_header(auth.clone())
+        .set_json(serde_json::json!({"product": "premium_monthly"}))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 400);
+    let req = test::TestRequest::post()
+        .uri("/me/boosts/checkout")
+        .insert_header(auth.clone())
+        .set_json(serde_json::json!({"product": "boost_5"}))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 201);
+    let checkout: serde_json::Value = test::read_body_json(resp).await;
+    let req = test::TestRequest::post()
+        .uri(checkout["url"].as_str().unwrap())
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let current: serde_json::Value = test::call_and_read_body_json(&app, boosts()).await;
+    assert_eq!(current["inventory"], 5);
+
+    // A like before the boost sets the usual rate; one during it counts as extra
+    {
+        let conn = state.db_conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO swipes (user_id, target_user_id, direction, created_at) VALUES (3, 4, 'like', ?1)",
+            params![chrono::Utc::now().timestamp_millis() - 60 * 60 * 1000],
+        )
+        .unwrap();
+    }
+    let started = test::call_service(&app, start()).await;
+    assert_eq!(started.status(), 201);
+    let started: serde_json::Value = test::read_body_json(started).await;
+    assert_eq!(started["boost"]["source"], "inventory");
+    assert_eq!(
+        started["boost"]["ends_at"].as_i64().unwrap()
+            - started["boost"]["started_at"].as_i64().unwrap(),
+        boosts::BOOST_DURATION_MS
+    );
+    assert_eq!(test::call_service(&app, start()).await.status(), 409);
+
+    for uid in [1, 2] {
+        let req = test::TestRequest::get()
+            .uri(&format!("/queue/{}", uid))
+            .to_request();
+        let queue: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(queue[0]["user_id"], 4);
+    }
+    let req = test::TestRequest::post()
+        .uri("/swipes")
+        .set_json(serde_json::json!({"user_id": 1, "target_user_id": 4, "direction": "like"}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+
+    let current: serde_json::Value = test::call_and_read_body_json(&app, boosts()).await;
+    assert_eq!(current["inventory"], 4);
+    assert_eq!(current["active"]["id"], started["boost"]["id"]);
+    assert_eq!(current["active"]["views"], 2);
+    assert_eq!(current["active"]["likes"], 1);
+    assert_eq!(current["active"]["extra_likes"], 1);
+    assert_eq!(current["boosts"].as_array().unwrap().len(), 1);
+
+    // Premium users get a free boost each month before their inventory is used
+    {
+        let conn = state.db_conn.lock().unwrap();
+        conn.execute(
+            "UPDATE boosts SET ends_at = ?1",
+            params![chrono::Utc::now().timestamp_millis()],
+        )
+        .unwrap();
+    }
+    let req = test::TestRequest::put()
+        .uri("/admin/users/4/premium")
+        .insert_header(("X-Admin-Key", "1234"))
+        .set_json(serde_json::json!({"days": 30}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let current: serde_json::Value = test::call_and_read_body_json(&app, boosts()).await;
+    assert!(current["active"].is_null());
+    assert_eq!(current["premium_boosts_left"], 1);
+    let started: serde_json::Value = test::call_and_read_body_json(&app, start()).await;
+    assert_eq!(started["boost"]["source"], "premium");
+    let current: serde_json::Value = test::call_and_read_body_json(&app, boosts()).await;
+    assert_eq!(current["premium_boosts_left"], 0);
+    assert_eq!(current["inventory"], 4);
+    assert_eq!(current["boosts"].as_array().unwrap().len(), 2);
+}
+
+#[actix_web::test]
+async fn impressions_are_counted_per_day_and_spread_the_queue() {
+    let state = test_state();
+    let app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(configure_routes),
+    )
+    .await;
+    for i in 0..3 {
+        let req = test::TestRequest::post()
+            .uri("/users/new")
+            .set_json(serde_json::json!({
+                "name": format!("u{}", i),
+                "password": "pw",
+                "email": format!("u{}@ku.edu", i)
+            }))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+    let req = test::TestRequest::post()
+        .uri("/login")
+        .set_json(serde_json::json!({"email": "u1@ku.edu", "password": "pw"}))
+        .to_request();
+    let login: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let auth = (
+        "Authorization",
+        format!("Bearer {}", login["token"].as_str().unwrap()),
+    );
+    let impressions = || {
+        test::TestRequest::get()
+            .uri("/me/impressions")
+            .insert_header(auth.clone())
+            .to_request()
+    };
+    let req = test::TestRequest::get().uri("/me/impressions").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 401);
+
+    // Queue batches and other users' profile views count once per viewer a day;
+    // looking at your own profile or without a session does not count
+    for _ in 0..2 {
+        let req = test::TestRequest::get().uri("/queue/1").to_request();
+        let queue: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(queue.len(), 2);
+    }
+    for viewer in [1, 1, 2, 3] {
+        let req = test::TestRequest::get()
+            .uri("/profiles/2")
+            .insert_header(bearer(&state, viewer))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+    let req = test::TestRequest::get()
+        .uri("/profiles/2?viewer_id=3")
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let seen: serde_json::Value = test::call_and_read_body_json(&app, impressions()).await;
+    assert_eq!(seen["seen_this_week"], 3);
+    assert_eq!(seen["queue_impressions"], 1);
+    assert_eq!(seen["profile_views"], 2);
+    let days = seen["days"].as_array().unwrap();
+    assert_eq!(days.len(), impressions::WEEK_DAYS as usize);
+    assert_eq!(days.last().unwrap()["profile"], 2);
+    assert_eq!(days[0]["day"], seen["since"]);
+
+    // Older impressions drop out of the week, and profiles seen less come first in queues
+    {
+        let conn = state.db_conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO profile_impressions (user_id, day, source, count) VALUES (2, 0, 'queue', 50), (3, ?1, 'queue', 50)",
+            params![seen["since"].as_i64().unwrap()],
+        )
+        .unwrap();
+    }
+    let seen: serde_json::Value = test::call_and_read_body_json(&app, impressions()).await;
+    assert_eq!(seen["seen_this_week"], 3);
+    for _ in 0..5 {
+        let req = test::TestRequest::get().uri("/queue/1").to_request();
+        let queue: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(queue[0]["user_id"], 2);
+    }
+}
+
+#[actix_web::test]
+async fn blocking_hides_both_users_and_only_the_blocker_can_unblock() {
+    let state = test_state();
+    let app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(configure_routes),
+    )
+    .await;
+    let mut auth = Vec::new();
+    for name in ["alice", "bob", "carol"] {
+        let req = test::TestRequest::post()
+            .uri("/users/new")
+            .set_json(serde_json::json!({
+                "name": name,
+                "password": "pw",
+                "email": format!("{}@ku.edu", name)
+            }))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let req = test::TestRequest::post()
+            .uri("/login")
+            .set_json(serde_json::json!({"email": format!("{}@ku.edu", name), "password": "pw"}))
+            .to_request();
+        let login: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        auth.push((
+            "Authorization",
+            format!("Bearer {}", login["token"].as_str().unwrap()),
+        ));
+    }
+    let queue_ids = |uid: i32| {
+        test::TestRequest::get()
+            .uri(&format!("/queue/{}", uid))
+            .to_request()
+    };
+    let profile = |id: i32, auth: Option<&(&'static str, String)>| {
+        let req = test::TestRequest::get().uri(&format!("/profiles/{}", id));
+        match auth {
+            Some(auth) => req.insert_header(auth.clone()).to_request(),
+            None => req.to_request(),
+        }
+    };
+    let req = test::TestRequest::post()
+        .uri("/matches")
+        .set_json(serde_json::json!({"user_id": 1, "matched_user_id": 2}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+
+    // Blocking needs the blocker's session, whatever user_id the body claims
+    let block = |auth: Option<&(&'static str, String)>| {
+        let req = test::TestRequest::post()
+            .uri("/blocks")
+            .set_json(serde_json::json!({"user_id": 3, "blocked_user_id": 2}));
+        match auth {
+            Some(auth) => req.insert_header(auth.clone()).to_request(),
+            None => req.to_request(),
+        }
+    };
+    assert_eq!(test::call_service(&app, block(None)).await.status(), 401);
+    let req = test::TestRequest::post()
+        .uri("/blocks")
+        .insert_header(auth[1].clone())
+        .set_json(serde_json::json!({"blocked_user_id": 2}))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 400);
+
+    // Blocking removes the match and hides the pair from each other's queues and profiles
+    let req = block(Some(&auth[0]));
+    let blocked: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let block_id = blocked["block_id"].as_i64().unwrap();
+    let req = test::TestRequest::get().uri("/matches/1").to_request();
+    let matches: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+    assert!(matches.is_empty());
+    for (uid, hidden) in [(1, 2), (2, 1)] {
+        let queue: Vec<serde_json::Value> =
+            test::call_and_read_body_json(&app, queue_ids(uid)).await;
+        assert!(queue.iter().all(|p| p["user_id"] != hidden));
+        assert_eq!(queue.len(), 1);
+    }
+    for (id, auth) in [(1, Some(&auth[1])), (2, Some(&auth[0])), (1, None)] {
+        assert_eq!(
+            test::call_service(&app, profile(id, auth)).await.status(),
+            404,
+            "{}",
+            id
+        );
+    }
+    // Only the session decides who is viewing, and owners always see their own profile
+    let req = test::TestRequest::get()
+        .uri("/profiles/1?viewer_id=3")
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 404);
+    for (id, auth) in [(1, &auth[2]), (1, &auth[0]), (2, &auth[1])] {
+        assert!(
+            test::call_service(&app, profile(id, Some(auth)))
+                .await
+                .status()
+                .is_success()
+        );
+    }
+    let req = test::TestRequest::post()
+        .uri("/matches")
+        .set_json(serde_json::json!({"user_id": 2, "matched_user_id": 1}))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 403);
+    let req = test::TestRequest::get().uri("/blocks/1").to_request();
+    let blocks: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(blocks.len(), 1);
+    assert_eq!(blocks[0]["blocked_user_id"], 2);
+
+    // Only the blocker's session can lift the block
+    let unblock = |auth: Option<&(&'static str, String)>| {
+        let req = test::TestRequest::delete().uri(&format!("/blocks/{}", block_id));
+        match auth {
+            Some(auth) => req.insert_header(auth.clone()).to_request(),
+            None => req.to_request(),
+        }
+    };
+    assert_eq!(test::call_service(&app, unblock(None)).await.status(), 401);
+    assert_eq!(
+        test::call_service(&app, unblock(Some(&auth[1])))
+            .await
+            .status(),
+        404
+    );
+    assert_eq!(
+        test::call_service(&app, unblock(Some(&auth[2])))
+            .await
+            .status(),
+        404
+    );
+    assert!(
+        test::call_service(&app, unblock(Some(&auth[0])))
+            .await
+            .status()
+            .is_success()
+    );
+    assert_eq!(
+        test::call_service(&app, unblock(Some(&auth[0])))
+            .await
+            .status(),
+        404
+    );
+    let queue: Vec<serde_json::Value> = test::call_and_read_body_json(&app, queue_ids(2)).await;
+    assert!(queue.iter().any(|p| p["user_id"] == 1));
+    assert!(
+        test::call_service(&app, profile(1, None))
+            .await
+            .status()
+            .is_success()
+    );
+    let req = test::TestRequest::get().uri("/matches/1").to_request();
+    let matches: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+    assert!(matches.is_empty());
+}
+
+#[actix_web::test]
+async fn reports_keep_an_immutable_snapshot_of_the_reported_content() {
+    let state = test_state();
+    let app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(configure_routes),
+    )
+    .await;
+    for name in ["alice", "bob", "carol"] {
+        let req = test::TestRequest::post()
+            .uri("/users/new")
+            .set_json(serde_json::json!({
+                "name": name,
+                "password": "pw",
+                "email": format!("{}@ku.edu", name)
+            }))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+    let requests = vec![
+        (
+            "/matches",
+            serde_json::json!({"user_id": 1, "matched_user_id": 2}),
+        ),
+        (
+            "/matches",
+            serde_json::json!({"user_id": 1, "matched_user_id": 3}),
+        ),
+        (
+            "/messages",
+            serde_json::json!({"sender_id": 2, "receiver_id": 1, "content": "rude"}),
+        ),
+        (
+            "/messages",
+            serde_json::json!({"sender_id": 1, "receiver_id": 3, "content": "hey carol"}),
+        ),
+    ];
+    for (uri, body) in requests {
+        let req = test::TestRequest::post()
+            .uri(uri)
+            .set_json(body)
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+    let message_id = |a: i32, b: i32| {
+        let conn = state.db_conn.lock().unwrap();
+        conn.query_row(
+            "SELECT id FROM messages WHERE sender_id = ?1 AND receiver_id = ?2",
+            params![a, b],
+            |row| row.get::<_, i64>(0),
+        )
+        .unwrap()
+    };
+    let rude = message_id(2, 1);
+    let other_conversation = message_id(1, 3);
+    let (content_type, body) = profile_picture_body(2, "bob.png", &png_image(400, 300));
+    let req = test::TestRequest::post()
+        .uri("/users/profile-picture")
+        .insert_header(("content-type", content_type))
+        .set_payload(body)
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let req = test::TestRequest::put()
+        .uri("/profiles/2/prompts")
+        .set_json(serde_json::json!({"answers": [{"prompt_id": 1, "answer": "Allen Fieldhouse"}]}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let picture: String = state
+        .db_conn
+        .lock()
+        .unwrap()
+        .query_row(
+            "SELECT profile_picture FROM profiles WHERE user_id = 2",
+            params![],
+            |row| row.get(0),
+        )
+        .unwrap();
+    let original = state.media.get(&picture).unwrap();
+    let report = |body: serde_json::Value| {
+        test::TestRequest::post()
+            .uri("/reports")
+            .set_json(body)
+            .to_request()
+    };
+    let admin_get = |id: i64| {
+        test::TestRequest::get()
+            .uri(&format!("/admin/reports/{}", id))
+            .insert_header(("X-Admin-Key", "1234"))
+            .to_request()
+    };
+
+    for body in [
+        serde_json::json!({"reporter_id": 1, "reported_user_id": 2, "report_type": "rudeness"}),
+        serde_json::json!({"reporter_id": 1, "reported_user_id": 1, "report_type": "spam"}),
+    ] {
+        assert_eq!(test::call_service(&app, report(body)).await.status(), 400);
+    }
+    // Every requested message must be between the reporter and the reported user
+    for ids in [vec![rude, other_conversation], vec![rude, 9999]] {
+        let resp = test::call_service(
+            &app,
+            report(serde_json::json!({
+                "reporter_id": 1,
+                "reported_user_id": 2,
+                "report_type": "harassment",
+                "message_ids": ids
+            })),
+        )
+        .await;
+        assert_eq!(resp.status(), 400);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["message_ids"], serde_json::json!([ids[1]]));
+    }
+    let filed: serde_json::Value = test::call_and_read_body_json(
+        &app,
+        report(serde_json::json!({
+            "reporter_id": 1,
+            "reported_user_id": 2,
+            "report_type": "Harassment",
+            "message": "see message",
+            "message_ids": [rude, rude]
+        })),
+    )
+    .await;
+    let message_report = filed["report_id"].as_i64().unwrap();
+    let filed: serde_json::Value = test::call_and_read_body_json(
+        &app,
+        report(serde_json::json!({"reporter_id": 3, "reported_user_id": 2, "report_type": "fake_profile"})),
+    )
+    .await;
+    let profile_report = filed["report_id"].as_i64().unwrap();
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/admin/reports/{}", message_report))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 401);
+    let stored: serde_json::Value =
+        test::call_and_read_body_json(&app, admin_get(message_report)).await;
+    assert_eq!(stored["report_type"], "harassment");
+    assert_eq!(stored["status"], "open");
+    assert_eq!(stored["snapshot"]["kind"], "messages");
+    assert_eq!(stored["snapshot"]["messages"].as_array().unwrap().len(), 1);
+    assert_eq!(stored["snapshot"]["messages"][0]["content"], "rude");
+    let stored: serde_json::Value =
+        test::call_and_read_body_json(&app, admin_get(profile_report)).await;
+    assert_eq!(stored["snapshot"]["kind"], "profile");
+    assert_eq!(stored["snapshot"]["profile"]["name"], "bob");
+    assert_eq!(
+        stored["snapshot"]["profile"]["prompts"][0]["answer"],
+        "Allen Fieldhouse"
+    );
+    let photos = stored["snapshot"]["photos"].as_array().unwrap();
+    assert_eq!(photos.len(), 1);
+    let copy = photos[0]["media_key"].as_str().unwrap();
+    assert!(copy.starts_with("reports/"));
+    assert_eq!(stored["snapshot"]["profile"]["profile_picture"], copy);
+
+    // Later edits, moderation and direct writes leave the snapshot as it was filed
+    let req = test::TestRequest::put()
+        .uri("/profiles/2")
+        .set_json(serde_json::json!({"name": "robert"}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let (content_type, body) = profile_picture_body(2, "new.png", &png_image(300, 300));
+    let req = test::TestRequest::post()
+        .uri("/users/profile-picture")
+        .insert_header(("content-type", content_type))
+        .set_payload(body)
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    assert!(state.media.get(&picture).is_err());
+    let report_photo = |index: usize, admin: bool| {
+        let req = test::TestRequest::get().uri(&format!(
+            "/admin/reports/{}/photos/{}",
+            profile_report, index
+        ));
+        if admin {
+            req.insert_header(("X-Admin-Key", "1234")).to_request()
+        } else {
+            req.to_request()
+        }
+    };
+    assert_eq!(
+        test::call_service(&app, report_photo(0, false))
+            .await
+            .status(),
+        401
+    );
+    assert_eq!(
+        test::call_service(&app, report_photo(1, true))
+            .await
+            .status(),
+        404
+    );
+    let kept = test::call_and_read_body(&app, report_photo(0, true)).await;
+    assert_eq!(kept.as_ref(), original.as_slice());
+    {
+        let conn = state.db_conn.lock().unwrap();
+        conn.execute("DELETE FROM messages", params![]).unwrap();
+        assert!(
+            conn.execute("UPDATE reports SET snapshot = '{}'", params![])
+                .is_err()
+        );
+    }
+    let req = test::TestRequest::put()
+        .uri(&format!("/admin/reports/{}", profile_report))
+        .insert_header(("X-Admin-Key", "1234"))
+        .set_json(serde_json::json!({"status": "closed"}))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 400);
+    let req = test::TestRequest::put()
+        .uri(&format!("/admin/reports/{}", profile_report))
+        .insert_header(("X-Admin-Key", "1234"))
+        .set_json(serde_json::json!({"status": "resolved", "resolution": "warned"}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let req = test::TestRequest::post()
+        .uri(&format!("/admin/reports/{}/notes", profile_report))
+        .insert_header(("X-Admin-Key", "1234"))
+        .set_json(serde_json::json!({"note": "photos are stock images"}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let stored: serde_json::Value =
+        test::call_and_read_body_json(&app, admin_get(profile_report)).await;
+    assert_eq!(stored["status"], "resolved");
+    assert_eq!(stored["resolution"], "warned");
+    assert_eq!(stored["notes"][0]["note"], "photos are stock images");
+    assert_eq!(stored["snapshot"]["profile"]["name"], "bob");
+    let stored: serde_json::Value =
+        test::call_and_read_body_json(&app, admin_get(message_report)).await;
+    assert_eq!(stored["snapshot"]["messages"][0]["content"], "rude");
+    let req = test::TestRequest::get()
+        .uri("/admin/report-counts")
+        .insert_header(("X-Admin-Key", "1234"))
+        .to_request();
+    let counts: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(counts[0]["user_id"], 2);
+    assert_eq!(counts[0]["report_count"], 2);
+    assert_eq!(counts[0]["unresolved_count"], 1);
+    remove_test_files(&state);
+}
+
+#[actix_web::test]
+async fn suspensions_and_bans_end_sessions_and_block_login_until_reinstated() {
+    let state = test_state();
+    let app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(configure_routes),
+    )
+    .await;
+    for name in ["alice", "bob", "carol"] {
+        let req = test::TestRequest::post()
+            .uri("/users/new")
+            .set_json(serde_json::json!({
+                "name": name,
+                "password": "pw",
+                "email": format!("{}@ku.edu", name)
+            }))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+    let login = |email: &str| {
+        test::TestRequest::post()
+            .uri("/login")
+            .set_json(serde_json::json!({"email": email, "password": "pw"}))
+            .to_request()
+    };
+    let admin = |uid: i32, action: &str, body: serde_json::Value| {
+        test::TestRequest::post()
+            .uri(&format!("/admin/users/{}/{}", uid, action))
+            .insert_header(("X-Admin-Key", "1234"))
+            .set_json(body)
+            .to_request()
+    };
+    let status = |uid: i32| {
+        test::TestRequest::get()
+            .uri(&format!("/admin/users/{}/status", uid))
+            .insert_header(("X-Admin-Key", "1234"))
+            .to_request()
+    };
+    let queue = |uid: i32| {
+        test::TestRequest::get()
+            .uri(&format!("/queue/{}", uid))
+            .to_request()
+    };
+
+    let session: serde_json::Value =
+        test::call_and_read_body_json(&app, login("alice@ku.edu")).await;
+    let auth = (
+        "Authorization",
+        format!("Bearer {}", session["token"].as_str().unwrap()),
+    );
+    let req = test::TestRequest::post()
+        .uri("/admin/users/1/suspend")
+        .set_json(serde_json::json!({"hours": 24}))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 401);
+    for (uid, body, code) in [
+        (1, serde_json::json!({}), 400),
+        (1, serde_json::json!({"until": 0}), 400),
+        (1, serde_json::json!({"hours": i64::MAX}), 400),
+        (1, serde_json::json!({"hours": i64::MIN}), 400),
+        (99, serde_json::json!({"hours": 24}), 404),
+    ] {
+        assert_eq!(
+            test::call_service(&app, admin(uid, "suspend", body))
+                .await
+                .status(),
+            code
+        );
+    }
+
+    // A suspension ends live sessions, refuses login and hides the user until it runs out
+    let req = admin(
+        1,
+        "suspend",
+        serde_json::json!({"hours": 24, "reason": "spam"}),
+    );
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let req = test::TestRequest::get()
+        .uri("/me/quotas")
+        .insert_header(auth.clone())
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 401);
+    let resp = test::call_service(&app, login("alice@ku.edu")).await;
+    assert_eq!(resp.status(), 403);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["reason"], "spam");
+    assert!(body["suspended_until"].as_i64().is_some());
+    assert_eq!(test::call_service(&app, queue(1)).await.status(), 403);
+    let others: Vec<serde_json::Value> = test::call_and_read_body_json(&app, queue(2)).await;
+    assert!(others.iter().all(|p| p["user_id"] != 1));
+    let current: serde_json::Value = test::call_and_read_body_json(&app, status(1)).await;
+    assert_eq!(current["account_status"], "suspended");
+    {
+        let conn = state.db_conn.lock().unwrap();
+        conn.execute(
+            "UPDATE profiles SET suspended_until = ?1 WHERE user_id = 1",
+            params![chrono::Utc::now().timestamp_millis() - 60_000],
+        )
+        .unwrap();
+    }
+    assert!(
+        test::call_service(&app, login("alice@ku.edu"))
+            .await
+            .status()
+            .is_success()
+    );
+    let others: Vec<serde_json::Value> = test::call_and_read_body_json(&app, queue(2)).await;
+    assert!(others.iter().any(|p| p["user_id"] == 1));
+
+    // Logging in while suspended must not restore an account pending deletion
+    let req = test::TestRequest::post()
+        .uri("/delete_user")
+        .set_json(serde_json::json!({"email": "alice@ku.edu", "password": "pw"}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let req = admin(1, "suspend", serde_json::json!({"hours": 1}));
+    assert!(test::call_service(&app, req).await.status().is_success());
+    assert_eq!(
+        test::call_service(&app, login("alice@ku.edu"))
+            .await
+            .status(),
+        403
+    );
+    let current: serde_json::Value = test::call_and_read_body_json(&app, status(1)).await;
+    assert!(current["deletion_due_at"].as_i64().is_some());
+    let req = admin(1, "reinstate", serde_json::json!({}));
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let session: serde_json::Value =
+        test::call_and_read_body_json(&app, login("alice@ku.edu")).await;
+    assert_eq!(session["restored"], true);
+    let current: serde_json::Value = test::call_and_read_body_json(&app, status(1)).await;
+    assert_eq!(current["account_status"], "active");
+    assert!(current["deletion_due_at"].is_null());
+
+    // A ban also stops the email from registering again until the user is reinstated
+    let req = admin(3, "ban", serde_json::json!({"reason": "fake"}));
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let resp = test::call_service(&app, login("carol@ku.edu")).await;
+    assert_eq!(resp.status(), 403);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["message"], "Account banned");
+    let register = || {
+        test::TestRequest::post()
+            .uri("/users/new")
+            .set_json(
+                serde_json::json!({"name": "carol", "password": "pw", "email": "Carol@KU.edu"}),
+            )
+            .to_request()
+    };
+    let resp = test::call_service(&app, register()).await;
+    assert_eq!(resp.status(), 403);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["message"], "This email cannot be used to register");
+    let req = admin(3, "reinstate", serde_json::json!({}));
+    assert!(test::call_service(&app, req).await.status().is_success());
+    assert!(
+        test::call_service(&app, login("carol@ku.edu"))
+            .await
+            .status()
+            .is_success()
+    );
+    let resp = test::call_service(&app, register()).await;
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_ne!(body["message"], "This email cannot be used to register");
+}
+
+#[actix_web::test]
+async fn hard_deletes_remove_exports_only_once_the_user_is_gone() {
+    let state = new_test_state();
+    fs::create_dir_all(&state.export_dir).unwrap();
+    let path = state.export_dir.join("export.zip");
+    fs::write(&path, b"zip").unwrap();
+    {
+        let conn = state.db_conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO profiles (user_id, email, password) VALUES (1, 'a@ku.edu', 'pw')",
+            params![],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO data_exports (user_id, token, status, file_path, created_at)
+             VALUES (1, 'token', 'ready', ?1, 0)",
+            params![path.to_string_lossy()],
+        )
+        .unwrap();
+        // A delete that fails leaves the user and their export as they were
+        conn.execute_batch(
+            "CREATE TRIGGER keep_profiles BEFORE DELETE ON profiles BEGIN SELECT RAISE(ABORT, 'kept'); END;",
+        )
+        .unwrap();
+        assert!(deletion::hard_delete_user(&conn, 1).is_err());
+        conn.execute_batch("DROP TRIGGER keep_profiles").unwrap();
+    }
+    assert!(path.is_file());
+
+    let files = deletion::hard_delete_user(&state.db_conn.lock().unwrap(), 1).unwrap();
+    assert_eq!(files.export_paths, vec![path.to_string_lossy().to_string()]);
+    assert!(path.is_file());
+    deletion::remove_files(&state, files).await;
+    assert!(!path.exists());
+    remove_test_files(&state);
+}
+
+#[actix_web::test]
+async fn deleted_accounts_can_be_restored_until_the_grace_period_ends() {
+    let state = test_state();
+    let app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(configure_routes),
+    )
+    .await;
+    for name in ["alice", "bob"] {
+        let req = test::TestRequest::post()
+            .uri("/users/new")
+            .set_json(serde_json::json!({
+                "name": name,
+                "password": "pw",
+                "email": format!("{}@ku.edu", name)
+            }))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+    let req = test::TestRequest::post()
+        .uri("/matches")
+        .set_json(serde_json::json!({"user_id": 1, "matched_user_id": 2}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let delete = || {
+        test::TestRequest::post()
+            .uri("/delete_user")
+            .set_json(serde_json::json!({"email": "alice@ku.edu", "password": "pw"}))
+            .to_request()
+    };
+    let login = || {
+        test::TestRequest::post()
+            .uri("/login")
+            .set_json(serde_json::json!({"email": "alice@ku.edu", "password": "pw"}))
+            .to_request()
+    };
+    let register = || {
+        test::TestRequest::post()
+            .uri("/users/new")
+            .set_json(
+                serde_json::json!({"name": "alice", "password": "pw", "email": "alice@ku.edu"}),
+            )
+            .to_request()
+    };
+    let profile = || {
+        test::TestRequest::get()
+            .uri("/profiles/1")
+            .insert_header(bearer(&state, 2))
+            .to_request()
+    };
+    let matches = || test::TestRequest::get().uri("/matches/2").to_request();
+    let purge = |now: i64| {
+        let conn = state.db_conn.lock().unwrap();
+        deletion::purge_expired_deletions(&conn, now).unwrap().0
+    };
+
+    // While the grace period runs the account is hidden but kept
+    let scheduled: serde_json::Value = test::call_and_read_body_json(&app, delete()).await;
+    let due_at = scheduled["deletion_due_at"].as_i64().unwrap();
+    assert!(due_at >= chrono::Utc::now().timestamp_millis() + state.deletion_grace_ms - 60_000);
+    assert_eq!(test::call_service(&app, profile()).await.status(), 404);
+    let req = test::TestRequest::get().uri("/queue/2").to_request();
+    let queue: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+    assert!(queue.iter().all(|p| p["user_id"] != 1));
+    let req = test::TestRequest::post()
+        .uri("/messages")
+        .set_json(serde_json::json!({"sender_id": 2, "receiver_id": 1, "content": "hi"}))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 403);
+    assert_eq!(test::call_service(&app, register()).await.status(), 409);
+    assert_eq!(purge(due_at - 1), 0);
+
+    // Logging in before it ends restores the account with its matches
+    let session: serde_json::Value = test::call_and_read_body_json(&app, login()).await;
+    assert_eq!(session["restored"], true);
+    assert!(
+        test::call_service(&app, profile())
+            .await
+            .status()
+            .is_success()
+    );
+    let current: Vec<serde_json::Value> = test::call_and_read_body_json(&app, matches()).await;
+    assert_eq!(current.len(), 1);
+    let session: serde_json::Value = test::call_and_read_body_json(&app, login()).await;
+    assert_eq!(session["restored"], false);
+    assert_eq!(purge(i64::MAX), 0);
+
+    // Once it ends the purge deletes the account for good and frees the email
+    let scheduled: serde_json::Value = test::call_and_read_body_json(&app, delete()).await;
+    let due_at = scheduled["deletion_due_at"].as_i64().unwrap();
+    assert_eq!(purge(due_at), 1);
+    assert_eq!(test::call_service(&app, login()).await.status(), 401);
+    assert_eq!(test::call_service(&app, profile()).await.status(), 404);
+    let current: Vec<serde_json::Value> = test::call_and_read_body_json(&app, matches()).await;
+    assert!(current.is_empty());
+    assert!(
+        test::call_service(&app, register())
+            .await
+            .status()
+            .is_success()
+    );
+}
+
+#[actix_web::test]
+async fn exports_are_built_in_the_background_and_expire() {
+    let state = test_state();
+    let app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(configure_routes),
+    )
+    .await;
+    let req = test::TestRequest::post()
+        .uri("/users/new")
+        .set_json(serde_json::json!({"name": "alice", "password": "pw", "email": "alice@ku.edu"}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let req = test::TestRequest::post()
+        .uri("/login")
+        .set_json(serde_json::json!({"email": "alice@ku.edu", "password": "pw"}))
+        .to_request();
+    let login: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let auth = format!("Bearer {}", login["token"].as_str().unwrap());
+    let export = || {
+        test::TestRequest::get()
+            .uri("/me/export")
+            .insert_header(("Authorization", auth.clone()))
+            .to_request()
+    };
+    let req = test::TestRequest::get().uri("/me/export").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 401);
+
+    // Creating an export answers straight away and the build finishes in the background
+    let resp = test::call_service(&app, export()).await;
+    assert_eq!(resp.status(), 202);
+    let started: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(started["status"], "pending");
+    assert!(started["download_url"].is_null());
+    let export_id = started["export_id"].as_i64().unwrap();
+    let mut ready = serde_json::Value::Null;
+    for _ in 0..100 {
+        let resp = test::call_service(&app, export()).await;
+        let status = resp.status();
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        // Polling never starts a second export
+        assert_eq!(body["export_id"], export_id);
+        if status == 200 {
+            ready = body;
+            break;
+        }
+        assert_eq!(status, 202);
+        actix_web::rt::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(ready["status"], "ready");
+    let url = ready["download_url"].as_str().unwrap().to_string();
+    let path: String = state
+        .db_conn
+        .lock()
+        .unwrap()
+        .query_row(
+            "SELECT file_path FROM data_exports WHERE id = ?1",
+            params![export_id],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert!(Path::new(&path).starts_with(&state.export_dir));
+
+    // The download is the ZIP with the user's data and no password
+    let resp = test::call_service(&app, test::TestRequest::get().uri(&url).to_request()).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(
+        resp.headers().get("content-type").unwrap(),
+        "application/zip"
+    );
+    assert!(
+        resp.headers()
+            .get("content-disposition")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .contains("jaymatch-export-1.zip")
+    );
+    let body = test::read_body(resp).await;
+    let mut zip = zip::ZipArchive::new(std::io::Cursor::new(body.to_vec())).unwrap();
+    let data: serde_json::Value =
+        serde_json::from_reader(zip.by_name("data.json").unwrap()).unwrap();
+    assert_eq!(data["profile"]["user_id"], 1);
+    assert_eq!(data["profile"]["email"], "alice@ku.edu");
+    assert!(data["profile"].get("password").is_none());
+    let req = test::TestRequest::get()
+        .uri("/exports/unknown")
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 404);
+
+    // An expired link is refused, a new export can be started and the old one is purged
+    let now = chrono::Utc::now().timestamp_millis();
+    state
+        .db_conn
+        .lock()
+        .unwrap()
+        .execute(
+            "UPDATE data_exports SET expires_at = ?1 WHERE id = ?2",
+            params![now - 60_000, export_id],
+        )
+        .unwrap();
+    let resp = test::call_service(&app, test::TestRequest::get().uri(&url).to_request()).await;
+    assert_eq!(resp.status(), 410);
+    let resp = test::call_service(&app, export()).await;
+    assert_eq!(resp.status(), 202);
+    let next: serde_json::Value = test::read_body_json(resp).await;
+    assert_ne!(next["export_id"], export_id);
+    let (purged, paths) = {
+        let conn = state.db_conn.lock().unwrap();
+        let purged = exports::purge_expired_exports(&conn, now).unwrap();
+        assert_eq!(
+            count(
+                &conn,
+                "SELECT COUNT(*) FROM data_exports WHERE id = ?1",
+                export_id as i32
+            ),
+            0
+        );
+        purged
+    };
+    // The file is only removed once the lock is released
+    assert_eq!((purged, paths.clone()), (1, vec![path.clone()]));
+    assert!(Path::new(&path).exists());
+    exports::remove_export_files(paths).await;
+    assert!(!Path::new(&path).exists());
+    let resp = test::call_service(&app, test::TestRequest::get().uri(&url).to_request()).await;
+    assert_eq!(resp.status(), 404);
+    remove_test_files(&state);
+}
+
+#[actix_web::test]
+async fn exports_left_pending_by_a_restart_or_timeout_are_failed() {
+    let state = test_state();
+    let app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(configure_routes),
+    )
+    .await;
+    let req = test::TestRequest::post()
+        .uri("/users/new")
+        .set_json(serde_json::json!({"name": "alice", "password": "pw", "email": "alice@ku.edu"}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let req = test::TestRequest::post()
+        .uri("/login")
+        .set_json(serde_json::json!({"email": "alice@ku.edu", "password": "pw"}))
+        .to_request();
+    let login: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let auth = format!("Bearer {}", login["token"].as_str().unwrap());
+    let now = chrono::Utc::now().timestamp_millis();
+    let status = |id: i64| -> String {
+        state
+            .db_conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT status FROM data_exports WHERE id = ?1",
+                params![id],
+                |row| row.get(0),
+            )
+            .unwrap()
+    };
+    {
+        let conn = state.db_conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO data_exports (id, user_id, token, status, created_at)
+             VALUES (1, 1, 'restarted', 'pending', ?1),
+                    (2, 1, 'stuck', 'pending', ?2)",
+            params![
+                now - 60_000,
+                now - exports::EXPORT_BUILD_TIMEOUT_MS - 60_000
+            ],
+        )
+        .unwrap();
+        // Startup fails every build that was running before the restart
+        assert_eq!(
+            exports::fail_stale_exports(&conn, now - 120_000, now).unwrap(),
+            1
+        );
+    }
+    assert_eq!(status(1), "pending");
+    assert_eq!(status(2), "failed");
+
+    // A build pending past the timeout no longer blocks a new export
+    state
+        .db_conn
+        .lock()
+        .unwrap()
+        .execute(
+            "UPDATE data_exports SET created_at = ?1 WHERE id = 1",
+            params![now - exports::EXPORT_BUILD_TIMEOUT_MS - 60_000],
+        )
+        .unwrap();
+    let req = test::TestRequest::get()
+        .uri("/me/export")
+        .insert_header(("Authorization", auth))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 202);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["export_id"], 3);
+    assert_eq!(status(1), "failed");
+    let conn = state.db_conn.lock().unwrap();
+    exports::purge_expired_exports(&conn, chrono::Utc::now().timestamp_millis()).unwrap();
+    assert_eq!(
+        count(&conn, "SELECT COUNT(*) FROM data_exports WHERE id < ?1", 3),
+        0
+    );
+    remove_test_files(&state);
+}
+
+// Helper function for checking the behaviour every media backend must share
+// Uses keys under prefix so runs against a shared bucket do not collide
+fn check_media_store(store: &dyn media::MediaStore, prefix: &str) {
+    let key = format!("{}/photo.png", prefix);
+    let image = png_image(200, 200);
+    let not_found = |e: std::io::Error| e.kind() == std::io::ErrorKind::NotFound;
+    assert!(store.get(&key).is_err_and(not_found));
+    assert!(store.info(&key).is_err_and(not_found));
+    assert!(store.open(&key, None).is_err_and(not_found));
+
+    store.put(&key, &image, "image/png").unwrap();
+    assert_eq!(store.get(&key).unwrap(), image);
+    let info = store.info(&key).unwrap();
+    assert_eq!(info.len, image.len() as u64);
+    assert_eq!(info.content_type, "image/png");
+    assert!(info.etag.starts_with('"') && info.etag.ends_with('"'));
+    assert_eq!(store.info(&key).unwrap().etag, info.etag);
+    let read = |range| {
+        let mut out = Vec::new();
+        std::io::Read::read_to_end(&mut store.open(&key, range).unwrap(), &mut out).unwrap();
+        out
+    };
+    assert_eq!(read(None), image);
+    assert_eq!(read(Some((10, 19))), image[10..20].to_vec());
+
+    // Replacing an object changes its entity tag
+    let replacement = png_image(300, 200);
+    store.put(&key, &replacement, "image/png").unwrap();
+    assert_eq!(store.get(&key).unwrap(), replacement);
+    assert_ne!(store.info(&key).unwrap().etag, info.etag);
+
+    store.delete(&key).unwrap();
+    assert!(store.get(&key).is_err_and(not_found));
+    // Deleting something that is already gone still succeeds
+    store.delete(&key).unwrap();
+    for bad in ["", "../escape.png", "/etc/passwd", "a/../../b.png"] {
+        let invalid = |e: std::io::Error| e.kind() == std::io::ErrorKind::InvalidInput;
+        assert!(
+            store.put(bad, &image, "image/png").is_err_and(invalid),
+            "{}",
+            bad
+        );
+        assert!(store.get(bad).is_err_and(invalid), "{}", bad);
+        assert!(store.delete(bad).is_err_and(invalid), "{}", bad);
+    }
+}
+
+#[actix_web::test]
+async fn local_store_keeps_objects_under_its_root() {
+    let root = test_dir("media");
+    let store = media::LocalStore::new(&root);
+    check_media_store(&store, "profile_pictures");
+    store
+        .put(
+            "profile_pictures/kept.png",
+            &png_image(200, 200),
+            "image/png",
+        )
+        .unwrap();
+    assert!(root.join("profile_pictures/kept.png").is_file());
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[actix_web::test]
+async fn s3_store_reports_unreachable_servers_as_errors() {
+    // Nothing listens on port 9, so every request fails without reaching a server
+    let store = media::S3Store::new(
+        "http://127.0.0.1:9",
+        "jaymatch",
+        "us-east-1",
+        "key",
+        "secret",
+    );
+    let image = png_image(200, 200);
+    let failed = |e: std::io::Error| e.kind() != std::io::ErrorKind::NotFound;
+    assert!(
+        store
+            .put("profile_pictures/a.png", &image, "image/png")
+            .is_err_and(failed)
+    );
+    assert!(store.get("profile_pictures/a.png").is_err_and(failed));
+    assert!(store.info("profile_pictures/a.png").is_err_and(failed));
+    assert!(store.delete("profile_pictures/a.png").is_err_and(failed));
+    let invalid = |e: std::io::Error| e.kind() == std::io::ErrorKind::InvalidInput;
+    assert!(store.get("../a.png").is_err_and(invalid));
+}
+
+// Runs against a real S3-compatible server, such as MinIO, only when TEST_S3_ENDPOINT is set
+// Also needs TEST_S3_BUCKET, TEST_S3_ACCESS_KEY and TEST_S3_SECRET_KEY, and optionally TEST_S3_REGION
+#[actix_web::test]
+async fn s3_store_stores_reads_and_removes_objects() {
+    let Ok(endpoint) = std::env::var("TEST_S3_ENDPOINT") else {
+        return;
+    };
+    let var = |name: &str| std::env::var(name).unwrap_or_else(|_| panic!("{} must be set", name));
+    let region = std::env::var("TEST_S3_REGION").unwrap_or_else(|_| "us-east-1".to_string());
+    let store = media::S3Store::new(
+        &endpoint,
+        &var("TEST_S3_BUCKET"),
+        &region,
+        &var("TEST_S3_ACCESS_KEY"),
+        &var("TEST_S3_SECRET_KEY"),
+    );
+    let prefix = format!("jaymatch-test-{}", uuid::Uuid::new_v4().simple());
+    check_media_store(&store, &prefix);
+}
+
+#[actix_web::test]
+async fn legacy_image_paths_are_moved_into_the_media_store() {
+    let root = test_dir("legacy");
+    let legacy_dir = root.join("uploads/profile_pictures");
+    fs::create_dir_all(&legacy_dir).unwrap();
+    let image = png_image(200, 200);
+    let path = legacy_dir.join("old.png").to_string_lossy().to_string();
+    fs::write(&path, &image).unwrap();
+    fs::write(images::thumbnail_path(&path, "small"), png_image(160, 160)).unwrap();
+    let missing = legacy_dir.join("gone.png").to_string_lossy().to_string();
+    let store = media::LocalStore::new(root.join("media"));
+    let conn = Connection::open_in_memory().unwrap();
+    db::init_db(&conn).unwrap();
+    conn.execute_batch(
+        "INSERT INTO profiles (user_id, email, password) VALUES (1, 'a@ku.edu', 'pw'), (2, 'b@ku.edu', 'pw');
+         INSERT INTO profile_photos (id, user_id, media_key, position, is_primary, created_at)
+         VALUES (1, 2, 'profile_pictures/current.jpg', 0, 1, 0);",
+    )
+    .unwrap();
+    conn.execute(
+        "INSERT INTO profile_photos (id, user_id, media_key, position, is_primary, created_at)
+         VALUES (2, 1, ?1, 0, 1, 0), (3, 1, ?2, 1, 0, 0)",
+        params![path, missing],
+    )
+    .unwrap();
+    conn.execute(
+        "UPDATE profiles SET profile_picture = ?1 WHERE user_id = 1",
+        params![path],
+    )
+    .unwrap();
+
+    assert_eq!(media::migrate_legacy_paths(&conn, &store).unwrap(), 1);
+    let key: String = conn
+        .query_row(
+            "SELECT media_key FROM profile_photos WHERE id = 2",
+            params![],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(key, "profile_pictures/old.png");
+    assert_eq!(store.get(&key).unwrap(), image);
+    assert!(store.get(&images::thumbnail_path(&key, "small")).is_ok());
+    assert_eq!(
+        count(
+            &conn,
+            "SELECT COUNT(*) FROM profiles WHERE user_id = ?1 AND profile_picture = 'profile_pictures/old.png'",
+            1
+        ),
+        1
+    );
+    // Rows whose file cannot be read and rows that already hold keys are left alone
+    let unread: String = conn
+        .query_row(
+            "SELECT media_key FROM profile_photos WHERE id = 3",
+            params![],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(unread, missing);
+    assert_eq!(
+        count(
+            &conn,
+            "SELECT COUNT(*) FROM profile_photos WHERE id = ?1 AND media_key = 'profile_pictures/current.jpg'",
+            1
+        ),
+        1
+    );
+    // The original file is kept and a second run has nothing to do until the missing file turns up
+    assert!(Path::new(&path).is_file());
+    assert_eq!(media::migrate_legacy_paths(&conn, &store).unwrap(), 0);
+    fs::write(&missing, &image).unwrap();
+    assert_eq!(media::migrate_legacy_paths(&conn, &store).unwrap(), 1);
+    assert_eq!(
+        count(
+            &conn,
+            "SELECT COUNT(*) FROM profile_photos WHERE id = ?1 AND media_key = 'profile_pictures/gone.png'",
+            3
+        ),
+        1
+    );
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[actix_web::test]
+async fn photos_are_served_with_etags_and_byte_ranges() {
+    let state = test_state();
+    let app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(configure_routes),
+    )
+    .await;
+    let req = test::TestRequest::post()
+        .uri("/users/new")
+        .set_json(serde_json::json!({"name": "a", "password": "pw", "email": "a@ku.edu"}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let image = png_image(300, 300);
+    let thumbnail = png_image(160, 160);
+    state
+        .media
+        .put("profile_pictures/p.png", &image, "image/png")
+        .unwrap();
+    state
+        .media
+        .put(
+            &images::thumbnail_path("profile_pictures/p.png", "small"),
+            &thumbnail,
+            "image/png",
+        )
+        .unwrap();
+    state
+        .db_conn
+        .lock()
+        .unwrap()
+        .execute(
+            "INSERT INTO profile_photos (id, user_id, media_key, position, is_primary, created_at)
+             VALUES (1, 1, 'profile_pictures/p.png', 0, 1, 0)",
+            params![],
+        )
+        .unwrap();
+    let len = image.len();
+    let get = |headers: &[(&'static str, String)]| {
+        let mut req = test::TestRequest::get().uri("/photos/1");
+        for (name, value) in headers {
+            req = req.insert_header((*name, value.clone()));
+        }
+        req.to_request()
+    };
+    let header = |resp: &actix_web::dev::ServiceResponse, name: &str| {
+        resp.headers()
+            .get(name)
+            .map(|v| v.to_str().unwrap().to_string())
+    };
+
+    // A plain request gets the whole image with its entity tag
+    let resp = test::call_service(&app, get(&[])).await;
+    assert_eq!(resp.status(), 200);
+    let etag = header(&resp, "etag").unwrap();
+    assert_eq!(header(&resp, "accept-ranges").unwrap(), "bytes");
+    assert_eq!(header(&resp, "content-type").unwrap(), "image/png");
+    assert!(header(&resp, "cache-control").unwrap().contains("max-age"));
+    assert_eq!(test::read_body(resp).await.to_vec(), image);
+
+    // A matching If-None-Match is answered without a body
+    for tags in [
+        etag.clone(),
+        format!("\"other\", W/{}", etag),
+        "*".to_string(),
+    ] {
+        let resp = test::call_service(&app, get(&[("if-none-match", tags.clone())])).await;
+        assert_eq!(resp.status(), 304, "{}", tags);
+        assert_eq!(header(&resp, "etag").unwrap(), etag);
+        assert!(test::read_body(resp).await.is_empty());
+    }
+    let resp = test::call_service(&app, get(&[("if-none-match", "\"other\"".to_string())])).await;
+    assert_eq!(resp.status(), 200);
+
+    // Satisfiable ranges return 206 with the matching Content-Range
+    let ranges = [
+        ("bytes=0-9", 0, 9),
+        ("bytes=10-", 10, len - 1),
+        ("bytes=-5", len - 5, len - 1),
+        ("bytes=5-100000", 5, len - 1),
+    ];
+    for (range, start, end) in ranges {
+        let resp = test::call_service(&app, get(&[("range", range.to_string())])).await;
+        assert_eq!(resp.status(), 206, "{}", range);
+        assert_eq!(
+            header(&resp, "content-range").unwrap(),
+            format!("bytes {}-{}/{}", start, end, len)
+        );
+        assert_eq!(header(&resp, "etag").unwrap(), etag);
+        assert_eq!(
+            test::read_body(resp).await.to_vec(),
+            image[start..=end].to_vec(),
+            "{}",
+            range
+        );
+    }
+
+    // Ranges past the end cannot be satisfied
+    for range in [format!("bytes={}-", len), "bytes=-0".to_string()] {
+        let resp = test::call_service(&app, get(&[("range", range.clone())])).await;
+        assert_eq!(resp.status(), 416, "{}", range);
+        assert_eq!(
+            header(&resp, "content-range").unwrap(),
+            format!("bytes */{}", len)
+        );
+    }
+    // Ranges in other units or with several parts are ignored
+    for range in ["items=0-9", "bytes=0-1,4-5"] {
+        let resp = test::call_service(&app, get(&[("range", range.to_string())])).await;
+        assert_eq!(resp.status(), 200, "{}", range);
+    }
+
+    // If-Range only allows the range while the client's copy is current
+    let resp = test::call_service(
+        &app,
+        get(&[
+            ("range", "bytes=0-9".to_string()),
+            ("if-range", "\"stale\"".to_string()),
+        ]),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+    assert!(header(&resp, "content-range").is_none());
+    assert_eq!(test::read_body(resp).await.to_vec(), image);
+    let resp = test::call_service(
+        &app,
+        get(&[
+            ("range", "bytes=0-9".to_string()),
+            ("if-range", etag.clone()),
+        ]),
+    )
+    .await;
+    assert_eq!(resp.status(), 206);
+    assert_eq!(test::read_body(resp).await.len(), 10);
+
+    // Thumbnails have entity tags of their own and missing sizes fall back to the full image
+    let req = test::TestRequest::get()
+        .uri("/photos/1?size=small")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    assert_ne!(header(&resp, "etag").unwrap(), etag);
+    assert_eq!(test::read_body(resp).await.to_vec(), thumbnail);
+    let req = test::TestRequest::get()
+        .uri("/photos/1?size=medium")
+        .to_request();
+    assert_eq!(
+        test::read_body(test::call_service(&app, req).await)
+            .await
+            .to_vec(),
+        image
+    );
+    let req = test::TestRequest::get()
+        .uri("/photos/1?size=huge")
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 400);
+    let req = test::TestRequest::get().uri("/photos/2").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 404);
+    remove_test_files(&state);
+}
+
+#[actix_web::test]
+async fn photos_are_hidden_like_the_profile_they_belong_to() {
+    let state = test_state();
+    let app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .configure(configure_routes),
+    )
+    .await;
+    for name in ["alice", "bob", "carol"] {
+        let req = test::TestRequest::post()
+            .uri("/users/new")
+            .set_json(serde_json::json!({"name": name, "password": "pw", "email": format!("{}@ku.edu", name)}))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+    state
+        .media
+        .put("profile_pictures/p.png", &png_image(100, 100), "image/png")
+        .unwrap();
+    state
+        .db_conn
+        .lock()
+        .unwrap()
+        .execute(
+            "INSERT INTO profile_photos (id, user_id, media_key, position, is_primary, created_at)
+             VALUES (1, 1, 'profile_pictures/p.png', 0, 1, 0)",
+            params![],
+        )
+        .unwrap();
+    let owner = bearer(&state, 1);
+    let bob = bearer(&state, 2);
+    let carol = bearer(&state, 3);
+    let statuses = |auth: Option<(&'static str, String)>| {
+        let photo = test::TestRequest::get().uri("/photos/1");
+        let list = test::TestRequest::get().uri("/users/1/photos");
+        match auth {
+            Some(auth) => [photo.insert_header(auth.clone()), list.insert_header(auth)],
+            None => [photo, list],
+        }
+        .map(|req| req.to_request())
+    };
+    let check = async |auth: Option<(&'static str, String)>, expected: u16| {
+        for req in statuses(auth) {
+            let path = req.path().to_string();
+            assert_eq!(
+                test::call_service(&app, req).await.status(),
+                expected,
+                "{}",
+                path
+            );
+        }
+    };
+    let execute = |sql: &str| {
+        state
+            .db_conn
+            .lock()
+            .unwrap()
+            .execute(sql, params![])
+            .unwrap();
+    };
+    check(None, 200).await;
+    check(Some(bob.clone()), 200).await;
+
+    // Blocked users and anonymous viewers lose the photos of someone who blocked
+    execute("INSERT INTO blocked_users (user_id, blocked_user_id, timestamp) VALUES (1, 2, 0)");
+    check(Some(bob.clone()), 404).await;
+    check(None, 404).await;
+    check(Some(carol.clone()), 200).await;
+    check(Some(owner.clone()), 200).await;
+    execute("DELETE FROM blocked_users");
+
+    // Hidden profiles keep their photos from everyone but the owner and matches
+    execute("UPDATE profiles SET visibility = 'hidden_except_matches' WHERE user_id = 1");
+    check(Some(carol.clone()), 404).await;
+    check(Some(owner.clone()), 200).await;
+    execute("UPDATE profiles SET visibility = 'active' WHERE user_id = 1");
+
+    // Accounts pending deletion only show their photos to the owner
+    execute("UPDATE profiles SET deletion_due_at = 9999999999999 WHERE user_id = 1");
+    check(Some(bob), 404).await;
+    check(Some(carol), 404).await;
+    check(Some(owner), 200).await;
+    remove_test_files(&state);
+}
@@ -0,0 +1,169 @@
+/*
+Name: JayMatch profile visibility
+Description: Lets users pause their profile or hide it from everyone but their matches without deleting their account, and lets premium users browse incognito
+Pre/Post Conditions: profiles.visibility and profiles.incognito must exist. Only active profiles are shown in queues; hidden_except_matches profiles can only be viewed by their owner and their matches. Incognito profiles are only shown in the queues of users they have liked, for as long as the owner stays premium. Matches and messages keep working in every state.
+Errors: Unknown visibility states return 400, turning on incognito without premium returns 403, unknown users return 404, restricted accounts receive 403, database errors return 500.
+*/
+
+use crate::{AppState, entitlements, moderation};
+use actix_web::{HttpResponse, Responder, web};
+use log::info;
+use rusqlite::{Connection, params};
+use serde::Deserialize;
+
+// Visibility states a profile can be in
+// active: shown in queues as usual
+// paused: left out of queues, but anyone can still view the profile
+// hidden_except_matches: left out of queues and only viewable by the user's matches
+pub const STATES: &[&str] = &["active", "paused", "hidden_except_matches"];
+
+// Condition that a profiles row can be shown in other users' queues
+pub const QUEUE_SQL: &str = "profiles.visibility = 'active'";
+
+// Condition that a profiles row can be viewed by users it is not matched with
+pub const UNHIDDEN_SQL: &str = "profiles.visibility != 'hidden_except_matches'";
+
+// Function for the SQL condition that a profiles row can be shown in the queue of the user bound to ?1
+// Incognito only hides users who are still premium, so it stops applying when premium ends
+pub fn incognito_sql() -> String {
+    format!(
+        "(profiles.incognito = 0 OR NOT {} OR EXISTS (
+            SELECT 1 FROM swipes
+            WHERE swipes.user_id = profiles.user_id AND swipes.target_user_id = ?1
+              AND swipes.direction = 'like'))",
+        entitlements::PREMIUM_SQL
+    )
+}
+
+// Structure for changing a user's visibility
+#[derive(Deserialize)]
+pub struct VisibilityUpdate {
+    pub visibility: String,
+}
+
+// Structure for turning incognito on or off
+#[derive(Deserialize)]
+pub struct IncognitoUpdate {
+    pub incognito: bool,
+}
+
+// Helper function for checking if two users are matched
+fn are_matched(conn: &Connection, a: i32, b: i32) -> bool {
+    let (lower_id, higher_id) = if a < b { (a, b) } else { (b, a) };
+    conn.query_row(
+        "SELECT 1 FROM matches WHERE user_id = ?1 AND matched_user_id = ?2",
+        params![lower_id, higher_id],
+        |_| Ok(()),
+    )
+    .is_ok()
+}
+
+// Helper function for checking if a profile is hidden from a viewer
+// Owners and matches always see the profile; viewer is the user behind the request's session, or None without one
+// Returns false on database errors so callers fall back to normal behavior
+pub fn hidden_from(conn: &Connection, viewer: Option<i32>, owner: i32) -> bool {
+    let hidden = conn
+        .query_row(
+            "SELECT 1 FROM profiles WHERE user_id = ?1 AND visibility = 'hidden_except_matches'",
+            params![owner],
+            |_| Ok(()),
+        )
+        .is_ok();
+    hidden && viewer.is_none_or(|v| v != owner && !are_matched(conn, v, owner))
+}
+
+// API for reading a user's visibility: GET /profiles/{user_id}/visibility
+// Also says whether the user has incognito turned on
+pub async fn get_visibility(user_id: web::Path<i32>, state: web::Data<AppState>) -> impl Responder {
+    let uid = user_id.into_inner();
+    let conn = state.db_conn.lock().unwrap();
+    let result = conn.query_row(
+        "SELECT visibility, incognito FROM profiles WHERE user_id = ?1",
+        params![uid],
+        |row| Ok((row.get::<_, String>(0)?, row.get::<_, bool>(1)?)),
+    );
+    match result {
+        Ok((visibility, incognito)) => HttpResponse::Ok().json(serde_json::json!({
+            "user_id": uid,
+            "visibility": visibility,
+            "incognito": incognito
+        })),
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            HttpResponse::NotFound().body("Profile not found")
+        }
+        Err(_) => HttpResponse::InternalServerError().body("Database error"),
+    }
+}
+
+// API for pausing, hiding or reactivating a profile: PUT /profiles/{user_id}/visibility
+// Takes one of STATES; existing matches and chats are not changed
+pub async fn put_visibility(
+    user_id: web::Path<i32>,
+    data: web::Json<VisibilityUpdate>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let uid = user_id.into_inner();
+    if !STATES.contains(&data.visibility.as_str()) {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "success": false,
+            "message": format!("visibility must be one of: {}", STATES.join(", "))
+        }));
+    }
+    let conn = state.db_conn.lock().unwrap();
+    if let Some(r) = moderation::account_restriction(&conn, uid) {
+        return moderation::restricted_response(&r);
+    }
+    match conn.execute(
+        "UPDATE profiles SET visibility = ?1 WHERE user_id = ?2",
+        params![data.visibility, uid],
+    ) {
+        Ok(0) => HttpResponse::NotFound().body("Profile not found"),
+        Ok(_) => {
+            info!("User {} set their visibility to {}", uid, data.visibility);
+            HttpResponse::Ok().json(serde_json::json!({
+                "success": true,
+                "user_id": uid,
+                "visibility": data.visibility
+            }))
+        }
+        Err(e) => HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
+    }
+}
+
+// API for turning incognito browsing on or off: PUT /profiles/{user_id}/incognito
+// While on, the profile only appears in the queues of users it has liked
+// Turning it on needs the incognito entitlement; turning it off is always allowed
+pub async fn put_incognito(
+    user_id: web::Path<i32>,
+    data: web::Json<IncognitoUpdate>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let uid = user_id.into_inner();
+    let conn = state.db_conn.lock().unwrap();
+    if let Some(r) = moderation::account_restriction(&conn, uid) {
+        return moderation::restricted_response(&r);
+    }
+    let incognito = entitlements::Entitlement::Incognito;
+    if data.incognito && !entitlements::has(&conn, uid, incognito) {
+        return entitlements::premium_required(incognito);
+    }
+    match conn.execute(
+        "UPDATE profiles SET incognito = ?1 WHERE user_id = ?2",
+        params![data.incognito, uid],
+    ) {
+        Ok(0) => HttpResponse::NotFound().body("Profile not found"),
+        Ok(_) => {
+            info!(
+                "User {} turned incognito {}",
+                uid,
+                if data.incognito { "on" } else { "off" }
+            );
+            HttpResponse::Ok().json(serde_json::json!({
+                "success": true,
+                "user_id": uid,
+                "incognito": data.incognito
+            }))
+        }
+        Err(e) => HttpResponse::InternalServerError().body(format!("DB error: {}", e)),
+    }
+}
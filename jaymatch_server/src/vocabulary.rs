@@ -0,0 +1,42 @@
+/*
+Name: JayMatch profile vocabulary
+Description: Matches values typed by users, such as class years and KU majors, to canonical names and the aliases users commonly type for them
+Pre/Post Conditions: The names and aliases come from the choice attributes in the attribute schema (attributes.json). Free text from clients is mapped to a canonical name before it is saved.
+Errors: Values that match no name or alias are rejected by the caller; the startup migration moves them aside for review.
+*/
+
+use std::collections::BTreeMap;
+
+// Helper function for the form of a name used when matching
+// Lowercases it and drops everything but letters and digits, with & read as "and"
+pub fn match_key(value: &str) -> String {
+    value
+        .replace('&', "and")
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+// Function for finding the canonical name for a value
+// Checks the canonical names first and then the aliases, which map an alias to a name
+pub fn canonicalize<'a>(
+    value: &str,
+    names: &'a [String],
+    aliases: &'a BTreeMap<String, String>,
+) -> Option<&'a str> {
+    let key = match_key(value);
+    if key.is_empty() {
+        return None;
+    }
+    names
+        .iter()
+        .find(|name| match_key(name) == key)
+        .or_else(|| {
+            aliases
+                .iter()
+                .find(|(alias, _)| match_key(alias) == key)
+                .map(|(_, name)| name)
+        })
+        .map(String::as_str)
+}